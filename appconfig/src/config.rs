@@ -58,38 +58,74 @@ impl ConfigItem {
 pub struct Config {
     data: Vec<u8>,
     key_values: Vec<ConfigItem>,
+    entries: Vec<(String, String)>,     // toml格式解析后的键值, 键以"表名.键名"表示
 }
 
 /// Config Implementation
 impl Config {
+    /// Load config from file, files with the `.toml` extension are parsed as TOML
     pub fn with_file<T: AsRef<Path>>(file: T) -> anyhow::Result<Self> {
+        let is_toml = file.as_ref().extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
         let data = fs::read(file)?;
-        std::str::from_utf8(&data)?;
+        let text = std::str::from_utf8(&data)?;
+        if is_toml {
+            return Self::with_toml(text);
+        }
         let kv = Self::parse(&data)?;
-        Ok(Self {data, key_values: kv})
+        Ok(Self {data, key_values: kv, entries: Vec::new()})
     }
 
     pub fn with_text(text: String) -> anyhow::Result<Self> {
         let data = text.into_bytes();
         let kv = Self::parse(&data)?;
-        Ok(Self {data, key_values: kv})
+        Ok(Self {data, key_values: kv, entries: Vec::new()})
     }
 
     pub fn with_data(data: Vec<u8>) -> anyhow::Result<Self> {
         let kv = Self::parse(&data)?;
         std::str::from_utf8(&data)?;
-        Ok(Self {data, key_values: kv})
+        Ok(Self {data, key_values: kv, entries: Vec::new()})
+    }
+
+    /// Parse TOML text, keys in tables are named `table.key`, keys in arrays of tables are named
+    /// `table.N.key`, arrays of simple values are joined with commas
+    ///
+    /// A key like `log-level` is also found as `level` in the `[log]` table.
+    pub fn with_toml(text: &str) -> anyhow::Result<Self> {
+        let entries = crate::toml::parse(text)?;
+        Ok(Self {data: Vec::new(), key_values: Vec::new(), entries})
+    }
+
+    /// Config from key value pairs, like the variables read from environment
+    pub fn with_entries(entries: Vec<(String, String)>) -> Self {
+        Self {data: Vec::new(), key_values: Vec::new(), entries}
+    }
+
+    /// All keys and values in the table `name`(keys with the `name.` prefix), the prefix is removed
+    pub fn table(&self, name: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let prefix = format!("{name}.");
+        let mut result = Vec::new();
+        for (key, val) in self.entries.iter() {
+            if let Some(key) = key.strip_prefix(&prefix) {
+                result.push((key.to_string(), val.clone()));
+            }
+        }
+        for kv in self.key_values.iter() {
+            let key = unsafe { std::str::from_utf8_unchecked(&self.data[kv.key_begin..kv.key_end]) };
+            if let Some(key) = key.strip_prefix(&prefix) {
+                result.push((key.to_string(), Self::decode(&self.data[kv.val_begin..kv.val_end])?.into_owned()));
+            }
+        }
+        Ok(result)
     }
 
     /// Get a value from config as ayn type (That Impls str::FromStr)
     pub fn get<T>(&self, key: &str) -> anyhow::Result<Option<T>>
             where T: FromStr, T::Err: Display {
-        match self.get_raw(key) {
-            Some(s) => {
-                match Self::decode(s)?.parse::<T>() {
-                    Ok(v) => Ok(Some(v)),
-                    Err(e) => return Err(anyhow::anyhow!("can't parse value error: {e}")),
-                }
+        match self.get_str(key)? {
+            Some(s) => match s.parse::<T>() {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => Err(anyhow::anyhow!("can't parse value error: {e}")),
             },
             None => Ok(None),
         }
@@ -97,6 +133,9 @@ impl Config {
 
     /// Get a value from config as a String
     pub fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+        if let Some(val) = self.get_entry(key) {
+            return Ok(Some(val.to_string()));
+        }
         match self.get_raw(key) {
             Some(s) => Ok(Some(Self::decode(s)?.into_owned())),
             None => Ok(None),
@@ -105,6 +144,9 @@ impl Config {
 
     /// Get a value as original data (not escape)
     pub fn get_raw<'a>(&'a self, key: &str) -> Option<&'a [u8]> {
+        if let Some(val) = self.get_entry(key) {
+            return Some(val.as_bytes());
+        }
         let key = key.as_bytes();
         for kv in self.key_values.iter() {
             if key == &self.data[kv.key_begin..kv.key_end] {
@@ -114,6 +156,12 @@ impl Config {
         None
     }
 
+    // 查找toml格式的键值, 找不到时把键的第一个'-'当作表名分隔符, 如log-level对应[log]表中的level
+    fn get_entry(&self, key: &str) -> Option<&str> {
+        let find = |key: &str| self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        find(key).or_else(|| key.contains('-').then(|| find(&key.replacen('-', ".", 1))).flatten())
+    }
+
     // decode value
     fn decode<'a>(val: &'a [u8]) -> anyhow::Result<Cow<'a, str>> {
         // 删除尾部空白
@@ -346,7 +394,7 @@ impl Config {
 
 impl Default for Config {
     fn default() -> Config {
-        Config {data: Vec::with_capacity(0), key_values: Vec::with_capacity(0)}
+        Config {data: Vec::with_capacity(0), key_values: Vec::with_capacity(0), entries: Vec::new()}
     }
 }
 
@@ -373,4 +421,24 @@ age=48#this is age
         assert_eq!("", cf.get_str("this").unwrap().unwrap());
         assert_eq!("ma\n\\n", cf.get_str("sex").unwrap().unwrap());
     }
+
+    #[test]
+    fn test_toml_config() {
+        let cf = Config::with_toml(r#"
+port = 53
+hosts-file = ["a.conf", "b.conf"]
+[log]
+level = "debug"
+[forward]
+lan = "192.168.1.1"
+corp = "10.0.0.1"
+"#).unwrap();
+
+        assert_eq!(Some(53), cf.get::<u16>("port").unwrap());
+        assert_eq!("a.conf,b.conf", cf.get_str("hosts-file").unwrap().unwrap());
+        assert_eq!("debug", cf.get_str("log-level").unwrap().unwrap());
+        assert_eq!(None, cf.get_str("log-file").unwrap());
+        assert_eq!(vec![("lan".to_string(), "192.168.1.1".to_string()), ("corp".to_string(), "10.0.0.1".to_string())],
+                cf.table("forward").unwrap());
+    }
 }
@@ -0,0 +1,91 @@
+//! .env文件解析
+//!
+//! 每行一个`KEY=VALUE`, 支持`export `前缀, #开头的注释行, 值后面以` #`开始的注释,
+//! 单引号(原样)和双引号(支持\n \r \t \" \\转义)包围的值
+
+use anyhow::Context;
+
+/// 读取.env文件, `path`为None时读取当前目录下的.env, 该文件不存在时返回空列表
+pub(crate) fn load(path: Option<&str>) -> anyhow::Result<Vec<(String, String)>> {
+    let text = match path {
+        Some(path) => std::fs::read_to_string(path).with_context(|| format!("can't read env file {path}"))?,
+        None => match std::fs::read_to_string(".env") {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("can't read env file .env"),
+        },
+    };
+    parse(&text).with_context(|| format!("parse env file {} failed", path.unwrap_or(".env")))
+}
+
+/// 解析.env文本, 返回变量名及值的列表
+pub(crate) fn parse(text: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut result = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (key, value) = line.split_once('=')
+            .with_context(|| format!("not found '=' at line {}", i + 1))?;
+        let key = key.trim();
+        if key.is_empty() || !key.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+            anyhow::bail!("invalid variable name at line {}", i + 1);
+        }
+        let value = parse_value(value.trim())
+            .with_context(|| format!("unterminated quoted value at line {}", i + 1))?;
+        result.push((key.to_owned(), value));
+    }
+    Ok(result)
+}
+
+// 解析变量值, 引号未闭合时返回None
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(s) = value.strip_prefix('\'') {
+        return s.find('\'').map(|end| s[..end].to_owned());
+    }
+    if let Some(s) = value.strip_prefix('"') {
+        let mut v = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(v),
+                '\\' => match chars.next()? {
+                    'n' => v.push('\n'),
+                    'r' => v.push('\r'),
+                    't' => v.push('\t'),
+                    c => v.push(c),
+                },
+                c => v.push(c),
+            }
+        }
+        return None;
+    }
+    // 未加引号的值, 空格加#之后为注释
+    let end = value.find(" #").unwrap_or(value.len());
+    Some(value[..end].trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let text = "# comment\n\nMINIDNS_PORT=5353\nexport MINIDNS_LOG_LEVEL = debug # inline\n\
+            MINIDNS_KEY='a#b\\n'\nMINIDNS_HOOK=\"echo \\\"hi\\\"\\n\"\nEMPTY=\n";
+        let vars = parse(text).unwrap();
+        assert_eq!(vec![
+            (String::from("MINIDNS_PORT"), String::from("5353")),
+            (String::from("MINIDNS_LOG_LEVEL"), String::from("debug")),
+            (String::from("MINIDNS_KEY"), String::from("a#b\\n")),
+            (String::from("MINIDNS_HOOK"), String::from("echo \"hi\"\n")),
+            (String::from("EMPTY"), String::new()),
+        ], vars);
+
+        assert!(parse("NO_VALUE\n").is_err());
+        assert!(parse("BAD-NAME=1\n").is_err());
+        assert!(parse("A=\"abc\n").is_err());
+    }
+}
@@ -5,29 +5,45 @@ pub use anyhow;
 #[cfg(feature="cfg-file")]
 mod config;
 #[cfg(feature="cfg-file")]
+mod toml;
+#[cfg(feature="cfg-file")]
+mod watch;
+mod dotenv;
+#[cfg(feature="cfg-file")]
 pub use config::Config;
+#[cfg(feature="cfg-file")]
+pub use watch::{Change, ConfigWatcher, diff, watch_config};
 #[cfg(not(feature="cfg-file"))]
-pub struct Config;
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
 
 #[cfg(not(feature="cfg-file"))]
 impl Config {
-    pub fn get_str(&self, _: &str) -> anyhow::Result<Option<String>> {
-        Ok(None)
+    pub fn with_entries(entries: Vec<(String, String)>) -> Self {
+        Self { entries }
+    }
+
+    pub fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()))
     }
 }
 
 /// Application Parameter Definition Macro.
 ///
+/// Field types are `String`, `bool`, `Vec<String>`, `Duration`(see [`parse_duration`]), [`Size`]
+/// and other types implementing `FromStr` and `Display` like `u16`, `u32`, `IpAddr` or `SocketAddr`.
+///
 /// ## Examples
 ///
-/// ```rust
+/// ```no_run
 /// use appconfig;
 ///
 /// appconfig::appconfig_define!(AppConf,
 ///     log_level: String => ["L",  "log-level", "LogLevel", "log level(trace/debug/info/warn/error/off)"],
 ///     log_file : String => ["F",  "log-file", "LogFile", "log filename"],
 ///     log_max  : String => ["M",  "log-max", "LogFileMaxSize", "log file max size(unit: k/m/g)"],
-///     listen   : String => ["l",  "", "Listen", "http service ip:port"],
+///     listen   : String => ["l",  "listen", "Listen", "http service ip:port"],
 ///     debug    : bool   => ["",  "debug", "", "debug mode"],
 /// );
 ///
@@ -44,21 +60,28 @@ impl Config {
 /// }
 ///
 /// let mut ac = AppConf::default();
-/// if !appconfig::parse_args(&mut ac, "example application").unwrap();
+/// if !appconfig::parse_args(&mut ac, "example application").unwrap() {
 ///     return;
 /// }
 /// ```
 #[macro_export]
 macro_rules! appconfig_define {
-    // set_opt_flag
+    // set_opt_flag, $val为缺省值的字符串形式
     (@set_opt_flag $opts:expr, $short_opt:literal, $long_opt:literal, $opt_name:literal, $desc:literal, $val:expr, bool) => {
         let s = format!("{} (\x1b[34mdefault: \x1b[32m{}\x1b[0m)", $desc, $val);
         $opts.optflag($short_opt, $long_opt, &s)
     };
+    (@set_opt_flag $opts:expr, $short_opt:literal, $long_opt:literal, $opt_name:literal, $desc:literal, $val:expr, Vec) => {
+        let s = match $val.is_empty() {
+            true => format!("{} (repeatable)", $desc),
+            false => format!("{} (repeatable, \x1b[34mdefault: \x1b[32m{}\x1b[0m)", $desc, $val),
+        };
+        $opts.optmulti($short_opt, $long_opt, &s, $opt_name)
+    };
     (@set_opt_flag $opts:expr, $short_opt:literal, $long_opt:literal, $opt_name:literal, $desc:literal, $val:expr, $_:ty) => {
-        let s = match $val.len() {
-            0 => String::from($desc),
-            _ => format!("{} (\x1b[34mdefault: \x1b[32m{}\x1b[0m)", $desc, $val),
+        let s = match $val.is_empty() {
+            true => String::from($desc),
+            false => format!("{} (\x1b[34mdefault: \x1b[32m{}\x1b[0m)", $desc, $val),
         };
         $opts.optopt($short_opt, $long_opt, &s, $opt_name)
     };
@@ -76,50 +99,84 @@ macro_rules! appconfig_define {
             $out_val = true;
         }
     };
+    (@get_opt_value $matches:expr, $name:expr, $out_val:expr, Vec) => {
+        let values = $matches.opt_strs($name);
+        if !values.is_empty() {
+            $out_val = values.iter().flat_map(|s| $crate::split_list(s)).collect();
+        }
+    };
+    (@get_opt_value $matches:expr, $name:expr, $out_val:expr, Duration) => {
+        if let Some(s) = $matches.opt_str($name) {
+            $out_val = $crate::anyhow::Context::with_context($crate::parse_duration(&s),
+                || format!("program argument {} is not a valid duration: {}", $name, s))?;
+        }
+    };
     (@get_opt_value $matches:expr, $name:expr, $out_val:expr, $t:ty) => {
         if let Some(s) = $matches.opt_str($name) {
-            $out_val = anyhow::Context::with_context(s.parse::<$t>(),
-                || format!("program argument {} is not a numbe", $name))?;
+            $out_val = $crate::anyhow::Context::with_context(s.trim().parse::<$t>(),
+                || format!("program argument {} has invalid value: {}", $name, s))?;
         }
     };
 
     // get_cfg_value
     (@get_cfg_value $cfg: expr, "conf-file", $out_val: expr, $t:ty) => {};
     (@get_cfg_value $cfg: expr, $name: expr, $out_val: expr, String) => {
-        if let Ok(s) = $cfg.get_str($name) {
-            if let Some(s) = s {
-                $out_val = s;
-            }
+        if let Ok(Some(s)) = $cfg.get_str($name) {
+            $out_val = s;
         }
     };
     (@get_cfg_value $cfg: expr, $name: expr, $out_val: expr, bool) => {
-        if let Ok(s) = $cfg.get_str($name) {
-            if let Some(s) = s {
-                $out_val = s.to_lowercase() == "true";
-            }
+        if let Ok(Some(s)) = $cfg.get_str($name) {
+            $out_val = s.to_lowercase() == "true";
         }
     };
+    (@get_cfg_value $cfg: expr, $name: expr, $out_val: expr, Vec) => {
+        if let Ok(Some(s)) = $cfg.get_str($name) {
+            $out_val = $crate::split_list(&s);
+        }
+    };
+
+    (@get_cfg_value $cfg: expr, $name: expr, $out_val: expr, Duration) => {
+        if let Ok(Some(s)) = $cfg.get_str($name) {
+            $out_val = $crate::anyhow::Context::with_context($crate::parse_duration(&s),
+                || format!("app config key {} is not a valid duration: {}", $name, s))?;
+        }
+    };
+
+    // to_string
+    (@to_string $val:expr, Vec) => { $val.join(",") };
+    (@to_string $val:expr, Duration) => { $crate::format_duration($val) };
+    (@to_string $val:expr, $t:ty) => { $val.to_string() };
+
+    // rule
+    (@rule required) => { $crate::Rule::Required };
+    (@rule range($min:expr, $max:expr)) => { $crate::Rule::Range($min, $max) };
+    (@rule min($min:expr)) => { $crate::Rule::Range($min, i64::MAX) };
+    (@rule max($max:expr)) => { $crate::Rule::Range(i64::MIN, $max) };
+    (@rule secret) => { $crate::Rule::Secret };
+    (@rule group($name:expr)) => { $crate::Rule::Group($name) };
+    (@rule hidden) => { $crate::Rule::Hidden };
     (@get_cfg_value $cfg: expr, $name: expr, $out_val: expr, $t:ty) => {
-        if let Ok(s) = $cfg.get_str($name) {
-            if let Some(s) = s {
-                $out_val = s.parse::<$t>().with_context(
-                    || format!("app config file key {} is not a number", $name))?;
-            }
+        if let Ok(Some(s)) = $cfg.get_str($name) {
+            $out_val = $crate::anyhow::Context::with_context(s.trim().parse::<$t>(),
+                || format!("app config key {} has invalid value: {}", $name, s))?;
         }
     };
 
-    ( $struct_name:ident, $($field:ident : $type:tt =>
-            [$short_opt:literal, $long_opt:tt, $opt_name:literal, $desc:literal]$(,)?)+ ) => {
+    ( $struct_name:ident, $($field:ident : $type:ident $(<$param:ident>)? =>
+            [$short_opt:literal, $long_opt:tt, $opt_name:literal, $desc:literal]
+            $(@ $rule:ident $(($($arg:expr),*))?)* $(,)?)+ ) => {
 
         #[derive(Debug)]
         pub struct $struct_name {
-            $( pub $field: $type,)*
+            $( pub $field: $type $(<$param>)?,)*
         }
 
         impl $crate::AppConfig for $struct_name {
             fn to_opts(&self) -> $crate::Options {
                 let mut opts = $crate::Options::new();
-                $( $crate::appconfig_define!(@set_opt_flag opts, $short_opt, $long_opt, $opt_name, $desc, self.$field, $type); )*
+                $( $crate::appconfig_define!(@set_opt_flag opts, $short_opt, $long_opt, $opt_name, $desc,
+                        $crate::appconfig_define!(@to_string self.$field, $type), $type); )*
                 opts
             }
 
@@ -132,8 +189,15 @@ macro_rules! appconfig_define {
                 $( $crate::appconfig_define!(@get_cfg_value cfg, $long_opt, self.$field, $type); )*
                 Ok(())
             }
+
+            fn fields(&self) -> Vec<$crate::Field> {
+                vec![ $( $crate::Field { name: $long_opt, desc: $desc,
+                        value: $crate::appconfig_define!(@to_string self.$field, $type),
+                        rules: vec![ $( $crate::appconfig_define!(@rule $rule $(($($arg),*))?) ),* ] }, )* ]
+            }
         }
 
+        #[allow(dead_code)]
         impl $struct_name {
             fn init() -> &'static mut Self {
                 unsafe {
@@ -144,21 +208,30 @@ macro_rules! appconfig_define {
                         }
                         FLAG = true;
                     }
-                    __APP_CONFIG.write(Self::default())
+                    (*Self::storage()).write(Self::default())
                 }
             }
 
             fn get() -> &'static Self {
-                unsafe { &*__APP_CONFIG.as_ptr() }
+                unsafe { &*(*Self::storage()).as_ptr() }
             }
-        }
 
-        pub static mut __APP_CONFIG: std::mem::MaybeUninit<$struct_name> = std::mem::MaybeUninit::uninit();
+            // 全局变量定义在函数内, 同一模块可以定义多个配置结构(如每个子命令一个)
+            fn storage() -> *mut std::mem::MaybeUninit<Self> {
+                static mut __APP_CONFIG: std::mem::MaybeUninit<$struct_name> = std::mem::MaybeUninit::uninit();
+                std::ptr::addr_of_mut!(__APP_CONFIG)
+            }
+        }
 
     };
 }
 
 const C_HELP: &str = "help";
+const C_HELP_ALL: &str = "help-all";
+const C_VERSION: &str = "version";
+const C_GEN_CONFIG: &str = "gen-config";
+const C_DUMP_CONFIG: &str = "dump-config";
+const C_ENV_FILE: &str = "env-file";
 #[cfg(feature="cfg-file")]
 const C_CONF_FILE: &str = "conf-file";
 
@@ -166,6 +239,340 @@ pub trait AppConfig {
     fn to_opts(&self) -> getopts::Options;
     fn set_from_getopts(&mut self, matches: &getopts::Matches) -> anyhow::Result<()>;
     fn set_from_cfg(&mut self, cfg: &Config) -> anyhow::Result<()>;
+    /// All fields with their config key(long option name), description and current value
+    fn fields(&self) -> Vec<Field>;
+}
+
+/// Field of the application config, see [`AppConfig::fields`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,     // 配置项名称, 即长选项名称
+    pub desc: &'static str,     // 配置项说明
+    pub value: String,          // 配置项的值
+    pub rules: Vec<Rule>,       // 配置项的校验规则
+}
+
+/// Validation rule of a config field, declared after the option definition in [`appconfig_define`]:
+/// `@required`, `@range(1, 65535)`, `@min(1)`, `@max(100)`, `@secret`, `@group("Logging")` or `@hidden`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// The value can't be empty
+    Required,
+    /// The value must be an integer in the range(inclusive), empty values are not checked
+    Range(i64, i64),
+    /// The value is a secret, it is masked by [`dump_config`] and can be read from a file:
+    /// a value like `@/path/to/file`(use `@@` for a leading `@`), or the environment variable
+    /// named like the option's variable with the `_FILE` suffix, unless it is the variable of
+    /// another option
+    Secret,
+    /// The option and the following options until the next group are listed in this section of
+    /// the help output, options before the first group are listed in the `Options` section
+    Group(&'static str),
+    /// The option is listed only by `--help-all`
+    Hidden,
+}
+
+impl Rule {
+    // 校验配置项的值, 不符合规则时返回错误说明
+    fn check(&self, value: &str) -> Option<String> {
+        match *self {
+            Rule::Required if value.is_empty() => Some(String::from("is required")),
+            Rule::Range(min, max) if !value.is_empty() => match value.parse::<i64>() {
+                Ok(v) if v < min => Some(format!("{v} is less than {min}")),
+                Ok(v) if v > max => Some(format!("{v} is greater than {max}")),
+                Ok(_) => None,
+                Err(_) => Some(format!("{value} is not an integer")),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Check all fields against their rules, returns the error of every invalid field
+pub fn validate<T: AppConfig>(app_config: &T) -> Vec<(&'static str, String)> {
+    app_config.fields().iter()
+        .flat_map(|f| f.rules.iter().filter_map(|r| r.check(&f.value)).map(|e| (f.name, e)).collect::<Vec<_>>())
+        .collect()
+}
+
+/// Generate an annotated sample config file, each field is written as a description comment
+/// followed by the commented out default value, fields without a long option name are skipped
+pub fn gen_config<T: AppConfig>(app_config: &T, title: &str) -> String {
+    let mut text = String::new();
+    if !title.is_empty() {
+        text.push_str(&format!("# {title}\n"));
+    }
+    for field in app_config.fields().iter().filter(|f| !f.name.is_empty() && f.name != C_HELP && f.name != "conf-file") {
+        match field.value.is_empty() {
+            true => text.push_str(&format!("\n# {}\n#{} =\n", field.desc, field.name)),
+            false => text.push_str(&format!("\n# {}\n#{} = {}\n", field.desc, field.name, escape_value(&field.value))),
+        }
+    }
+    text
+}
+
+/// Split a comma separated list value of `Vec<String>` fields, items are trimmed and empty items are skipped
+/// Byte size field type, parsed from `512`, `64k`, `10m` or `1g`(1024 based units,
+/// an optional `b` suffix is allowed like `10mb`) and displayed with the largest exact unit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Size(pub u64);
+
+impl std::str::FromStr for Size {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_ascii_lowercase();
+        let value = value.strip_suffix('b').unwrap_or(&value);
+        let n_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        let shift = match value[n_end..].trim_start() {
+            "" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            "t" => 40,
+            u => anyhow::bail!("invalid size unit {u} in {s}"),
+        };
+        let n: u64 = value[..n_end].parse().map_err(|_| anyhow::anyhow!("invalid size {s}"))?;
+        match n.checked_mul(1 << shift) {
+            Some(n) => Ok(Size(n)),
+            None => anyhow::bail!("size {s} overflow"),
+        }
+    }
+}
+
+impl std::fmt::Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (unit, shift) in [("t", 40), ("g", 30), ("m", 20), ("k", 10)] {
+            if self.0 != 0 && self.0.is_multiple_of(1 << shift) {
+                return write!(f, "{}{}", self.0 >> shift, unit);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parse a duration like `30s`, `5m`, `1h30m`, `500ms` or `2d`, units are ms/s/m/h/d/w,
+/// a number without unit is seconds
+pub fn parse_duration(value: &str) -> anyhow::Result<std::time::Duration> {
+    let value = value.trim();
+    if value.is_empty() {
+        anyhow::bail!("empty duration");
+    }
+    let mut ms: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let n_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n: u64 = rest[..n_end].parse().map_err(|_| anyhow::anyhow!("invalid duration {value}"))?;
+        rest = rest[n_end..].trim_start();
+        let u_end = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        let unit = match &rest[..u_end] {
+            "ms" => 1,
+            "" | "s" => 1000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            "w" => 604_800_000,
+            u => anyhow::bail!("invalid duration unit {u} in {value}"),
+        };
+        ms = n.checked_mul(unit).and_then(|v| v.checked_add(ms))
+            .ok_or_else(|| anyhow::anyhow!("duration {value} overflow"))?;
+        rest = rest[u_end..].trim_start();
+    }
+    Ok(std::time::Duration::from_millis(ms))
+}
+
+/// Format a duration in the syntax of [`parse_duration`], like `1h30m`
+pub fn format_duration(value: std::time::Duration) -> String {
+    let mut ms = value.as_millis();
+    if ms == 0 {
+        return String::from("0s");
+    }
+    let mut s = String::new();
+    for (unit, n) in [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1000), ("ms", 1)] {
+        if ms >= n {
+            s.push_str(&format!("{}{}", ms / n, unit));
+            ms %= n;
+        }
+    }
+    s
+}
+
+pub fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+/// Where the effective value of a config field comes from, see [`dump_config`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    ConfigFile(String),
+    Environment(String),
+    CommandLine,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::ConfigFile(path) => write!(f, "config file {path}"),
+            Source::Environment(name) => write!(f, "environment {name}"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Print the effective config in config file format, each field is followed by a comment
+/// showing where its value comes from, `sources` is in the same order as [`AppConfig::fields`]
+///
+/// Values of fields named like `key`, `password`, `secret` or `token` are masked.
+pub fn dump_config<T: AppConfig>(app_config: &T, sources: &[Source]) -> String {
+    let lines: Vec<(String, &Source)> = app_config.fields().iter().zip(sources.iter())
+        .filter(|(f, _)| !f.name.is_empty() && f.name != C_HELP && f.name != "conf-file")
+        .map(|(f, source)| {
+            let secret = f.rules.contains(&Rule::Secret)
+                || ["key", "password", "secret", "token"].iter().any(|s| f.name.ends_with(s));
+            let value = match (f.value.is_empty(), secret) {
+                (true, _) => String::new(),
+                (false, true) => String::from(" ******"),
+                (false, false) => format!(" {}", escape_value(&f.value)),
+            };
+            (format!("{} ={}", f.name, value), source)
+        })
+        .collect();
+
+    let width = lines.iter().map(|(line, _)| line.chars().count()).max().unwrap_or(0);
+    let mut text = String::new();
+    for (line, source) in lines {
+        text.push_str(&format!("{line:width$}    # {source}\n"));
+    }
+    text
+}
+
+// 读取秘密配置项的文件, 删除末尾的换行符
+fn read_secret(name: &str, path: &str) -> anyhow::Result<String> {
+    let value = std::fs::read_to_string(path.trim())
+        .map_err(|e| anyhow::anyhow!("can't read secret {name} from file {path}: {e}"))?;
+    Ok(value.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+// 转义配置文件中的值, 首尾的空格使用\s表示
+fn escape_value(value: &str) -> String {
+    let mut s = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            ' ' if i == 0 || i == last => s.push_str("\\s"),
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+/// Program name, version and build metadata printed by `-V/--version`,
+/// created by [`build_info`] and set by [`set_build_info`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub name: &'static str,         // 程序名称
+    pub version: &'static str,      // 程序版本
+    pub git_hash: &'static str,     // 编译时的git提交, 为空表示未知
+    pub build_date: &'static str,   // 编译日期
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.version)?;
+        match (self.git_hash.is_empty(), self.build_date.is_empty()) {
+            (true, true) => Ok(()),
+            (true, false) => write!(f, " (built {})", self.build_date),
+            (false, true) => write!(f, " ({})", self.git_hash),
+            (false, false) => write!(f, " ({} built {})", self.git_hash, self.build_date),
+        }
+    }
+}
+
+/// Create a [`BuildInfo`] with the git hash and build date from the environment variables
+/// `BUILD_GIT_HASH` and `BUILD_DATE` set by the build script of the calling crate, like:
+///
+/// ```text
+/// println!("cargo:rustc-env=BUILD_GIT_HASH={git_hash}");
+/// println!("cargo:rustc-env=BUILD_DATE={date}");
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    ($name:expr, $version:expr) => {
+        $crate::BuildInfo {
+            name: $name,
+            version: $version,
+            git_hash: option_env!("BUILD_GIT_HASH").unwrap_or(""),
+            build_date: option_env!("BUILD_DATE").unwrap_or(""),
+        }
+    };
+}
+
+static BUILD_INFO: std::sync::RwLock<Option<BuildInfo>> = std::sync::RwLock::new(None);
+
+/// Set the build information printed by `-V/--version`, without it the version text
+/// passed to the parsing function is printed
+pub fn set_build_info(info: BuildInfo) {
+    *BUILD_INFO.write().unwrap() = Some(info);
+}
+
+// 最近一次解析参数时读取的配置文件, 及每个配置项的来源
+static PARSED: std::sync::RwLock<(String, Vec<(&'static str, Source)>)> = std::sync::RwLock::new((String::new(), Vec::new()));
+
+/// The config file read by the last argument parsing, returns None when no config file was read
+pub fn config_file() -> Option<String> {
+    let parsed = PARSED.read().unwrap();
+    (!parsed.0.is_empty()).then(|| parsed.0.clone())
+}
+
+/// Where the value of each field comes from in the last argument parsing, see [`Source`]
+pub fn sources() -> Vec<(&'static str, Source)> {
+    PARSED.read().unwrap().1.clone()
+}
+
+static FREE_ARGS: std::sync::RwLock<Vec<String>> = std::sync::RwLock::new(Vec::new());
+
+/// The positional arguments(not options) of the last argument parsing,
+/// like `www.lan` and `@127.0.0.1` of `mdns-cli query www.lan @127.0.0.1`
+pub fn free_args() -> Vec<String> {
+    FREE_ARGS.read().unwrap().clone()
+}
+
+static ENV_PREFIX: std::sync::RwLock<String> = std::sync::RwLock::new(String::new());
+
+/// Set the prefix of environment variables, must be called before parsing arguments
+///
+/// Each option can then be set by the variable named prefix, '_' and the upper case long option
+/// name with dashes mapped to underscores, like `MINIDNS_LOG_LEVEL` for `log-level` with the prefix
+/// `MINIDNS`. Environment variables are not read when the prefix is empty(the default).
+///
+/// Variables are also loaded from the `.env` file in the working directory or the file given by
+/// `--env-file`, variables already in the environment take precedence.
+pub fn set_env_prefix(prefix: &str) {
+    *ENV_PREFIX.write().unwrap() = prefix.to_owned();
+}
+
+/// The environment variable name of the option `name` with the prefix set by [`set_env_prefix`],
+/// returns None when the prefix is not set
+pub fn env_name(name: &str) -> Option<String> {
+    let prefix = ENV_PREFIX.read().unwrap();
+    match prefix.is_empty() || name.is_empty() {
+        true => None,
+        false => Some(format!("{}_{}", prefix, name.to_uppercase().replace('-', "_"))),
+    }
+}
+
+// 读取选项对应的环境变量, 环境变量中没有时从.env文件中查找, 返回变量名及值
+fn env_value(name: &str, dotenv: &[(String, String)]) -> Option<(String, String)> {
+    let env = env_name(name)?;
+    let value = std::env::var(&env).ok()
+        .or_else(|| dotenv.iter().find(|(k, _)| *k == env).map(|(_, v)| v.clone()))?;
+    Some((env, value))
 }
 
 pub fn print_banner(banner: &str, use_color: bool) {
@@ -220,46 +627,210 @@ pub fn parse_args<T: AppConfig>(app_config: &mut T, banner: &str) -> anyhow::Res
 /// * `app_config`: application config variable
 /// * `banner`: application banner
 /// * `f`: A user-defined callback function that checks the validity of parameters.
-/// If it returns false, this function will print the help information and return Ok(false)
+///   If it returns false, this function will print the help information and return Ok(false)
 ///
 /// Returns:
 ///
 /// Ok(true): success, Ok(false): require terminated, Err(e): error
 ///
 pub fn parse_args_ext<T: AppConfig, F: Fn(&T) -> bool>(app_config: &mut T, version: &str, f: F) -> anyhow::Result<bool> {
+    parse_args_from(app_config, version, std::env::args().skip(1).collect(), &Usage::default(), f)
+}
+
+/// Subcommand list of the program, each item is the command name and its description
+pub type Commands<'a> = &'a [(&'a str, &'a str)];
 
-    let mut args = std::env::args();
-    let prog = args.next().unwrap();
+/// Get the subcommand from the first program argument, like `mdns serve -p 53`
+///
+/// Returns `default` when there is no argument or the first argument is an option,
+/// returns Err when the first argument is not in `commands`.
+pub fn get_command<'a>(commands: Commands<'a>, default: &'a str) -> anyhow::Result<&'a str> {
+    get_command_from(commands, default, std::env::args().nth(1))
+}
+
+fn get_command_from<'a>(commands: Commands<'a>, default: &'a str, arg: Option<String>) -> anyhow::Result<&'a str> {
+    match arg {
+        Some(arg) if !arg.starts_with('-') => match commands.iter().find(|(name, _)| *name == arg) {
+            Some((name, _)) => Ok(name),
+            None => {
+                print_commands(commands, default);
+                anyhow::bail!("unknown command {arg}")
+            },
+        },
+        _ => Ok(default),
+    }
+}
+
+/// Parsing the options of the subcommand returned by [`get_command`], each subcommand
+/// can have its own config struct defined by [`appconfig_define`]
+///
+/// The help output shows the subcommand in the usage line and lists all subcommands.
+///
+/// Returns:
+///
+/// Ok(true): success, Ok(false): require terminated, Err(e): error
+pub fn parse_command_args<T: AppConfig, F: Fn(&T) -> bool>(app_config: &mut T, version: &str,
+        command: &str, commands: Commands, default: &str, f: F) -> anyhow::Result<bool> {
+    let args = command_args(command, std::env::args().skip(1).collect());
+    parse_args_from(app_config, version, args, &Usage { command, commands, default }, f)
+}
+
+// 去掉参数中的子命令, 缺省子命令可以省略
+fn command_args(command: &str, mut args: Vec<String>) -> Vec<String> {
+    if args.first().is_some_and(|arg| arg == command) {
+        args.remove(0);
+    }
+    args
+}
+
+// 帮助信息中的子命令
+#[derive(Default)]
+struct Usage<'a> {
+    command: &'a str,           // 当前子命令, 为空表示程序没有子命令
+    commands: Commands<'a>,     // 所有子命令
+    default: &'a str,           // 缺省的子命令
+}
+
+fn parse_args_from<T: AppConfig, F: Fn(&T) -> bool>(app_config: &mut T, version: &str, args: Vec<String>,
+        usage: &Usage, f: F) -> anyhow::Result<bool> {
+
+    let prog = std::env::args().next().unwrap();
 
     let mut opts = app_config.to_opts();
+    let fields = app_config.fields();
+    let has_hidden = fields.iter().any(|f| f.rules.contains(&Rule::Hidden));
     opts.optflag("h", C_HELP, "this help");
+    if has_hidden {
+        opts.optflag("", C_HELP_ALL, "this help with all advanced options");
+    }
+    opts.optflag("V", C_VERSION, "print version information and exit");
+    opts.optflag("", C_GEN_CONFIG, "print an annotated sample config file and exit");
+    opts.optflag("", C_DUMP_CONFIG, "print the effective config with the source of each value and exit");
     #[cfg(feature="cfg-file")]
-    opts.optopt("c",  C_CONF_FILE, "set configuration file", "ConfigFile");
+    opts.optopt("c",  C_CONF_FILE, "set configuration file(toml format if the extension is .toml)", "ConfigFile");
+    let use_env = env_name(C_ENV_FILE).is_some();
+    if use_env {
+        opts.optopt("", C_ENV_FILE, "set .env file of environment variables(default: .env)", "EnvFile");
+    }
 
     let matches = match anyhow::Context::context(opts.parse(args), "parse program arguments failed") {
         Ok(m) => m,
         Err(e) => {
-            print_usage(&prog, version, &opts);
+            print_usage(&prog, version, &opts, usage, &fields, false);
             return Err(e);
         },
     };
+    FREE_ARGS.write().unwrap().clone_from(&matches.free);
 
-    if matches.opt_present(C_HELP) {
-        print_usage(&prog, version, &opts);
+    let help_all = has_hidden && matches.opt_present(C_HELP_ALL);
+    if matches.opt_present(C_HELP) || help_all {
+        print_usage(&prog, version, &opts, usage, &fields, help_all);
         return Ok(false);
     }
 
-    // 参数设置优先级：命令行参数 > 配置文件参数
-    // 因此, 先从配置文件读取参数覆盖缺省值, 然后用命令行参数覆盖
+    if matches.opt_present(C_VERSION) {
+        match BUILD_INFO.read().unwrap().as_ref() {
+            Some(info) => println!("{info}"),
+            None => println!("{version}"),
+        }
+        return Ok(false);
+    }
+
+    // 在读取配置文件之前输出, 示例中的值为缺省值
+    if matches.opt_present(C_GEN_CONFIG) {
+        print!("{}", gen_config(app_config, version));
+        return Ok(false);
+    }
+
+    // 读取.env文件, 未指定时读取当前目录下的.env, 该文件不存在则忽略
+    let dotenv = match use_env {
+        true => dotenv::load(matches.opt_str(C_ENV_FILE).as_deref())?,
+        false => Vec::new(),
+    };
+
+    // 记录每个配置项的来源, 用于--dump-config
+    let names: Vec<&'static str> = app_config.fields().iter().map(|f| f.name).collect();
+    let mut sources = vec![Source::Default; names.len()];
+
+    // 参数设置优先级：命令行参数 > 环境变量 > 配置文件参数
+    // 因此, 先从配置文件读取参数覆盖缺省值, 然后依次用环境变量和命令行参数覆盖
     // 从配置文件读取参数, 如果环境变量及命令行未提供配置文件参数, 则允许读取失败, 否则, 读取失败返回错误
     #[cfg(feature="cfg-file")]
-    get_from_config_file(app_config, &matches, &prog)?;
+    let parsed_file = match get_from_config_file(app_config, &matches, &prog, &dotenv)? {
+        Some((cfg, conf_file)) => {
+            for (name, source) in names.iter().zip(sources.iter_mut()) {
+                if cfg.get_raw(name).is_some() {
+                    *source = Source::ConfigFile(conf_file.clone());
+                }
+            }
+            conf_file
+        },
+        None => String::new(),
+    };
+    #[cfg(not(feature="cfg-file"))]
+    let parsed_file = String::new();
+
+    // 从环境变量读取参数, 秘密配置项还可以从"变量名_FILE"指定的文件读取
+    let secrets: Vec<&str> = app_config.fields().iter()
+        .filter(|f| f.rules.contains(&Rule::Secret))
+        .map(|f| f.name)
+        .collect();
+    let mut env_vars = Vec::new();
+    for (name, source) in names.iter().zip(sources.iter_mut()) {
+        let file_opt = format!("{name}-file");
+        if let Some((env, value)) = env_value(name, &dotenv) {
+            *source = Source::Environment(env);
+            env_vars.push((name.to_string(), value));
+        } else if secrets.contains(name) && !names.contains(&file_opt.as_str()) {
+            if let Some((env, path)) = env_value(&file_opt, &dotenv) {
+                env_vars.push((name.to_string(), read_secret(name, &path)?));
+                *source = Source::Environment(env);
+            }
+        }
+    }
+    if !env_vars.is_empty() {
+        app_config.set_from_cfg(&Config::with_entries(env_vars))?;
+    }
 
     // 从命令行读取参数
     app_config.set_from_getopts(&matches)?;
+    for (name, source) in names.iter().zip(sources.iter_mut()) {
+        if !name.is_empty() && matches.opt_present(name) {
+            *source = Source::CommandLine;
+        }
+    }
+
+    // 值为"@文件路径"的秘密配置项从文件读取
+    let secret_values: Vec<(String, String)> = app_config.fields().into_iter()
+        .filter(|f| f.rules.contains(&Rule::Secret) && f.value.starts_with('@'))
+        .map(|f| match f.value.strip_prefix("@@") {
+            Some(value) => Ok((f.name.to_string(), format!("@{value}"))),
+            None => read_secret(f.name, &f.value[1..]).map(|v| (f.name.to_string(), v)),
+        })
+        .collect::<anyhow::Result<_>>()?;
+    if !secret_values.is_empty() {
+        app_config.set_from_cfg(&Config::with_entries(secret_values))?;
+    }
+
+    if matches.opt_present(C_DUMP_CONFIG) {
+        print!("{}", dump_config(app_config, &sources));
+        return Ok(false);
+    }
+    *PARSED.write().unwrap() = (parsed_file, names.iter().copied().zip(sources.iter().cloned()).collect());
+
+    // 一次列出所有不符合规则的配置项及其来源
+    let errors = validate(app_config);
+    if !errors.is_empty() {
+        eprintln!("\x1b[31minvalid config:\x1b[0m");
+        for (name, error) in errors.iter() {
+            let source = names.iter().position(|n| n == name).map_or(&Source::Default, |i| &sources[i]);
+            eprintln!("    \x1b[33m{name}\x1b[0m: {error} \x1b[90m({source})\x1b[0m");
+        }
+        anyhow::bail!("{} invalid config fields", errors.len());
+    }
 
     if !f(app_config) {
-        print_usage(&prog, version, &opts);
+        print_usage(&prog, version, &opts, usage, &fields, false);
         return Ok(false);
     }
 
@@ -268,36 +839,413 @@ pub fn parse_args_ext<T: AppConfig, F: Fn(&T) -> bool>(app_config: &mut T, versi
     Ok(true)
 }
 
-fn print_usage(prog: &str, version: &str, opts: &getopts::Options) {
-    if version.len() > 0 {
+fn print_usage(prog: &str, version: &str, opts: &getopts::Options, usage: &Usage, fields: &[Field], all: bool) {
+    if !version.is_empty() {
         println!("\n{}", version);
     }
     let path = std::path::Path::new(prog);
     let prog = path.file_name().unwrap().to_str().unwrap();
-    let brief = format!("\nUsage: \x1b[36m{} \x1b[33m{}\x1b[0m", &prog, "[options]");
-    println!("{}", opts.usage(&brief));
+    let brief = match usage.command.is_empty() {
+        true => format!("\nUsage: \x1b[36m{} \x1b[33m{}\x1b[0m", &prog, "[options]"),
+        false => format!("\nUsage: \x1b[36m{} \x1b[35m{} \x1b[33m{}\x1b[0m", &prog, usage.command, "[options]"),
+    };
+    println!("{}\n{}", brief, options_usage(opts, fields, all));
+    if let Some(name) = env_name("long-option") {
+        println!("Options can also be set by environment variables like \x1b[33m{name}\x1b[0m\n");
+    }
+    if !usage.commands.is_empty() {
+        print_commands(usage.commands, usage.default);
+    }
+}
+
+// 按分组生成选项的帮助信息, 选项与配置项按定义顺序一一对应, 之后是内置选项,
+// 隐藏的选项只在all为true时输出
+fn options_usage(opts: &getopts::Options, fields: &[Field], all: bool) -> String {
+    const DEFAULT_GROUP: &str = "Options";
+    opts.usage_with_format(|rows| {
+        let mut sections: Vec<(&str, Vec<String>)> = vec![(DEFAULT_GROUP, Vec::new())];
+        let (mut group, mut hidden) = (DEFAULT_GROUP, 0);
+        for (i, row) in rows.enumerate() {
+            let section = match fields.get(i) {
+                Some(field) => {
+                    if let Some(g) = field.rules.iter().find_map(|r| match r { Rule::Group(g) => Some(*g), _ => None }) {
+                        group = g;
+                    }
+                    if !all && field.rules.contains(&Rule::Hidden) {
+                        hidden += 1;
+                        continue;
+                    }
+                    group
+                },
+                None => DEFAULT_GROUP,
+            };
+            match sections.iter_mut().find(|(name, _)| *name == section) {
+                Some((_, rows)) => rows.push(row),
+                None => sections.push((section, vec![row])),
+            }
+        }
+
+        let mut text = String::new();
+        for (name, rows) in sections.iter().filter(|(_, rows)| !rows.is_empty()) {
+            text.push_str(&format!("\n{}:\n{}\n", name, rows.join("\n")));
+        }
+        if hidden > 0 {
+            text.push_str(&format!("\n{hidden} advanced options are hidden, use --{C_HELP_ALL} to list them\n"));
+        }
+        text
+    })
+}
+
+// 输出子命令列表
+fn print_commands(commands: Commands, default: &str) {
+    let width = commands.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    println!("Commands:");
+    for (name, desc) in commands.iter() {
+        let mark = if *name == default { " (default)" } else { "" };
+        println!("    \x1b[35m{name:width$}\x1b[0m    {desc}{mark}");
+    }
+    println!();
 }
 
 #[cfg(feature="cfg-file")]
-fn get_from_config_file<T: AppConfig>(ac: &mut T, matches: &Matches, prog: &str) -> anyhow::Result<()> {
+// 从配置文件读取参数, 返回读取的配置文件及其路径, 未指定的缺省配置文件不存在时返回None
+fn get_from_config_file<T: AppConfig>(ac: &mut T, matches: &Matches, prog: &str,
+        dotenv: &[(String, String)]) -> anyhow::Result<Option<(Config, String)>> {
     let mut conf_is_set = false;
     let mut conf_file = String::new();
-    if let Some(cf) = matches.opt_str(C_CONF_FILE) {
+    if let Some(cf) = matches.opt_str(C_CONF_FILE).or_else(|| env_value(C_CONF_FILE, dotenv).map(|(_, v)| v)) {
         conf_is_set = true;
         conf_file = cf;
     }
     if !conf_is_set {
+        // 缺省配置文件为程序名.conf, 不存在时使用程序名.toml
         let mut path = std::path::PathBuf::from(prog);
         path.set_extension("conf");
+        if !path.exists() && path.with_extension("toml").exists() {
+            path.set_extension("toml");
+        }
         conf_file = path.to_str().ok_or(anyhow::anyhow!("program name error"))?.to_owned();
     }
     match Config::with_file(&conf_file) {
-        Ok(cfg) => ac.set_from_cfg(&cfg),
+        Ok(cfg) => {
+            ac.set_from_cfg(&cfg)?;
+            Ok(Some((cfg, conf_file)))
+        },
         Err(_) => {
             match conf_is_set {
                 true => anyhow::bail!("can't read app config file {conf_file}"),
-                false => Ok(())
+                false => Ok(None)
             }
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    appconfig_define!(TestConf,
+        log_level: String => ["L", "log-level", "LogLevel", "log level"],
+        prefix   : String => ["",  "prefix", "Prefix", "line prefix"],
+        port     : String => ["p", "port", "Port", "listen port"],
+        debug    : bool   => ["",  "debug", "", "debug mode"],
+    );
+
+    impl Default for TestConf {
+        fn default() -> Self {
+            TestConf { log_level: String::from("info"), prefix: String::from(" a\\b "), port: String::from("53"), debug: false }
+        }
+    }
+
+    #[test]
+    #[cfg(feature="cfg-file")]
+    fn test_gen_config() {
+        let text = gen_config(&TestConf::default(), "test app");
+        assert!(text.starts_with("# test app\n\n# log level\n#log-level = info\n"));
+        assert!(text.contains("\n# debug mode\n#debug = false\n"));
+
+        // 去掉注释符号后可以读回缺省值
+        let uncommented = text.lines().filter(|s| !s.starts_with("# ")).map(|s| s.trim_start_matches('#')).collect::<Vec<_>>().join("\n");
+        let mut ac = TestConf { log_level: String::new(), prefix: String::new(), port: String::new(), debug: true };
+        ac.set_from_cfg(&Config::with_text(uncommented).unwrap()).unwrap();
+        assert_eq!(TestConf::default().fields(), ac.fields());
+    }
+
+    #[test]
+    #[cfg(feature="cfg-file")]
+    fn test_list_field() {
+        appconfig_define!(ListConf,
+            dns  : Vec<String> => ["d", "dns", "Dns", "upstream dns servers"],
+            hosts: Vec<String> => ["b", "hosts", "Hosts", "hosts files"],
+        );
+        impl Default for ListConf {
+            fn default() -> Self {
+                ListConf { dns: vec![String::from("0.0.0.0")], hosts: Vec::new() }
+            }
+        }
+
+        let mut ac = ListConf::default();
+        ac.set_from_cfg(&Config::with_text(String::from("dns = 1.0.0.1\nhosts = a.conf, b.conf")).unwrap()).unwrap();
+        assert_eq!(vec!["a.conf", "b.conf"], ac.hosts);
+
+        // 命令行参数可以重复, 每个值也可以用逗号分隔
+        let args = ["--dns", "1.1.1.1", "-d", "8.8.8.8,9.9.9.9"].map(String::from).to_vec();
+        assert!(parse_args_from(&mut ac, "", args, &Usage::default(), |_| true).unwrap());
+        assert_eq!(vec!["1.1.1.1", "8.8.8.8", "9.9.9.9"], ac.dns);
+        assert_eq!(vec!["a.conf", "b.conf"], ac.hosts);
+        assert_eq!("1.1.1.1,8.8.8.8,9.9.9.9", ac.fields()[0].value);
+    }
+
+    #[test]
+    fn test_env() {
+        appconfig_define!(EnvConf,
+            log_level: String => ["L", "log-level", "LogLevel", "log level"],
+            log_file : String => ["F", "log-file", "LogFile", "log file"],
+            port     : String => ["p", "port", "Port", "listen port"],
+        );
+        impl Default for EnvConf {
+            fn default() -> Self {
+                EnvConf { log_level: String::from("info"), log_file: String::new(), port: String::from("53") }
+            }
+        }
+
+        set_env_prefix("APPCONFIG_TEST");
+        assert_eq!(Some(String::from("APPCONFIG_TEST_LOG_LEVEL")), env_name("log-level"));
+        std::env::set_var("APPCONFIG_TEST_LOG_LEVEL", "debug");
+        std::env::set_var("APPCONFIG_TEST_PORT", "5353");
+        std::env::set_var("LOG_FILE", "/tmp/bare.log");
+
+        // 命令行参数优先于环境变量, 不带前缀的变量被忽略
+        let mut ac = EnvConf::default();
+        let args = ["-p", "53"].map(String::from).to_vec();
+        assert!(parse_args_from(&mut ac, "", args, &Usage::default(), |_| true).unwrap());
+        assert_eq!("debug", ac.log_level);
+        assert_eq!("", ac.log_file);
+        assert_eq!("53", ac.port);
+
+        // .env中的变量不覆盖已有的环境变量
+        let env_file = std::env::temp_dir().join("appconfig_test.env");
+        std::fs::write(&env_file, "APPCONFIG_TEST_LOG_LEVEL=warn\nAPPCONFIG_TEST_LOG_FILE='/tmp/app.log'\n").unwrap();
+        let mut ac = EnvConf::default();
+        let args = ["--env-file", env_file.to_str().unwrap()].map(String::from).to_vec();
+        assert!(parse_args_from(&mut ac, "", args, &Usage::default(), |_| true).unwrap());
+        assert_eq!("debug", ac.log_level);
+        assert_eq!("/tmp/app.log", ac.log_file);
+        assert_eq!("5353", ac.port);
+        std::fs::remove_file(&env_file).unwrap();
+
+        let args = ["--env-file", "/nonexistent/.env"].map(String::from).to_vec();
+        assert!(parse_args_from(&mut EnvConf::default(), "", args, &Usage::default(), |_| true).is_err());
+    }
+
+    #[test]
+    fn test_typed_field() {
+        use std::net::IpAddr;
+        use std::time::Duration;
+
+        appconfig_define!(TypedConf,
+            host   : IpAddr   => ["H", "host", "Host", "listen address"],
+            port   : u16      => ["p", "port", "Port", "listen port"] @range(1, 65535),
+            ttl    : u32      => ["t", "ttl", "Ttl", "record ttl"],
+            refresh: Duration => ["r", "refresh", "Refresh", "refresh interval"],
+        );
+        impl Default for TypedConf {
+            fn default() -> Self {
+                TypedConf { host: IpAddr::from([0, 0, 0, 0]), port: 53, ttl: 300, refresh: Duration::from_secs(3600) }
+            }
+        }
+
+        let mut ac = TypedConf::default();
+        assert_eq!("1h", ac.fields()[3].value);
+        let args = ["-H", "::1", "-p", "5353", "--refresh", "1h30m"].map(String::from).to_vec();
+        assert!(parse_args_from(&mut ac, "", args, &Usage::default(), |_| true).unwrap());
+        assert_eq!("::1".parse::<IpAddr>().unwrap(), ac.host);
+        assert_eq!(5353, ac.port);
+        assert_eq!(300, ac.ttl);
+        assert_eq!(Duration::from_secs(5400), ac.refresh);
+
+        for args in [["-p", "70000"], ["-H", "1.2.3"], ["-r", "5x"]] {
+            let args = args.map(String::from).to_vec();
+            assert!(parse_args_from(&mut TypedConf::default(), "", args, &Usage::default(), |_| true).is_err());
+        }
+
+        assert_eq!(Duration::from_millis(500), parse_duration("500ms").unwrap());
+        assert_eq!(Duration::from_secs(90), parse_duration("1m 30s").unwrap());
+        assert_eq!(Duration::from_secs(30), parse_duration("30").unwrap());
+        assert_eq!(Duration::from_secs(2 * 86400), parse_duration("2d").unwrap());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert_eq!("1d2h3m4s5ms", format_duration(Duration::from_millis(93_784_005)));
+        assert_eq!("0s", format_duration(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(Size(512), "512".parse().unwrap());
+        assert_eq!(Size(64 << 10), "64k".parse().unwrap());
+        assert_eq!(Size(10 << 20), "10M".parse().unwrap());
+        assert_eq!(Size(10 << 20), "10mb".parse().unwrap());
+        assert_eq!(Size(1 << 30), "1 g".parse().unwrap());
+        assert!("".parse::<Size>().is_err());
+        assert!("10x".parse::<Size>().is_err());
+        assert!("m".parse::<Size>().is_err());
+        assert!("99999999999t".parse::<Size>().is_err());
+
+        assert_eq!("10m", Size(10 << 20).to_string());
+        assert_eq!("1536k", Size(1536 << 10).to_string());
+        assert_eq!("1000", Size(1000).to_string());
+        assert_eq!("0", Size(0).to_string());
+    }
+
+    #[test]
+    fn test_secret() {
+        appconfig_define!(SecretConf,
+            api_token  : String => ["", "api-token", "ApiToken", "api token"] @secret,
+            db_password: String => ["", "db-password", "DbPassword", "db password"] @secret,
+            db_user    : String => ["", "db-user", "DbUser", "db user"] @secret,
+            note       : String => ["", "note", "Note", "not a secret"],
+        );
+        impl Default for SecretConf {
+            fn default() -> Self {
+                SecretConf { api_token: String::new(), db_password: String::new(), db_user: String::from("guest"), note: String::new() }
+            }
+        }
+
+        let dir = std::env::temp_dir();
+        let (token_file, password_file) = (dir.join("appconfig_test.token"), dir.join("appconfig_test.password"));
+        std::fs::write(&token_file, "t0ken\n").unwrap();
+        std::fs::write(&password_file, "pa ss\r\n").unwrap();
+        set_env_prefix("APPCONFIG_TEST");
+        std::env::set_var("APPCONFIG_TEST_API_TOKEN_FILE", &token_file);
+
+        let mut ac = SecretConf::default();
+        let password_arg = format!("@{}", password_file.to_str().unwrap());
+        let args = ["--db-password", &password_arg, "--db-user", "@@admin", "--note", "@note"].map(String::from).to_vec();
+        assert!(parse_args_from(&mut ac, "", args, &Usage::default(), |_| true).unwrap());
+        assert_eq!("t0ken", ac.api_token);
+        assert_eq!("pa ss", ac.db_password);
+        assert_eq!("@admin", ac.db_user);
+        assert_eq!("@note", ac.note);
+        assert!(dump_config(&ac, &vec![Source::Default; 4]).starts_with("api-token = ******"));
+
+        let args = ["--db-password", "@/nonexistent/password"].map(String::from).to_vec();
+        assert!(parse_args_from(&mut SecretConf::default(), "", args, &Usage::default(), |_| true).is_err());
+        std::fs::remove_file(&token_file).unwrap();
+        std::fs::remove_file(&password_file).unwrap();
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info!("app", "1.0.0");
+        assert_eq!("app", info.name);
+        let mut info = BuildInfo { name: "app", version: "1.0.0", git_hash: "", build_date: "" };
+        assert_eq!("app 1.0.0", info.to_string());
+        info.build_date = "2026-10-15";
+        assert_eq!("app 1.0.0 (built 2026-10-15)", info.to_string());
+        info.git_hash = "5b29cce";
+        assert_eq!("app 1.0.0 (5b29cce built 2026-10-15)", info.to_string());
+    }
+
+    #[test]
+    fn test_options_usage() {
+        appconfig_define!(GroupConf,
+            debug    : bool   => ["D", "debug", "", "debug mode"],
+            log_level: String => ["L", "log-level", "LogLevel", "log level"] @group("Logging"),
+            log_rate : u32    => ["", "log-rate", "LogRate", "log rate"] @hidden,
+            port     : u16    => ["p", "port", "Port", "listen port"] @group("Network"),
+            log_file : String => ["F", "log-file", "LogFile", "log file"] @group("Logging"),
+        );
+        impl Default for GroupConf {
+            fn default() -> Self {
+                GroupConf { debug: false, log_level: String::new(), log_rate: 0, port: 53, log_file: String::new() }
+            }
+        }
+
+        let ac = GroupConf::default();
+        let mut opts = ac.to_opts();
+        opts.optflag("h", C_HELP, "this help");
+        let fields = ac.fields();
+        let sections = |text: &str| text.lines().filter(|l| l.ends_with(':') || l.trim_start().starts_with('-'))
+            .map(|l| l.split_whitespace().find(|w| w.starts_with("--") || w.ends_with(':')).unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        let text = options_usage(&opts, &fields, false);
+        assert_eq!(vec!["Options:", "--debug", "--help", "Logging:", "--log-level", "--log-file", "Network:", "--port"],
+                sections(&text));
+        assert!(text.ends_with("1 advanced options are hidden, use --help-all to list them\n"));
+
+        let text = options_usage(&opts, &fields, true);
+        assert_eq!(vec!["Options:", "--debug", "--help", "Logging:", "--log-level", "--log-rate", "--log-file",
+                "Network:", "--port"], sections(&text));
+        assert!(!text.contains("hidden"));
+    }
+
+    #[test]
+    fn test_command_args() {
+        const COMMANDS: Commands = &[("serve", "run the dns server"), ("ctl", "send a control command")];
+        let args = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // 没有参数或第一个参数是选项时使用缺省子命令
+        assert_eq!("serve", get_command_from(COMMANDS, "serve", None).unwrap());
+        assert_eq!("serve", get_command_from(COMMANDS, "serve", Some("-p".to_string())).unwrap());
+        assert_eq!("ctl", get_command_from(COMMANDS, "serve", Some("ctl".to_string())).unwrap());
+        assert!(get_command_from(COMMANDS, "serve", Some("stop".to_string())).is_err());
+
+        // 子命令之后的参数按选项解析, 含空格的值由shell引号合并为一个参数
+        let mut ac = TestConf::default();
+        let argv = command_args("ctl", args(&["ctl", "-p", "5353", "--prefix", "a b", "--debug", "status"]));
+        assert!(parse_args_from(&mut ac, "", argv, &Usage::default(), |_| true).unwrap());
+        assert_eq!(("5353", "a b", true), (ac.port.as_str(), ac.prefix.as_str(), ac.debug));
+        assert_eq!(vec!["status"], *FREE_ARGS.read().unwrap());
+
+        let argv = command_args("serve", args(&["--prefix=x=\"y\"", "-p53"]));
+        assert!(parse_args_from(&mut ac, "", argv, &Usage::default(), |_| true).unwrap());
+        assert_eq!(("53", "x=\"y\""), (ac.port.as_str(), ac.prefix.as_str()));
+
+        // 缺少选项值或未知选项返回错误
+        assert!(parse_args_from(&mut ac, "", args(&["-p"]), &Usage::default(), |_| true).is_err());
+        assert!(parse_args_from(&mut ac, "", args(&["--unknown"]), &Usage::default(), |_| true).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        appconfig_define!(RuleConf,
+            port: String => ["p", "port", "Port", "listen port"] @required @range(1, 65535),
+            ttl : String => ["t", "ttl", "Ttl", "record ttl"] @min(1),
+            key : String => ["k", "key", "Key", "update key"] @required,
+        );
+        impl Default for RuleConf {
+            fn default() -> Self {
+                RuleConf { port: String::from("53"), ttl: String::new(), key: String::from("abc") }
+            }
+        }
+
+        let mut ac = RuleConf::default();
+        assert!(validate(&ac).is_empty());
+
+        ac.port = String::from("70000");
+        ac.ttl = String::from("0");
+        ac.key = String::new();
+        assert_eq!(vec![("port", String::from("70000 is greater than 65535")), ("ttl", String::from("0 is less than 1")),
+                ("key", String::from("is required"))], validate(&ac));
+        ac.port = String::from("http");
+        assert_eq!(("port", String::from("http is not an integer")), validate(&ac)[0]);
+    }
+
+    #[test]
+    fn test_dump_config() {
+        appconfig_define!(KeyConf,
+            host: String => ["H", "host", "Host", "listen address"],
+            key : String => ["k", "key", "Key", "update key"],
+        );
+        impl Default for KeyConf {
+            fn default() -> Self {
+                KeyConf { host: String::from("0.0.0.0"), key: String::from("password") }
+            }
+        }
+        let ac = KeyConf::default();
+        let sources = [Source::ConfigFile(String::from("/etc/app.conf")), Source::CommandLine];
+        assert_eq!("host = 0.0.0.0    # config file /etc/app.conf\nkey = ******      # command line\n",
+                dump_config(&ac, &sources));
+    }
+}
@@ -0,0 +1,423 @@
+//! toml格式配置文件解析
+//!
+//! 支持常用的子集: 注释, 键值对, 点分隔的键, [表], [[表数组]], 基本字符串, 字面量字符串, 多行字符串,
+//! 整数, 浮点数, 布尔值, 日期时间, 数组及内联表
+//!
+//! 解析结果为扁平的键值列表, 表中的键以"表名.键名"表示, 表数组中的键以"表名.序号.键名"表示,
+//! 元素都是简单值的数组转为逗号分隔的字符串
+
+use std::collections::HashMap;
+
+// 解析后的值
+enum Value {
+    Str(String),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    data: &'a [u8],
+    pos: usize,
+}
+
+/// 解析toml文本, 返回扁平的键值列表
+pub(crate) fn parse(text: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut parser = Parser { text, data: text.as_bytes(), pos: 0 };
+    let mut result = Vec::new();
+    let mut prefix = String::new();
+    let mut array_tables: HashMap<String, usize> = HashMap::new();
+
+    loop {
+        parser.skip_blank_lines();
+        match parser.peek() {
+            None => break,
+            Some(b'[') => {
+                // [[表数组]]每出现一次增加一个序号
+                let is_array = parser.data.get(parser.pos + 1) == Some(&b'[');
+                parser.pos += if is_array { 2 } else { 1 };
+                parser.skip_spaces();
+                let path = parser.parse_key()?.join(".");
+                parser.skip_spaces();
+                parser.expect(if is_array { "]]" } else { "]" })?;
+                prefix = match is_array {
+                    true => {
+                        let count = array_tables.entry(path.clone()).or_default();
+                        *count += 1;
+                        format!("{path}.{}.", *count - 1)
+                    },
+                    false => format!("{path}."),
+                };
+            },
+            Some(_) => {
+                let key = parser.parse_key()?.join(".");
+                parser.skip_spaces();
+                parser.expect("=")?;
+                parser.skip_spaces();
+                let value = parser.parse_value()?;
+                flatten(&mut result, format!("{prefix}{key}"), value, parser.line())?;
+            },
+        }
+        parser.end_of_line()?;
+    }
+
+    Ok(result)
+}
+
+// 展开值并加入结果列表, 不允许重复的键
+fn flatten(result: &mut Vec<(String, String)>, key: String, value: Value, line: usize) -> anyhow::Result<()> {
+    match value {
+        Value::Str(s) => {
+            if result.iter().any(|(k, _)| *k == key) {
+                anyhow::bail!("duplicate key {key} at line {line}");
+            }
+            result.push((key, s));
+        },
+        Value::Array(items) if items.iter().all(|v| matches!(v, Value::Str(_))) => {
+            let items: Vec<String> = items.into_iter()
+                .map(|v| match v { Value::Str(s) => s, _ => unreachable!() })
+                .collect();
+            flatten(result, key, Value::Str(items.join(",")), line)?;
+        },
+        Value::Array(items) => {
+            for (i, v) in items.into_iter().enumerate() {
+                flatten(result, format!("{key}.{i}"), v, line)?;
+            }
+        },
+        Value::Table(items) => {
+            for (k, v) in items {
+                flatten(result, format!("{key}.{k}"), v, line)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    // 当前位置所在的行号, 用于错误信息
+    fn line(&self) -> usize {
+        self.data[..self.pos].iter().filter(|c| **c == b'\n').count() + 1
+    }
+
+    fn expect(&mut self, s: &str) -> anyhow::Result<()> {
+        if !self.data[self.pos..].starts_with(s.as_bytes()) {
+            anyhow::bail!("expected '{s}' at line {}", self.line());
+        }
+        self.pos += s.len();
+        Ok(())
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some(b'#') {
+            while !matches!(self.peek(), None | Some(b'\n')) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    // 跳过空白, 注释及换行, 用于行首及数组内部
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some(b'\r' | b'\n') => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    // 键值对或表头之后只允许空白及注释
+    fn end_of_line(&mut self) -> anyhow::Result<()> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.peek() {
+            None | Some(b'\n') => Ok(()),
+            Some(b'\r') if self.data.get(self.pos + 1) == Some(&b'\n') => Ok(()),
+            Some(_) => anyhow::bail!("unexpected character at line {}", self.line()),
+        }
+    }
+
+    // 解析键, 点分隔的键返回各部分
+    fn parse_key(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_spaces();
+            let part = match self.peek() {
+                Some(b'"') => self.parse_basic_string()?,
+                Some(b'\'') => self.parse_literal_string()?,
+                _ => {
+                    let begin = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_' || c == b'-') {
+                        self.pos += 1;
+                    }
+                    if begin == self.pos {
+                        anyhow::bail!("invalid key at line {}", self.line());
+                    }
+                    self.text[begin..self.pos].to_string()
+                },
+            };
+            parts.push(part);
+            self.skip_spaces();
+            match self.peek() {
+                Some(b'.') => self.pos += 1,
+                _ => return Ok(parts),
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> anyhow::Result<Value> {
+        let data = &self.data[self.pos..];
+        let value = match self.peek() {
+            Some(b'"') if data.starts_with(b"\"\"\"") => self.parse_multiline_string(b'"')?,
+            Some(b'\'') if data.starts_with(b"'''") => self.parse_multiline_string(b'\'')?,
+            Some(b'"') => self.parse_basic_string()?,
+            Some(b'\'') => self.parse_literal_string()?,
+            Some(b'[') => return self.parse_array(),
+            Some(b'{') => return self.parse_inline_table(),
+            _ => self.parse_scalar()?,
+        };
+        Ok(Value::Str(value))
+    }
+
+    // 基本字符串, 支持转义字符
+    fn parse_basic_string(&mut self) -> anyhow::Result<String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let begin = self.pos;
+            while !matches!(self.peek(), None | Some(b'"' | b'\\' | b'\n')) {
+                self.pos += 1;
+            }
+            s.push_str(&self.text[begin..self.pos]);
+            match self.peek() {
+                Some(b'"') => { self.pos += 1; return Ok(s); },
+                Some(b'\\') => self.parse_escape(&mut s)?,
+                _ => anyhow::bail!("unterminated string at line {}", self.line()),
+            }
+        }
+    }
+
+    // 字面量字符串, 不处理转义字符
+    fn parse_literal_string(&mut self) -> anyhow::Result<String> {
+        self.pos += 1;
+        let begin = self.pos;
+        while !matches!(self.peek(), None | Some(b'\'' | b'\n')) {
+            self.pos += 1;
+        }
+        if self.peek() != Some(b'\'') {
+            anyhow::bail!("unterminated string at line {}", self.line());
+        }
+        self.pos += 1;
+        Ok(self.text[begin..self.pos - 1].to_string())
+    }
+
+    // 多行字符串, 紧跟开始引号的换行符被忽略, 基本字符串的行尾'\'连接下一行的非空白内容
+    fn parse_multiline_string(&mut self, quote: u8) -> anyhow::Result<String> {
+        self.pos += 3;
+        if self.data[self.pos..].starts_with(b"\r\n") {
+            self.pos += 2;
+        } else if self.peek() == Some(b'\n') {
+            self.pos += 1;
+        }
+
+        let mut s = String::new();
+        loop {
+            let begin = self.pos;
+            while !matches!(self.peek(), None | Some(b'\\')) && self.peek() != Some(quote) {
+                self.pos += 1;
+            }
+            s.push_str(&self.text[begin..self.pos]);
+            match self.peek() {
+                None => anyhow::bail!("unterminated multi-line string at line {}", self.line()),
+                Some(b'\\') if quote == b'"' => {
+                    let rest = &self.data[self.pos + 1..];
+                    let trimmed = rest.iter().position(|c| !matches!(c, b' ' | b'\t')).unwrap_or(rest.len());
+                    if matches!(rest.get(trimmed), Some(b'\r' | b'\n')) {
+                        self.pos += 1;
+                        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+                            self.pos += 1;
+                        }
+                    } else {
+                        self.parse_escape(&mut s)?;
+                    }
+                },
+                Some(c) if c == quote && self.data[self.pos..].starts_with(&[quote; 3]) => {
+                    self.pos += 3;
+                    // 结束引号之后的引号(最多2个)属于字符串内容
+                    for _ in 0..2 {
+                        if self.peek() != Some(quote) {
+                            break;
+                        }
+                        s.push(quote as char);
+                        self.pos += 1;
+                    }
+                    return Ok(s);
+                },
+                Some(c) => {
+                    s.push(c as char);
+                    self.pos += 1;
+                },
+            }
+        }
+    }
+
+    fn parse_escape(&mut self, s: &mut String) -> anyhow::Result<()> {
+        self.pos += 1;
+        let c = match self.peek() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'n') => '\n',
+            Some(b't') => '\t',
+            Some(b'r') => '\r',
+            Some(b'b') => '\x08',
+            Some(b'f') => '\x0c',
+            Some(b'e') => '\x1b',
+            Some(c @ (b'u' | b'U')) => {
+                let len = if c == b'u' { 4 } else { 8 };
+                let hex = self.text.get(self.pos + 1..self.pos + 1 + len).unwrap_or_default();
+                let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+                match c {
+                    Some(c) => { self.pos += len; c },
+                    None => anyhow::bail!("invalid unicode escape at line {}", self.line()),
+                }
+            },
+            _ => anyhow::bail!("invalid escape character at line {}", self.line()),
+        };
+        s.push(c);
+        self.pos += 1;
+        Ok(())
+    }
+
+    // 整数, 浮点数, 布尔值及日期时间, 数字中的下划线被删除
+    fn parse_scalar(&mut self) -> anyhow::Result<String> {
+        let begin = self.pos;
+        while !matches!(self.peek(), None | Some(b' ' | b'\t' | b'\r' | b'\n' | b',' | b']' | b'}' | b'#')) {
+            self.pos += 1;
+        }
+        let token = &self.text[begin..self.pos];
+        match token {
+            "true" | "false" | "inf" | "+inf" | "-inf" | "nan" | "+nan" | "-nan" => Ok(token.to_string()),
+            _ if token.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') => {
+                match token.contains(['-', ':']) && !token.starts_with(['+', '-']) {
+                    true => Ok(token.to_string()),
+                    false => Ok(token.replace('_', "")),
+                }
+            },
+            _ => anyhow::bail!("invalid value at line {}", self.line()),
+        }
+    }
+
+    // 数组, 允许跨行, 允许注释及末尾的逗号
+    fn parse_array(&mut self) -> anyhow::Result<Value> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(b']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_blank_lines();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {},
+                _ => anyhow::bail!("expected ',' or ']' in array at line {}", self.line()),
+            }
+        }
+    }
+
+    // 内联表, 只能写在一行内
+    fn parse_inline_table(&mut self) -> anyhow::Result<Value> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_spaces();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Table(items));
+        }
+        loop {
+            let key = self.parse_key()?.join(".");
+            self.skip_spaces();
+            self.expect("=")?;
+            self.skip_spaces();
+            items.push((key, self.parse_value()?));
+            self.skip_spaces();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; return Ok(Value::Table(items)); },
+                _ => anyhow::bail!("expected ',' or '}}' in inline table at line {}", self.line()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let items = parse(r#"
+# 注释
+log-level = "debug"   # 行尾注释
+port = 5_353
+strict = true
+hosts-file = ["a.conf", 'b.conf',
+    "http://c/hosts",   # 远程hosts
+]
+path = 'C:\temp'
+escape = "tab\there \u4e2d"
+"quoted key" = 1979-05-27T07:32:00Z
+text = """
+line1 \
+    line2"""
+
+[forward]
+lan = "192.168.1.1"
+corp.example = { server = "10.0.0.1", port = 53 }
+
+[[dyndns.keys]]
+id = "home"
+[[dyndns.keys]]
+id = "office"
+"#).unwrap();
+
+        let get = |key: &str| items.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        assert_eq!(Some("debug"), get("log-level"));
+        assert_eq!(Some("5353"), get("port"));
+        assert_eq!(Some("true"), get("strict"));
+        assert_eq!(Some("a.conf,b.conf,http://c/hosts"), get("hosts-file"));
+        assert_eq!(Some("C:\\temp"), get("path"));
+        assert_eq!(Some("tab\there 中"), get("escape"));
+        assert_eq!(Some("1979-05-27T07:32:00Z"), get("quoted key"));
+        assert_eq!(Some("line1 line2"), get("text"));
+        assert_eq!(Some("192.168.1.1"), get("forward.lan"));
+        assert_eq!(Some("10.0.0.1"), get("forward.corp.example.server"));
+        assert_eq!(Some("53"), get("forward.corp.example.port"));
+        assert_eq!(Some("home"), get("dyndns.keys.0.id"));
+        assert_eq!(Some("office"), get("dyndns.keys.1.id"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse("a = 1\na = 2").is_err());
+        assert!(parse("a = \"abc").is_err());
+        assert!(parse("a = 1 2").is_err());
+        assert!(parse("a = [1, 2").is_err());
+        assert!(parse("a = bare").is_err());
+        assert!(parse("[table").is_err());
+        assert!(parse("= 1").is_err());
+    }
+}
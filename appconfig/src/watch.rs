@@ -0,0 +1,147 @@
+//! 配置文件监视, 文件变化时重新解析并通知变化的配置项
+
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::{Duration, SystemTime};
+
+use crate::{AppConfig, Config, Source};
+
+/// Changed field of the config, see [`diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub name: &'static str,     // 配置项名称, 即长选项名称
+    pub old: String,            // 原来的值
+    pub new: String,            // 新的值
+}
+
+/// Compare two configs field by field, returns the changed fields
+pub fn diff<T: AppConfig>(old: &T, new: &T) -> Vec<Change> {
+    old.fields().into_iter().zip(new.fields())
+        .filter(|(o, n)| o.value != n.value)
+        .map(|(o, n)| Change { name: o.name, old: o.value, new: n.value })
+        .collect()
+}
+
+/// Background watcher created by [`watch_config`], stops watching when dropped
+pub struct ConfigWatcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Watch the config file `path`, check its modification time every `interval` and re-parse it
+/// on change, then call `f` with the new config and the changed fields
+///
+/// Fields set on the command line or by environment variables(see [`crate::sources`]) override
+/// the config file, so their changes are not reported. When the changed file can't be parsed,
+/// `f` is called with the error and the previous config is kept.
+pub fn watch_config<T, F>(path: &str, interval: Duration, mut f: F) -> anyhow::Result<ConfigWatcher>
+where
+    T: AppConfig + Default + Send + 'static,
+    F: FnMut(anyhow::Result<(&T, &[Change])>) + Send + 'static,
+{
+    let path = path.to_owned();
+    let mut modified = modified_time(&path)?;
+    let mut current: T = load(&path)?;
+    let overridden: Vec<&str> = crate::sources().into_iter()
+        .filter(|(_, s)| matches!(s, Source::CommandLine | Source::Environment(_)))
+        .map(|(name, _)| name)
+        .collect();
+    let stop = Arc::new(AtomicBool::new(false));
+    let watcher = ConfigWatcher { stop: stop.clone() };
+
+    std::thread::Builder::new().name(String::from("config-watch")).spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(interval);
+            // 文件暂时不存在(如编辑器先删除后写入)时等待下一次检查
+            let time = match modified_time(&path) {
+                Ok(time) if time != modified => time,
+                _ => continue,
+            };
+            modified = time;
+            match load::<T>(&path) {
+                Ok(config) => {
+                    let changes: Vec<Change> = diff(&current, &config).into_iter()
+                        .filter(|c| !overridden.contains(&c.name))
+                        .collect();
+                    current = config;
+                    if !changes.is_empty() {
+                        f(Ok((&current, &changes)));
+                    }
+                },
+                Err(e) => f(Err(e)),
+            }
+        }
+    })?;
+
+    Ok(watcher)
+}
+
+// 读取配置文件的修改时间
+fn modified_time(path: &str) -> anyhow::Result<SystemTime> {
+    let meta = std::fs::metadata(path).map_err(|e| anyhow::anyhow!("can't read app config file {path}: {e}"))?;
+    Ok(meta.modified()?)
+}
+
+// 从缺省值及配置文件生成配置
+fn load<T: AppConfig + Default>(path: &str) -> anyhow::Result<T> {
+    let cfg = Config::with_file(path).map_err(|e| anyhow::anyhow!("can't read app config file {path}: {e}"))?;
+    let mut config = T::default();
+    config.set_from_cfg(&cfg)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use super::*;
+
+    crate::appconfig_define!(WatchConf,
+        log_level: String => ["L", "log-level", "LogLevel", "log level"],
+        ttl      : u32    => ["t", "ttl", "Ttl", "record ttl"],
+    );
+
+    impl Default for WatchConf {
+        fn default() -> Self {
+            WatchConf { log_level: String::from("info"), ttl: 300 }
+        }
+    }
+
+    #[test]
+    fn test_watch_config() {
+        let path = std::env::temp_dir().join("appconfig_watch_test.conf");
+        let path_str = path.to_str().unwrap().to_owned();
+        std::fs::write(&path, "log-level = info\n").unwrap();
+
+        let (tx, rx) = channel();
+        let watcher = watch_config(&path_str, Duration::from_millis(20), move |r: anyhow::Result<(&WatchConf, &[Change])>| {
+            tx.send(r.map(|(c, changes)| (c.ttl, changes.to_vec())).map_err(|e| e.to_string())).unwrap();
+        }).unwrap();
+
+        // 修改时间的精度可能较低, 等待后再修改文件
+        std::thread::sleep(Duration::from_millis(50));
+        std::fs::write(&path, "log-level = debug\nttl = 60\n").unwrap();
+        let filetime = SystemTime::now() + Duration::from_secs(1);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(filetime).unwrap();
+
+        let (ttl, changes) = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(60, ttl);
+        assert_eq!(vec![
+            Change { name: "log-level", old: String::from("info"), new: String::from("debug") },
+            Change { name: "ttl", old: String::from("300"), new: String::from("60") },
+        ], changes);
+
+        std::fs::write(&path, "ttl = abc\n").unwrap();
+        let filetime = SystemTime::now() + Duration::from_secs(2);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(filetime).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().is_err());
+
+        drop(watcher);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
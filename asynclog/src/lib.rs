@@ -1,45 +1,190 @@
-use std::{io::{Write, BufWriter, LineWriter}};
-use std::sync::{mpsc::Sender, Mutex};
+use std::{io::{Write, BufWriter, IsTerminal, LineWriter}};
+use std::fmt::Write as FmtWrite;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::str::FromStr;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-static mut LOG_INITED: bool = false;
+const DEDUP_WINDOW: std::time::Duration = std::time::Duration::from_secs(30);         // 重复消息合并的最长时间
+const FILE_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);   // 日志文件出错后重新打开的间隔
+
+static DROPPED: AtomicU64 = AtomicU64::new(0);                         // 异步队列满时丢弃的消息数
+static WRITTEN: AtomicU64 = AtomicU64::new(0);                         // 已输出的消息数
+static BYTES: AtomicU64 = AtomicU64::new(0);                           // 已输出的字节数
+static ROTATIONS: AtomicU64 = AtomicU64::new(0);                       // 日志文件轮转次数
+static LOGGER: RwLock<Option<Arc<AsyncLogger>>> = RwLock::new(None);   // 当前的日志对象，重新初始化时替换
+static PROXY_INSTALLED: AtomicBool = AtomicBool::new(false);           // 转发对象是否已设置为全局日志对象
+static PROXY: LogProxy = LogProxy;
+
+/// What to do when the async log queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait until the writer thread takes a message from the queue
+    Block,
+    /// Discard the oldest message in the queue
+    DropOldest,
+    /// Discard the new message
+    DropNewest,
+}
+
+/// When to colorize the console output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Colorize when stdout is a terminal and the `NO_COLOR` environment variable is not set
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parses `auto`/`always`/`never`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(format!("can't parse color mode {s}").into()),
+        }
+    }
+}
+
+impl ColorMode {
+    // 是否输出彩色日志
+    fn enabled(self) -> bool {
+        match self {
+            ColorMode::Auto => std::io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|s| s.is_empty()),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+/// Sub-second precision of log timestamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+impl FromStr for Precision {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parses `s`/`ms`/`us`(or `seconds`/`millis`/`micros`)
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "s" | "seconds" => Ok(Precision::Seconds),
+            "ms" | "millis" => Ok(Precision::Millis),
+            "us" | "micros" => Ok(Precision::Micros),
+            _ => Err(format!("can't parse timestamp precision {s}").into()),
+        }
+    }
+}
+
+/// Custom log line formatter, returns the line without the trailing newline
+pub type FormatFn = Box<dyn Fn(&log::Record) -> String + Send + Sync>;
 
 /// `Builder` is a struct that holds the configuration for the logger.
 ///
 /// The `level` field is the minimum log level that will be logged. The `log_file` field is the name of
-/// the log file. The `log_file_max` field is the maximum size of the log file in megabytes. The
+/// the log file. The `log_file_max` field is the maximum size of the log file in bytes. The
+/// `log_backups` field is the number of rotated log files to keep. The
 /// `use_console` field is a boolean that indicates whether or not to log to the console. The
 /// `use_async` field is a boolean that indicates whether or not to use an asynchronous logger.
 ///
-/// The `new()` method
-///
 /// Properties:
 ///
 /// * `level`: The log level to use.
+/// * `filter`: Per-module level filter, overrides `level`, see [`parse_filter`].
+/// * `env`: Environment variable name, its value is parsed as filter directives and overrides `filter`.
 /// * `log_file`: The name of the log file.
-/// * `log_file_max`: The maximum number of log files to keep.
+/// * `log_file_max`: The maximum size of the log file.
+/// * `log_backups`: The number of rotated log files(app.log.1 .. app.log.N) to keep.
+/// * `routes`: Target prefixes routed to their own log files, see [`Builder::route`].
+/// * `json`: If true, the log file is written as JSON lines, see [`Builder::json`].
+/// * `pattern`: Log line pattern, see [`Builder::format`].
+/// * `formatter`: Custom log line formatter, overrides `pattern`.
+/// * `syslog`: Syslog target, see [`Builder::syslog`].
+/// * `journald`: If true, the logger will log to systemd-journald(linux only).
+/// * `event_log`: Windows Event Log source name, empty means disabled(windows only).
+/// * `precision`: Sub-second precision of timestamps, default is milliseconds.
+/// * `utc`: If true, timestamps are in UTC instead of local time.
+/// * `thread`: If true, the name(or ID) of the logging thread is included in each record.
+/// * `color`: When to colorize the console output, see [`ColorMode`].
+/// * `dedup`: If true, repeated messages are collapsed into "last message repeated N times".
+/// * `rate_limit`: The maximum number of messages written per second, 0 means unlimited.
 /// * `use_console`: If true, the logger will log to the console.
+/// * `use_stderr`: If true, warn and error records are written to stderr instead of stdout.
 /// * `use_async`: Whether to use the async logger or not.
+/// * `capacity`: The maximum number of messages waiting to be written in async mode.
+/// * `overflow`: What to do when the async queue is full, see [`Overflow`].
 ///
 /// # Examples
 ///
-/// ```
-/// asnyclog::Builder::new()
+/// ```no_run
+/// asynclog::Builder::new()
 ///     .level(log::LevelFilter::Debug)
+///     .filter(asynclog::parse_filter("info,minidns::dnsserver=trace,mio=warn").unwrap())
+///     .env("APP_LOG")
 ///     .log_file(String::from("./app.log"))
 ///     .log_file_max(1024 * 1024)
+///     .backups(5)
+///     .route("minidns::query", "./query.log")
+///     .json(true)
+///     .format("%d %l [%t] %m")
+///     .syslog("udp://192.168.1.1:514")
+///     .precision(asynclog::Precision::Micros)
+///     .utc(true)
+///     .thread(true)
+///     .color(asynclog::ColorMode::Auto)
+///     .dedup(true)
+///     .rate_limit(1000)
 ///     .use_console(true)
+///     .use_stderr(true)
 ///     .use_async(true)
-///     .builder()?;
+///     .capacity(10000)
+///     .overflow(asynclog::Overflow::DropOldest)
+///     .builder()
+///     .unwrap();
 /// ```
 pub struct Builder {
     level: log::LevelFilter,
+    filter: Option<Filter>,
+    env: String,
     log_file: String,
     log_file_max: u32,
+    log_backups: u32,
+    routes: Vec<(String, String)>,
+    json: bool,
+    pattern: String,
+    formatter: Option<FormatFn>,
+    syslog: String,
+    journald: bool,
+    event_log: String,
+    precision: Precision,
+    utc: bool,
+    thread: bool,
+    color: ColorMode,
+    dedup: bool,
+    rate_limit: u32,
     use_console: bool,
+    use_stderr: bool,
     use_async: bool,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Builder {
@@ -47,16 +192,35 @@ impl Builder {
     pub fn new() -> Self {
         Self {
             level: log::LevelFilter::Info,
+            filter: None,
+            env: String::new(),
             log_file: String::new(),
             log_file_max: 10 * 1024 * 1024,
+            log_backups: 1,
+            routes: Vec::new(),
+            json: false,
+            pattern: String::new(),
+            formatter: None,
+            syslog: String::new(),
+            journald: false,
+            event_log: String::new(),
+            precision: Precision::Millis,
+            utc: false,
+            thread: false,
+            color: ColorMode::Auto,
+            dedup: false,
+            rate_limit: 0,
             use_console: true,
-            use_async: true
+            use_stderr: false,
+            use_async: true,
+            capacity: 10000,
+            overflow: Overflow::Block,
         }
     }
 
     #[inline]
     pub fn builder(self) -> Result<()> {
-        init_log(self.level, self.log_file, self.log_file_max, self.use_console, self.use_async)
+        init(self)
     }
 
     #[inline]
@@ -64,6 +228,17 @@ impl Builder {
         self.level = level; self
     }
 
+    #[inline]
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter); self
+    }
+
+    /// Read filter directives from the environment variable when it is set
+    #[inline]
+    pub fn env(mut self, name: &str) -> Self {
+        self.env = name.to_string(); self
+    }
+
     #[inline]
     pub fn log_file(mut self, log_file: String) -> Self {
         self.log_file = log_file; self
@@ -74,19 +249,139 @@ impl Builder {
         self.log_file_max = log_file_max; self
     }
 
+    /// Number of rotated log files to keep, 0 means the log file is discarded when it is full
+    #[inline]
+    pub fn backups(mut self, log_backups: u32) -> Self {
+        self.log_backups = log_backups; self
+    }
+
+    /// Write records whose target is `prefix` or below it(`prefix::*`) to a separate log file
+    /// instead of the main log file, the first matching route wins
+    ///
+    /// Routed files share the size limit and backups of the main log file, the console is not affected.
+    #[inline]
+    pub fn route(mut self, prefix: &str, log_file: &str) -> Self {
+        self.routes.push((prefix.to_string(), log_file.to_string())); self
+    }
+
+    /// Write the log file as JSON lines(timestamp, level, target, message and key-value fields),
+    /// the console output is not affected
+    ///
+    /// Key-value fields are taken from the log macros, like `log::info!(client = "10.0.0.1"; "query")`
+    #[inline]
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json; self
+    }
+
+    /// Log line pattern, the default format is used when it is empty
+    ///
+    /// Placeholders: `%d` date time, `%l` level, `%t` target, `%n` line number,
+    /// `%m` message, `%k` key-value fields, `%%` percent sign
+    #[inline]
+    pub fn format(mut self, pattern: &str) -> Self {
+        self.pattern = pattern.to_string(); self
+    }
+
+    /// Custom log line formatter, overrides the pattern
+    #[inline]
+    pub fn formatter<F>(mut self, formatter: F) -> Self
+            where F: Fn(&log::Record) -> String + Send + Sync + 'static {
+        self.formatter = Some(Box::new(formatter)); self
+    }
+
+    /// Also write logs to syslog, the target can be `local`(/dev/log), a unix socket path,
+    /// `udp://host[:port]` or `tcp://host[:port]`(default port 514), empty means disabled
+    #[inline]
+    pub fn syslog(mut self, target: &str) -> Self {
+        self.syslog = target.to_string(); self
+    }
+
+    /// Also write logs to systemd-journald with structured fields(PRIORITY, TARGET, CODE_LINE and
+    /// key-value fields), only supported on linux
+    #[inline]
+    pub fn journald(mut self, journald: bool) -> Self {
+        self.journald = journald; self
+    }
+
+    /// Also write logs to Windows Event Log with the source name, only supported on windows
+    #[inline]
+    pub fn event_log(mut self, source: &str) -> Self {
+        self.event_log = source.to_string(); self
+    }
+
+    /// Sub-second precision of timestamps, timestamps always include the year
+    #[inline]
+    pub fn precision(mut self, precision: Precision) -> Self {
+        self.precision = precision; self
+    }
+
+    /// Use UTC timestamps(with a `Z` suffix) instead of local time
+    #[inline]
+    pub fn utc(mut self, utc: bool) -> Self {
+        self.utc = utc; self
+    }
+
+    /// Include the name of the logging thread(or its ID for unnamed threads) in each record,
+    /// the `%T` placeholder of [`Builder::format`] is always available
+    #[inline]
+    pub fn thread(mut self, thread: bool) -> Self {
+        self.thread = thread; self
+    }
+
+    /// When to colorize the console output, the log file is never colorized
+    #[inline]
+    pub fn color(mut self, color: ColorMode) -> Self {
+        self.color = color; self
+    }
+
+    /// Collapse consecutive identical messages(same level, target and text) into
+    /// "last message repeated N times", which is written when a different message arrives
+    #[inline]
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup; self
+    }
+
+    /// The maximum number of messages written per second, excess messages are discarded and counted
+    /// in a notice written in the next second, 0 means unlimited
+    #[inline]
+    pub fn rate_limit(mut self, rate_limit: u32) -> Self {
+        self.rate_limit = rate_limit; self
+    }
+
     #[inline]
     pub fn use_console(mut self, use_console: bool) -> Self {
         self.use_console = use_console; self
     }
 
+    /// Write warn and error records to stderr, info and below to stdout, only works with the console output
+    #[inline]
+    pub fn use_stderr(mut self, use_stderr: bool) -> Self {
+        self.use_stderr = use_stderr; self
+    }
+
     #[inline]
     pub fn use_async(mut self, use_async: bool) -> Self {
         self.use_async = use_async; self
     }
+
+    /// The maximum number of messages waiting to be written in async mode
+    #[inline]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1); self
+    }
+
+    /// What to do when the async queue is full, the number of discarded messages is returned by [`dropped`]
+    #[inline]
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow; self
+    }
 }
 
 /// It creates a new logger, initializes it, and then sets it as the global logger
 ///
+/// It can be called again to reconfigure logging, the previous logger is shut down(see [`shutdown`])
+/// after the new one takes over.
+///
 /// Arguments:
 ///
 /// * `level`: log level
@@ -94,7 +389,7 @@ impl Builder {
 /// * `log_file_max`: The maximum size of the log file, The units that can be used are k/m/g.
 /// * `use_console`: Whether to output to the console
 /// * `use_async`: Whether to use asynchronous logging, if true, the log will be written to the file in
-/// a separate thread, and the log will not be blocked.
+///   a separate thread, and the log will not be blocked.
 ///
 /// Returns:
 ///
@@ -102,62 +397,127 @@ impl Builder {
 ///
 /// # Examples
 ///
+/// ```no_run
+/// asynclog::init_log(log::LevelFilter::Debug, String::from("./app.log"), 1024 * 1024, true, true).unwrap();
 /// ```
-/// asnyclog::init_log(log::LevelFilter::Debug, String::from("./app.log", 1024 * 1024, true, true)?;
-/// ````
 pub fn init_log(level: log::LevelFilter, log_file: String, log_file_max: u32, use_console: bool, use_async: bool) -> Result<()> {
-    if unsafe { LOG_INITED } { return Err("init_log must run once!".into()); }
-    unsafe { LOG_INITED = true; }
+    Builder::new()
+        .level(level)
+        .log_file(log_file)
+        .log_file_max(log_file_max)
+        .use_console(use_console)
+        .use_async(use_async)
+        .builder()
+}
 
-    log::set_max_level(level);
+fn init(builder: Builder) -> Result<()> {
+    let Builder { level, filter, env, log_file, log_file_max, log_backups, routes, json, pattern, formatter, syslog, journald, event_log, precision, utc, thread, color, dedup, rate_limit, use_console, use_stderr, use_async, capacity, overflow } = builder;
 
-    let logger = Box::new(AsyncLogger {
-        level,
-        log_file: log_file,
-        max_size: log_file_max,
-        logger_data: Mutex::new(LogData {
-            log_size: 0, console: None, fileout: None, sender: None,
-        }),
-    });
+    let format = match formatter {
+        Some(f) => Format::Custom(f),
+        None if pattern.is_empty() => Format::Default,
+        None => Format::Pattern(parse_pattern(&pattern)?),
+    };
 
-    let logger = Box::leak(logger);
-    let plog = logger as *mut AsyncLogger;
-    let mut logger_data = logger.logger_data.lock().expect("init_log call mutex lock error");
+    // 日志过滤优先级: 环境变量 > filter > level
+    let mut filter = filter.unwrap_or_else(|| Filter::new(level));
+    if !env.is_empty() {
+        if let Ok(directives) = std::env::var(&env) {
+            if !directives.trim().is_empty() {
+                filter = parse_filter(&directives)?;
+            }
+        }
+    }
+
+    let mut logger_data = LogData {
+        console: None, stderr: None, file: None, routes: Vec::new(), syslog: None, journald: None, event_log: None,
+        console_error: false,
+    };
 
     // 如果启用控制台输出，创建一个控制台共享句柄
     if use_console {
         logger_data.console = Some(LineWriter::new(std::io::stdout()));
+        if use_stderr {
+            logger_data.stderr = Some(std::io::stderr());
+        }
     }
 
     // 如果启用文件输出，打开日志文件
-    if !logger.log_file.is_empty() {
-        let f = std::fs::OpenOptions::new().append(true).create(true).open(&logger.log_file)?;
-        logger_data.log_size = std::fs::metadata(&logger.log_file)?.len() as u32;
-        logger_data.fileout = Some(LogWriter::new(f));
-    }
-
-    // 如果启用异步日志，开启一个线程不停读取channel中的数据进行日志写入，属于多生产者单消费者模式
-    if use_async {
-        let (sender, receiver) = std::sync::mpsc::channel::<AsyncLogType>();
-        logger_data.sender = Some(sender);
-        let logger = unsafe { &*plog };
-        std::thread::spawn(move || {
-            loop {
-                match receiver.recv() {
-                    Ok(data) => match data {
-                        AsyncLogType::Message(msg) => logger.write(msg.as_bytes()),
-                        AsyncLogType::Flush => logger.flush_inner(),
-                    },
-                    Err(e) => {
-                        panic!("logger channel recv error: {}", e);
-                    }
+    if !log_file.is_empty() {
+        logger_data.file = Some(LogFile::open(&log_file)?);
+    }
+
+    // 打开按日志目标分流的日志文件
+    for (_, route_file) in routes.iter() {
+        logger_data.routes.push(LogFile::open(route_file)?);
+    }
+
+    // 如果启用syslog输出，连接syslog服务
+    if !syslog.is_empty() {
+        logger_data.syslog = Some(Syslog::connect(&syslog)?);
+    }
+
+    // 如果启用journald输出，连接journald服务
+    if journald {
+        logger_data.journald = Some(Journald::connect()?);
+    }
+
+    // 如果启用windows事件日志输出，注册事件源
+    if !event_log.is_empty() {
+        logger_data.event_log = Some(EventLog::open(&event_log)?);
+    }
+
+    let logger = Arc::new(AsyncLogger {
+        filter: RwLock::new(filter),
+        log_file,
+        routes: routes.into_iter().map(|(prefix, _)| prefix).collect(),
+        max_size: log_file_max,
+        backups: log_backups,
+        json,
+        format,
+        precision,
+        utc,
+        thread,
+        color: use_console && color.enabled(),
+        throttle: if dedup || rate_limit > 0 { Some(Mutex::new(Throttle::new(dedup, rate_limit))) } else { None },
+        use_record: !syslog.is_empty() || journald || !event_log.is_empty(),
+        queue: if use_async { Some(LogQueue::new(capacity, overflow)) } else { None },
+        writer: Mutex::new(None),
+        logger_data: Mutex::new(logger_data),
+    });
+
+    // 如果启用异步日志，开启一个线程不停读取队列中的数据进行日志写入，属于多生产者单消费者模式
+    if logger.queue.is_some() {
+        let thread_logger = logger.clone();
+        let writer = std::thread::spawn(move || {
+            let logger = thread_logger;
+            let queue = logger.queue.as_ref().unwrap();
+            // 队列关闭且已取完所有消息后退出
+            while let Some(data) = queue.pop() {
+                match data {
+                    AsyncLogType::Message(line) => logger.write(&line),
+                    AsyncLogType::Flush => logger.flush_inner(),
                 }
             }
+            logger.flush_inner();
         });
+        *logger.writer.lock().unwrap_or_else(PoisonError::into_inner) = Some(writer);
     }
 
-    // 设置全局日志对象
-    log::set_logger(logger).expect("init_log call set_logger error");
+    // 全局日志对象只能设置一次，设置为转发对象，由转发对象调用当前的日志对象
+    if !PROXY_INSTALLED.swap(true, Ordering::SeqCst) {
+        if let Err(e) = log::set_logger(&PROXY) {
+            PROXY_INSTALLED.store(false, Ordering::SeqCst);
+            return Err(format!("init_log call set_logger error: {e}").into());
+        }
+    }
+
+    // 替换当前的日志对象，旧的日志对象写完剩余的消息后关闭
+    log::set_max_level(logger.filter().max_level());
+    let old = LOGGER.write().unwrap_or_else(PoisonError::into_inner).replace(logger);
+    if let Some(old) = old {
+        old.shutdown();
+    }
 
     Ok(())
 }
@@ -178,6 +538,65 @@ pub fn parse_level(level: &str) -> Result<log::LevelFilter> {
     }
 }
 
+/// It parses RUST_LOG-style filter directives, like `info,minidns::dnsserver=trace,mio=warn`
+///
+/// A directive without `=` sets the default level, `target=level` sets the level of the target
+/// and its submodules, the longest matching target wins.
+///
+/// Arguments:
+///
+/// * `directives`: Comma separated directives.
+///
+/// Returns:
+///
+/// A Result<Filter>
+pub fn parse_filter(directives: &str) -> Result<Filter> {
+    let mut filter = Filter::new(log::LevelFilter::Info);
+    for directive in directives.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                let level = parse_level(level.trim())
+                    .map_err(|_| format!("can't parse log level of directive {directive}"))?;
+                filter.modules.push((target.trim().to_string(), level));
+            },
+            None => filter.default = parse_level(directive)?,
+        }
+    }
+    // 按目标长度降序排列, 优先匹配最长的目标
+    filter.modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+    Ok(filter)
+}
+
+/// Log level filter by module(target)
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: log::LevelFilter,                 // 未匹配任何模块时的级别
+    modules: Vec<(String, log::LevelFilter)>,  // 模块及其级别, 按模块名长度降序排列
+}
+
+impl Filter {
+    pub fn new(level: log::LevelFilter) -> Self {
+        Filter { default: level, modules: Vec::new() }
+    }
+
+    /// The level of the target(module path)
+    pub fn level(&self, target: &str) -> log::LevelFilter {
+        self.modules.iter()
+            .find(|(module, _)| in_module(target, module))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level of all directives
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, std::cmp::max)
+    }
+}
+
+// 日志目标是否为指定模块或其子模块
+fn in_module(target: &str, module: &str) -> bool {
+    target.strip_prefix(module).is_some_and(|s| s.is_empty() || s.starts_with("::"))
+}
+
 /// It parses a string into a number, The units that can be used are k/m/g
 ///
 /// Arguments:
@@ -206,160 +625,1118 @@ pub fn parse_size(size: &str) -> Result<u32> {
     }
 }
 
+// 日志条目的文本格式
+enum Format {
+    Default,                // 内置格式，根据日志级别选择简略或详细格式
+    Pattern(Vec<Token>),    // 用户定义的格式模板
+    Custom(FormatFn),       // 用户定义的格式化函数
+}
+
+// 格式模板的组成部分
+#[derive(Debug, PartialEq)]
+enum Token {
+    Text(String),   // 原样输出的文本
+    Date,           // %d
+    Level,          // %l
+    Target,         // %t
+    Line,           // %n
+    Message,        // %m
+    Fields,         // %k
+    Thread,         // %T
+}
+
+// 解析格式模板，如"%d %l [%t] %m"
+fn parse_pattern(pattern: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            text.push(c);
+            continue;
+        }
+        let token = match chars.next() {
+            Some('%') => { text.push('%'); continue; },
+            Some('d') => Token::Date,
+            Some('l') => Token::Level,
+            Some('t') => Token::Target,
+            Some('n') => Token::Line,
+            Some('m') => Token::Message,
+            Some('k') => Token::Fields,
+            Some('T') => Token::Thread,
+            Some(c) => return Err(format!("unknown log pattern placeholder %{c}").into()),
+            None => return Err("log pattern ends with %".into()),
+        };
+        if !text.is_empty() {
+            tokens.push(Token::Text(std::mem::take(&mut text)));
+        }
+        tokens.push(token);
+    }
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    Ok(tokens)
+}
+
+// 按格式模板生成日志条目，以换行符结尾
+fn format_pattern(tokens: &[Token], now: &str, record: &log::Record, fields: &Fields) -> String {
+    let mut s = String::with_capacity(128);
+    for token in tokens.iter() {
+        let _ = match token {
+            Token::Text(text) => { s.push_str(text); Ok(()) },
+            Token::Date => write!(s, "{now}"),
+            Token::Level => write!(s, "{:5}", record.level()),
+            Token::Target => write!(s, "{}", record.target()),
+            Token::Line => write!(s, "{}", record.line().unwrap_or(0)),
+            Token::Message => write!(s, "{}", record.args()),
+            Token::Fields => write!(s, "{}", fields.to_text().trim_start()),
+            Token::Thread => write!(s, "{}", thread_name()),
+        };
+    }
+    s.push('\n');
+    s
+}
+
 enum AsyncLogType {
-    Message(String),
+    Message(LogLine),
     Flush,
 }
 
+// 格式化后的日志条目
+struct LogLine {
+    level: log::Level,                      // 日志级别
+    text: String,                           // 文本格式，用于控制台，未启用json时也用于日志文件
+    plain: Option<String>,                  // 不含颜色的文本格式，控制台输出彩色日志时用于日志文件
+    json: Option<String>,                   // json格式，启用json时用于日志文件
+    record: Option<LogRecord>,              // 结构化条目，启用syslog/journald/事件日志时使用
+    route: Option<usize>,                   // 匹配的分流规则序号，None表示写入主日志文件
+}
+
+// 结构化的日志条目
+struct LogRecord {
+    level:   log::Level,   // 日志级别
+    target:  String,       // 日志目标(模块路径)
+    line:    u32,          // 源代码行号
+    message: String,       // 日志消息
+    fields:  Fields,       // 附带的键值对字段
+}
+
+impl LogRecord {
+    // 消息及文本格式的字段
+    fn text(&self) -> String {
+        format!("{}{}", self.message, self.fields.to_text())
+    }
+}
+
+// 日志条目附带的键值对字段
+#[derive(Default)]
+struct Fields(Vec<(String, String, bool)>);   // 字段名, 字段值, 值是否为数字或布尔类型(json中不加引号)
+
+impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> std::result::Result<(), log::kv::Error> {
+        let bare = value.to_bool().is_some() || value.to_i64().is_some() || value.to_u64().is_some()
+                || value.to_f64().is_some_and(f64::is_finite);
+        self.0.push((key.to_string(), value.to_string(), bare));
+        Ok(())
+    }
+}
+
+impl Fields {
+    fn collect(record: &log::Record) -> Self {
+        let mut fields = Fields::default();
+        let _ = record.key_values().visit(&mut fields);
+        fields
+    }
+
+    // 文本格式的字段，如 " client=10.0.0.1 ms=3"
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (key, value, _) in self.0.iter() {
+            let _ = write!(text, " {key}={value}");
+        }
+        text
+    }
+}
+
 struct LogData {
-    log_size:   u32,                                    // 当前日志文件的大小，跟随写入新的日志内容而变化
     console:    Option<LineWriter<std::io::Stdout>>,    // 控制台对象，如果启用了控制台输出，则对象有值
-    fileout:    Option<LogWriter>,                      // 文件对象，如果启用了文件输出，则对象有值
-    sender:     Option<Sender<AsyncLogType>>,           // 异步发送频道，如果启用了异步日志模式，则对象有值
+    stderr:     Option<std::io::Stderr>,                // 标准错误输出，如果启用了警告及错误输出到stderr，则对象有值
+    file:       Option<LogFile>,                        // 日志文件，如果启用了文件输出，则对象有值
+    routes:     Vec<LogFile>,                           // 按日志目标分流的日志文件，与AsyncLogger.routes一一对应
+    syslog:     Option<Syslog>,                         // syslog对象，如果启用了syslog输出，则对象有值
+    journald:   Option<Journald>,                       // journald对象，如果启用了journald输出，则对象有值
+    event_log:  Option<EventLog>,                       // windows事件日志对象，如果启用了事件日志输出，则对象有值
+    console_error: bool,                                // 控制台是否处于写入失败状态
+}
+
+// 日志文件，出错后关闭文件，间隔一段时间后尝试重新打开
+struct LogFile {
+    path:       String,                                 // 日志文件名
+    size:       u32,                                    // 当前日志文件的大小，跟随写入新的日志内容而变化
+    out:        Option<LogWriter>,                      // 文件对象，出错后被关闭
+    error:      Option<std::time::Instant>,             // 日志文件最近一次出错的时间
+}
+
+impl LogFile {
+    fn open(path: &str) -> std::io::Result<LogFile> {
+        let (f, size) = open_log_file(path)?;
+        Ok(LogFile { path: path.to_string(), size, out: Some(LogWriter::new(f)), error: None })
+    }
+
+    // 写入日志，文件长度超过max_size时先轮转，出错后未恢复前丢弃日志
+    fn write(&mut self, msg: &[u8], max_size: u32, backups: u32) {
+        // 日志文件出错后被关闭，间隔一段时间后尝试重新打开
+        if self.out.is_none() {
+            match self.error {
+                Some(t) if t.elapsed() >= FILE_RETRY_INTERVAL => match open_log_file(&self.path) {
+                    Ok((f, size)) => {
+                        eprintln!("asynclog: log file {} recovered", self.path);
+                        self.out = Some(LogWriter::new(f));
+                        self.size = size;
+                        self.error = None;
+                    },
+                    Err(_) => {
+                        self.error = Some(std::time::Instant::now());
+                        return;
+                    },
+                },
+                _ => return,
+            }
+        }
+
+        // 判断日志长度是否到达最大限制，如果到了，需要备份当前日志文件并重新创建新的日志文件
+        if self.size > max_size {
+            let result = self.out.as_mut().map_or(Ok(()), |f| f.flush())
+                // 备份文件依次后移，当前日志文件成为第1个备份
+                .and_then(|_| rotate_files(&self.path, backups))
+                .and_then(|_| std::fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&self.path));
+
+            match result {
+                Ok(f) => {
+                    self.out = Some(LogWriter::new(f));
+                    self.size = 0;
+                    ROTATIONS.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(e) => return self.failed("rotate", &e),
+            }
+        }
+
+        if let Some(ref mut out) = self.out {
+            match out.write_all(msg) {
+                Ok(_) => self.size += msg.len() as u32,
+                Err(e) => self.failed("write", &e),
+            }
+        }
+    }
+
+    // 关闭当前日志文件并重新打开
+    fn reopen(&mut self) -> std::io::Result<()> {
+        if let Some(ref mut out) = self.out {
+            let _ = out.flush();
+        }
+        match open_log_file(&self.path) {
+            Ok((f, size)) => {
+                self.out = Some(LogWriter::new(f));
+                self.size = size;
+                self.error = None;
+                Ok(())
+            },
+            Err(e) => {
+                self.failed("reopen", &e);
+                Err(e)
+            },
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(ref mut out) = self.out {
+            if let Err(e) = out.flush() {
+                self.failed("flush", &e);
+            }
+        }
+    }
+
+    // 日志文件出错，报告错误并关闭文件，之后定期尝试重新打开
+    fn failed(&mut self, action: &str, e: &std::io::Error) {
+        eprintln!("asynclog: {action} log file {} error: {e}, retry in {} seconds",
+                self.path, FILE_RETRY_INTERVAL.as_secs());
+        self.out = None;
+        self.error = Some(std::time::Instant::now());
+    }
 }
 
 struct AsyncLogger {
-    level:          log::LevelFilter,   // 日志的有效级别，小于该级别的日志允许输出
+    filter:         RwLock<Filter>,     // 日志的有效级别，小于该级别的日志允许输出，运行时可调整
     log_file:       String,             // 日志文件名
+    routes:         Vec<String>,        // 分流到独立日志文件的日志目标前缀
     max_size:       u32,                // 日志文件允许的最大长度
+    backups:        u32,                // 保留的日志备份文件数量
+    json:           bool,               // 日志文件是否采用json格式
+    format:         Format,             // 日志条目的文本格式
+    precision:      Precision,          // 时间戳精度
+    utc:            bool,               // 时间戳是否采用utc时间
+    thread:         bool,               // 是否输出写日志的线程名称
+    color:          bool,               // 控制台是否输出彩色日志
+    throttle:       Option<Mutex<Throttle>>,    // 重复消息合并及限速，两者都未启用时为None
+    use_record:     bool,               // 是否需要结构化条目(启用了syslog/journald/事件日志)
+    queue:          Option<LogQueue>,   // 异步日志队列，如果启用了异步日志模式，则对象有值
+    writer:         Mutex<Option<JoinHandle<()>>>,  // 写日志线程，关闭日志时等待其结束
     logger_data:    Mutex<LogData>,     // 日志关联的动态变化的数据
 }
 
 impl AsyncLogger {
     // 输出日志到控制台和文件
-    fn write(&self, msg: &[u8]) {
-        let mut logger_data = self.logger_data.lock().unwrap();
+    fn write(&self, line: &LogLine) {
+        let mut logger_data = self.lock_data();
 
-        // 如果启用了控制台输出，则写入控制台
-        if let Some(ref mut console) = logger_data.console {
-            console.write_all(msg).expect("output log console error");
+        // 如果启用了控制台输出，则写入控制台，失败时只报告一次，直到恢复正常
+        let data = &mut *logger_data;
+        let result = match (&mut data.stderr, &mut data.console) {
+            (Some(stderr), _) if line.level <= log::Level::Warn => Some(stderr.write_all(line.text.as_bytes())),
+            (_, Some(console)) => Some(console.write_all(line.text.as_bytes())),
+            _ => None,
+        };
+        if let Some(result) = result {
+            match result {
+                Ok(_) => logger_data.console_error = false,
+                Err(e) => {
+                    if !logger_data.console_error {
+                        eprintln!("asynclog: write log console error: {e}");
+                        logger_data.console_error = true;
+                    }
+                },
+            }
         }
 
-        let mut curr_size = logger_data.log_size;
-
-        // 判断日志长度是否到达最大限制，如果到了，需要备份当前日志文件并重新创建新的日志文件
-        if curr_size > self.max_size {
-            let mut log_file_closed = false;
-
-            // 如果启用了日志文件，刷新缓存并关闭日志文件
-            if let Some(ref mut fileout) = logger_data.fileout {
-                fileout.flush().unwrap();
-                drop(fileout);
-                log_file_closed = true;
+        if let Some(ref record) = line.record {
+            if let Some(ref mut syslog) = logger_data.syslog {
+                let _ = syslog.send(record.level, &record.text());
+            }
+            if let Some(ref journald) = logger_data.journald {
+                let _ = journald.send(record);
+            }
+            if let Some(ref event_log) = logger_data.event_log {
+                let _ = event_log.report(record.level, &record.text());
             }
+        }
 
-            // 之所以把关闭文件和重新创建文件分开写，是因为rust限制了可变借用(fileout)只允许1次
-            if log_file_closed {
-                // 删除已有备份，并重命名现有文件为备份文件
-                let bak = format!("{}.bak", self.log_file);
-                std::fs::remove_file(&bak).unwrap_or_default();
+        // 匹配分流规则的日志写入对应的文件，其它日志写入主日志文件
+        let file = match line.route {
+            Some(i) => logger_data.routes.get_mut(i),
+            None => logger_data.file.as_mut(),
+        };
+        let msg = match file {
+            Some(file) => {
+                let msg = line.json.as_ref().or(line.plain.as_ref()).unwrap_or(&line.text).as_bytes();
+                file.write(msg, self.max_size, self.backups);
+                msg
+            },
+            None => line.text.as_bytes(),
+        };
 
-                std::fs::rename(&self.log_file, &bak)
-                        .expect("rename log file to backup error");
+        WRITTEN.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(msg.len() as u64, Ordering::Relaxed);
+    }
 
-                let f = std::fs::OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open(&self.log_file)
-                        .expect("reopen log file error");
+    // 当前的日志过滤条件
+    fn filter(&self) -> std::sync::RwLockReadGuard<'_, Filter> {
+        self.filter.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 替换日志过滤条件
+    fn set_filter(&self, filter: Filter) {
+        *self.filter.write().unwrap_or_else(PoisonError::into_inner) = filter;
+    }
+
+    // 日志数据加锁，其它线程持有锁时崩溃不影响日志输出
+    fn lock_data(&self) -> std::sync::MutexGuard<'_, LogData> {
+        self.logger_data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 格式化日志条目并输出
+    fn emit(&self, record: &log::Record) {
+        let time = chrono::Utc::now();
+        let now = format_time(time, self.precision, self.utc);
+
+        let fields = Fields::collect(record);
+        // 格式化在调用者线程中进行，异步模式下写日志线程无法获取调用者的线程名称
+        let thread = match self.thread {
+            true => Some(thread_name()),
+            false => None,
+        };
+        let thread = thread.as_deref();
+
+        // 日志条目格式化，只有内置的详细格式带颜色
+        let detailed = self.filter().max_level() >= log::LevelFilter::Debug;
+        let text = match self.format {
+            Format::Pattern(ref tokens) => format_pattern(tokens, &now, record, &fields),
+            Format::Custom(ref f) => { let mut s = f(record); s.push('\n'); s },
+            Format::Default => format_default(&now, record, &fields, thread, detailed, self.color),
+        };
+        let plain = match self.color && detailed && !self.json && !(self.log_file.is_empty() && self.routes.is_empty()) {
+            true if matches!(self.format, Format::Default) => Some(format_default(&now, record, &fields, thread, detailed, false)),
+            _ => None,
+        };
+        let json = match self.json {
+            true => Some(format_json(&format_rfc3339(time, self.precision, self.utc), record, &fields, thread)),
+            false => None,
+        };
+        let structured = match self.use_record {
+            true => Some(LogRecord {
+                level: record.level(),
+                target: record.target().to_string(),
+                line: record.line().unwrap_or(0),
+                message: record.args().to_string(),
+                fields,
+            }),
+            false => None,
+        };
+        let route = self.routes.iter().position(|prefix| in_module(record.target(), prefix));
+        let line = LogLine { level: record.level(), text, plain, json, record: structured, route };
+
+        // 采用独立的单线程写入日志的方式，向队列发送要写入的日志消息即可，队列已关闭时直接写入
+        let line = match self.queue {
+            Some(ref queue) => match queue.push(AsyncLogType::Message(line)) {
+                Some(AsyncLogType::Message(line)) => line,
+                _ => return,
+            },
+            None => line,
+        };
+        self.write(&line);
+    }
 
-                logger_data.fileout = Some(LogWriter::new(f));
-                curr_size = 0;
+    // 关闭所有日志文件并重新打开，用于外部工具轮转日志文件之后，返回第一个出错的结果
+    fn reopen(&self) -> std::io::Result<()> {
+        let mut logger_data = self.lock_data();
+        let data = &mut *logger_data;
+        let mut result = Ok(());
+        for file in data.file.iter_mut().chain(data.routes.iter_mut()) {
+            let r = file.reopen();
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+
+    // 关闭异步队列，等待写日志线程写完剩余的消息后结束，然后刷新缓存
+    fn shutdown(&self) {
+        if let Some(ref throttle) = self.throttle {
+            let notice = throttle.lock().unwrap_or_else(PoisonError::into_inner).take_repeats();
+            if let Some((level, target, msg)) = notice {
+                self.emit(&log::Record::builder().args(format_args!("{msg}")).level(level).target(&target).build());
             }
         }
 
-        if let Some(ref mut fileout) = logger_data.fileout {
-            fileout.write_all(msg).expect("write log file error");
-            logger_data.log_size = curr_size + msg.len() as u32;
+        let writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let (Some(ref queue), Some(writer)) = (&self.queue, writer) {
+            queue.close();
+            let _ = writer.join();
         }
+        self.flush_inner();
     }
 
     // 刷新日志的控制台和文件缓存
     fn flush_inner(&self) {
-        let mut logger_data = self.logger_data.lock().unwrap();
+        let mut logger_data = self.lock_data();
 
         if let Some(ref mut console) = logger_data.console {
-            console.flush().expect("flush log console error");
+            let _ = console.flush();
         }
 
-        if let Some(ref mut fileout) = logger_data.fileout {
-            fileout.flush().expect("flush log file error");
-        }
+        let data = &mut *logger_data;
+        data.file.iter_mut().chain(data.routes.iter_mut()).for_each(LogFile::flush);
     }
 }
 
 impl log::Log for AsyncLogger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool { metadata.level() <= self.level }
+    fn enabled(&self, metadata: &log::Metadata) -> bool { metadata.level() <= self.filter().level(metadata.target()) }
 
     fn log(&self, record: &log::Record) {
-        if record.metadata().level() > self.level { return; }
-
-        let now = chrono::Local::now().format("%m-%d %H:%M:%S");
-
-        // 日志条目格式化
-        let msg = if self.level >= log::LevelFilter::Debug {
-            format!("[\x1b[36m{}\x1b[0m] [{}{:5}\x1b[0m] [{}::{}] - {}\n",
-                    now,
-                    level_color(record.level()), record.level(),
-                    record.target(), record.line().unwrap_or(0),
-                    record.args())
-        } else {
-            format!("[{}] [{:5}] - {}\n", now, record.level(), record.args())
-        };
+        if !self.enabled(record.metadata()) { return; }
 
-        let logger_data = self.logger_data.lock().unwrap();
-        // 采用独立的单线程写入日志的方式，向channel发送要写入的日志消息即可
-        if let Some(ref sender) = logger_data.sender {
-            sender.send(AsyncLogType::Message(msg)).unwrap();
-        } else {
-            // 不采用独立写日志线程的情况下，需要先释放锁，因为write函数里面会进行加锁，
-            // 如果不释放，则会造成死锁
-            drop(logger_data);
-            self.write(msg.as_bytes());
+        // 合并重复消息及限速，需要先输出合并或丢弃消息的提示
+        if let Some(ref throttle) = self.throttle {
+            let mut notices = Vec::new();
+            let pass = throttle.lock().unwrap_or_else(PoisonError::into_inner)
+                    .check(record.level(), record.target(), &record.args().to_string(), std::time::Instant::now(), &mut notices);
+            for (level, target, msg) in notices {
+                self.emit(&log::Record::builder().args(format_args!("{msg}")).level(level).target(&target).build());
+            }
+            if !pass { return; }
         }
+
+        self.emit(record);
     }
 
     fn flush(&self) {
-        let logger_data = self.logger_data.lock().unwrap();
-        if let Some(ref sender) = logger_data.sender {
-            sender.send(AsyncLogType::Flush).unwrap();
-        } else {
-            drop(logger_data);
-            self.flush_inner();
+        if let Some(ref queue) = self.queue {
+            if queue.push(AsyncLogType::Flush).is_none() {
+                return;
+            }
         }
+        self.flush_inner();
     }
 }
 
-// 日志文件写入对象，提供过滤掉ansi颜色字符的功能
-struct LogWriter(BufWriter<std::fs::File>);
-
-impl LogWriter {
-    pub fn new(file: std::fs::File) -> Self {
-        LogWriter(BufWriter::with_capacity(512, file))
+/// Drain the async queue, flush all outputs and join the writer thread, call it before the process
+/// exits so that no messages are lost, messages logged afterwards are written synchronously
+pub fn shutdown() {
+    if let Some(logger) = current_logger() {
+        logger.shutdown();
     }
 }
 
-impl Write for LogWriter {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let len = buf.len();
-        let (mut last_pos, mut i) = (0, 0);
-
-        // 过滤ansi颜色
-        if len > 3 {
-            while i < len - 3 {
-                if buf[i] == 0x1b && buf[i + 1] == b'[' {
-                    let n = if buf[i + 3] == b'm' { 4 } else { 5 };
-                    self.0.write_all(&buf[last_pos .. i])?;
-                    i += n;
-                    last_pos = i;
-                } else {
-                    i += 1;
-                }
-            }
+// 当前的日志对象
+fn current_logger() -> Option<Arc<AsyncLogger>> {
+    LOGGER.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+// 转发对象，log库只允许设置一次全局日志对象，通过转发支持重新初始化
+struct LogProxy;
+
+impl log::Log for LogProxy {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        current_logger().is_some_and(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(logger) = current_logger() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = current_logger() {
+            logger.flush();
+        }
+    }
+}
+
+/// Reopen the log file, call it after the log file is renamed by external tools like logrotate,
+/// usually on SIGHUP
+pub fn reopen() -> Result<()> {
+    match current_logger() {
+        Some(logger) => Ok(logger.reopen()?),
+        None => Ok(()),
+    }
+}
+
+/// Change the log level of the installed logger at runtime, per-module filter directives are replaced
+///
+/// # Examples
+///
+/// ```no_run
+/// asynclog::Builder::new().builder().unwrap();
+/// asynclog::set_level(log::LevelFilter::Debug);
+/// ```
+pub fn set_level(level: log::LevelFilter) {
+    set_filter(Filter::new(level));
+}
+
+/// Replace the filter of the installed logger at runtime, see [`parse_filter`]
+pub fn set_filter(filter: Filter) {
+    if let Some(logger) = current_logger() {
+        log::set_max_level(filter.max_level());
+        logger.set_filter(filter);
+    }
+}
+
+/// The filter of the installed logger, `None` if the logger is not initialized
+pub fn filter() -> Option<Filter> {
+    current_logger().map(|logger| logger.filter().clone())
+}
+
+/// Returns a guard that calls [`shutdown`] when it is dropped
+///
+/// # Examples
+///
+/// ```no_run
+/// asynclog::Builder::new().builder().unwrap();
+/// let _log_guard = asynclog::guard();
+/// log::info!("written before the guard is dropped");
+/// ```
+pub fn guard() -> LogGuard {
+    LogGuard
+}
+
+/// Flush-on-drop guard, see [`guard`]
+pub struct LogGuard;
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        shutdown();
+    }
+}
+
+/// The number of log messages discarded because the async queue was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Logger health counters since the process started, see [`stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Messages written to the console or log files
+    pub written: u64,
+    /// Bytes of the written messages, counted once per message
+    pub bytes: u64,
+    /// Log file rotations performed
+    pub rotations: u64,
+    /// Messages discarded because the async queue was full
+    pub dropped: u64,
+}
+
+/// Logger health counters, so the application can surface them in its own metrics
+pub fn stats() -> Stats {
+    Stats {
+        written: WRITTEN.load(Ordering::Relaxed),
+        bytes: BYTES.load(Ordering::Relaxed),
+        rotations: ROTATIONS.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+// 合并重复消息及限速
+struct Throttle {
+    dedup:      bool,                                   // 是否合并重复消息
+    last:       Option<(log::Level, String, String)>,   // 上一条输出的消息的级别、目标及内容
+    repeats:    u32,                                    // 上一条消息被合并的重复次数
+    since:      std::time::Instant,                     // 上一条消息输出的时间
+    rate:       u32,                                    // 每秒最多输出的消息数，0表示不限制
+    window:     std::time::Instant,                     // 当前限速窗口的开始时间
+    count:      u32,                                    // 当前限速窗口内已输出的消息数
+    suppressed: u64,                                    // 被限速丢弃、尚未提示的消息数
+}
+
+// 需要输出的提示: 级别、目标及内容
+type Notice = (log::Level, String, String);
+
+impl Throttle {
+    fn new(dedup: bool, rate: u32) -> Self {
+        let now = std::time::Instant::now();
+        Throttle { dedup, last: None, repeats: 0, since: now, rate, window: now, count: 0, suppressed: 0 }
+    }
+
+    // 检查消息是否允许输出，需要先输出的提示加入notices
+    fn check(&mut self, level: log::Level, target: &str, message: &str, now: std::time::Instant, notices: &mut Vec<Notice>) -> bool {
+        if self.dedup {
+            if let Some((ref l, ref t, ref m)) = self.last {
+                if *l == level && t == target && m == message && now.duration_since(self.since) < DEDUP_WINDOW {
+                    self.repeats += 1;
+                    return false;
+                }
+            }
+            notices.extend(self.take_repeats());
+        }
+
+        if self.rate > 0 {
+            if now.duration_since(self.window) >= std::time::Duration::from_secs(1) {
+                self.window = now;
+                self.count = 0;
+                if self.suppressed > 0 {
+                    notices.push((log::Level::Warn, String::from("asynclog"),
+                            format!("{} messages suppressed by rate limit", self.suppressed)));
+                    self.suppressed = 0;
+                }
+            }
+            if self.count >= self.rate {
+                self.suppressed += 1;
+                return false;
+            }
+            self.count += 1;
+        }
+
+        if self.dedup {
+            self.last = Some((level, target.to_string(), message.to_string()));
+            self.since = now;
+        }
+        true
+    }
+
+    // 取出上一条消息的重复次数提示
+    fn take_repeats(&mut self) -> Option<Notice> {
+        let repeats = std::mem::take(&mut self.repeats);
+        match self.last.take() {
+            Some((level, target, _)) if repeats > 0 => Some((level, target, format!("last message repeated {repeats} times"))),
+            _ => None,
+        }
+    }
+}
+
+// 有界的异步日志队列，队列满时按溢出策略处理
+struct LogQueue {
+    items:     Mutex<VecDeque<AsyncLogType>>,   // 等待写入的日志消息
+    not_empty: Condvar,                         // 队列非空时通知写日志线程
+    not_full:  Condvar,                         // 队列未满时通知等待的生产者
+    capacity:  usize,                           // 队列容量
+    overflow:  Overflow,                        // 溢出策略
+    closed:    AtomicBool,                      // 队列是否已关闭，只在持有items锁时修改
+}
+
+impl LogQueue {
+    fn new(capacity: usize, overflow: Overflow) -> Self {
+        LogQueue {
+            items: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            overflow,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    // 加入消息，队列已关闭时返回该消息，由调用者直接写入
+    fn push(&self, item: AsyncLogType) -> Option<AsyncLogType> {
+        let mut items = self.items.lock().unwrap();
+        while items.len() >= self.capacity {
+            if self.closed.load(Ordering::Relaxed) {
+                return Some(item);
+            }
+            match self.overflow {
+                Overflow::Block => items = self.not_full.wait(items).unwrap(),
+                Overflow::DropOldest => {
+                    items.pop_front();
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                },
+                Overflow::DropNewest => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                },
+            }
+        }
+        if self.closed.load(Ordering::Relaxed) {
+            return Some(item);
+        }
+        items.push_back(item);
+        self.not_empty.notify_one();
+        None
+    }
+
+    // 取出消息，队列为空时等待，队列已关闭且为空时返回None
+    fn pop(&self) -> Option<AsyncLogType> {
+        let mut items = self.items.lock().unwrap();
+        loop {
+            if let Some(item) = items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            items = self.not_empty.wait(items).unwrap();
+        }
+    }
+
+    // 关闭队列，之后加入的消息由调用者直接写入
+    fn close(&self) {
+        let _items = self.items.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+// syslog输出对象，按RFC3164格式发送日志
+struct Syslog {
+    conn:     SyslogConn,   // syslog服务连接
+    hostname: String,       // 本机名称，发送给远程syslog服务时使用
+    tag:      String,       // 应用名称及进程id，如mdns[1234]
+}
+
+enum SyslogConn {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+    Udp(std::net::UdpSocket),
+    Tcp(String, Option<std::net::TcpStream>),   // 服务地址，连接断开后下次发送时重连
+}
+
+const SYSLOG_PORT: u16 = 514;          // syslog服务的缺省端口
+const SYSLOG_FACILITY: u8 = 3;         // daemon，系统守护进程
+
+impl Syslog {
+    fn connect(target: &str) -> Result<Self> {
+        let with_port = |addr: &str| match addr.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => addr.to_string(),
+            _ => format!("{addr}:{SYSLOG_PORT}"),
+        };
+
+        let conn = if let Some(addr) = target.strip_prefix("udp://") {
+            let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(with_port(addr))?;
+            SyslogConn::Udp(socket)
+        } else if let Some(addr) = target.strip_prefix("tcp://") {
+            let addr = with_port(addr);
+            let stream = std::net::TcpStream::connect(&addr)?;
+            SyslogConn::Tcp(addr, Some(stream))
+        } else {
+            Self::connect_unix(if target == "local" { "/dev/log" } else { target })?
+        };
+
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|_| String::from("localhost"));
+        let tag = format!("{}[{}]", app_name(), std::process::id());
+
+        Ok(Syslog { conn, hostname, tag })
+    }
+
+    #[cfg(unix)]
+    fn connect_unix(path: &str) -> Result<SyslogConn> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path).map_err(|e| format!("connect syslog {path} error: {e}"))?;
+        Ok(SyslogConn::Unix(socket))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(path: &str) -> Result<SyslogConn> {
+        Err(format!("unsupported syslog target {path}").into())
+    }
+
+    fn send(&mut self, level: log::Level, msg: &str) -> std::io::Result<()> {
+        let now = chrono::Local::now().format("%b %e %H:%M:%S");
+        match self.conn {
+            #[cfg(unix)]
+            SyslogConn::Unix(ref socket) => {
+                let data = format_syslog(level, &now.to_string(), None, &self.tag, msg);
+                socket.send(data.as_bytes()).map(|_| ())
+            },
+            SyslogConn::Udp(ref socket) => {
+                let data = format_syslog(level, &now.to_string(), Some(&self.hostname), &self.tag, msg);
+                socket.send(data.as_bytes()).map(|_| ())
+            },
+            SyslogConn::Tcp(ref addr, ref mut stream) => {
+                // tcp采用换行符分隔消息，消息中的换行符替换为空格
+                let mut data = format_syslog(level, &now.to_string(), Some(&self.hostname), &self.tag, &msg.replace('\n', " "));
+                data.push('\n');
+                if stream.is_none() {
+                    *stream = Some(std::net::TcpStream::connect(addr.as_str())?);
+                }
+                let result = stream.as_mut().unwrap().write_all(data.as_bytes());
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            },
+        }
+    }
+}
+
+// 应用名称，即可执行文件名
+fn app_name() -> String {
+    std::env::args().next()
+        .and_then(|s| std::path::Path::new(&s).file_name().map(|s| s.to_string_lossy().to_string()))
+        .unwrap_or_else(|| String::from("app"))
+}
+
+// journald输出对象，采用journald原生协议发送结构化日志
+#[cfg(target_os = "linux")]
+struct Journald {
+    socket:     std::os::unix::net::UnixDatagram,   // journald服务连接
+    identifier: String,                             // 应用名称(SYSLOG_IDENTIFIER)
+}
+
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[cfg(target_os = "linux")]
+impl Journald {
+    fn connect() -> Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET).map_err(|e| format!("connect journald {JOURNALD_SOCKET} error: {e}"))?;
+        Ok(Journald { socket, identifier: app_name() })
+    }
+
+    fn send(&self, record: &LogRecord) -> std::io::Result<()> {
+        self.socket.send(&format_journal(record, &self.identifier)).map(|_| ())
+    }
+}
+
+// 生成journald原生协议的数据包，每个字段一行，值包含换行符时采用二进制格式
+#[cfg(target_os = "linux")]
+fn format_journal(record: &LogRecord, identifier: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(256);
+    let mut push_field = |key: &str, value: &str| {
+        if value.contains('\n') {
+            data.extend_from_slice(key.as_bytes());
+            data.push(b'\n');
+            data.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            data.extend_from_slice(key.as_bytes());
+            data.push(b'=');
+        }
+        data.extend_from_slice(value.as_bytes());
+        data.push(b'\n');
+    };
+
+    push_field("MESSAGE", &record.message);
+    push_field("PRIORITY", &syslog_severity(record.level).to_string());
+    push_field("SYSLOG_IDENTIFIER", identifier);
+    push_field("TARGET", &record.target);
+    push_field("CODE_LINE", &record.line.to_string());
+    for (key, value, _) in record.fields.0.iter() {
+        push_field(&journal_field_name(key), value);
+    }
+    data
+}
+
+// journald字段名只能由大写字母、数字及下划线组成，且不能以下划线或数字开头
+#[cfg(target_os = "linux")]
+fn journal_field_name(key: &str) -> String {
+    let name: String = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    let name = name.trim_start_matches('_');
+    match name.starts_with(|c: char| c.is_ascii_digit()) || name.is_empty() {
+        true => format!("F_{name}"),
+        false => name.to_string(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Journald;
+
+#[cfg(not(target_os = "linux"))]
+impl Journald {
+    fn connect() -> Result<Self> {
+        Err("journald is only supported on linux".into())
+    }
+
+    fn send(&self, _record: &LogRecord) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// windows事件日志输出对象
+#[cfg(windows)]
+struct EventLog(*mut std::ffi::c_void);   // 事件源句柄
+
+// 事件源句柄只在持有日志锁时使用
+#[cfg(windows)]
+unsafe impl Send for EventLog {}
+
+#[cfg(windows)]
+#[link(name = "advapi32")]
+extern "system" {
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> *mut std::ffi::c_void;
+    fn DeregisterEventSource(handle: *mut std::ffi::c_void) -> i32;
+    fn ReportEventW(handle: *mut std::ffi::c_void, event_type: u16, category: u16, event_id: u32,
+            sid: *mut std::ffi::c_void, num_strings: u16, data_size: u32,
+            strings: *const *const u16, data: *mut std::ffi::c_void) -> i32;
+}
+
+#[cfg(windows)]
+impl EventLog {
+    fn open(source: &str) -> Result<Self> {
+        let source: Vec<u16> = source.encode_utf16().chain(std::iter::once(0)).collect();
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(format!("register event source error: {}", std::io::Error::last_os_error()).into());
+        }
+        Ok(EventLog(handle))
+    }
+
+    fn report(&self, level: log::Level, msg: &str) -> std::io::Result<()> {
+        const EVENTLOG_ERROR_TYPE: u16 = 1;
+        const EVENTLOG_WARNING_TYPE: u16 = 2;
+        const EVENTLOG_INFORMATION_TYPE: u16 = 4;
+
+        let event_type = match level {
+            log::Level::Error => EVENTLOG_ERROR_TYPE,
+            log::Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let msg: Vec<u16> = msg.encode_utf16().chain(std::iter::once(0)).collect();
+        let strings = [msg.as_ptr()];
+        let ok = unsafe {
+            ReportEventW(self.0, event_type, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null_mut())
+        };
+        match ok {
+            0 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.0); }
+    }
+}
+
+#[cfg(not(windows))]
+struct EventLog;
+
+#[cfg(not(windows))]
+impl EventLog {
+    fn open(_source: &str) -> Result<Self> {
+        Err("event log is only supported on windows".into())
+    }
+
+    fn report(&self, _level: log::Level, _msg: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// 日志级别对应的syslog严重程度
+fn syslog_severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,   // err
+        log::Level::Warn => 4,    // warning
+        log::Level::Info => 6,    // info
+        log::Level::Debug | log::Level::Trace => 7,   // debug
+    }
+}
+
+// 生成RFC3164格式的syslog消息: <PRI>TIMESTAMP [HOSTNAME] TAG: MSG
+fn format_syslog(level: log::Level, timestamp: &str, hostname: Option<&str>, tag: &str, msg: &str) -> String {
+    let pri = SYSLOG_FACILITY * 8 + syslog_severity(level);
+    match hostname {
+        Some(hostname) => format!("<{pri}>{timestamp} {hostname} {tag}: {msg}"),
+        None => format!("<{pri}>{timestamp} {tag}: {msg}"),
+    }
+}
+
+// 内置的日志格式，detailed为true时包含日志目标及行号，thread有值时在级别后输出线程名称
+fn format_default(now: &str, record: &log::Record, fields: &Fields, thread: Option<&str>, detailed: bool, color: bool) -> String {
+    let thread = match thread {
+        Some(name) => format!(" [{name}]"),
+        None => String::new(),
+    };
+    match (detailed, color) {
+        (true, true) => format!("[\x1b[36m{}\x1b[0m] [{}{:5}\x1b[0m]{} [{}::{}] - {}{}\n",
+                now,
+                level_color(record.level()), record.level(), thread,
+                record.target(), record.line().unwrap_or(0),
+                record.args(), fields.to_text()),
+        (true, false) => format!("[{}] [{:5}]{} [{}::{}] - {}{}\n",
+                now, record.level(), thread, record.target(), record.line().unwrap_or(0), record.args(), fields.to_text()),
+        (false, _) => format!("[{}] [{:5}]{} - {}{}\n", now, record.level(), thread, record.args(), fields.to_text()),
+    }
+}
+
+// 当前线程的名称，未命名的线程使用线程id(如ThreadId(5)中的5)
+fn thread_name() -> String {
+    let current = std::thread::current();
+    match current.name() {
+        Some(name) => name.to_string(),
+        None => format!("{:?}", current.id()).chars().filter(char::is_ascii_digit).collect(),
+    }
+}
+
+// 文本格式的时间戳，如2026-10-15 10:00:00.123，utc时间以Z结尾
+fn format_time(time: chrono::DateTime<chrono::Utc>, precision: Precision, utc: bool) -> String {
+    let fmt = match precision {
+        Precision::Seconds => "%Y-%m-%d %H:%M:%S",
+        Precision::Millis => "%Y-%m-%d %H:%M:%S%.3f",
+        Precision::Micros => "%Y-%m-%d %H:%M:%S%.6f",
+    };
+    match utc {
+        true => format!("{}Z", time.format(fmt)),
+        false => time.with_timezone(&chrono::Local).format(fmt).to_string(),
+    }
+}
+
+// RFC3339格式的时间戳，用于json日志
+fn format_rfc3339(time: chrono::DateTime<chrono::Utc>, precision: Precision, utc: bool) -> String {
+    let secs = match precision {
+        Precision::Seconds => chrono::SecondsFormat::Secs,
+        Precision::Millis => chrono::SecondsFormat::Millis,
+        Precision::Micros => chrono::SecondsFormat::Micros,
+    };
+    match utc {
+        true => time.to_rfc3339_opts(secs, true),
+        false => time.with_timezone(&chrono::Local).to_rfc3339_opts(secs, false),
+    }
+}
+
+// 生成json格式的日志条目，以换行符结尾
+fn format_json(timestamp: &str, record: &log::Record, fields: &Fields, thread: Option<&str>) -> String {
+    let mut s = String::with_capacity(128);
+    s.push_str("{\"timestamp\":");
+    push_json_str(&mut s, timestamp);
+    s.push_str(",\"level\":");
+    push_json_str(&mut s, record.level().as_str());
+    s.push_str(",\"target\":");
+    push_json_str(&mut s, record.target());
+    if let Some(thread) = thread {
+        s.push_str(",\"thread\":");
+        push_json_str(&mut s, thread);
+    }
+    s.push_str(",\"message\":");
+    push_json_str(&mut s, &record.args().to_string());
+    for (key, value, bare) in fields.0.iter() {
+        s.push(',');
+        push_json_str(&mut s, key);
+        s.push(':');
+        match bare {
+            true => s.push_str(value),
+            false => push_json_str(&mut s, value),
+        }
+    }
+    s.push_str("}\n");
+    s
+}
+
+// 追加json字符串(含引号)，转义引号、反斜杠及控制字符
+fn push_json_str(s: &mut String, value: &str) {
+    s.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => { let _ = write!(s, "\\u{:04x}", c as u32); },
+            c => s.push(c),
         }
+    }
+    s.push('"');
+}
 
-        // 写入剩余的数据
-        self.0.write_all(&buf[last_pos .. len])?;
+// 以追加方式打开日志文件，返回文件对象及当前长度
+fn open_log_file(log_file: &str) -> std::io::Result<(std::fs::File, u32)> {
+    let f = std::fs::OpenOptions::new().append(true).create(true).open(log_file)?;
+    let size = f.metadata()?.len() as u32;
+    Ok((f, size))
+}
+
+// 轮转日志文件: 删除最旧的备份app.log.N，其余备份依次改名为下一个序号，app.log改名为app.log.1，
+// 备份数量为0时直接删除当前日志文件
+fn rotate_files(log_file: &str, backups: u32) -> std::io::Result<()> {
+    if backups == 0 {
+        return std::fs::remove_file(log_file);
+    }
+
+    let backup = |i: u32| format!("{log_file}.{i}");
+    std::fs::remove_file(backup(backups)).unwrap_or_default();
+    for i in (1..backups).rev() {
+        if std::path::Path::new(&backup(i)).exists() {
+            std::fs::rename(backup(i), backup(i + 1))?;
+        }
+    }
+    std::fs::rename(log_file, backup(1))
+}
+
+// 日志文件写入对象，每行日志写入后刷新缓冲区
+struct LogWriter(BufWriter<std::fs::File>);
+
+impl LogWriter {
+    pub fn new(file: std::fs::File) -> Self {
+        LogWriter(BufWriter::with_capacity(512, file))
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len();
+        self.0.write_all(buf)?;
 
         // 如果已换行符结尾, 则刷新缓冲区
         if len > 0 && buf[len - 1] == b'\n' {
@@ -394,3 +1771,391 @@ fn level_color(level: log::Level) -> &'static str {
         log::Level::Error => RED,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = parse_filter("warn, minidns::dnsserver=trace,mio=error,minidns=info").unwrap();
+        assert_eq!(log::LevelFilter::Warn, filter.level("ansicolor"));
+        assert_eq!(log::LevelFilter::Trace, filter.level("minidns::dnsserver"));
+        assert_eq!(log::LevelFilter::Info, filter.level("minidns::dnsutil"));
+        assert_eq!(log::LevelFilter::Error, filter.level("mio::net"));
+        assert_eq!(log::LevelFilter::Warn, filter.level("mionix"));
+        assert_eq!(log::LevelFilter::Trace, filter.max_level());
+
+        assert_eq!(log::LevelFilter::Debug, parse_filter("debug").unwrap().max_level());
+        assert!(parse_filter("mio=loud").is_err());
+        assert!(parse_filter("verbose").is_err());
+    }
+
+    #[test]
+    fn test_format_json() {
+        let kvs = [
+            ("client", log::kv::Value::from("10.0.0.1")),
+            ("ms", log::kv::Value::from(12u64)),
+            ("hit", log::kv::Value::from(true)),
+        ];
+        let record = log::Record::builder()
+            .level(log::Level::Warn)
+            .target("minidns::dnsserver")
+            .key_values(&kvs)
+            .build();
+        let fields = Fields::collect(&record);
+        assert_eq!(" client=10.0.0.1 ms=12 hit=true", fields.to_text());
+
+        let record = log::Record::builder().args(format_args!("say \"hi\"\n\x01")).level(log::Level::Warn)
+            .target("minidns::dnsserver").key_values(&kvs).build();
+        assert_eq!(concat!(r#"{"timestamp":"2026-10-15T10:00:00.000+08:00","level":"WARN","target":"minidns::dnsserver","#,
+                r#""message":"say \"hi\"\n\u0001","client":"10.0.0.1","ms":12,"hit":true}"#, "\n"),
+                format_json("2026-10-15T10:00:00.000+08:00", &record, &fields, None));
+        assert!(format_json("2026-10-15T10:00:00.000+08:00", &record, &fields, Some("worker-1"))
+                .contains(r#""target":"minidns::dnsserver","thread":"worker-1","message""#));
+    }
+
+    #[test]
+    fn test_format_pattern() {
+        let tokens = parse_pattern("%d %l [%t:%n] %m %k 100%%").unwrap();
+        assert_eq!(Token::Date, tokens[0]);
+        assert_eq!(Token::Text(" 100%".to_string()), tokens[tokens.len() - 1]);
+
+        let kvs = [("ms", log::kv::Value::from(3u64))];
+        let record = log::Record::builder().args(format_args!("query")).level(log::Level::Info)
+            .target("minidns::dnsserver").line(Some(42)).key_values(&kvs).build();
+        assert_eq!("10-15 10:00:00 INFO  [minidns::dnsserver:42] query ms=3 100%\n",
+                format_pattern(&tokens, "10-15 10:00:00", &record, &Fields::collect(&record)));
+
+        assert!(parse_pattern("%d %x").is_err());
+        assert!(parse_pattern("%m %").is_err());
+
+        // 命名线程输出名称，未命名线程输出线程id
+        let tokens = parse_pattern("[%T] %m").unwrap();
+        let text = std::thread::Builder::new().name("worker-1".to_string())
+            .spawn(move || {
+                let record = log::Record::builder().args(format_args!("query")).build();
+                format_pattern(&tokens, "", &record, &Fields::default())
+            }).unwrap().join().unwrap();
+        assert_eq!("[worker-1] query\n", text);
+        let id = std::thread::spawn(thread_name).join().unwrap();
+        assert!(!id.is_empty() && id.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_syslog() {
+        assert_eq!("<27>Oct 15 10:00:00 router mdns[7]: upstream timeout",
+                format_syslog(log::Level::Error, "Oct 15 10:00:00", Some("router"), "mdns[7]", "upstream timeout"));
+        assert_eq!("<31>Oct  5 10:00:00 mdns[7]: query", format_syslog(log::Level::Trace, "Oct  5 10:00:00", None, "mdns[7]", "query"));
+
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut syslog = Syslog::connect(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        syslog.send(log::Level::Warn, "disk full").unwrap();
+        let mut buf = [0u8; 256];
+        let n = server.recv(&mut buf).unwrap();
+        let msg = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(msg.starts_with("<28>"));
+        assert!(msg.ends_with(&format!("[{}]: disk full", std::process::id())));
+
+        assert!(Syslog::connect("/nonexistent/syslog.sock").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_journald() {
+        let mut fields = Fields::default();
+        fields.0.push(("client-ip".to_string(), "10.0.0.1".to_string(), false));
+        fields.0.push(("2xx".to_string(), "3".to_string(), true));
+        let record = LogRecord {
+            level: log::Level::Warn,
+            target: "minidns::dnsserver".to_string(),
+            line: 42,
+            message: "line1\nline2".to_string(),
+            fields,
+        };
+        let mut expect = b"MESSAGE\n".to_vec();
+        expect.extend_from_slice(&11u64.to_le_bytes());
+        expect.extend_from_slice(b"line1\nline2\nPRIORITY=4\nSYSLOG_IDENTIFIER=mdns\nTARGET=minidns::dnsserver\n\
+                CODE_LINE=42\nCLIENT_IP=10.0.0.1\nF_2XX=3\n");
+        assert_eq!(expect, format_journal(&record, "mdns"));
+        assert_eq!("line1\nline2 client-ip=10.0.0.1 2xx=3", record.text());
+    }
+
+    #[test]
+    fn test_log_queue() {
+        let message = |text: &str| AsyncLogType::Message(LogLine { level: log::Level::Info, text: text.to_string(), plain: None, json: None, record: None, route: None });
+        let text = |item: Option<AsyncLogType>| match item {
+            Some(AsyncLogType::Message(line)) => line.text,
+            Some(AsyncLogType::Flush) => String::from("flush"),
+            None => String::from("closed"),
+        };
+        let dropped = dropped();
+
+        let queue = LogQueue::new(2, Overflow::DropOldest);
+        for s in ["a", "b", "c"] { queue.push(message(s)); }
+        assert_eq!("b", text(queue.pop()));
+        assert_eq!("c", text(queue.pop()));
+
+        let queue = LogQueue::new(2, Overflow::DropNewest);
+        for s in ["a", "b", "c"] { queue.push(message(s)); }
+        assert_eq!("a", text(queue.pop()));
+        assert_eq!("b", text(queue.pop()));
+        assert_eq!(dropped + 2, super::dropped());
+
+        // 队列满时阻塞，直到消费者取出消息
+        let queue: &'static LogQueue = Box::leak(Box::new(LogQueue::new(1, Overflow::Block)));
+        queue.push(message("a"));
+        let producer = std::thread::spawn(move || queue.push(AsyncLogType::Flush));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!producer.is_finished());
+        assert_eq!("a", text(queue.pop()));
+        producer.join().unwrap();
+        assert_eq!("flush", text(queue.pop()));
+
+        // 关闭后取完剩余消息，新消息退回给调用者
+        queue.push(message("b"));
+        queue.close();
+        assert_eq!("c", text(queue.push(message("c"))));
+        assert_eq!("b", text(queue.pop()));
+        assert_eq!("closed", text(queue.pop()));
+    }
+
+    // 只输出到日志文件的同步日志对象
+    fn file_logger(log_file: &str) -> AsyncLogger {
+        AsyncLogger {
+            filter: RwLock::new(Filter::new(log::LevelFilter::Info)),
+            log_file: log_file.to_string(),
+            routes: Vec::new(),
+            max_size: u32::MAX,
+            backups: 1,
+            json: false,
+            format: Format::Default,
+            precision: Precision::Millis,
+            utc: false,
+            thread: false,
+            color: false,
+            throttle: None,
+            use_record: false,
+            queue: None,
+            writer: Mutex::new(None),
+            logger_data: Mutex::new(LogData {
+                console: None, stderr: None, file: Some(LogFile::open(log_file).unwrap()), routes: Vec::new(),
+                syslog: None, journald: None, event_log: None, console_error: false,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_file_error() {
+        let logger = file_logger("/dev/full");
+        let line = LogLine { level: log::Level::Error, text: String::from("disk full\n"), plain: None, json: None, record: None, route: None };
+
+        // 写入失败时关闭文件，不再panic
+        logger.write(&line);
+        assert!(logger.lock_data().file.as_ref().unwrap().out.is_none());
+        assert!(logger.lock_data().file.as_ref().unwrap().error.is_some());
+
+        // 重试间隔内不重新打开，之后重新打开
+        logger.write(&line);
+        assert!(logger.lock_data().file.as_ref().unwrap().out.is_none());
+        logger.lock_data().file.as_mut().unwrap().error = Some(std::time::Instant::now() - FILE_RETRY_INTERVAL);
+        logger.write(&line);
+        assert!(logger.lock_data().file.as_ref().unwrap().error.is_some());
+        logger.flush_inner();
+    }
+
+    #[test]
+    fn test_reinit() {
+        let dir = std::env::temp_dir().join(format!("asynclog-reinit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_file = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let init = |name: &str| Builder::new().log_file(log_file(name)).use_console(false).builder();
+
+        init("first.log").unwrap();
+        log::info!("one");
+        init("second.log").unwrap();
+        log::info!("two");
+        shutdown();
+
+        let first = std::fs::read_to_string(log_file("first.log")).unwrap();
+        let second = std::fs::read_to_string(log_file("second.log")).unwrap();
+        assert!(first.contains("one") && !first.contains("two"));
+        assert!(second.contains("two") && !second.contains("one"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timestamp() {
+        use chrono::TimeZone;
+        let time = chrono::Utc.with_ymd_and_hms(2026, 10, 15, 9, 8, 7).unwrap() + chrono::Duration::microseconds(123456);
+        assert_eq!("2026-10-15 09:08:07Z", format_time(time, Precision::Seconds, true));
+        assert_eq!("2026-10-15 09:08:07.123Z", format_time(time, Precision::Millis, true));
+        assert_eq!("2026-10-15 09:08:07.123456Z", format_time(time, Precision::Micros, true));
+        assert_eq!("2026-10-15T09:08:07.123456Z", format_rfc3339(time, Precision::Micros, true));
+        assert_eq!(23, format_time(time, Precision::Millis, false).len());
+
+        assert_eq!(Precision::Micros, "us".parse::<Precision>().unwrap());
+        assert_eq!(Precision::Seconds, "Seconds".parse::<Precision>().unwrap());
+        assert!("ns".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn test_format_default() {
+        let record = log::Record::builder().args(format_args!("query")).level(log::Level::Warn)
+            .target("minidns::dnsserver").line(Some(42)).build();
+        let fields = Fields::default();
+        assert_eq!("[\x1b[36m2026-10-15 10:00:00\x1b[0m] [\x1b[35mWARN \x1b[0m] [minidns::dnsserver::42] - query\n",
+                format_default("2026-10-15 10:00:00", &record, &fields, None, true, true));
+        assert_eq!("[2026-10-15 10:00:00] [WARN ] [minidns::dnsserver::42] - query\n",
+                format_default("2026-10-15 10:00:00", &record, &fields, None, true, false));
+        assert_eq!("[2026-10-15 10:00:00] [WARN ] - query\n", format_default("2026-10-15 10:00:00", &record, &fields, None, false, true));
+        assert_eq!("[2026-10-15 10:00:00] [WARN ] [worker-1] [minidns::dnsserver::42] - query\n",
+                format_default("2026-10-15 10:00:00", &record, &fields, Some("worker-1"), true, false));
+        assert_eq!("[2026-10-15 10:00:00] [WARN ] [worker-1] - query\n",
+                format_default("2026-10-15 10:00:00", &record, &fields, Some("worker-1"), false, false));
+
+        assert_eq!(ColorMode::Never, "NEVER".parse::<ColorMode>().unwrap());
+        assert!(!ColorMode::Never.enabled());
+        assert!("rainbow".parse::<ColorMode>().is_err());
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = std::env::temp_dir().join(format!("asynclog-reopen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_file = dir.join("app.log").to_str().unwrap().to_string();
+        let line = |text: &str| LogLine { level: log::Level::Info, text: text.to_string(), plain: None, json: None, record: None, route: None };
+
+        // 模拟logrotate改名后通知重新打开
+        let logger = file_logger(&log_file);
+        logger.write(&line("one\n"));
+        std::fs::rename(&log_file, dir.join("app.log.1")).unwrap();
+        logger.write(&line("two\n"));
+        logger.reopen().unwrap();
+        logger.write(&line("three\n"));
+
+        assert_eq!("one\ntwo\n", std::fs::read_to_string(dir.join("app.log.1")).unwrap());
+        assert_eq!("three\n", std::fs::read_to_string(&log_file).unwrap());
+        assert_eq!(6, logger.lock_data().file.as_ref().unwrap().size);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_filter() {
+        use log::Log;
+        let logger = file_logger("/dev/null");
+        let debug = log::Metadata::builder().level(log::Level::Debug).target("minidns::dnsserver").build();
+        assert!(!logger.enabled(&debug));
+
+        logger.set_filter(Filter::new(log::LevelFilter::Debug));
+        assert!(logger.enabled(&debug));
+        logger.set_filter(parse_filter("info,minidns::dnsserver=trace").unwrap());
+        assert!(logger.enabled(&debug));
+        logger.set_filter(Filter::new(log::LevelFilter::Warn));
+        assert!(!logger.enabled(&debug));
+    }
+
+    #[test]
+    fn test_route() {
+        let dir = std::env::temp_dir().join(format!("asynclog-route-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_file = dir.join("app.log").to_str().unwrap().to_string();
+        let query_file = dir.join("query.log").to_str().unwrap().to_string();
+
+        let mut logger = file_logger(&log_file);
+        logger.routes.push("minidns::query".to_string());
+        logger.lock_data().routes.push(LogFile::open(&query_file).unwrap());
+
+        let log = |target: &str, text: &str| logger.emit(&log::Record::builder()
+                .args(format_args!("{text}")).level(log::Level::Info).target(target).build());
+        log("minidns::query", "a");
+        log("minidns::query::tcp", "b");
+        log("minidns::queryx", "c");
+        log("minidns::dnsserver", "d");
+        logger.flush_inner();
+
+        let messages = |file: &str| std::fs::read_to_string(file).unwrap().lines()
+                .map(|s| s.rsplit(" - ").next().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(vec!["a", "b"], messages(&query_file));
+        assert_eq!(vec!["c", "d"], messages(&log_file));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_throttle() {
+        let start = std::time::Instant::now();
+        let at = |ms: u64| start + std::time::Duration::from_millis(ms);
+        let mut notices = Vec::new();
+        let texts = |notices: &mut Vec<Notice>| notices.drain(..).map(|(_, _, msg)| msg).collect::<Vec<_>>();
+
+        let mut throttle = Throttle::new(true, 0);
+        assert!(throttle.check(log::Level::Warn, "dns", "upstream timeout", at(0), &mut notices));
+        assert!(!throttle.check(log::Level::Warn, "dns", "upstream timeout", at(10), &mut notices));
+        assert!(!throttle.check(log::Level::Warn, "dns", "upstream timeout", at(20), &mut notices));
+        assert!(throttle.check(log::Level::Error, "dns", "upstream timeout", at(30), &mut notices));
+        assert_eq!(vec!["last message repeated 2 times"], texts(&mut notices));
+
+        // 超过合并时间后重新输出
+        assert!(!throttle.check(log::Level::Error, "dns", "upstream timeout", at(40), &mut notices));
+        assert!(throttle.check(log::Level::Error, "dns", "upstream timeout", at(40) + DEDUP_WINDOW, &mut notices));
+        assert_eq!(vec!["last message repeated 1 times"], texts(&mut notices));
+        assert_eq!(None, throttle.take_repeats());
+
+        let mut throttle = Throttle::new(false, 2);
+        throttle.window = start;
+        let passed = (0..5).filter(|i| throttle.check(log::Level::Info, "dns", &i.to_string(), at(*i), &mut notices)).count();
+        assert_eq!(2, passed);
+        assert!(throttle.check(log::Level::Info, "dns", "next", at(1000), &mut notices));
+        assert_eq!(vec!["3 messages suppressed by rate limit"], texts(&mut notices));
+    }
+
+    #[test]
+    fn test_rotate_files() {
+        let dir = std::env::temp_dir().join(format!("asynclog-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_file = dir.join("app.log").to_str().unwrap().to_string();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+
+        for i in 1..=4 {
+            std::fs::write(&log_file, i.to_string()).unwrap();
+            rotate_files(&log_file, 3).unwrap();
+        }
+        assert_eq!(None, read("app.log"));
+        assert_eq!(Some("4".to_string()), read("app.log.1"));
+        assert_eq!(Some("3".to_string()), read("app.log.2"));
+        assert_eq!(Some("2".to_string()), read("app.log.3"));
+        assert_eq!(None, read("app.log.4"));
+
+        std::fs::write(&log_file, "5").unwrap();
+        rotate_files(&log_file, 0).unwrap();
+        assert_eq!(None, read("app.log"));
+        assert_eq!(Some("4".to_string()), read("app.log.1"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = std::env::temp_dir().join(format!("asynclog-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_file = dir.join("app.log").to_str().unwrap().to_string();
+        let line = |text: &str| LogLine { level: log::Level::Info, text: text.to_string(), plain: None, json: None, record: None, route: None };
+
+        // 计数器为全局变量，其它测试同时运行时也会增加
+        let before = stats();
+        let mut logger = file_logger(&log_file);
+        logger.max_size = 4;
+        logger.write(&line("1234\n"));
+        logger.write(&line("5678\n"));
+        let after = stats();
+        assert!(after.written - before.written >= 2);
+        assert!(after.bytes - before.bytes >= 10);
+        assert!(after.rotations > before.rotations);
+        assert_eq!("1234\n", std::fs::read_to_string(dir.join("app.log.1")).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
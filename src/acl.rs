@@ -0,0 +1,111 @@
+//! 客户端访问控制列表, 由ipv4/ipv6网段组成
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use anyhow::{Context, Result};
+
+/// ip网段, 如192.168.1.0/24、fd00::/8, 不带前缀长度时表示单个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr  : IpAddr,   // 网络地址, 主机位已清零
+    prefix: u8,       // 前缀长度
+}
+
+impl IpNet {
+    /// 地址是否属于该网段, ipv4映射的ipv6地址(::ffff:a.b.c.d)按ipv4地址比较
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(ip) & mask(self.prefix, 32) as u32 == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(ip) & mask(self.prefix, 128) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (ip, bits) = match value.split_once('/') {
+            Some((ip, bits)) => (ip, Some(bits)),
+            None => (value, None),
+        };
+        let ip: IpAddr = ip.parse().with_context(|| format!("network {value} format error"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match bits.map(str::parse::<u8>) {
+            None => max,
+            Some(Ok(bits)) if bits <= max => bits,
+            _ => anyhow::bail!("network {value} prefix length error"),
+        };
+        let addr = match ip {
+            IpAddr::V4(ip) => IpAddr::from((u32::from(ip) & mask(prefix, 32) as u32).to_be_bytes()),
+            IpAddr::V6(ip) => IpAddr::from((u128::from(ip) & mask(prefix, 128)).to_be_bytes()),
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+/// 访问控制列表, 地址属于任一网段即为允许
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    nets: Vec<IpNet>,
+}
+
+impl Acl {
+    /// 解析逗号分隔的网段列表
+    pub fn parse(list: &str) -> Result<Acl> {
+        let nets = list.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Acl { nets })
+    }
+
+    /// 添加网段
+    pub fn push(&mut self, net: IpNet) {
+        self.nets.push(net);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+}
+
+// 前缀长度对应的掩码, 取低`bits`位
+fn mask(prefix: u8, bits: u32) -> u128 {
+    let shift = bits - prefix as u32;
+    let all = match bits {
+        32 => u32::MAX as u128,
+        _ => u128::MAX,
+    };
+    all.checked_shr(shift).map_or(0, |m| m << shift) & all
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl::parse("192.168.1.0/24, 10.0.0.1 ,fd00::/8,0.0.0.0/0").unwrap();
+        assert_eq!(4, acl.nets.len());
+        let acl = Acl::parse("192.168.1.77/24, 10.0.0.1 ,fd00::/8").unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(acl.contains(&ip("192.168.1.200")));
+        assert!(acl.contains(&ip("::ffff:192.168.1.3")));
+        assert!(acl.contains(&ip("10.0.0.1")));
+        assert!(!acl.contains(&ip("10.0.0.2")));
+        assert!(acl.contains(&ip("fd12::1")));
+        assert!(!acl.contains(&ip("fe80::1")));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(&ip("8.8.8.8")));
+        assert!(Acl::parse("192.168.1.0/33").is_err());
+        assert!(Acl::parse("lan").is_err());
+        assert!(Acl::parse("").unwrap().is_empty());
+    }
+}
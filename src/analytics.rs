@@ -0,0 +1,148 @@
+//! 查询统计, 滑动时间窗口内查询最多的域名及查询最频繁的客户端
+//!
+//! 时间窗口划分为固定数量的时间片, 每个时间片独立计数, 过期的时间片整体丢弃,
+//! 统计时合并窗口内的所有时间片; 每个时间片记录的不同域名数量有上限, 超出的查询只计入总数
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const SLOTS: u64 = 10;              // 时间窗口划分的时间片数量
+const MAX_SLOT_KEYS: usize = 10000; // 每个时间片最多记录的不同域名(客户端)数量
+
+// 一个时间片内的计数
+#[derive(Default)]
+struct Slot {
+    index  : u64,                     // 时间片序号(unix时间 / 时间片长度)
+    total  : u64,                     // 查询总数
+    domains: HashMap<String, u64>,    // 各域名的查询次数
+    clients: HashMap<IpAddr, u64>,    // 各客户端的查询次数
+}
+
+/// 滑动窗口查询统计
+pub struct Analytics {
+    slot_secs: u64,         // 时间片长度(秒)
+    slots    : Vec<Slot>,   // 循环使用的时间片
+}
+
+impl Analytics {
+    /// 创建统计窗口为`window`的查询统计, 窗口最短为10秒
+    pub fn new(window: Duration) -> Analytics {
+        let slot_secs = (window.as_secs() / SLOTS).max(1);
+        Analytics { slot_secs, slots: (0..SLOTS).map(|_| Slot::default()).collect() }
+    }
+
+    /// 统计窗口的长度
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.slot_secs * SLOTS)
+    }
+
+    /// 记录一次查询, `now`为unix时间(秒)
+    pub fn record(&mut self, name: &str, client: IpAddr, now: u64) {
+        let index = now / self.slot_secs;
+        let slot = &mut self.slots[(index % SLOTS) as usize];
+        if slot.index != index {
+            *slot = Slot { index, ..Slot::default() };
+        }
+
+        slot.total += 1;
+        let name = match name.bytes().any(|b| b.is_ascii_uppercase()) {
+            true => Cow::Owned(name.to_ascii_lowercase()),
+            false => Cow::Borrowed(name),
+        };
+        let full = slot.domains.len() >= MAX_SLOT_KEYS;
+        match slot.domains.get_mut(name.as_ref()) {
+            Some(count) => *count += 1,
+            None if !full => { slot.domains.insert(name.into_owned(), 1); },
+            None => {},
+        }
+        if slot.clients.len() < MAX_SLOT_KEYS || slot.clients.contains_key(&client) {
+            *slot.clients.entry(client).or_insert(0) += 1;
+        }
+    }
+
+    /// 窗口内的查询总数
+    pub fn total(&self, now: u64) -> u64 {
+        self.live_slots(now).map(|slot| slot.total).sum()
+    }
+
+    /// 窗口内查询次数最多的`n`个域名, 按次数从多到少排列
+    pub fn top_domains(&self, n: usize, now: u64) -> Vec<(String, u64)> {
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        for slot in self.live_slots(now) {
+            for (name, count) in &slot.domains {
+                *counts.entry(name.as_str()).or_insert(0) += count;
+            }
+        }
+        top_n(counts.into_iter().map(|(name, count)| (name.to_string(), count)).collect(), n)
+    }
+
+    /// 窗口内查询次数最多的`n`个客户端, 按次数从多到少排列
+    pub fn top_clients(&self, n: usize, now: u64) -> Vec<(IpAddr, u64)> {
+        let mut counts: HashMap<IpAddr, u64> = HashMap::new();
+        for slot in self.live_slots(now) {
+            for (client, count) in &slot.clients {
+                *counts.entry(*client).or_insert(0) += count;
+            }
+        }
+        top_n(counts.into_iter().collect(), n)
+    }
+
+    /// 文本格式的统计报告, 包含窗口内的查询总数、前`n`个域名及客户端
+    pub fn report(&self, n: usize, now: u64) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "queries in last {}s: {}", self.window().as_secs(), self.total(now));
+        let _ = writeln!(out, "top domains:");
+        for (name, count) in self.top_domains(n, now) {
+            let _ = writeln!(out, "{count:>10}  {name}");
+        }
+        let _ = writeln!(out, "top clients:");
+        for (client, count) in self.top_clients(n, now) {
+            let _ = writeln!(out, "{count:>10}  {client}");
+        }
+        out
+    }
+
+    // 仍在统计窗口内的时间片
+    fn live_slots(&self, now: u64) -> impl Iterator<Item = &Slot> {
+        let index = now / self.slot_secs;
+        self.slots.iter().filter(move |slot| slot.total > 0 && slot.index + SLOTS > index && slot.index <= index)
+    }
+}
+
+// 按次数从多到少排序后取前n项, 次数相同时按键排序使结果稳定
+fn top_n<K: Ord>(mut items: Vec<(K, u64)>, n: usize) -> Vec<(K, u64)> {
+    items.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    items.truncate(n);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analytics() {
+        let mut stats = Analytics::new(Duration::from_secs(100));
+        let (a, b): (IpAddr, IpAddr) = ("192.168.1.2".parse().unwrap(), "192.168.1.3".parse().unwrap());
+        for _ in 0..3 {
+            stats.record("www.example.com", a, 1000);
+        }
+        stats.record("WWW.Example.com", b, 1005);
+        stats.record("iot.vendor.com", a, 1050);
+        stats.record("iot.vendor.com", a, 1095);
+
+        assert_eq!(6, stats.total(1095));
+        assert_eq!(vec![("www.example.com".to_string(), 4), ("iot.vendor.com".to_string(), 2)], stats.top_domains(5, 1095));
+        assert_eq!(vec![(a, 5)], stats.top_clients(1, 1095));
+
+        // 窗口滑过后早期的时间片不再计入
+        assert_eq!(vec![("iot.vendor.com".to_string(), 2)], stats.top_domains(5, 1100));
+        assert_eq!(1, stats.total(1180));
+        stats.record("new.example.com", b, 1200);
+        assert_eq!(vec![(b, 1)], stats.top_clients(5, 1200));
+        assert!(stats.report(5, 1200).starts_with("queries in last 100s: 1\n"));
+    }
+}
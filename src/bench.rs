@@ -0,0 +1,283 @@
+//! dns压力测试, 按指定速率向服务器循环发送域名列表中的查询, 统计回复延迟的分位数及错误率
+//!
+//! 发送及接收分别在两个线程中进行, 使用请求id关联请求与回复, 因此同一时刻等待回复的请求
+//! 不能超过65536个(即qps * timeout < 65536), 否则id重复会导致统计不准确
+
+use std::fmt;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use crate::dnsutil::{DnsPacket, DnsQuestion, QueryType, ResultCode};
+
+const RECV_POLL: Duration = Duration::from_millis(100);   // 接收线程检查停止标志的间隔
+const MAX_REPLY_LEN: usize = 4096;                        // 回复数据包的最大长度
+const RCODE_COUNT: usize = 6;                             // 统计的回复码数量(NOERROR ~ REFUSED)
+
+/// 压力测试参数
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub server  : SocketAddr,   // 服务器地址
+    pub qps     : u32,          // 每秒发送的查询数量
+    pub duration: Duration,     // 发送查询的持续时间
+    pub timeout : Duration,     // 等待回复的超时时间, 超时未回复的请求计为丢失
+}
+
+/// 压力测试结果
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport {
+    pub sent       : u64,                   // 发送的查询数量
+    pub send_errors: u64,                   // 发送失败的查询数量
+    pub received   : u64,                   // 收到的有效回复数量
+    pub lost       : u64,                   // 超时未回复的查询数量
+    pub malformed  : u64,                   // 无法解析或无法关联到请求的回复数量
+    pub rcodes     : [u64; RCODE_COUNT],    // 各回复码的回复数量, 以回复码为下标
+    pub elapsed    : Duration,              // 发送查询实际耗费的时间
+    latencies      : Vec<Duration>,         // 各回复的延迟, 已排序
+}
+
+impl BenchReport {
+    /// 实际达到的每秒查询数
+    pub fn qps(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.sent as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// 指定回复码的回复数量
+    pub fn rcode(&self, rcode: ResultCode) -> u64 {
+        self.rcodes[rcode as usize]
+    }
+
+    /// 错误率: 发送失败、丢失、格式错误及SERVFAIL/REFUSED等非NOERROR/NXDOMAIN的回复占发送数量的比例
+    pub fn error_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        let bad_rcodes: u64 = self.rcodes.iter().enumerate()
+            .filter(|(i, _)| *i != ResultCode::NOERROR as usize && *i != ResultCode::NXDOMAIN as usize)
+            .map(|(_, n)| n)
+            .sum();
+        (self.send_errors + self.lost + self.malformed + bad_rcodes) as f64 / self.sent as f64
+    }
+
+    /// 延迟的分位数, `p`的取值范围为0 ~ 100, 没有收到回复时返回None
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let index = ((p / 100.0) * last as f64).round() as usize;
+        Some(self.latencies[index.min(last)])
+    }
+
+    /// 平均延迟, 没有收到回复时返回None
+    pub fn mean(&self) -> Option<Duration> {
+        match self.latencies.len() {
+            0 => None,
+            n => Some(self.latencies.iter().sum::<Duration>() / n as u32),
+        }
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |n: u64| if self.sent == 0 { 0.0 } else { n as f64 * 100.0 / self.sent as f64 };
+        writeln!(f, "queries sent:     {} ({:.1} qps in {:.2}s)", self.sent, self.qps(), self.elapsed.as_secs_f64())?;
+        writeln!(f, "replies received: {} ({:.2}%)", self.received, percent(self.received))?;
+        writeln!(f, "queries lost:     {} ({:.2}%)", self.lost, percent(self.lost))?;
+        if self.send_errors > 0 {
+            writeln!(f, "send errors:      {}", self.send_errors)?;
+        }
+        if self.malformed > 0 {
+            writeln!(f, "malformed:        {}", self.malformed)?;
+        }
+        for (i, n) in self.rcodes.iter().enumerate().filter(|(_, n)| **n > 0) {
+            writeln!(f, "{:<18}{} ({:.2}%)", format!("{}:", ResultCode::from_num(i as u8)), n, percent(*n))?;
+        }
+        writeln!(f, "error rate:       {:.2}%", self.error_rate() * 100.0)?;
+        let ms = |d: Option<Duration>| d.map_or(String::from("-"), |d| format!("{:.3}", d.as_secs_f64() * 1000.0));
+        write!(f, "latency(ms):      min {}, avg {}, p50 {}, p90 {}, p99 {}, max {}",
+            ms(self.percentile(0.0)), ms(self.mean()), ms(self.percentile(50.0)),
+            ms(self.percentile(90.0)), ms(self.percentile(99.0)), ms(self.percentile(100.0)))
+    }
+}
+
+/// 解析域名列表, 每行为`域名 [查询类型]`, 未指定类型时使用`qtype`, 忽略空行及'#'开头的注释行
+pub fn parse_names(text: &str, qtype: QueryType) -> Result<Vec<DnsQuestion>> {
+    let mut questions = Vec::new();
+    for (no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut cols = line.split_whitespace();
+        let name = cols.next().unwrap_or_default().trim_end_matches('.').to_ascii_lowercase();
+        let qtype = match cols.next() {
+            Some(s) => s.parse().with_context(|| format!("line {}: invalid query type", no + 1))?,
+            None => qtype,
+        };
+        questions.push(DnsQuestion::new(name, qtype));
+    }
+    Ok(questions)
+}
+
+/// 执行压力测试, 按`opts.qps`的速率循环发送`questions`中的查询, 持续`opts.duration`后
+/// 再等待`opts.timeout`接收剩余的回复
+pub fn run(opts: &BenchOptions, questions: &[DnsQuestion]) -> Result<BenchReport> {
+    if questions.is_empty() {
+        anyhow::bail!("no query names");
+    }
+    if opts.qps == 0 {
+        anyhow::bail!("qps must be greater than 0");
+    }
+
+    // 预先生成所有请求数据包, 发送时仅修改请求id, 避免序列化开销影响发送速率
+    let packets = questions.iter()
+        .map(|q| DnsPacket::builder().recursion_desired(true).question(q.clone()).build().to_bytes())
+        .collect::<Result<Vec<_>>>()?;
+
+    let bind_addr = if opts.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr).with_context(|| format!("bind bench socket {bind_addr} failed"))?;
+    socket.connect(opts.server).with_context(|| format!("connect {} failed", opts.server))?;
+    socket.set_read_timeout(Some(RECV_POLL))?;
+
+    // 各请求id的发送时间(相对开始时间的纳秒数+1), 0表示没有等待回复的请求
+    let pending: Arc<Vec<AtomicU64>> = Arc::new((0..=u16::MAX).map(|_| AtomicU64::new(0)).collect());
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+
+    let receiver = {
+        let (socket, pending, stop, timeout) = (socket.try_clone()?, pending.clone(), stop.clone(), opts.timeout);
+        std::thread::spawn(move || receive(&socket, &pending, &stop, start, timeout))
+    };
+
+    let mut report = BenchReport::default();
+    let interval = Duration::from_secs(1) / opts.qps;
+    let mut next = start;
+    while next.duration_since(start) < opts.duration {
+        let now = Instant::now();
+        if now < next {
+            std::thread::sleep(next - now);
+        }
+        let index = (report.sent % packets.len() as u64) as usize;
+        let id = report.sent as u16;
+        let mut packet = packets[index].clone();
+        packet[..2].copy_from_slice(&id.to_be_bytes());
+        pending[id as usize].store(start.elapsed().as_nanos() as u64 + 1, Ordering::Release);
+        if let Err(e) = socket.send(&packet) {
+            log::debug!("send query to {} failed: {e}", opts.server);
+            pending[id as usize].store(0, Ordering::Release);
+            report.send_errors += 1;
+        }
+        report.sent += 1;
+        next += interval;
+    }
+    report.elapsed = start.elapsed();
+
+    // 等待最后发送的请求超时后停止接收, 仍未回复的请求计为丢失
+    std::thread::sleep(opts.timeout);
+    stop.store(true, Ordering::Release);
+    let mut received = receiver.join().map_err(|_| anyhow::anyhow!("bench receiver panicked"))?;
+
+    received.latencies.sort_unstable();
+    report.received = received.latencies.len() as u64;
+    report.lost = pending.iter().filter(|t| t.load(Ordering::Acquire) != 0).count() as u64 + received.late;
+    report.malformed = received.malformed;
+    report.rcodes = received.rcodes;
+    report.latencies = received.latencies;
+    Ok(report)
+}
+
+// 接收线程的统计结果
+#[derive(Default)]
+struct Received {
+    latencies: Vec<Duration>,         // 各有效回复的延迟
+    rcodes   : [u64; RCODE_COUNT],    // 各回复码的有效回复数量
+    late     : u64,                   // 超过超时时间才收到的回复数量, 计为丢失
+    malformed: u64,                   // 无法解析或无法关联到请求的回复数量
+}
+
+/// 接收线程, 直到`stop`被设置
+fn receive(socket: &UdpSocket, pending: &[AtomicU64], stop: &AtomicBool, start: Instant, timeout: Duration) -> Received {
+    let mut received = Received::default();
+    let mut buf = vec![0; MAX_REPLY_LEN];
+    while !stop.load(Ordering::Acquire) {
+        // 超时或服务器端口不可达(ConnectionRefused)时继续等待, 未回复的请求最终计为丢失
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let now = start.elapsed().as_nanos() as u64;
+        let packet = match DnsPacket::from_bytes(&buf[..n]) {
+            Ok(packet) if packet.header.response => packet,
+            _ => {
+                received.malformed += 1;
+                continue;
+            },
+        };
+        let sent = pending[packet.header.id as usize].swap(0, Ordering::AcqRel);
+        if sent == 0 {
+            received.malformed += 1;
+            continue;
+        }
+        let latency = Duration::from_nanos(now.saturating_sub(sent - 1));
+        if latency > timeout {
+            received.late += 1;
+            continue;
+        }
+        received.latencies.push(latency);
+        received.rcodes[packet.header.rescode as usize] += 1;
+    }
+    received
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+    use crate::dnsutil::DnsRecord;
+
+    #[test]
+    fn test_bench() {
+        // 模拟服务器, 对a.lan回复地址, 其它域名回复NXDOMAIN, 丢弃drop.lan的请求
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = server.recv_from(&mut buf) {
+                let request = DnsPacket::from_bytes(&buf[..n]).unwrap();
+                let question = request.questions[0].clone();
+                let builder = DnsPacket::builder().id(request.header.id).question(question.clone());
+                let reply = match question.name.as_str() {
+                    "a.lan" => builder.response(ResultCode::NOERROR)
+                        .answer(DnsRecord::A { domain: question.name, addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 }),
+                    "drop.lan" => continue,
+                    _ => builder.response(ResultCode::NXDOMAIN),
+                };
+                server.send_to(&reply.build().to_bytes().unwrap(), from).unwrap();
+            }
+        });
+
+        let questions = parse_names("# names\nA.lan.\n\nb.lan AAAA\ndrop.lan\n", QueryType::A).unwrap();
+        assert_eq!(3, questions.len());
+        assert_eq!(DnsQuestion::new("a.lan".to_string(), QueryType::A), questions[0]);
+        assert_eq!(QueryType::AAAA, questions[1].qtype);
+        assert!(parse_names("a.lan BAD", QueryType::A).is_err());
+
+        let opts = BenchOptions {
+            server: addr,
+            qps: 200,
+            duration: Duration::from_millis(300),
+            timeout: Duration::from_millis(200),
+        };
+        let report = run(&opts, &questions).unwrap();
+        assert_eq!(60, report.sent);
+        assert_eq!(40, report.received);
+        assert_eq!(20, report.lost);
+        assert_eq!(20, report.rcode(ResultCode::NOERROR));
+        assert_eq!(20, report.rcode(ResultCode::NXDOMAIN));
+        assert!((report.error_rate() - 1.0 / 3.0).abs() < 1e-9);
+        assert!(report.percentile(0.0) <= report.percentile(99.0));
+        assert!(report.to_string().contains("NXDOMAIN:"));
+    }
+}
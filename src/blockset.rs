@@ -0,0 +1,252 @@
+use std::collections::HashSet;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::Write;
+use anyhow::{Context, Result};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;   // FNV-1a 64位初始值
+const FNV_PRIME: u64  = 0x0000_0100_0000_01b3;   // FNV-1a 64位质数
+const DB_MAGIC: &[u8; 8] = b"MDNSBLK1";          // 屏蔽域名库文件的标识
+const DB_HEADER_LEN: usize = 16;                 // 文件头长度: 标识(8字节) + 哈希值数量(8字节, 小端)
+
+/// 屏蔽域名集合, 只保存域名的64位哈希值, 不保存域名字符串本身,
+/// 百万级别的屏蔽列表仅占用十余MB内存, 哈希冲突的概率可以忽略不计
+#[derive(Default)]
+pub struct BlockSet {
+    hashes: HashSet<u64, BuildHasherDefault<IdentityHasher>>,
+}
+
+impl BlockSet {
+
+    pub fn insert(&mut self, host: &str) {
+        self.hashes.insert(fnv1a(host.as_bytes()));
+    }
+
+    pub fn remove(&mut self, host: &str) -> bool {
+        self.hashes.remove(&fnv1a(host.as_bytes()))
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.hashes.contains(&fnv1a(host.as_bytes()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+}
+
+/// 内存映射的屏蔽域名库, 文件中保存排序后的域名哈希值, 使用二分查找,
+/// 打开时不需要加载及解析列表, 千万级别的屏蔽列表也能立即启动, 页面由操作系统按需载入,
+/// 内存紧张时可以直接丢弃, 不占用进程的常驻内存
+///
+/// 库文件由[`write_db`]生成, 更新时写入新文件后改名替换, 已打开的旧文件映射不受影响
+pub struct MappedBlockSet {
+    data: Mapped,   // 文件内容
+    len : usize,    // 哈希值数量
+}
+
+impl MappedBlockSet {
+
+    pub fn open(path: &str) -> Result<MappedBlockSet> {
+        let data = Mapped::open(path).with_context(|| format!("open block db {path} failed"))?;
+        let bytes = data.bytes();
+        if bytes.len() < DB_HEADER_LEN || &bytes[..8] != DB_MAGIC {
+            anyhow::bail!("{path} isn't a block db file");
+        }
+        let len = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        // 文件头中的数量可能被篡改, 计算长度时需防止溢出
+        let size = usize::try_from(len).ok()
+            .and_then(|len| len.checked_mul(8))
+            .and_then(|n| n.checked_add(DB_HEADER_LEN));
+        if size != Some(bytes.len()) {
+            anyhow::bail!("block db {path} is truncated");
+        }
+        let len = len as usize;
+        Ok(MappedBlockSet { data, len })
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        let hash = fnv1a(host.as_bytes());
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.get(mid).cmp(&hash) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn get(&self, index: usize) -> u64 {
+        let pos = DB_HEADER_LEN + index * 8;
+        u64::from_le_bytes(self.data.bytes()[pos..pos + 8].try_into().unwrap())
+    }
+
+}
+
+/// 生成屏蔽域名库文件, 域名格式与hosts文件中的屏蔽域名相同(以`.`开头表示同时屏蔽所有子域名),
+/// 先写入临时文件再改名替换, 返回去重后的域名数量
+pub fn write_db<'a, I: IntoIterator<Item = &'a str>>(path: &str, hosts: I) -> Result<usize> {
+    let mut hashes: Vec<u64> = hosts.into_iter().map(|host| fnv1a(host.as_bytes())).collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let tmp_path = format!("{path}.tmp");
+    let file = std::fs::File::create(&tmp_path).with_context(|| format!("create block db {tmp_path} failed"))?;
+    let mut out = std::io::BufWriter::new(file);
+    out.write_all(DB_MAGIC)?;
+    out.write_all(&(hashes.len() as u64).to_le_bytes())?;
+    for hash in hashes.iter() {
+        out.write_all(&hash.to_le_bytes())?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp_path, path).with_context(|| format!("rename block db {tmp_path} to {path} failed"))?;
+    Ok(hashes.len())
+}
+
+// 只读映射的文件内容
+#[cfg(unix)]
+struct Mapped {
+    ptr: *const u8,   // 映射的起始地址
+    len: usize,       // 映射的长度
+}
+
+// 映射的内存只读, 可以在线程间共享
+#[cfg(unix)]
+unsafe impl Send for Mapped {}
+#[cfg(unix)]
+unsafe impl Sync for Mapped {}
+
+#[cfg(unix)]
+impl Mapped {
+    fn open(path: &str) -> std::io::Result<Mapped> {
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(Mapped { ptr: std::ptr::null(), len });
+        }
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Mapped { ptr: ptr as *const u8, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.ptr.is_null() {
+            true => &[],
+            false => unsafe { std::slice::from_raw_parts(self.ptr, self.len) },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+// 不支持mmap的平台读入内存
+#[cfg(not(unix))]
+struct Mapped(Vec<u8>);
+
+#[cfg(not(unix))]
+impl Mapped {
+    fn open(path: &str) -> std::io::Result<Mapped> {
+        std::fs::read(path).map(Mapped)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// 计算FNV-1a 64位哈希
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(FNV_OFFSET, |h, c| (h ^ *c as u64).wrapping_mul(FNV_PRIME))
+}
+
+// 集合中的值已经是哈希值, 直接使用, 避免二次哈希
+#[derive(Default)]
+struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for c in bytes {
+            self.0 = (self.0 << 8) | *c as u64;
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockSet, MappedBlockSet, write_db};
+
+    #[test]
+    fn test_blockset() {
+        let mut set = BlockSet::default();
+        set.insert("ad.com");
+        set.insert(".track.com");
+        set.insert("ad.com");
+        assert_eq!(2, set.len());
+        assert!(set.contains("ad.com"));
+        assert!(set.contains(".track.com"));
+        assert!(!set.contains("track.com"));
+        assert!(!set.contains("www.ad.com"));
+        assert!(set.remove("ad.com"));
+        assert!(!set.remove("ad.com"));
+        assert!(!set.contains("ad.com"));
+    }
+
+    #[test]
+    fn test_mapped_blockset() {
+        let path = std::env::temp_dir().join(format!("minidns-blockdb-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let hosts: Vec<String> = (0..1000).map(|i| format!("ad{i}.com")).collect();
+        let count = write_db(path, hosts.iter().map(String::as_str).chain([".track.com", "ad1.com"])).unwrap();
+        assert_eq!(1001, count);
+
+        let set = MappedBlockSet::open(path).unwrap();
+        assert_eq!(1001, set.len());
+        assert!(hosts.iter().all(|host| set.contains(host)));
+        assert!(set.contains(".track.com"));
+        assert!(!set.contains("track.com"));
+        assert!(!set.contains("ad1000.com"));
+
+        std::fs::write(path, b"MDNSBLK1\x02\0\0\0\0\0\0\0").unwrap();
+        assert!(MappedBlockSet::open(path).is_err());
+        // 数量乘8后溢出的文件头不能通过长度校验
+        std::fs::write(path, b"MDNSBLK1\x02\0\0\0\0\0\0\x20").unwrap();
+        assert!(MappedBlockSet::open(path).is_err());
+        std::fs::remove_file(path).unwrap();
+        assert!(MappedBlockSet::open(path).is_err());
+    }
+}
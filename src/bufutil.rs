@@ -1,40 +1,144 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
 use anyhow::Result;
 
+/// 缓冲区缺省的最大容量, 可容纳EDNS及TCP的dns数据包
+pub const DEFAULT_BUFFER_CAP: usize = 4096;
+const QNAME_RESERVE: usize = 32;   // 读取域名时预留的容量, 大部分域名无需再次分配
+const MAX_LABEL_LEN: usize = 63;   // 域名标签的最大长度
+const MAX_NAME_LEN: usize  = 255;  // 域名的最大长度
+const MAX_JUMPS: usize     = 5;    // 域名压缩指针的最大跳转次数
+
+/// 缓冲区读写错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BufferError {
+    EndOfBuffer { pos: usize, len: usize },           // 读写位置超出数据范围
+    TooManyJumps(usize),                              // 域名压缩指针跳转次数过多
+    LabelTooLong(usize),                              // 标签长度超过63
+    NameTooLong(usize),                               // 严格模式: 域名长度超过255
+    BadLabelType(u8),                                 // 严格模式: 保留的标签类型(0x40/0x80)
+    ForwardPointer { pos: usize, target: usize },     // 严格模式: 压缩指针未指向之前的位置
+    RdataLength { expected: usize, actual: usize },   // 严格模式: 记录数据长度与声明不一致
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferError::EndOfBuffer { pos, len } => write!(f, "End of buffer, position {pos} exceeds length {len}"),
+            BufferError::TooManyJumps(n) => write!(f, "Limit of {n} jumps exceeded"),
+            BufferError::LabelTooLong(n) => write!(f, "Single label length {n} exceeds {MAX_LABEL_LEN}"),
+            BufferError::NameTooLong(n) => write!(f, "Name length {n} exceeds {MAX_NAME_LEN}"),
+            BufferError::BadLabelType(t) => write!(f, "Unsupported label type 0x{t:02x}"),
+            BufferError::ForwardPointer { pos, target } => write!(f, "Name pointer at {pos} points forward to {target}"),
+            BufferError::RdataLength { expected, actual } => write!(f, "Record data length {actual} mismatch, expect {expected}"),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {}
+
+/// dns数据包读写缓冲区, 写入时按需增长, 最大不超过创建时指定的容量
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
-    pub pos: usize,
-    pub len: usize,
+    pub buf: Vec<u8>,    // 数据
+    pub pos: usize,      // 当前读写位置
+    pub len: usize,      // 有效数据长度, 读取不能超过该长度
+    cap: usize,          // 最大容量, 写入不能超过该容量
+    strict: bool,        // 严格解析模式, 拒绝不规范的数据包
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        BytePacketBuffer::new()
+    }
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_capacity(DEFAULT_BUFFER_CAP)
+    }
+
+    pub fn with_capacity(cap: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: Vec::new(),
             pos: 0,
-            len: 512,
+            len: 0,
+            cap,
+            strict: false,
         }
     }
 
+    /// 设置严格解析模式, 严格模式下拒绝超长域名、保留标签类型、向后跳转的压缩指针
+    /// 以及长度与声明不一致的记录数据
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// 清空缓冲区以便重新使用, 保留已分配的内存
+    pub fn clear(&mut self) {
+        self.pos = 0;
+        self.len = 0;
+    }
+
+    /// 返回用于接收数据的缓冲区(长度为最大容量), 接收后需调用`set_len`设置数据长度
+    pub fn recv_buf(&mut self) -> &mut [u8] {
+        self.buf.resize(self.cap, 0);
+        self.pos = 0;
+        &mut self.buf
+    }
+
+    /// 设置接收到的数据长度, 并将读写位置重置到开头
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(self.buf.len());
+        self.pos = 0;
+    }
+
+    /// 有效数据
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
     fn check_range(&self, pos: usize) -> Result<()> {
         if pos >= self.len {
-            anyhow::bail!("End of buffer");
+            anyhow::bail!(BufferError::EndOfBuffer { pos, len: self.len });
+        }
+        Ok(())
+    }
+
+    /// 确保可以写入到指定位置(不含), 必要时扩展缓冲区
+    fn check_write(&mut self, end: usize) -> Result<()> {
+        if end > self.cap {
+            anyhow::bail!(BufferError::EndOfBuffer { pos: end, len: self.cap });
+        }
+        if self.buf.len() < end {
+            self.buf.resize(end, 0);
+        }
+        if self.len < end {
+            self.len = end;
         }
         Ok(())
     }
 
     pub fn pos(&self) -> usize { self.pos }
 
+    /// 向后移动读写位置, 移动后的位置不能超过数据长度
     pub fn step(&mut self, steps: usize) -> Result<()> {
-        self.pos += steps;
-        Ok(())
+        self.seek(self.pos + steps)
     }
 
+    /// 设置读写位置, 位置不能超过数据长度(等于数据长度表示已读取完毕)
     pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > self.len {
+            anyhow::bail!(BufferError::EndOfBuffer { pos, len: self.len });
+        }
         self.pos = pos;
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn read(&mut self) -> Result<u8> {
         self.check_range(self.pos)?;
         let res = self.buf[self.pos];
@@ -48,7 +152,9 @@ impl BytePacketBuffer {
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        self.check_range(start + len - 1)?;
+        if start + len > self.len {
+            anyhow::bail!(BufferError::EndOfBuffer { pos: start + len, len: self.len });
+        }
         Ok(&self.buf[start .. start + len])
     }
 
@@ -64,27 +170,34 @@ impl BytePacketBuffer {
         let res = ((self.buf[self.pos] as u32) << 24)
             | ((self.buf[self.pos + 1] as u32) << 16)
             | ((self.buf[self.pos + 2] as u32) << 8)
-            | ((self.buf[self.pos + 3] as u32) << 0);
+            | (self.buf[self.pos + 3] as u32);
         self.pos += 4;
         Ok(res)
     }
 
+    /// 读取域名并转为小写追加到outstr, 支持压缩指针
     pub fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+        outstr.reserve(QNAME_RESERVE);
         let mut pos = self.pos();
         let mut jumped = false;
 
         let mut first = true;
         let delim = ".";
-        let max_jumps = 5;
         let mut jumps_performed = 0;
+        let mut name_len = 0;
         loop {
             // Dns数据包是不受信任的数据，因此我们需要警惕。某人可以用跳转指令中的循环来制作数据包。这个守卫针对这样的分组
-            if jumps_performed > max_jumps {
-                anyhow::bail!("Limit of {max_jumps} jumps exceeded");
+            if jumps_performed > MAX_JUMPS {
+                anyhow::bail!(BufferError::TooManyJumps(MAX_JUMPS));
             }
 
             let len = self.get(pos)?;
 
+            // 0x40和0x80是保留的标签类型, 宽松模式下按普通长度处理
+            if self.strict && (len & 0xC0) != 0 && (len & 0xC0) != 0xC0 {
+                anyhow::bail!(BufferError::BadLabelType(len & 0xC0));
+            }
+
             // 最高2位为1(0xC0), 表示这不是长度, 而是一个跳转地址
             if (len & 0xC0) == 0xC0 {
                 // 首次跳转, 需要更新读取位置, 后续跳转不再更新
@@ -95,6 +208,10 @@ impl BytePacketBuffer {
                 // 跳转地址用2个字节表示
                 let b2 = self.get(pos + 1)? as u16;
                 let offset = (((len as u16) ^ 0xC0) << 8) | b2;
+                // 严格模式下压缩指针只能指向之前出现过的位置
+                if self.strict && offset as usize >= pos {
+                    anyhow::bail!(BufferError::ForwardPointer { pos, target: offset as usize });
+                }
                 pos = offset as usize;
                 jumped = true;
                 jumps_performed += 1;
@@ -108,11 +225,22 @@ impl BytePacketBuffer {
                 break;
             }
 
+            // 域名长度按线上格式计算: 各标签长度字节 + 标签内容 + 结尾的0
+            name_len += len as usize + 1;
+            if self.strict && name_len + 1 > MAX_NAME_LEN {
+                anyhow::bail!(BufferError::NameTooLong(name_len + 1));
+            }
+
             if first { first = false; }
             else { outstr.push_str(delim); }
 
-            let str_buffer = self.get_range(pos, len as usize)?;
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            // 直接从缓冲区转为小写追加, 避免每个标签都分配临时字符串
+            let label = self.get_range(pos, len as usize)?;
+            if label.is_ascii() {
+                outstr.extend(label.iter().map(|c| c.to_ascii_lowercase() as char));
+            } else {
+                outstr.push_str(&String::from_utf8_lossy(label).to_lowercase());
+            }
 
             pos += len as usize;
         }
@@ -125,14 +253,14 @@ impl BytePacketBuffer {
     }
 
     pub fn write(&mut self, val: u8) -> Result<()> {
-        self.check_range(self.pos)?;
+        self.check_write(self.pos + 1)?;
         self.buf[self.pos] = val;
         self.pos += 1;
         Ok(())
     }
 
     pub fn write_u16(&mut self, val: u16) -> Result<()> {
-        self.check_range(self.pos + 1)?;
+        self.check_write(self.pos + 2)?;
         self.buf[self.pos] = (val >> 8) as u8;
         self.buf[self.pos + 1] = val as u8;
         self.pos += 2;
@@ -140,7 +268,7 @@ impl BytePacketBuffer {
     }
 
     pub fn write_u32(&mut self, val: u32) -> Result<()> {
-        self.check_range(self.pos + 3)?;
+        self.check_write(self.pos + 4)?;
         self.buf[self.pos] = (val >> 24) as u8;
         self.buf[self.pos + 1] = (val >> 16) as u8;
         self.buf[self.pos + 2] = (val >> 8) as u8;
@@ -150,13 +278,14 @@ impl BytePacketBuffer {
     }
 
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        self.check_range(self.pos + qname.len())?;
+        // 各标签长度字节及结尾的0, 共比域名多2个字节
+        self.check_write(self.pos + qname.len() + 2)?;
 
         let mut pos = self.pos;
         for label in qname.split('.') {
             let len = label.len();
-            if len > 0x34 {
-                anyhow::bail!("Single label exceeds 63 characters of length");
+            if len > MAX_LABEL_LEN {
+                anyhow::bail!(BufferError::LabelTooLong(len));
             }
 
             self.buf[pos] = len as u8;
@@ -173,7 +302,6 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn set(&mut self, pos: usize, val: u8) -> Result<()> {
         self.check_range(pos)?;
         self.buf[pos] = val;
@@ -187,3 +315,216 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+/// 缓冲池统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub allocated: u64,     // 新分配的缓冲区数量
+    pub reused   : u64,     // 从池中复用的次数
+    pub dropped  : u64,     // 池已满时丢弃的归还缓冲区数量
+    pub idle     : usize,   // 当前池中空闲的缓冲区数量
+}
+
+/// 数据包缓冲池, 复用已分配的缓冲区, 避免高并发时每个数据包都分配并清零内存
+pub struct BufferPool {
+    buffers : Vec<BytePacketBuffer>,   // 空闲的缓冲区
+    max_idle: usize,                   // 最多保留的空闲缓冲区数量
+    cap     : usize,                   // 新分配缓冲区的最大容量
+    stats   : PoolStats,               // 统计信息
+}
+
+impl BufferPool {
+    pub fn new(max_idle: usize, cap: usize) -> BufferPool {
+        BufferPool { buffers: Vec::new(), max_idle, cap, stats: PoolStats::default() }
+    }
+
+    /// 获取一个已清空的缓冲区, 池为空时新分配
+    pub fn get(&mut self) -> BytePacketBuffer {
+        match self.buffers.pop() {
+            Some(mut buf) => {
+                self.stats.reused += 1;
+                buf.clear();
+                buf
+            },
+            None => {
+                self.stats.allocated += 1;
+                BytePacketBuffer::with_capacity(self.cap)
+            },
+        }
+    }
+
+    /// 归还缓冲区
+    pub fn put(&mut self, buf: BytePacketBuffer) {
+        if self.buffers.len() < self.max_idle {
+            self.buffers.push(buf);
+        } else {
+            self.stats.dropped += 1;
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats { idle: self.buffers.len(), ..self.stats }
+    }
+}
+
+/// 从共享缓冲池借出的缓冲区, 释放时自动归还, 出错提前返回时缓冲区也不会丢失
+pub struct PooledBuffer<'a> {
+    pool: &'a RefCell<BufferPool>,    // 借出缓冲区的池
+    buf : Option<BytePacketBuffer>,   // 借出的缓冲区, 归还后为None
+}
+
+impl<'a> PooledBuffer<'a> {
+    pub fn new(pool: &'a RefCell<BufferPool>) -> PooledBuffer<'a> {
+        let buf = pool.borrow_mut().get();
+        PooledBuffer { pool, buf: Some(buf) }
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = BytePacketBuffer;
+
+    fn deref(&self) -> &BytePacketBuffer {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut BytePacketBuffer {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.borrow_mut().put(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer() {
+        let mut buf = BytePacketBuffer::with_capacity(16);
+        buf.write_u32(0x01020304).unwrap();
+        buf.write_qname("ab.c").unwrap();
+        assert_eq!(10, buf.pos());
+        assert_eq!(&[1, 2, 3, 4, 2, b'a', b'b', 1, b'c', 0], buf.data());
+        buf.set_u16(0, 0x0506).unwrap();
+        assert!(buf.set_u16(10, 0).is_err());
+        assert!(buf.write_qname("abcdef").is_err());
+
+        buf.seek(4).unwrap();
+        let mut name = String::new();
+        buf.read_qname(&mut name).unwrap();
+        assert_eq!("ab.c", name);
+        assert!(buf.read().is_err());
+
+        // 大写转为小写, 以及压缩指针
+        let mut buf = BytePacketBuffer::with_capacity(32);
+        for b in [3, b'W', b'w', b'W', 3, b'L', b'a', b'N', 0, 1, b'x', 0xC0, 4] {
+            buf.write(b).unwrap();
+        }
+        buf.seek(0).unwrap();
+        let mut name = String::new();
+        buf.read_qname(&mut name).unwrap();
+        assert_eq!("www.lan", name);
+        name.clear();
+        buf.read_qname(&mut name).unwrap();
+        assert_eq!("x.lan", name);
+        assert_eq!(13, buf.pos());
+
+        let data = buf.recv_buf();
+        assert_eq!(32, data.len());
+        data[..2].copy_from_slice(&[0x12, 0x34]);
+        buf.set_len(2);
+        assert_eq!(0x1234, buf.read_u16().unwrap());
+        assert!(buf.read().is_err());
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut buf = BytePacketBuffer::with_capacity(128);
+        buf.write_u16(0x1234).unwrap();
+        buf.seek(2).unwrap();
+        assert!(buf.seek(3).is_err());
+        buf.seek(0).unwrap();
+        assert!(buf.step(3).is_err());
+        assert_eq!(0, buf.pos());
+        let err = buf.step(5).unwrap_err();
+        assert_eq!(Some(&BufferError::EndOfBuffer { pos: 5, len: 2 }), err.downcast_ref());
+        assert!(buf.get_range(2, 0).unwrap().is_empty());
+        assert!(buf.get_range(1, 2).is_err());
+
+        assert!(buf.write_qname(&"a".repeat(63)).is_ok());
+        buf.clear();
+        let err = buf.write_qname(&"a".repeat(64)).unwrap_err();
+        assert_eq!(Some(&BufferError::LabelTooLong(64)), err.downcast_ref());
+    }
+
+    #[test]
+    fn test_strict() {
+        // 向后跳转的压缩指针, 宽松模式下允许
+        let mut buf = BytePacketBuffer::with_capacity(32);
+        for b in [0xC0, 2, 1, b'a', 0] {
+            buf.write(b).unwrap();
+        }
+        buf.seek(0).unwrap();
+        let mut name = String::new();
+        buf.read_qname(&mut name).unwrap();
+        assert_eq!("a", name);
+
+        buf.set_strict(true);
+        buf.seek(0).unwrap();
+        let err = buf.read_qname(&mut name).unwrap_err();
+        assert_eq!(Some(&BufferError::ForwardPointer { pos: 0, target: 2 }), err.downcast_ref());
+
+        // 保留的标签类型
+        buf.set(2, 0x41).unwrap();
+        buf.seek(2).unwrap();
+        let err = buf.read_qname(&mut name).unwrap_err();
+        assert_eq!(Some(&BufferError::BadLabelType(0x40)), err.downcast_ref());
+
+        // 超过255字节的域名
+        let mut buf = BytePacketBuffer::with_capacity(512);
+        let label = "a".repeat(63);
+        buf.write_qname(&[label.as_str(); 4].join(".")).unwrap();
+        buf.set_strict(true);
+        buf.seek(0).unwrap();
+        let err = buf.read_qname(&mut name).unwrap_err();
+        assert_eq!(Some(&BufferError::NameTooLong(257)), err.downcast_ref());
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut pool = BufferPool::new(1, 64);
+        let mut a = pool.get();
+        a.write_u16(0x1234).unwrap();
+        let b = pool.get();
+        pool.put(a);
+        pool.put(b);
+        let a = pool.get();
+        assert_eq!(0, a.pos());
+        assert!(a.data().is_empty());
+        assert_eq!(PoolStats { allocated: 2, reused: 1, dropped: 1, idle: 0 }, pool.stats());
+    }
+
+    #[test]
+    fn test_pooled_buffer() {
+        let pool = RefCell::new(BufferPool::new(4, 64));
+        let write = |fail: bool| -> Result<()> {
+            let mut buf = PooledBuffer::new(&pool);
+            buf.write_u16(0x1234)?;
+            if fail {
+                anyhow::bail!("write failed");
+            }
+            Ok(())
+        };
+        write(false).unwrap();
+        assert!(write(true).is_err());
+        assert_eq!(PoolStats { allocated: 1, reused: 1, dropped: 0, idle: 1 }, pool.borrow().stats());
+    }
+}
@@ -0,0 +1,216 @@
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{Receiver, channel};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use super::bufutil::BytePacketBuffer;
+use super::dnsutil::{DnsPacket, DnsQuestion, QueryType};
+
+const DEFAULT_PORT: u16 = 53;                                 // 未指定端口时使用的dns端口
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);     // 每次请求等待回复的超时时间
+const DEFAULT_RETRIES: u32 = 2;                               // 超时后的重试次数
+const MAX_REPLY_LEN: usize = 4096;                            // 回复数据包的最大长度
+
+/// dns查询客户端, 向指定服务器发送请求并等待回复, 超时后自动重试
+#[derive(Debug, Clone)]
+pub struct Client {
+    server : SocketAddr,   // 服务器地址
+    timeout: Duration,     // 每次请求的超时时间
+    retries: u32,          // 超时后的重试次数
+    backoff: Duration,     // 首次重试前的等待时间, 之后每次加倍
+}
+
+/// 超过重试次数仍未收到回复时返回的错误, 可用`anyhow::Error::downcast_ref`与其它错误区分
+#[derive(Debug, Clone)]
+pub struct NoReply {
+    pub server  : SocketAddr,   // 服务器地址
+    pub attempts: u32,          // 发送请求的次数
+}
+
+impl std::fmt::Display for NoReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no reply from {} after {} attempts", self.server, self.attempts)
+    }
+}
+
+impl std::error::Error for NoReply {}
+
+impl Client {
+
+    /// 创建客户端, 服务器地址格式为`host`或`host:port`, 未指定端口时使用53,
+    /// ipv6地址带端口时需用方括号括起, 如`[::1]:53`
+    pub fn new(server: &str) -> Result<Client> {
+        let addr = match server.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => match server.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(server).parse() {
+                Ok(ip) => SocketAddr::new(ip, DEFAULT_PORT),
+                Err(_) => {
+                    let host = if server.contains(':') { server.to_string() } else { format!("{server}:{DEFAULT_PORT}") };
+                    host.to_socket_addrs()
+                        .with_context(|| format!("resolve server address {server} failed"))?
+                        .next()
+                        .with_context(|| format!("server address {server} not found"))?
+                },
+            },
+        };
+        Ok(Client { server: addr, timeout: DEFAULT_TIMEOUT, retries: DEFAULT_RETRIES, backoff: Duration::ZERO })
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// 设置每次请求等待回复的超时时间
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// 设置超时后的重试次数, 0表示不重试
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// 设置首次重试前的等待时间, 之后每次重试加倍, 缺省为0即立即重试
+    pub fn set_backoff(&mut self, backoff: Duration) {
+        self.backoff = backoff;
+    }
+
+    /// 发送原始数据包并返回服务器的回复
+    pub fn exchange(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.send_recv(data, |_| true)
+    }
+
+    /// 发送查询请求, 返回服务器的应答数据包
+    pub fn query(&self, question: DnsQuestion) -> Result<DnsPacket> {
+        let id = query_id();
+        let packet = DnsPacket::builder()
+            .id(id)
+            .recursion_desired(true)
+            .question(question)
+            .build();
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer)?;
+
+        // 忽略请求id不一致的回复(如上一次超时请求的迟到回复)
+        let reply = self.send_recv(buffer.data(), |reply| reply.len() >= 2 && u16::from_be_bytes([reply[0], reply[1]]) == id)?;
+        let response = DnsPacket::from_bytes(&reply).with_context(|| format!("parse reply from {} failed", self.server))?;
+        if !response.header.response {
+            anyhow::bail!("reply from {} is not a response", self.server);
+        }
+        Ok(response)
+    }
+
+    /// 查询指定域名及类型
+    pub fn resolve(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        self.query(DnsQuestion::new(name.trim_end_matches('.').to_ascii_lowercase(), qtype))
+    }
+
+    /// 在后台线程中查询, 通过返回的通道接收结果
+    pub fn resolve_async(&self, name: &str, qtype: QueryType) -> Receiver<Result<DnsPacket>> {
+        let (tx, rx) = channel();
+        let (client, name) = (self.clone(), name.to_string());
+        std::thread::spawn(move || {
+            let _ = tx.send(client.resolve(&name, qtype));
+        });
+        rx
+    }
+
+    fn send_recv<F: Fn(&[u8]) -> bool>(&self, data: &[u8], accept: F) -> Result<Vec<u8>> {
+        let bind_addr = match self.server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(bind_addr).with_context(|| format!("bind client socket {bind_addr} failed"))?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.set_write_timeout(Some(self.timeout))?;
+
+        let mut buf = vec![0; MAX_REPLY_LEN];
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                let wait = self.backoff * 2u32.saturating_pow(attempt - 1);
+                log::debug!("request to {} failed, retry {attempt} after {wait:?}", self.server);
+                std::thread::sleep(wait);
+            }
+            // 网络暂时不可用时发送失败, 与超时一样进行重试
+            if let Err(e) = socket.send_to(data, self.server) {
+                if attempt == self.retries {
+                    return Err(anyhow::Error::new(e).context(format!("send request to {} failed", self.server)));
+                }
+                log::debug!("send request to {} failed: {e}", self.server);
+                continue;
+            }
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((n, addr)) if addr == self.server && accept(&buf[..n]) => return Ok(buf[..n].to_vec()),
+                    Ok(_) => continue,
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+                    Err(e) => return Err(anyhow::Error::new(e).context(format!("recv reply from {} failed", self.server))),
+                }
+            }
+        }
+        Err(NoReply { server: self.server, attempts: self.retries + 1 }.into())
+    }
+
+}
+
+/// 使用缺省的超时及重试次数查询指定域名
+pub fn resolve(name: &str, qtype: QueryType, server: &str) -> Result<DnsPacket> {
+    Client::new(server)?.resolve(name, qtype)
+}
+
+/// 基于当前时间生成查询请求id
+fn query_id() -> u16 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    (nanos ^ (nanos >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+    use crate::dnsutil::{DnsRecord, ResultCode};
+
+    #[test]
+    fn test_resolve() {
+        // 模拟服务器, 丢弃第一个请求以测试重试
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            server.recv_from(&mut buf).unwrap();
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::from_bytes(&buf[..n]).unwrap();
+            let name = request.questions[0].name.clone();
+            let reply = DnsPacket::builder()
+                .id(request.header.id)
+                .response(ResultCode::NOERROR)
+                .question(request.questions[0].clone())
+                .answer(DnsRecord::A { domain: name, addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 })
+                .build();
+            server.send_to(&reply.to_bytes().unwrap(), from).unwrap();
+        });
+
+        let mut client = Client::new(&addr.to_string()).unwrap();
+        client.set_timeout(Duration::from_millis(200));
+        let packet = client.resolve_async("WWW.lan.", QueryType::A).recv().unwrap().unwrap();
+        assert_eq!("www.lan", packet.questions[0].name);
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 1)), packet.get_random_a());
+
+        // 服务器不再回复, 等待时间依次为0.05s及0.1s
+        client.set_retries(2);
+        client.set_timeout(Duration::from_millis(50));
+        client.set_backoff(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        let e = client.resolve("www.lan", QueryType::A).unwrap_err();
+        assert_eq!(3, e.downcast_ref::<NoReply>().unwrap().attempts);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_server_addr() {
+        assert_eq!("10.0.0.1:53", Client::new("10.0.0.1").unwrap().server().to_string());
+        assert_eq!("10.0.0.1:5353", Client::new("10.0.0.1:5353").unwrap().server().to_string());
+        assert_eq!("[::1]:53", Client::new("::1").unwrap().server().to_string());
+        assert_eq!("[::1]:53", Client::new("[::1]").unwrap().server().to_string());
+        assert_eq!("[::1]:5353", Client::new("[::1]:5353").unwrap().server().to_string());
+    }
+}
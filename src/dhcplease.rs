@@ -0,0 +1,180 @@
+//! dhcp租约文件解析, 将dhcp服务器分配给客户端的地址注册为客户端主机名的域名
+//!
+//! 支持dnsmasq(dnsmasq.leases)及ISC dhcpd(dhcpd.leases)两种格式, 只接受由字母、数字及`-`组成的
+//! 单标签主机名, 客户端可以任意声明主机名, 因此保留名称(如wpad)不会被注册
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::SystemTime;
+use anyhow::{Result, Context};
+use chrono::TimeZone;
+
+// 不允许客户端通过dhcp主机名注册的名称, wpad及isatap会被浏览器及系统自动查询, 可被用于劫持代理设置
+const RESERVED_NAMES: [&str; 4] = ["wpad", "isatap", "localhost", "local"];
+
+/// dhcp服务器分配的一条租约
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    pub host  : String,         // 客户端主机名(小写)
+    pub ip    : IpAddr,         // 分配的地址
+    pub expire: Option<u64>,    // 租约到期的unix时间(秒), None表示永不过期
+}
+
+/// dhcp租约文件, 支持dnsmasq(dnsmasq.leases)及ISC dhcpd(dhcpd.leases)格式
+///
+/// 文件修改时间发生变化时重新读取全部租约, 没有主机名的租约将被忽略
+pub struct LeaseFile {
+    path    : String,                // 租约文件路径
+    modified: Option<SystemTime>,    // 最后一次读取时文件的修改时间
+}
+
+impl LeaseFile {
+
+    pub fn new(path: &str) -> LeaseFile {
+        LeaseFile { path: path.to_string(), modified: None }
+    }
+
+    /// 文件修改时间发生变化时重新读取租约, 未变化时返回None
+    pub fn read_if_changed(&mut self) -> Result<Option<Vec<DhcpLease>>> {
+        let modified = std::fs::metadata(&self.path)
+            .with_context(|| format!("read lease file {} metadata failed", self.path))?
+            .modified().ok();
+        if modified.is_some() && modified == self.modified {
+            return Ok(None);
+        }
+
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("read lease file {} failed", self.path))?;
+        self.modified = modified;
+        Ok(Some(parse_leases(&text)))
+    }
+
+}
+
+/// 解析租约文件内容, 根据内容自动识别dnsmasq或ISC dhcpd格式
+pub fn parse_leases(text: &str) -> Vec<DhcpLease> {
+    let isc = text.lines().any(|line| {
+        let line = line.trim();
+        line.starts_with("lease ") && line.ends_with('{')
+    });
+    match isc {
+        true => parse_isc(text),
+        false => parse_dnsmasq(text),
+    }
+}
+
+/// 解析dnsmasq租约文件, 每行格式: 到期时间 mac地址(ipv6为iaid) ip 主机名 客户端id,
+/// 到期时间为0表示永不过期, 主机名为`*`表示客户端未提供主机名, ipv6租约前的duid行被忽略
+pub fn parse_dnsmasq(text: &str) -> Vec<DhcpLease> {
+    text.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [expire, _, ip, host, ..] => Some(DhcpLease {
+                host: host_name(host)?,
+                ip: ip.parse().ok()?,
+                expire: match expire.parse().ok()? {
+                    0 => None,
+                    expire => Some(expire),
+                },
+            }),
+            _ => None,
+        }
+    }).collect()
+}
+
+/// 解析ISC dhcpd租约文件, 文件以追加方式记录租约变化, 同一地址以最后一条记录为准,
+/// 只保留binding state为active的租约, ends为never表示永不过期
+pub fn parse_isc(text: &str) -> Vec<DhcpLease> {
+    let mut leases: HashMap<IpAddr, DhcpLease> = HashMap::new();
+    let mut current: Option<(IpAddr, Option<String>, Option<u64>, bool)> = None;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some(ip) = line.strip_prefix("lease ").and_then(|s| s.strip_suffix('{')) {
+            current = ip.trim().parse().ok().map(|ip| (ip, None, None, true));
+            continue;
+        }
+        let Some((ip, host, expire, active)) = current.as_mut() else {
+            continue;
+        };
+        if line == "}" {
+            let ip = *ip;
+            match (host.take(), *active) {
+                (Some(host), true) => leases.insert(ip, DhcpLease { host, ip, expire: *expire }),
+                _ => leases.remove(&ip),
+            };
+            current = None;
+            continue;
+        }
+
+        let line = line.trim_end_matches(';');
+        if let Some(value) = line.strip_prefix("client-hostname ") {
+            *host = host_name(value.trim().trim_matches('"'));
+        } else if let Some(value) = line.strip_prefix("binding state ") {
+            *active = value.trim() == "active";
+        } else if let Some(value) = line.strip_prefix("ends ") {
+            *expire = parse_isc_time(value.trim());
+        }
+    }
+    let mut leases: Vec<DhcpLease> = leases.into_values().collect();
+    leases.sort_by_key(|lease| lease.ip);
+    leases
+}
+
+/// 解析ISC dhcpd的时间, 格式为`星期 yyyy/mm/dd hh:mm:ss`(utc)或`epoch 秒数`, never返回None
+fn parse_isc_time(value: &str) -> Option<u64> {
+    if let Some(secs) = value.strip_prefix("epoch ") {
+        return secs.trim().parse().ok();
+    }
+    let (_, datetime) = value.split_once(' ')?;
+    let time = chrono::NaiveDateTime::parse_from_str(datetime.trim(), "%Y/%m/%d %H:%M:%S").ok()?;
+    u64::try_from(chrono::Utc.from_utc_datetime(&time).timestamp()).ok()
+}
+
+/// 是否为不允许通过dhcp主机名注册的保留名称
+pub fn is_reserved_name(host: &str) -> bool {
+    RESERVED_NAMES.iter().any(|name| host.eq_ignore_ascii_case(name))
+}
+
+/// 规范化客户端提供的主机名, 只接受由字母、数字及`-`组成的单个标签
+fn host_name(host: &str) -> Option<String> {
+    let valid = !host.is_empty() && host.len() <= 63
+        && host.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !host.starts_with('-') && !host.ends_with('-');
+    valid.then(|| host.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dnsmasq_leases() {
+        let leases = parse_leases("1760529600 3c:22:fb:01:02:03 192.168.1.20 Laptop 01:3c:22:fb:01:02:03\n\
+            0 3c:22:fb:01:02:04 192.168.1.21 printer *\n\
+            1760529600 3c:22:fb:01:02:05 192.168.1.22 * *\n\
+            duid 00:01:00:01:2c:5b:1a:2b:3c:22:fb:01:02:03\n\
+            1760529600 1023 fd00::20 laptop 00:01:00:01\n");
+        assert_eq!(3, leases.len());
+        assert_eq!(DhcpLease { host: "laptop".to_string(), ip: "192.168.1.20".parse().unwrap(), expire: Some(1760529600) }, leases[0]);
+        assert_eq!(("printer", None), (leases[1].host.as_str(), leases[1].expire));
+        assert_eq!("fd00::20".parse::<IpAddr>().unwrap(), leases[2].ip);
+    }
+
+    #[test]
+    fn test_isc_leases() {
+        let leases = parse_leases("# The format of this file is documented in the dhcpd.leases(5) manual page.\n\
+            lease 192.168.1.20 {\n  starts 3 2026/10/14 08:00:00;\n  ends 3 2026/10/14 20:00:00;\n\
+              binding state active;\n  client-hostname \"old-name\";\n}\n\
+            lease 192.168.1.30 {\n  ends never;\n  binding state active;\n  client-hostname \"nas\";\n}\n\
+            lease 192.168.1.40 {\n  ends epoch 1760529600; # Wed Oct 15 12:00:00 2025\n\
+              binding state free;\n  client-hostname \"gone\";\n}\n\
+            lease 192.168.1.20 {\n  ends 4 2026/10/15 00:00:00;\n  binding state active;\n\
+              next binding state free;\n  client-hostname \"Laptop\";\n}\n");
+        assert_eq!(2, leases.len());
+        assert_eq!(DhcpLease { host: "laptop".to_string(), ip: "192.168.1.20".parse().unwrap(), expire: Some(1792022400) }, leases[0]);
+        assert_eq!(DhcpLease { host: "nas".to_string(), ip: "192.168.1.30".parse().unwrap(), expire: None }, leases[1]);
+        assert_eq!(Some(1760529600), parse_isc_time("epoch 1760529600"));
+        assert_eq!(None, host_name("bad.name"));
+        assert!(is_reserved_name("WPAD") && !is_reserved_name("laptop"));
+    }
+}
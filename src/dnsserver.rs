@@ -1,12 +1,33 @@
-use std::cell::Cell;
-use std::collections::HashMap;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use mio::{Events, Interest, Poll, Token, net::UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use mio::{Events, Interest, Poll, Token, Waker, net::{TcpListener, TcpStream, UdpSocket}};
 use anyhow::{Result, Context};
 use super::bufutil::*;
 use super::dnsutil::*;
+use super::dyndns::{AuditLog, AuditRecord, AuthLock, is_allowed_domain, json_reply, parse_suffixes, ChangeHook};
+use super::keyfile::KeyFile;
+use super::dhcplease::{DhcpLease, LeaseFile, is_reserved_name};
+use super::mdnsbridge::{LOOKUP_TIMEOUT, MdnsBridge};
+use super::lanquery::PendingQueries;
+use super::lanname::{FALLBACK_TIMEOUT, NameFallback};
+use super::acl::Acl;
+use super::analytics::Analytics;
+use super::blockset::{BlockSet, MappedBlockSet};
+use super::hostsconf::{HostEntry, HostRecord, HostsConfig};
+use super::remotehosts::RemoteHosts;
+use super::hooks::{BlockHook, HookAction, QueryHook, ResponseHook};
+use super::metrics::{MetricsSink, NoopMetrics};
+use super::resolver::{resolver_chain, AnswerMap, ResolveContext, ResolveResult, Resolver};
+use super::script::ScriptResolver;
+use super::groups::{GroupConfig, MacAddr, load_groups, read_arp_table};
 
 // dyndns 常量定义
 const C_2023_01_01: u64            = 1672531200;                          // 动态dns更新的时间基数: 2023-01-01起到现在的秒数
@@ -17,15 +38,43 @@ const C_DYNDNS_PARAM_DIGEST: usize = 1;
 const C_DYNDNS_PARAM_ID: usize     = 2;
 const C_DYNDNS_PARAM_HOST: usize   = 3;
 const C_DYNDNS_PARAM_IP: usize     = 4;
+const C_DYNDNS_PARAM_FORMAT: usize = 5;                                   // 可选参数, 回复格式
+const C_DYNDNS_FORMAT_JSON: &str   = "json";                              // json格式回复, 缺省为兼容旧版的文本格式
 const C_DYNDNS_TIME_RANGE: u64     = 60 * 10;                             // 动态dns更新时间允许的误差
+const C_DYNDNS_MAX_LEN: usize      = 512;                                 // 动态dns数据包最大长度
+const C_DYNDNS_MAX_CONNS: usize    = 64;                                  // 动态dns同时处理的tcp连接数上限
 
 // dnsserver 常量定义
 const QUERY_TIMEOUT: u64          = 10;        // 查询超时时间(秒)
-const CLEAR_QUERIES_INTERVAL: u64 = 10;        // 定期清理查询队列时间间隔(秒)
-const MAX_FORWARD_COUNT: u8       = 10;        // 转发查询的最大跳转次数, 防止无限循环
+const MAINTAIN_INTERVAL: u64      = 10;        // 定期维护(重新加载密钥、清理过期租约等)的时间间隔(秒)
+const MAX_FORWARD_COUNT: u8       = 10;        // 缺省的转发查询最大跳转次数, 防止无限循环
+const QUERY_BUDGET: u16           = 16;        // 缺省的单个查询工作量上限(转发跳转次数与别名跟随次数之和)
 const MAX_QUERIES_LEN: usize      = 4096;      // 队列允许的最大长度
+const MAX_SERVFAIL_ENTRIES: usize = 10000;     // 缓存的上级失败结果的最大条目数
+const MAX_LOCAL_ANSWERS: usize    = 4096;      // 缓存的本地域名查询结果的最大条目数, 超出时清空
+const MAX_UDP_PACKET_LEN: usize   = 512;       // 未使用EDNS时udp响应的最大长度
+const POOL_MAX_IDLE: usize        = 64;        // 缓冲池最多保留的空闲缓冲区数量
+const BLOCKED_TARGET: &str        = "minidns::blocked"; // 屏蔽日志的目标, 可以用log-route分流到独立的文件
+const SLOW_QUERY_TARGET: &str     = "minidns::slowquery"; // 慢查询日志的目标, 可以用log-route分流到独立的文件
+const MAX_CONTROL_CONNS: usize    = 8;         // 管理控制连接的最大数量
+const DEFAULT_TOP_COUNT: usize    = 10;        // 管理命令top缺省输出的域名及客户端数量
+const MAX_EVENT_CAPACITY: usize   = 8192;      // 自适应模式下事件容量的上限
+const EVENT_SHRINK_POLLS: u32     = 1000;      // 连续多少次poll的事件数低于容量的1/4时缩小容量
+const MIN_POLL_TIMEOUT: Duration  = Duration::from_millis(100); // 自适应模式下繁忙时poll的超时时间
 const SERVER_TOKEN: Token         = Token(0);  // 监听服务的token
 const UP_SERVER_TOKEN: Token      = Token(1);  // 向上级dns转发查询服务的token
+const DYNDNS_TOKEN: Token         = Token(2);  // 动态dns独立端口udp服务的token
+const DYNDNS_TCP_TOKEN: Token     = Token(3);  // 动态dns独立端口tcp服务的token
+const HOST_CMD_TOKEN: Token       = Token(4);  // 运行时域名注册命令的唤醒token
+const NS_SERVER_TOKEN: Token      = Token(5);  // 向其它域名服务器迭代查询的token
+#[cfg(unix)]
+const HANDOVER_TOKEN: Token       = Token(6);  // 监听socket交接服务的token
+#[cfg(unix)]
+const CONTROL_TOKEN: Token        = Token(7);  // 管理控制socket的token
+const MDNS_TOKEN: Token           = Token(8);  // mdns桥接查询的token
+const FALLBACK_TOKEN: Token       = Token(9);  // llmnr/netbios名称查询的token
+const NS6_SERVER_TOKEN: Token     = Token(10); // 向其它ipv6域名服务器迭代查询的token
+const DYNDNS_CONN_TOKEN: usize    = 16;        // 动态dns tcp连接及管理控制连接的起始token
 
 // 待解析的查询项
 struct QueryData {
@@ -33,24 +82,401 @@ struct QueryData {
     addr    : SocketAddr,    // 来自dns查询请求的客户端地址
     question: DnsQuestion,   // 来自dns查询请求的查询条目
     forword : u16,           // 当前递归查询指向的上一级QueryData的id
-    expire  : u64,           // 查询过期时间戳, Unix格式: 自1970-01-01至今的秒数
+    expire  : Instant,       // 查询的超时时刻, 到期仍未收到上级回复时从队列中删除
     count   : Cell<u8>,      // 当前的转发查询次数, 需要做一些限制, 否则有可能陷入死循环
+    start   : Instant,       // 收到查询的时间, 用于统计回复耗时
+    upstream: Cell<Option<IpAddr>>, // 最近一次转发查询的上级dns服务器, 用于慢查询日志
+    sent    : Cell<Instant>,  // 最近一次向上级发送查询的时间, 用于统计上级的回复耗时
+    recursion: bool,         // 客户端是否期望递归查询(RD标志), 为false时只用本地数据回复
 }
 
 type Query   = Rc<QueryData>;
-type Queries = HashMap<u16, Query>;
-type HOSTS   = HashMap<String, Ipv4Addr>;
+type Hosts   = HashMap<String, Vec<HostAddr>>;
+type Records = HashMap<String, Vec<(HostRecord, Option<u32>)>>;
+
+const MAX_CNAME_CHAIN: u8 = 8;      // 缺省的别名记录最大跟随次数
+
+// 屏蔽域名的查询结果
+const BLOCKED_ADDRS: &[HostAddr] = &[HostAddr { addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED), ttl: None }];
+
+// 等待上级回复的查询队列, 以超时时刻为序的最小堆记录各查询的到期时间,
+// 事件循环据此设置poll的超时时间, 查询到期后立即清理, 无需定期扫描整个队列
+#[derive(Default)]
+struct Queries {
+    queries  : HashMap<u16, Query>,                 // 以转发请求id为键的查询
+    deadlines: BinaryHeap<Reverse<(Instant, u16)>>, // 查询的超时时刻及请求id, 可能包含已回复查询的过期条目
+}
+
+impl Queries {
+    fn new() -> Queries {
+        Queries::default()
+    }
+
+    fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    fn get(&self, id: &u16) -> Option<&Query> {
+        self.queries.get(id)
+    }
+
+    fn insert(&mut self, id: u16, query: Query) {
+        self.deadlines.push(Reverse((query.expire, id)));
+        self.queries.insert(id, query);
+    }
+
+    fn remove(&mut self, id: &u16) -> Option<Query> {
+        self.queries.remove(id)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&u16, &Query)> {
+        self.queries.iter()
+    }
+
+    /// 最早的超时时刻, 可能早于实际值(对应的查询已收到回复), 此时只是提前唤醒一次
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    /// 删除并返回在`now`之前到期的查询, 请求id已被新查询复用时保留新查询
+    fn expire(&mut self, now: Instant) -> Vec<Query> {
+        let mut expired = Vec::new();
+        while let Some(Reverse((deadline, id))) = self.deadlines.peek().copied() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            if self.queries.get(&id).is_some_and(|q| q.expire == deadline) {
+                log::trace!("request id {id} is timeout, remove it");
+                expired.extend(self.queries.remove(&id));
+            }
+        }
+        expired
+    }
+}
+
+/// 上级dns服务器失败(端口不可达或超时未回复)时回复客户端的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailurePolicy {
+    #[default]
+    ServFail,   // 回复SERVFAIL
+    Stale,      // 回复解析链中缓存的过期结果, 没有缓存时回复SERVFAIL
+    NxDomain,   // 回复域名不存在
+}
+
+impl std::str::FromStr for FailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "servfail" => Ok(FailurePolicy::ServFail),
+            "stale" => Ok(FailurePolicy::Stale),
+            "nxdomain" => Ok(FailurePolicy::NxDomain),
+            _ => anyhow::bail!("unknown upstream failure policy {s}, expect servfail/stale/nxdomain"),
+        }
+    }
+}
+
+/// 事件循环的运行参数及统计信息, 用于调整事件容量及poll超时时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventStats {
+    pub capacity    : usize,     // 当前每次poll最多获取的事件数量
+    pub poll_timeout: Duration,  // 当前poll的最长等待时间(有查询即将超时时提前唤醒)
+    pub peak        : usize,     // 单次poll获取的最多事件数量
+    pub full_polls  : u64,       // 事件数量达到容量上限的poll次数, 较多时说明容量不足
+    pub resizes     : u64,       // 自适应模式下调整事件容量的次数
+}
+
+/// 单个上级dns服务器(含迭代查询的其它域名服务器)的查询统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpstreamStats {
+    pub queries    : u64,        // 发送的查询数量
+    pub responses  : u64,        // 收到的回复数量
+    pub errors     : u64,        // 失败的查询数量(回复SERVFAIL/REFUSED等错误码或端口不可达)
+    pub timeouts   : u64,        // 超时未回复的查询数量
+    pub latency_sum: Duration,   // 回复耗时的总和
+    pub latency_max: Duration,   // 最长的回复耗时
+}
+
+impl UpstreamStats {
+    /// 失败及超时的查询占发送查询的比例
+    pub fn error_rate(&self) -> f64 {
+        match self.queries {
+            0 => 0.0,
+            n => (self.errors + self.timeouts) as f64 / n as f64,
+        }
+    }
+
+    /// 平均回复耗时
+    pub fn avg_latency(&self) -> Duration {
+        match self.responses {
+            0 => Duration::ZERO,
+            n => self.latency_sum / n as u32,
+        }
+    }
+}
+
+// 客户端分组, 组的hosts及规则脚本优先于全局的解析链
+struct ClientGroup {
+    config: GroupConfig,             // 分组配置
+    table : HostTable,               // 组的hosts文件中的域名及屏蔽域名
+    script: Option<ScriptResolver>,  // 组的规则脚本
+}
+
+impl ClientGroup {
+    fn load(config: GroupConfig, ttl: u32) -> Result<ClientGroup> {
+        let mut table = HostTable::default();
+        for path in &config.hosts {
+            for entry in HostsConfig::new(path)? {
+                table.add(&entry?)?;
+            }
+        }
+        let script = match config.script.is_empty() {
+            true => None,
+            false => Some(ScriptResolver::load(&config.script, ttl)?),
+        };
+        Ok(ClientGroup { config, table, script })
+    }
+
+    /// 在组的域名表及规则脚本中解析, 返回None表示交给全局的解析链
+    fn lookup(&mut self, ctx: &ResolveContext, question: &DnsQuestion, ttl: u32) -> Option<ResolveResult> {
+        if let Some(addrs) = find_in_hosts(&self.table.hosts, &self.table.blocked, &question.name) {
+            return Some(ResolveResult::Answer(host_answers(&question.name, addrs, question.qtype, ttl).into()));
+        }
+        match self.script.as_mut().map(|script| script.lookup(ctx, question)) {
+            Some(ResolveResult::Next) | None => None,
+            result => result,
+        }
+    }
+}
+
+// 事件容量及poll超时时间的调整器, 固定模式下只做统计;
+// 自适应模式下事件填满容量时容量翻倍, 长时间负载较低时减半(不低于初始容量),
+// 繁忙时使用较短的poll超时, 空闲时超时时间逐次翻倍直至设置的最大值
+struct EventTuner {
+    adaptive        : bool,      // 是否启用自适应模式
+    min_capacity    : usize,     // 初始事件容量, 缩小容量时的下限
+    max_poll_timeout: Duration,  // poll的最长等待时间
+    low_polls       : u32,       // 连续负载较低的poll次数
+    stats           : EventStats,
+}
+
+impl EventTuner {
+    fn new(capacity: usize, max_poll_timeout: Duration, adaptive: bool) -> EventTuner {
+        let poll_timeout = match adaptive {
+            true => MIN_POLL_TIMEOUT.min(max_poll_timeout),
+            false => max_poll_timeout,
+        };
+        EventTuner {
+            adaptive,
+            min_capacity: capacity,
+            max_poll_timeout,
+            low_polls: 0,
+            stats: EventStats { capacity, poll_timeout, peak: 0, full_polls: 0, resizes: 0 },
+        }
+    }
+
+    /// 记录一次poll获取的事件数量, 事件容量发生变化时返回true
+    fn observe(&mut self, count: usize) -> bool {
+        let stats = &mut self.stats;
+        stats.peak = stats.peak.max(count);
+        if count >= stats.capacity {
+            stats.full_polls += 1;
+        }
+        if !self.adaptive {
+            return false;
+        }
+
+        stats.poll_timeout = match count {
+            0 => (stats.poll_timeout * 2).min(self.max_poll_timeout),
+            _ => MIN_POLL_TIMEOUT.min(self.max_poll_timeout),
+        };
+
+        let capacity = stats.capacity;
+        if count >= capacity && capacity < MAX_EVENT_CAPACITY {
+            stats.capacity = (capacity * 2).min(MAX_EVENT_CAPACITY);
+        } else if count <= capacity / 4 && capacity > self.min_capacity {
+            self.low_polls += 1;
+            if self.low_polls >= EVENT_SHRINK_POLLS {
+                stats.capacity = (capacity / 2).max(self.min_capacity);
+            }
+        } else {
+            self.low_polls = 0;
+        }
+
+        if stats.capacity == capacity {
+            return false;
+        }
+        self.low_polls = 0;
+        stats.resizes += 1;
+        log::debug!("event capacity changed from {capacity} to {}", stats.capacity);
+        true
+    }
+}
+
+// 本地域名对应的地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HostAddr {
+    addr: IpAddr,         // ipv4或ipv6地址
+    ttl : Option<u32>,    // 该地址的生存时间, 为None时使用服务器缺省值
+}
+
+// 域名表, 屏蔽域名(地址为0.0.0.0)以哈希集合紧凑存储
+#[derive(Default)]
+struct HostTable {
+    hosts  : Hosts,      // 域名字典
+    blocked: BlockSet,   // 屏蔽域名集合
+    records: Records,    // 非A记录(CNAME/TXT)
+}
+
+/// 运行时注册/注销域名及修改可重新加载的设置的命令
+#[derive(Debug, Clone)]
+pub enum HostCommand {
+    Register(HostEntry),   // 添加记录, 同一域名多次添加时累加为记录集
+    Replace(HostEntry),    // 删除该域名原有的全部记录后添加
+    Unregister(String),    // 删除该域名的全部记录
+    SetTtl(u32),           // 修改缺省的生存时间
+    ReloadHosts(Vec<String>), // 用新的本地hosts文件列表重新生成本地域名表, 保留运行时注册的域名
+}
+
+/// 域名注册句柄, 可以克隆并在其它线程中使用, 向运行中的DnsServer注册或注销域名,
+/// 命令通过通道发送并唤醒服务器的事件循环, 由服务器线程依次执行
+#[derive(Clone)]
+pub struct HostHandle {
+    tx   : Sender<HostCommand>,   // 命令发送通道
+    waker: Arc<Waker>,            // 唤醒服务器事件循环
+}
+
+impl HostHandle {
+
+    /// 注册A记录, ip可以是逗号分隔的多个地址, ttl为None时使用服务器缺省的生存时间
+    pub fn register(&self, host: &str, ip: &str, ttl: Option<u32>) -> Result<()> {
+        self.send(HostCommand::Register(host_entry(host, ip, ttl)))
+    }
+
+    /// 用新的地址替换域名原有的全部记录, 适用于租约续期等地址变化的场景
+    pub fn replace(&self, host: &str, ip: &str, ttl: Option<u32>) -> Result<()> {
+        self.send(HostCommand::Replace(host_entry(host, ip, ttl)))
+    }
+
+    /// 注销域名的全部记录
+    pub fn unregister(&self, host: &str) -> Result<()> {
+        self.send(HostCommand::Unregister(host.trim_end_matches('.').to_ascii_lowercase()))
+    }
+
+    /// 修改服务器缺省的生存时间
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.send(HostCommand::SetTtl(ttl))
+    }
+
+    /// 重新加载本地hosts文件(可以是新的文件列表), 运行时注册及动态dns注册的域名保留不变
+    pub fn reload_hosts(&self, paths: Vec<String>) -> Result<()> {
+        self.send(HostCommand::ReloadHosts(paths))
+    }
+
+    /// 发送命令, 服务器已停止时返回错误
+    pub fn send(&self, cmd: HostCommand) -> Result<()> {
+        self.tx.send(cmd).map_err(|_| anyhow::anyhow!("dns server is stopped"))?;
+        self.waker.wake().with_context(|| "wake dns server failed")
+    }
+
+}
+
+// 动态dns的tcp连接
+struct DynDnsConn {
+    stream: TcpStream,    // tcp连接
+    addr  : SocketAddr,   // 客户端地址
+    data  : Vec<u8>,      // 已接收的数据
+    expire: u64,          // 连接过期时间戳
+}
+
+// 管理控制连接
+#[cfg(unix)]
+struct ControlConn {
+    stream: mio::net::UnixStream, // unix socket连接
+    data  : Vec<u8>,      // 已接收的数据
+    reply : Vec<u8>,      // 待发送的回复, 回复可能大于socket缓冲区(如export), 在可写时继续发送
+    expire: u64,          // 连接过期时间戳
+}
 
 pub struct DnsServer {
     socket     : UdpSocket,    // DNS服务socket
-    up_socket  : UdpSocket,    // 上级dns连接地址
+    up_socket  : UdpSocket,    // 已连接到上级dns服务器的socket, 端口不可达等错误可以立即得知
+    ns_socket  : UdpSocket,    // 向上级返回的其它域名服务器迭代查询的socket
+    ns_socket6 : Option<UdpSocket>, // 向ipv6域名服务器迭代查询的socket, 主机不支持ipv6时为None
     poll       : Poll,         // DNS服务事件提取器
     queries    : Queries,      // 所有向上级发送的查询请求但尚未收到回复的连接信息
     curr_req_id: u16,          // 向上级DNS发送查询请求的当前请求id
     up_dns_addr: IpAddr,       // 上级dns服务器地址
     ttl        : u32,          // dns服务器回复的查询结果的生存时间
-    hosts      : HOSTS,        // 本服务器可以解析的域名字典
+    local      : HostTable,    // 本服务器可以解析的本地域名表
+    pool       : RefCell<BufferPool>, // 数据包缓冲池
     key        : String,       // 动态域名更新密钥
+    key_file   : Option<KeyFile>, // 动态域名更新密钥文件, 支持运行时重新加载
+    auth_lock  : AuthLock,     // 动态域名更新认证失败锁定
+    lease_time : u64,          // 动态域名的租约时间(秒), 0表示永不过期
+    leases     : HashMap<String, u64>, // 动态域名的租约过期时间
+    change_hook: Option<ChangeHook>, // 动态域名ip变化时触发的webhook或外部命令
+    domains    : Vec<String>,  // 允许动态注册的域名后缀, 为空表示不限制
+    audit_log  : Option<AuditLog>, // 动态域名更新审计日志
+    dyndns_socket  : Option<UdpSocket>,   // 动态dns独立端口udp服务, 启用后不再处理53端口上的动态dns数据包
+    dyndns_listener: Option<TcpListener>, // 动态dns独立端口tcp服务
+    dyndns_conns   : HashMap<Token, DynDnsConn>, // 动态dns的tcp连接
+    next_conn_token: usize,               // 下一个动态dns tcp连接或管理控制连接的token
+    remote_sources : Vec<RemoteHosts>,    // 远程hosts来源, 启动后台刷新后移交给刷新线程
+    remote_tables  : Vec<HostTable>,      // 每个远程hosts来源最近一次下载得到的域名表
+    remote_refresh : u64,                 // 远程hosts刷新间隔(秒), 0表示不刷新
+    remote_rx      : Option<Receiver<(usize, HostTable)>>, // 接收后台刷新结果的通道
+    block_db       : Option<MappedBlockSet>, // 内存映射的屏蔽域名库, 优先级低于本地及远程hosts
+    hosts_files    : Vec<String>,         // 已加载的本地hosts文件, 导出时保留其中的注释
+    runtime_hosts  : HashSet<String>,     // 运行时注册(含动态dns)的域名, 重新加载hosts文件时保留
+    overridden     : Hosts,               // 被动态dns覆盖的静态域名的原地址, 租约过期时恢复
+    dhcp_leases    : Option<LeaseFile>,   // dhcp租约文件, 变化后重新注册客户端主机名
+    dhcp_domain    : String,              // dhcp客户端主机名附加的域名后缀, 为空表示不附加
+    dhcp_hosts     : HashSet<String>,     // 由dhcp租约注册的域名
+    export_file    : String,              // 域名表导出文件, 为空表示不导出
+    hosts_changed  : bool,                // 本地域名表自上次导出后是否发生变化
+    client_names   : Option<HashMap<IpAddr, String>>, // 本地域名表中地址到域名的反向索引, 域名表变化后清空, 使用时重新生成
+    local_answers  : RefCell<AnswerMap<Arc<[DnsRecord]>>>, // 本地域名的查询结果, 域名表变化后清空, 命中时共享记录集
+    strict_parsing : bool,                // 严格解析收到的数据包, 拒绝不规范的数据包
+    resolvers      : Vec<Box<dyn Resolver>>, // 解析链, 按顺序查询直到某个解析器给出结果
+    query_hooks    : Vec<QueryHook>,      // 收到查询请求时调用的钩子
+    response_hooks : Vec<ResponseHook>,   // 回复客户端前调用的钩子
+    block_hooks    : Vec<BlockHook>,      // 查询屏蔽域名时调用的钩子
+    host_tx        : Sender<HostCommand>, // 运行时域名注册命令的发送通道, 用于创建句柄
+    host_rx        : Receiver<HostCommand>, // 运行时域名注册命令的接收通道
+    waker          : Arc<Waker>,          // 收到域名注册命令时唤醒事件循环
+    metrics        : Box<dyn MetricsSink>, // 运行指标输出
+    #[cfg(unix)]
+    handover       : Option<mio::net::UnixListener>, // 监听socket交接服务, 新进程连接后移交监听socket
+    draining       : bool,                // 监听socket已移交, 等待未完成的查询结束后退出
+    #[cfg(unix)]
+    control        : Option<mio::net::UnixListener>, // 管理控制socket, 每个连接执行一行命令并返回文本结果
+    #[cfg(unix)]
+    control_conns  : HashMap<Token, ControlConn>, // 管理控制连接
+    analytics      : Option<Analytics>,   // 滑动窗口内查询最多的域名及客户端统计
+    poll_timeout   : Duration,            // poll的最长等待时间
+    failure_policy : FailurePolicy,       // 上级dns服务器失败时回复客户端的策略
+    recursion_acl  : Acl,                 // 允许递归查询的客户端网段, 为空表示不限制
+    slow_query     : Duration,            // 慢查询日志阈值, 回复耗时超过该值的查询写入日志, 0表示不记录
+    block_log      : bool,                // 查询屏蔽域名时记录客户端及域名
+    search_domains : Vec<String>,         // 单标签域名查询在转发前尝试补全的搜索域后缀
+    upstreams      : RefCell<HashMap<IpAddr, UpstreamStats>>, // 各上级dns服务器的查询统计
+    servfail_ttl   : Duration,            // 上级失败结果的缓存时间, 期间相同的查询直接回复SERVFAIL, 0表示不缓存
+    servfail_cache : HashMap<(String, QueryType), Instant>, // 上级失败的查询及其缓存过期时刻
+    max_forwards   : u8,                  // 单个查询转发跳转(迭代查询ns)的最大次数
+    max_cnames     : u8,                  // 单个查询别名记录的最大跟随次数
+    query_budget   : u16,                 // 单个查询的工作量上限, 转发跳转次数与别名跟随次数之和
+    groups         : Vec<ClientGroup>,    // 客户端分组, 按顺序匹配第一个分组
+    client_macs    : HashMap<IpAddr, MacAddr>, // 从arp表读取的客户端mac地址, 用于按mac地址分组
+    mdns_bridge    : Option<MdnsBridge>,  // mdns到单播dns的桥接
+    mdns_queries   : PendingQueries<Query>, // 以mdns查询id为键的等待应答的查询及其超时时刻
+    name_fallback  : Option<NameFallback>, // 单标签域名的llmnr/netbios名称查询
+    fallback_queries: PendingQueries<Query>, // 以llmnr/netbios查询id为键的等待应答的查询及其超时时刻
+    chaos_version  : String,              // CHAOS类别version.bind查询回复的版本信息, 为空表示拒绝
+    chaos_hostname : String,              // CHAOS类别hostname.bind/id.server查询回复的主机名, 为空表示拒绝
+    adaptive_events: bool,                // 根据负载自动调整事件容量及poll超时时间
+    event_tuner    : EventTuner,          // 事件循环参数调整器, 运行时由run创建
 }
 
 impl DnsServer {
@@ -60,112 +486,1096 @@ impl DnsServer {
                 || format!("dns server listen address {listen_addr} format error"))?;
         let socket = UdpSocket::bind(s_addr).with_context(
                 || format!("bind dns server socket {listen_addr} failed"))?;
-        let up_socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))
-                .with_context(|| "bind dns parent server socket 0.0.0.0:0 failed")?;
+        let up_dns_addr: IpAddr = up_dns_addr.parse().with_context(
+                || format!("parent dns server address {up_dns_addr} format error"))?;
+        let up_socket = connect_upstream(up_dns_addr)?;
+        let ns_socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))
+                .with_context(|| "bind dns name server socket 0.0.0.0:0 failed")?;
+        let ns_socket6 = match UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log::debug!("bind dns name server socket [::]:0 failed, ipv6 name servers are unavailable: {e}");
+                None
+            },
+        };
+
+        let resolvers = resolver_chain("local,forward", up_dns_addr)?;
+
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), HOST_CMD_TOKEN)
+                .with_context(|| "create dns server waker failed")?);
+        let (host_tx, host_rx) = channel();
 
-        log::info!("dns server startup {listen_addr}, parent dns server {up_dns_addr}");
         Ok(DnsServer {
             socket,
             up_socket,
-            poll: Poll::new()?,
+            ns_socket,
+            ns_socket6,
+            poll,
             queries: Queries::new(),
             curr_req_id: 0,
-            up_dns_addr: up_dns_addr.parse()?,
+            up_dns_addr,
             ttl,
-            hosts: HOSTS::new(),
+            local: HostTable::default(),
+            pool: RefCell::new(BufferPool::new(POOL_MAX_IDLE, DEFAULT_BUFFER_CAP)),
             key: key.to_string(),
+            key_file: None,
+            auth_lock: AuthLock::new(),
+            lease_time: 0,
+            leases: HashMap::new(),
+            change_hook: None,
+            domains: Vec::new(),
+            audit_log: None,
+            dyndns_socket: None,
+            dyndns_listener: None,
+            dyndns_conns: HashMap::new(),
+            next_conn_token: DYNDNS_CONN_TOKEN,
+            remote_sources: Vec::new(),
+            remote_tables: Vec::new(),
+            remote_refresh: 0,
+            remote_rx: None,
+            block_db: None,
+            hosts_files: Vec::new(),
+            runtime_hosts: HashSet::new(),
+            overridden: HashMap::new(),
+            dhcp_leases: None,
+            dhcp_domain: String::new(),
+            dhcp_hosts: HashSet::new(),
+            export_file: String::new(),
+            hosts_changed: true,
+            client_names: None,
+            local_answers: RefCell::default(),
+            strict_parsing: false,
+            resolvers,
+            query_hooks: Vec::new(),
+            response_hooks: Vec::new(),
+            block_hooks: Vec::new(),
+            host_tx,
+            host_rx,
+            waker,
+            metrics: Box::new(NoopMetrics),
+            #[cfg(unix)]
+            handover: None,
+            draining: false,
+            #[cfg(unix)]
+            control: None,
+            #[cfg(unix)]
+            control_conns: HashMap::new(),
+            analytics: None,
+            poll_timeout: Duration::from_secs(MAINTAIN_INTERVAL),
+            failure_policy: FailurePolicy::default(),
+            recursion_acl: Acl::default(),
+            slow_query: Duration::ZERO,
+            block_log: false,
+            search_domains: Vec::new(),
+            upstreams: RefCell::new(HashMap::new()),
+            servfail_ttl: Duration::from_secs(5),
+            servfail_cache: HashMap::new(),
+            max_forwards: MAX_FORWARD_COUNT,
+            max_cnames: MAX_CNAME_CHAIN,
+            query_budget: QUERY_BUDGET,
+            groups: Vec::new(),
+            client_macs: HashMap::new(),
+            mdns_bridge: None,
+            mdns_queries: PendingQueries::default(),
+            name_fallback: None,
+            fallback_queries: PendingQueries::default(),
+            chaos_version: String::new(),
+            chaos_hostname: String::new(),
+            adaptive_events: false,
+            event_tuner: EventTuner::new(0, Duration::from_secs(MAINTAIN_INTERVAL), false),
         })
     }
 
-    pub fn register_host(&mut self, host: &str, ip: &str) -> Result<()> {
-        log::debug!("register local host: {} {}", host, ip);
-        self.hosts.insert(host.to_string(), ip.parse().with_context(
-                || format!("ip {ip} isn't ipv4 address"))?);
+    /// 替换dns服务socket, 用于接管正在运行的实例或systemd传递的已绑定socket
+    pub fn set_listen_socket(&mut self, socket: std::net::UdpSocket) -> Result<()> {
+        socket.set_nonblocking(true)?;
+        self.socket = UdpSocket::from_std(socket);
+        Ok(())
+    }
+
+    /// 注册本地域名记录, A记录的ip可以是逗号分隔的多个地址, 同一域名多次注册时累加为记录集,
+    /// ttl为None时使用服务器缺省的生存时间
+    pub fn register_host(&mut self, entry: &HostEntry) -> Result<()> {
+        log::debug!("register local host: {} {:?} {:?}", entry.host, entry.record, entry.ttl);
+        self.client_names = None;
+        self.clear_local_answers();
+        self.local.add(entry)
+    }
+
+    /// 加载本地hosts文件, 注册其中的所有域名
+    pub fn load_hosts_file(&mut self, path: &str) -> Result<()> {
+        for entry in HostsConfig::new(path)? {
+            self.register_host(&entry?)?;
+        }
+        self.hosts_files.push(path.to_string());
+        Ok(())
+    }
+
+    /// 设置dhcp租约文件(dnsmasq或ISC dhcpd格式), 立即注册其中的客户端主机名, 之后定期检查文件变化,
+    /// 主机名附加`domain`后缀(为空表示不附加), 域名的生存时间不超过租约的剩余时间, 租约到期后自动删除
+    pub fn set_dhcp_leases(&mut self, path: &str, domain: &str) -> Result<()> {
+        let mut lease_file = LeaseFile::new(path);
+        let leases = lease_file.read_if_changed()?.unwrap_or_default();
+        self.dhcp_domain = domain.trim_matches('.').to_ascii_lowercase();
+        self.apply_dhcp_leases(leases, now_of_unix());
+        log::info!("dhcp lease file {path} loaded, {} hosts", self.dhcp_hosts.len());
+        self.dhcp_leases = Some(lease_file);
+        Ok(())
+    }
+
+    /// 添加远程hosts来源(http://), 立即下载一次, 之后按刷新间隔在后台重新下载,
+    /// 首次下载失败时返回错误, 但该来源仍会保留, 在之后的刷新中重试
+    pub fn add_remote_hosts(&mut self, url: &str) -> Result<()> {
+        let mut source = RemoteHosts::new(url);
+        let fetched = source.fetch();
+        self.remote_sources.push(source);
+        let table = match fetched {
+            Ok(config) => config.map(HostTable::load).unwrap_or_default(),
+            Err(e) => {
+                self.remote_tables.push(HostTable::default());
+                return Err(e);
+            },
+        };
+        log::info!("remote hosts {url} loaded, {} hosts, {} blocked", table.hosts.len(), table.blocked.len());
+        self.remote_tables.push(table);
+        self.clear_local_answers();
+        Ok(())
+    }
+
+    /// 打开内存映射的屏蔽域名库(由`mdns blockdb`生成), 库中的域名解析为0.0.0.0
+    pub fn set_block_db(&mut self, path: &str) -> Result<()> {
+        let db = MappedBlockSet::open(path)?;
+        log::info!("block db {path} opened, {} blocked", db.len());
+        self.block_db = Some(db);
+        self.clear_local_answers();
+        Ok(())
+    }
+
+    /// 设置远程hosts的刷新间隔(秒), 0表示不刷新
+    pub fn set_remote_refresh(&mut self, interval: u64) {
+        self.remote_refresh = interval;
+    }
+
+    /// 设置解析链, 缺省为先查本地域名表再转发给上级dns服务器
+    pub fn set_resolvers(&mut self, resolvers: Vec<Box<dyn Resolver>>) {
+        self.resolvers = resolvers;
+    }
+
+    /// 按逗号分隔的名称(local/cache/forward)设置解析链
+    pub fn set_resolver_chain(&mut self, names: &str) -> Result<()> {
+        self.resolvers = resolver_chain(names, self.up_dns_addr)?;
+        Ok(())
+    }
+
+    /// 加载规则脚本, 脚本在解析链中缓存及转发之前执行
+    pub fn set_script_file(&mut self, path: &str) -> Result<()> {
+        let script = ScriptResolver::load(path, self.ttl)?;
+        log::info!("script {path} loaded, {} rules", script.len());
+        let pos = self.resolvers.iter()
+            .position(|r| r.name() == "cache" || r.name() == "forward")
+            .unwrap_or(self.resolvers.len());
+        self.resolvers.insert(pos, Box::new(script));
+        Ok(())
+    }
+
+    /// 获取域名注册句柄, 用于在其它线程中向运行中的服务器注册或注销域名
+    pub fn host_handle(&self) -> HostHandle {
+        HostHandle { tx: self.host_tx.clone(), waker: self.waker.clone() }
+    }
+
+    /// 执行所有已收到的域名注册命令
+    fn apply_host_commands(&mut self) {
+        while let Ok(cmd) = self.host_rx.try_recv() {
+            log::debug!("host command: {:?}", cmd);
+            let result = match cmd {
+                HostCommand::Register(entry) => {
+                    self.runtime_hosts.insert(entry.host.clone());
+                    self.local.add(&entry)
+                },
+                HostCommand::Replace(entry) => {
+                    self.runtime_hosts.insert(entry.host.clone());
+                    self.local.remove(&entry.host);
+                    self.local.add(&entry)
+                },
+                HostCommand::Unregister(host) => {
+                    self.local.remove(&host);
+                    self.leases.remove(&host);
+                    self.overridden.remove(&host);
+                    self.runtime_hosts.remove(&host);
+                    Ok(())
+                },
+                HostCommand::SetTtl(ttl) => {
+                    log::info!("default ttl changed from {} to {}", self.ttl, ttl);
+                    self.ttl = ttl;
+                    self.clear_local_answers();
+                    continue;
+                },
+                HostCommand::ReloadHosts(paths) => self.reload_hosts_files(paths),
+            };
+            match result {
+                Ok(()) => self.local_changed(),
+                Err(e) => log::error!("host command failed: {e:?}"),
+            }
+        }
+    }
+
+    /// 设置运行指标输出, 缺省不输出
+    pub fn set_metrics_sink(&mut self, metrics: Box<dyn MetricsSink>) {
+        self.metrics = metrics;
+    }
+
+    /// 注册查询钩子, 按注册顺序在解析链之前调用, 第一个不返回Continue的钩子决定回复内容
+    pub fn on_query<F>(&mut self, hook: F)
+            where F: Fn(&SocketAddr, &DnsQuestion) -> HookAction + 'static {
+        self.query_hooks.push(Box::new(hook));
+    }
+
+    /// 注册应答钩子, 按注册顺序在回复客户端前调用, 可以观察或修改回复码及应答记录
+    pub fn on_response<F>(&mut self, hook: F)
+            where F: Fn(&SocketAddr, &DnsQuestion, &mut ResultCode, &mut Vec<DnsRecord>) + 'static {
+        self.response_hooks.push(Box::new(hook));
+    }
+
+    /// 注册屏蔽钩子, 查询的域名被屏蔽时调用
+    pub fn on_block<F>(&mut self, hook: F) where F: Fn(&SocketAddr, &DnsQuestion) + 'static {
+        self.block_hooks.push(Box::new(hook));
+    }
+
+    /// 设置严格解析模式, 启用后拒绝超长域名、保留的标签类型、非向前的压缩指针及记录长度不一致的数据包
+    pub fn set_strict_parsing(&mut self, strict: bool) {
+        self.strict_parsing = strict;
+    }
+
+    /// 更新本地域名, 用新的ip替换该域名原有的同类(ipv4/ipv6)地址, 另一类地址保留,
+    /// 以便双栈主机分别更新A及AAAA记录, 覆盖静态域名时保存其原地址, 租约过期后恢复
+    fn update_host(&mut self, host: &str, ip: &str) -> Result<()> {
+        log::debug!("update local host: {} {}", host, ip);
+        let addrs = parse_ips(ip, None)?;
+        if !self.runtime_hosts.contains(host) {
+            if let Some(static_addrs) = self.local.hosts.get(host) {
+                self.overridden.insert(host.to_string(), static_addrs.clone());
+            }
+        }
+        let entry = self.local.hosts.entry(host.to_string()).or_default();
+        entry.retain(|a| !addrs.iter().any(|n| n.addr.is_ipv4() == a.addr.is_ipv4()));
+        entry.extend(addrs);
+        self.runtime_hosts.insert(host.to_string());
+        self.local_changed();
+        Ok(())
+    }
+
+    /// 本地域名表发生变化, 需要重新导出及重新生成反向索引
+    fn local_changed(&mut self) {
+        self.hosts_changed = true;
+        self.client_names = None;
+        self.clear_local_answers();
+    }
+
+    /// 清空本地域名的查询结果, 域名表、缺省ttl或别名跟随次数变化后调用
+    fn clear_local_answers(&mut self) {
+        self.local_answers.get_mut().clear();
+    }
+
+    /// 重新加载本地hosts文件, 任意文件加载失败时保留原来的域名表,
+    /// 文件中定义的域名优先于运行时注册的同名域名, 后者被丢弃
+    fn reload_hosts_files(&mut self, paths: Vec<String>) -> Result<()> {
+        let mut table = HostTable::default();
+        for path in paths.iter() {
+            for entry in HostsConfig::new(path)? {
+                table.add(&entry?)?;
+            }
+        }
+        // 运行时注册的域名不在hosts文件中, 从原域名表中移入
+        let runtime_hosts: Vec<String> = self.runtime_hosts.iter().cloned().collect();
+        for host in runtime_hosts.iter() {
+            // 被覆盖的静态域名以新加载的hosts文件为准
+            self.overridden.remove(host);
+            if table.hosts.contains_key(host) || table.records.contains_key(host) || table.blocked.contains(host) {
+                log::info!("host {host} is defined in hosts files, runtime registration of it is discarded");
+                self.runtime_hosts.remove(host);
+                self.leases.remove(host);
+                self.dhcp_hosts.remove(host);
+                continue;
+            }
+            if let Some(addrs) = self.local.hosts.remove(host) {
+                table.hosts.insert(host.clone(), addrs);
+            }
+            if let Some(records) = self.local.records.remove(host) {
+                table.records.insert(host.clone(), records);
+            }
+            if self.local.blocked.contains(host) {
+                table.blocked.insert(host);
+            }
+        }
+        log::info!("hosts files reloaded, {} hosts, {} blocked", table.hosts.len(), table.blocked.len());
+        self.local = table;
+        self.client_names = None;
+        self.clear_local_answers();
+        self.hosts_files = paths;
+        Ok(())
+    }
+
+    /// 数据包缓冲池的统计信息, 用于调整缓冲池参数
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.borrow().stats()
+    }
+
+    /// 设置poll的最长等待时间(最长不超过定期维护的间隔)及是否根据负载自动调整事件容量及poll超时时间,
+    /// 自适应模式下run的`event_capacity`作为初始容量及下限, 容量最大增长到8192
+    pub fn set_event_tuning(&mut self, poll_timeout: Duration, adaptive: bool) {
+        self.poll_timeout = poll_timeout.clamp(Duration::from_millis(1), Duration::from_secs(MAINTAIN_INTERVAL));
+        self.adaptive_events = adaptive;
+    }
+
+    /// 设置上级dns服务器失败(端口不可达或超时未回复)时回复客户端的策略,
+    /// 使用stale策略时解析链需要包含cache
+    pub fn set_failure_policy(&mut self, policy: FailurePolicy) {
+        self.failure_policy = policy;
+    }
+
+    /// 设置允许递归查询的客户端网段(逗号分隔, 如192.168.0.0/16,fd00::/8), 为空表示不限制,
+    /// 其它客户端仍可查询本地域名, 需要转发或缓存的查询回复REFUSED
+    pub fn set_recursion_acl(&mut self, acl: &str) -> Result<()> {
+        self.recursion_acl = Acl::parse(acl).with_context(|| format!("recursion acl {acl} format error"))?;
+        Ok(())
+    }
+
+    /// 设置慢查询日志阈值, 从收到查询到回复的耗时超过该值时以日志目标minidns::slowquery记录查询、客户端、
+    /// 回复码及使用的上级dns, 0表示不记录
+    pub fn set_slow_query(&mut self, threshold: Duration) {
+        self.slow_query = threshold;
+    }
+
+    /// 设置搜索域后缀(逗号分隔, 如home.lan), 单标签域名(如nas)本地无法解析时, 在转发前依次补全后缀
+    /// 在本地域名表中查找, 找到时回复指向补全域名的别名记录及其记录, 用于忽略dhcp搜索域的设备
+    pub fn set_search_domains(&mut self, domains: &str) {
+        self.search_domains = parse_suffixes(domains);
+    }
+
+    /// 设置上级失败(回复SERVFAIL或超时未回复)结果的缓存时间, 期间相同的查询直接回复SERVFAIL,
+    /// 避免客户端反复查询故障域名时放大为对上级的查询风暴, 缺省为5秒, 0表示不缓存
+    pub fn set_servfail_ttl(&mut self, ttl: Duration) {
+        self.servfail_ttl = ttl;
+        if ttl.is_zero() {
+            self.servfail_cache.clear();
+        }
+    }
+
+    /// 设置单个查询的工作量限制: 转发跳转(迭代查询ns)的最大次数、别名记录的最大跟随次数,
+    /// 及两者之和的上限, 超出时回复SERVFAIL并记录日志
+    pub fn set_query_limits(&mut self, max_forwards: u8, max_cnames: u8, budget: u16) {
+        self.max_forwards = max_forwards;
+        self.max_cnames = max_cnames;
+        self.query_budget = budget;
+        self.clear_local_answers();
+    }
+
+    /// 加载客户端分组配置文件, 每组可以有独立的hosts(屏蔽列表)、规则脚本及上级dns, 格式见[`groups`](super::groups)
+    pub fn load_client_groups(&mut self, path: &str) -> Result<()> {
+        let mut groups = Vec::new();
+        for config in load_groups(path)? {
+            let name = config.name.clone();
+            let group = ClientGroup::load(config, self.ttl).with_context(|| format!("load client group [{name}] failed"))?;
+            log::info!("client group [{name}] loaded, {} hosts, {} blocked, {} rules", group.table.hosts.len(),
+                    group.table.blocked.len(), group.script.as_ref().map_or(0, |s| s.len()));
+            groups.push(group);
+        }
+        self.groups = groups;
+        self.refresh_client_macs();
+        Ok(())
+    }
+
+    /// 客户端所属的分组名称
+    pub fn client_group_name(&self, client: &IpAddr) -> Option<&str> {
+        self.client_group(client).map(|i| self.groups[i].config.name.as_str())
+    }
+
+    /// 设置mdns桥接后缀(如mdns.lan), 本地无法解析的`主机名.后缀`查询转为局域网内`主机名.local`的mdns查询,
+    /// 为空表示不桥接
+    pub fn set_mdns_bridge(&mut self, suffix: &str) -> Result<()> {
+        self.mdns_bridge = match suffix.trim().is_empty() {
+            true => None,
+            false => Some(MdnsBridge::new(suffix)?),
+        };
+        if let Some(ref bridge) = self.mdns_bridge {
+            log::info!("mdns bridge enabled, resolve *.{} via mdns *.local", bridge.suffix());
+        }
+        Ok(())
+    }
+
+    /// 设置单标签域名的局域网名称查询协议(逗号分隔的llmnr/netbios), 本地无法解析的单标签域名
+    /// 不再转发给上级dns服务器, 改为在局域网内查询, 为空表示不查询
+    pub fn set_name_fallback(&mut self, protocols: &str) -> Result<()> {
+        self.name_fallback = match protocols.trim().is_empty() {
+            true => None,
+            false => Some(NameFallback::new(protocols)?),
+        };
+        if let Some(ref fallback) = self.name_fallback {
+            log::info!("name fallback enabled, resolve single-label names via {}", fallback.protocols());
+        }
+        Ok(())
+    }
+
+    /// 设置CHAOS类别TXT查询(version.bind/version.server、hostname.bind/id.server)回复的版本信息及主机名,
+    /// 供监控系统盘点服务器, 为空时回复REFUSED
+    pub fn set_chaos(&mut self, version: &str, hostname: &str) {
+        self.chaos_version = version.to_string();
+        self.chaos_hostname = hostname.to_string();
+    }
+
+    /// 设置是否记录屏蔽日志, 查询命中屏蔽列表时记录客户端地址(及其在本地域名表中的名称)、域名及类型,
+    /// 日志目标为`minidns::blocked`
+    pub fn set_block_log(&mut self, enabled: bool) {
+        self.block_log = enabled;
+    }
+
+    /// 设置查询统计的滑动窗口, 统计窗口内查询最多的域名及客户端, 0表示不统计
+    pub fn set_analytics_window(&mut self, window: Duration) {
+        self.analytics = match window.is_zero() {
+            true => None,
+            false => Some(Analytics::new(window)),
+        };
+    }
+
+    /// 查询统计报告, 包含窗口内查询最多的`n`个域名及客户端, 未启用统计时返回None
+    pub fn analytics_report(&self, n: usize) -> Option<String> {
+        self.analytics.as_ref().map(|analytics| analytics.report(n, now_of_unix()))
+    }
+
+    /// 各上级dns服务器的查询统计, 按发送的查询数量从多到少排列
+    pub fn upstream_stats(&self) -> Vec<(IpAddr, UpstreamStats)> {
+        let mut stats: Vec<_> = self.upstreams.borrow().iter().map(|(addr, s)| (*addr, *s)).collect();
+        stats.sort_unstable_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(&b.0)));
+        stats
+    }
+
+    /// 文本格式的上级dns服务器统计报告
+    pub fn upstream_report(&self) -> String {
+        let mut out = String::from("upstream                       queries  errors  timeouts  error%  avg(ms)  max(ms)\n");
+        for (addr, s) in self.upstream_stats() {
+            out.push_str(&format!("{:<28} {:>9} {:>7} {:>9} {:>7.1} {:>8.1} {:>8.1}\n", addr.to_string(),
+                    s.queries, s.errors, s.timeouts, s.error_rate() * 100.0,
+                    s.avg_latency().as_secs_f64() * 1000.0, s.latency_max.as_secs_f64() * 1000.0));
+        }
+        out
+    }
+
+    /// 设置管理控制socket(unix socket)路径, 支持的命令: `top [N]`(查询统计报告), `stats`(运行状态),
+    /// `upstreams`(各上级dns服务器的查询数、错误率及回复耗时), `log-level [LEVEL]`(查看或修改日志级别)
+    pub fn set_control_socket(&mut self, path: &str) -> Result<()> {
+        #[cfg(unix)]
+        {
+            super::handover::remove_stale_socket(path)?;
+            self.control = Some(mio::net::UnixListener::bind(path).with_context(
+                    || format!("bind control socket {path} failed"))?);
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("control socket {path} is only supported on unix")
+    }
+
+    /// 执行一行管理命令, 返回文本结果
+    pub fn control_command(&self, line: &str) -> String {
+        let mut args = line.split_whitespace();
+        match args.next() {
+            Some("top") => {
+                let n = args.next().and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_TOP_COUNT);
+                self.analytics_report(n).unwrap_or_else(|| String::from("error: analytics disabled, set top-window to enable\n"))
+            },
+            Some("stats") => format!("pending queries: {}\nlocal hosts: {}\nbuffer pool: {:?}\nevent loop: {:?}\n",
+                    self.queries.len(), self.local.hosts.len(), self.pool_stats(), self.event_stats()),
+            Some("upstreams") => self.upstream_report(),
+            // 含运行期间注册的动态域名, 不需要重新下载远程hosts
+            Some("export") => self.export_hosts(),
+            // 不带参数时输出当前级别, 参数格式与log-level配置项相同, 如debug或info,minidns::dnsserver=trace
+            Some("log-level") => match args.next() {
+                None => format!("log level: {}\n", log::max_level()),
+                Some(directives) => match asynclog::parse_filter(directives) {
+                    Ok(filter) => {
+                        asynclog::set_filter(filter);
+                        log::warn!("log level changed to {directives} by control command");
+                        format!("log level changed to {directives}\n")
+                    },
+                    Err(e) => format!("error: {e}\n"),
+                },
+            },
+            Some(cmd) => format!("error: unknown command {cmd}, supported: top [N], stats, upstreams, export, log-level [LEVEL]\n"),
+            None => String::from("error: empty command\n"),
+        }
+    }
+
+    /// 事件循环的运行参数及统计信息
+    pub fn event_stats(&self) -> EventStats {
+        self.event_tuner.stats
+    }
+
+    /// 设置域名表导出文件, 启动时及本地域名表(含动态域名)发生变化后自动写入, 用于备份及查看
+    pub fn set_export_file(&mut self, path: &str) {
+        self.export_file = path.to_string();
+    }
+
+    /// 以hosts文件格式导出当前生效的本地域名表(静态域名及动态域名, 不含远程hosts)
+    ///
+    /// 按原hosts文件的顺序输出并保留其中的注释, 域名的地址替换为当前值,
+    /// 已删除的域名不再输出, 原文件中不存在的域名(如动态注册的域名)追加在末尾
+    pub fn export_hosts(&self) -> String {
+        let mut out = String::new();
+        let mut written = HashSet::new();
+        for path in &self.hosts_files {
+            match std::fs::read(path) {
+                Ok(data) => {
+                    out.push_str(&format!("# ---- {path} ----\n"));
+                    export_lines(&mut out, &String::from_utf8_lossy(&data), &self.local.hosts, &self.local.blocked, &mut written);
+                },
+                Err(e) => log::warn!("read hosts file {path} for export failed: {e}"),
+            }
+        }
+        export_rest(&mut out, &self.local.hosts, &written);
+        out
+    }
+
+    /// 本地域名表发生变化时写入导出文件
+    fn export_if_changed(&mut self) {
+        if self.export_file.is_empty() || !self.hosts_changed {
+            return;
+        }
+        self.hosts_changed = false;
+
+        // 先写入临时文件再改名, 避免导出文件处于写入一半的状态
+        let tmp = format!("{}.tmp", self.export_file);
+        let r = std::fs::write(&tmp, self.export_hosts())
+            .and_then(|_| std::fs::rename(&tmp, &self.export_file));
+        match r {
+            Ok(()) => log::debug!("export hosts to {}", self.export_file),
+            Err(e) => log::error!("export hosts to {} failed: {e}", self.export_file),
+        }
+    }
+
+    /// 设置动态域名更新密钥文件, 文件内容变化后会在运行时自动重新加载
+    pub fn set_key_file(&mut self, path: &str) -> Result<()> {
+        let key_file = KeyFile::new(path)?;
+        log::info!("dyndns key file {path} loaded, {} keys", key_file.keys().len());
+        self.key_file = Some(key_file);
+        Ok(())
+    }
+
+    /// 设置动态域名的租约时间(秒), 超过租约时间未刷新的动态域名将被自动删除, 0表示永不过期
+    pub fn set_lease_time(&mut self, lease_time: u64) {
+        self.lease_time = lease_time;
+    }
+
+    /// 设置动态域名ip变化时触发的钩子, 可以是webhook地址(http://)或外部命令
+    pub fn set_change_hook(&mut self, hook: &str) {
+        self.change_hook = (!hook.is_empty()).then(|| ChangeHook::new(hook));
+    }
+
+    /// 设置允许动态注册的域名后缀(逗号分隔), 防止动态客户端覆盖任意公共域名
+    pub fn set_dyndns_domains(&mut self, domains: &str) {
+        self.domains = parse_suffixes(domains);
+    }
+
+    /// 设置动态域名更新审计日志文件, 每次更新请求(无论接受或拒绝)都会记录一行json
+    pub fn set_audit_file(&mut self, path: &str) -> Result<()> {
+        self.audit_log = Some(AuditLog::new(path)?);
+        Ok(())
+    }
+
+    /// 设置动态dns更新协议的独立监听地址(同时监听udp及tcp),
+    /// 启用后dns服务端口不再识别动态dns数据包
+    pub fn set_dyndns_listen(&mut self, listen_addr: &str) -> Result<()> {
+        let addr: SocketAddr = listen_addr.parse().with_context(
+                || format!("dyndns listen address {listen_addr} format error"))?;
+        let socket = std::net::UdpSocket::bind(addr).with_context(
+                || format!("bind dyndns udp socket {listen_addr} failed"))?;
+        let listener = std::net::TcpListener::bind(addr).with_context(
+                || format!("bind dyndns tcp socket {listen_addr} failed"))?;
+        self.set_dyndns_sockets(socket, listener)
+    }
+
+    /// 设置已绑定的动态dns独立端口socket, 用于接管正在运行的实例的socket
+    pub fn set_dyndns_sockets(&mut self, socket: std::net::UdpSocket, listener: std::net::TcpListener) -> Result<()> {
+        socket.set_nonblocking(true)?;
+        listener.set_nonblocking(true)?;
+        log::info!("dyndns server startup {}", socket.local_addr()?);
+        self.dyndns_socket = Some(UdpSocket::from_std(socket));
+        self.dyndns_listener = Some(TcpListener::from_std(listener));
+        Ok(())
+    }
+
+    /// 设置监听socket交接的unix socket路径, 新启动的实例连接该路径接管监听socket,
+    /// 本实例停止接收查询, 等待已转发的查询完成后run返回, 实现零停机升级
+    pub fn set_handover(&mut self, path: &str) -> Result<()> {
+        #[cfg(unix)]
+        {
+            // 接管后旧实例不会删除路径, 由新实例删除后重新监听
+            super::handover::remove_stale_socket(path)?;
+            self.handover = Some(mio::net::UnixListener::bind(path).with_context(
+                    || format!("bind handover socket {path} failed"))?);
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("socket handover {path} is only supported on unix")
+    }
+
+    pub fn run(&mut self, event_capacity: usize) -> Result<()> {
+        let mut req_buffer = self.pool.borrow_mut().get();
+        req_buffer.set_strict(self.strict_parsing);
+        self.event_tuner = EventTuner::new(event_capacity.max(1), self.poll_timeout, self.adaptive_events);
+        let mut events = Events::with_capacity(self.event_tuner.stats.capacity);
+        let mut next_maintain_time = now_of_unix() + MAINTAIN_INTERVAL;
+
+        self.poll.registry().register(&mut self.socket, SERVER_TOKEN, Interest::READABLE)
+                .with_context(|| format!("register socket event {} fail", SERVER_TOKEN.0))?;
+        self.poll.registry().register(&mut self.up_socket, UP_SERVER_TOKEN, Interest::READABLE)
+                .with_context(|| format!("register socket event {} fail", UP_SERVER_TOKEN.0))?;
+        self.poll.registry().register(&mut self.ns_socket, NS_SERVER_TOKEN, Interest::READABLE)
+                .with_context(|| format!("register socket event {} fail", NS_SERVER_TOKEN.0))?;
+        if let Some(ref mut socket) = self.ns_socket6 {
+            self.poll.registry().register(socket, NS6_SERVER_TOKEN, Interest::READABLE)
+                    .with_context(|| format!("register socket event {} fail", NS6_SERVER_TOKEN.0))?;
+        }
+        if let Some(ref mut socket) = self.dyndns_socket {
+            self.poll.registry().register(socket, DYNDNS_TOKEN, Interest::READABLE)
+                    .with_context(|| format!("register socket event {} fail", DYNDNS_TOKEN.0))?;
+        }
+        if let Some(ref mut listener) = self.dyndns_listener {
+            self.poll.registry().register(listener, DYNDNS_TCP_TOKEN, Interest::READABLE)
+                    .with_context(|| format!("register socket event {} fail", DYNDNS_TCP_TOKEN.0))?;
+        }
+        #[cfg(unix)]
+        if let Some(ref mut listener) = self.handover {
+            self.poll.registry().register(listener, HANDOVER_TOKEN, Interest::READABLE)
+                    .with_context(|| format!("register socket event {} fail", HANDOVER_TOKEN.0))?;
+        }
+        #[cfg(unix)]
+        if let Some(ref mut listener) = self.control {
+            self.poll.registry().register(listener, CONTROL_TOKEN, Interest::READABLE)
+                    .with_context(|| format!("register socket event {} fail", CONTROL_TOKEN.0))?;
+        }
+        if let Some(ref mut bridge) = self.mdns_bridge {
+            self.poll.registry().register(bridge.socket_mut(), MDNS_TOKEN, Interest::READABLE)
+                    .with_context(|| format!("register socket event {} fail", MDNS_TOKEN.0))?;
+        }
+        if let Some(ref mut fallback) = self.name_fallback {
+            fallback.register(self.poll.registry(), FALLBACK_TOKEN)?;
+        }
+
+        self.start_remote_refresh();
+        self.export_if_changed();
+        log::info!("dns server startup {}, parent dns server {}", self.socket.local_addr()?, self.up_dns_addr);
+
+        loop {
+            // 在最早的查询超时时刻唤醒, 最长不超过当前的poll超时时间
+            let timeout = self.event_tuner.stats.poll_timeout;
+            let timeout = self.queries.next_deadline().into_iter()
+                .chain(self.mdns_queries.deadlines())
+                .chain(self.fallback_queries.deadlines())
+                .min()
+                .map_or(timeout, |deadline| deadline.saturating_duration_since(Instant::now()).min(timeout));
+            self.poll.poll(&mut events, Some(timeout))
+                    .with_context(|| "socket event poll faild")?;
+
+            for event in events.iter() {
+                match event.token() {
+                    SERVER_TOKEN => self.server_recv(&mut req_buffer)?,
+                    UP_SERVER_TOKEN | NS_SERVER_TOKEN | NS6_SERVER_TOKEN => self.client_recv(&mut req_buffer, event.token())?,
+                    DYNDNS_TOKEN => self.dyndns_recv(&mut req_buffer)?,
+                    DYNDNS_TCP_TOKEN => self.dyndns_accept()?,
+                    HOST_CMD_TOKEN => self.apply_host_commands(),
+                    #[cfg(unix)]
+                    HANDOVER_TOKEN => self.handover_accept(),
+                    #[cfg(unix)]
+                    CONTROL_TOKEN => self.control_accept(),
+                    MDNS_TOKEN => self.mdns_recv(&mut req_buffer),
+                    FALLBACK_TOKEN => self.fallback_recv(&mut req_buffer),
+                    token => self.conn_recv(token),
+                }
+            }
+
+            if self.event_tuner.observe(events.iter().count()) {
+                events = Events::with_capacity(self.event_tuner.stats.capacity);
+            }
+            self.clear_queries_of_timeout();
+            self.clear_lan_queries_of_timeout();
+
+            // 监听socket已移交给新实例, 转发中的查询及动态dns连接全部结束后退出
+            if self.draining && self.queries.len() == 0 && self.mdns_queries.is_empty()
+                    && self.fallback_queries.is_empty() && self.dyndns_conns.is_empty() {
+                self.export_if_changed();
+                log::info!("all pending queries finished after handover, dns server exit");
+                return Ok(());
+            }
+
+            // 定期维护
+            let now = now_of_unix();
+            if next_maintain_time < now {
+                self.reload_key_file();
+                self.refresh_dhcp_leases(now);
+                self.auth_lock.clear_expired(now);
+                self.clear_leases_of_expired(now);
+                self.clear_conns_of_timeout(now);
+                self.clear_servfail_of_expired();
+                self.refresh_client_macs();
+                self.update_remote_hosts();
+                self.export_if_changed();
+                log::debug!("buffer pool stats: {:?}", self.pool_stats());
+                log::debug!("event loop stats: {:?}", self.event_stats());
+                self.report_gauges();
+                next_maintain_time = now + MAINTAIN_INTERVAL;
+            }
+        }
+    }
+
+    fn server_recv(&mut self, req_buffer: &mut BytePacketBuffer) -> Result<()> {
+        loop {
+            let (packet_size, source_address) = match self.socket.recv_from(req_buffer.recv_buf()) {
+                Ok((packet_size, source_address)) => (packet_size, source_address),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => anyhow::bail!(anyhow::Error::new(e).context("server recv data failed")),
+            };
+            req_buffer.set_len(packet_size);
+
+            // 未启用独立端口时, 在dns服务端口上处理动态dns更新
+            let data = req_buffer.data();
+            if self.dyndns_socket.is_none() && is_dyn_dns(data) {
+                if let Some(rep) = self.dyn_dns(data, &source_address) {
+                    if let Err(e) = self.socket.send_to(rep.as_bytes(), source_address) {
+                        log::error!("dyndns reply to {} failed: {}", source_address, e);
+                    }
+                }
+                continue;
+            }
+
+            match DnsPacket::from_buffer(req_buffer) {
+                Ok(ref mut request) => match request.questions.pop() {
+                    Some(question) => {
+                        // 处理dns请求
+                        let query = Query::new(QueryData {
+                            id: request.header.id,
+                            addr: source_address,
+                            question,
+                            forword: 0,
+                            expire: query_deadline(),
+                            count: Cell::new(0),
+                            start: Instant::now(),
+                            upstream: Cell::new(None),
+                            sent: Cell::new(Instant::now()),
+                            recursion: request.header.recursion_desired,
+                        });
+
+                        if let Err(e) = self.handle_query(&query) {
+                            log::error!("failed to process query request: {}", e);
+                        }
+                    },
+                    None => log::error!("serve_recv no question found in the received request package"),
+                },
+                Err(e) => {
+                    self.metrics.counter("dns.malformed", 1);
+                    log::error!("serve_recv data format error: {}", e);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 动态dns独立端口的udp数据接收
+    fn dyndns_recv(&mut self, req_buffer: &mut BytePacketBuffer) -> Result<()> {
+        loop {
+            let socket = match self.dyndns_socket {
+                Some(ref socket) => socket,
+                None => break,
+            };
+            let (packet_size, source_address) = match socket.recv_from(req_buffer.recv_buf()) {
+                Ok((packet_size, source_address)) => (packet_size, source_address),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => anyhow::bail!(anyhow::Error::new(e).context("dyndns recv data failed")),
+            };
+            req_buffer.set_len(packet_size);
+
+            if let Some(rep) = self.dyn_dns(req_buffer.data(), &source_address) {
+                if let Some(ref socket) = self.dyndns_socket {
+                    if let Err(e) = socket.send_to(rep.as_bytes(), source_address) {
+                        log::error!("dyndns reply to {} failed: {}", source_address, e);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 接收动态dns独立端口的tcp连接, 连接数达到上限时直接关闭新连接
+    fn dyndns_accept(&mut self) -> Result<()> {
+        loop {
+            let listener = match self.dyndns_listener {
+                Some(ref listener) => listener,
+                None => break,
+            };
+            let (mut stream, addr) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => anyhow::bail!(anyhow::Error::new(e).context("dyndns accept failed")),
+            };
+            if self.dyndns_conns.len() >= C_DYNDNS_MAX_CONNS {
+                log::warn!("too many dyndns connections, connection from {addr} closed");
+                continue;
+            }
+
+            let token = self.next_token();
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                log::error!("register dyndns connection {} failed: {}", addr, e);
+                continue;
+            }
+            self.dyndns_conns.insert(token, DynDnsConn { stream, addr, data: Vec::new(), expire: expire_of_unix() });
+        }
+
         Ok(())
     }
 
-    pub fn run(&mut self, event_capacity: usize) -> Result<()> {
-        let mut req_buffer = BytePacketBuffer::new();
-        let mut events = Events::with_capacity(event_capacity);
-        let mut next_clear_time = now_of_unix() + CLEAR_QUERIES_INTERVAL;
+    /// 接受新实例的交接连接, 发送监听socket后停止接收查询, 等待转发中的查询完成
+    #[cfg(unix)]
+    fn handover_accept(&mut self) {
+        use std::os::unix::io::AsRawFd;
+        use super::handover::{send_sockets, DNS_SOCKET, DYNDNS_TCP_SOCKET, DYNDNS_UDP_SOCKET};
 
-        self.poll.registry().register(&mut self.socket, SERVER_TOKEN, Interest::READABLE)
-                .with_context(|| format!("register socket event {} fail", SERVER_TOKEN.0))?;
-        self.poll.registry().register(&mut self.up_socket, UP_SERVER_TOKEN, Interest::READABLE)
-                .with_context(|| format!("register socket event {} fail", UP_SERVER_TOKEN.0))?;
+        let listener = match self.handover {
+            Some(ref listener) => listener,
+            None => return,
+        };
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                log::error!("handover accept failed: {e}");
+                return;
+            }
+        };
 
-        loop {
-            self.poll.poll(&mut events, None).with_context(|| "socket event poll faild")?;
+        let mut sockets = vec![(DNS_SOCKET, self.socket.as_raw_fd())];
+        if let (Some(socket), Some(listener)) = (&self.dyndns_socket, &self.dyndns_listener) {
+            sockets.push((DYNDNS_UDP_SOCKET, socket.as_raw_fd()));
+            sockets.push((DYNDNS_TCP_SOCKET, listener.as_raw_fd()));
+        }
+        if let Err(e) = send_sockets(stream.as_raw_fd(), &sockets) {
+            log::error!("hand over listening sockets failed: {e:?}");
+            return;
+        }
 
-            for event in events.iter() {
-                match event.token() {
-                    SERVER_TOKEN => self.server_recv(&mut req_buffer)?,
-                    UP_SERVER_TOKEN => self.client_recv(&mut req_buffer)?,
-                    _ => {},
+        // 停止读取监听socket, 但保持打开以便回复转发中的查询, 之后关闭交接连接通知新实例开始服务
+        let registry = self.poll.registry();
+        let _ = registry.deregister(&mut self.socket);
+        if let Some(ref mut socket) = self.dyndns_socket {
+            let _ = registry.deregister(socket);
+        }
+        if let Some(ref mut listener) = self.dyndns_listener {
+            let _ = registry.deregister(listener);
+        }
+        if let Some(mut listener) = self.handover.take() {
+            let _ = registry.deregister(&mut listener);
+        }
+        drop(stream);
+
+        self.draining = true;
+        log::info!("listening sockets handed over, waiting for {} pending queries", self.queries.len());
+    }
+
+    /// 接受管理控制连接, 与动态dns的tcp连接一样注册到事件循环, 读取一行命令后回复结果并关闭连接,
+    /// 客户端发送缓慢时不会阻塞事件循环, 连接数达到上限时直接关闭新连接
+    #[cfg(unix)]
+    fn control_accept(&mut self) {
+        loop {
+            let listener = match self.control {
+                Some(ref listener) => listener,
+                None => return,
+            };
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::error!("control accept failed: {e}");
+                    return;
                 }
+            };
+            if self.control_conns.len() >= MAX_CONTROL_CONNS {
+                log::warn!("too many control connections, new connection closed");
+                continue;
             }
 
-            // 定时清理待查询队列
-            let now = now_of_unix();
-            if next_clear_time < now {
-                self.clear_queries_of_timeout();
-                next_clear_time = now + CLEAR_QUERIES_INTERVAL;
+            let token = self.next_token();
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                log::error!("register control connection failed: {e}");
+                continue;
             }
+            self.control_conns.insert(token, ControlConn { stream, data: Vec::new(), reply: Vec::new(), expire: expire_of_unix() });
         }
     }
 
-    fn server_recv(&mut self, req_buffer: &mut BytePacketBuffer) -> Result<()> {
-        loop {
-            req_buffer.pos = 0;
-            let (packet_size, source_address) = match self.socket.recv_from(&mut req_buffer.buf) {
-                Ok((packet_size, source_address)) => (packet_size, source_address),
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                Err(e) => anyhow::bail!(anyhow::Error::new(e).context("server recv data failed")),
-            };
-            req_buffer.len = packet_size;
+    /// 管理控制连接的数据接收, 收到一行命令后执行并回复结果, 回复发送完毕后关闭连接
+    #[cfg(unix)]
+    fn control_conn_recv(&mut self, token: Token) {
+        let conn = match self.control_conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if !conn.reply.is_empty() {
+            return self.control_conn_send(token);
+        }
+        match read_request_line(&mut conn.stream, &mut conn.data) {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(e) => {
+                log::warn!("control connection read failed: {e}");
+                return self.close_control_conn(token);
+            },
+        }
 
-            // 处理动态dns更新
-            match self.dyn_dns(req_buffer, &source_address) {
-                Ok(true) => continue,
-                Ok(false) => {},
-                Err(e) => log::error!("dyndns server error: {}", e),
+        let line = String::from_utf8_lossy(&std::mem::take(&mut conn.data)).into_owned();
+        let rep = self.control_command(&line);
+        if let Some(conn) = self.control_conns.get_mut(&token) {
+            conn.reply = rep.into_bytes();
+        }
+        self.control_conn_send(token);
+    }
+
+    /// 发送管理控制连接的回复, socket缓冲区已满时等待可写事件后继续发送, 发送完毕或出错时关闭连接
+    #[cfg(unix)]
+    fn control_conn_send(&mut self, token: Token) {
+        let conn = match self.control_conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        while !conn.reply.is_empty() {
+            match conn.stream.write(&conn.reply) {
+                Ok(0) => break,
+                Ok(n) => { conn.reply.drain(..n); },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if let Err(e) = self.poll.registry().reregister(&mut conn.stream, token, Interest::WRITABLE) {
+                        log::error!("register control connection failed: {e}");
+                        break;
+                    }
+                    return;
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    log::error!("control reply send failed: {e}");
+                    break;
+                },
             }
+        }
+        self.close_control_conn(token);
+    }
 
-            match DnsPacket::from_buffer(req_buffer) {
-                Ok(ref mut request) => match request.questions.pop() {
-                    Some(question) => {
-                        // 处理dns请求
-                        let query = Query::new(QueryData {
-                            id: request.header.id,
-                            addr: source_address,
-                            question: question,
-                            forword: 0,
-                            expire: expire_of_unix(),
-                            count: Cell::new(0),
-                        });
+    /// 关闭管理控制连接
+    #[cfg(unix)]
+    fn close_control_conn(&mut self, token: Token) {
+        if let Some(mut conn) = self.control_conns.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+        }
+    }
 
-                        if let Err(e) = self.handle_query(&query) {
-                            log::error!("failed to process query request: {}", e);
-                        }
-                    },
-                    None => log::error!("serve_recv no question found in the received request package"),
-                },
-                Err(e) => log::error!("serve_recv data format error: {}", e),
+    /// 分配动态dns的tcp连接或管理控制连接的token
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_conn_token);
+        self.next_conn_token = self.next_conn_token.checked_add(1).unwrap_or(DYNDNS_CONN_TOKEN);
+        token
+    }
+
+    /// 动态dns的tcp连接或管理控制连接的数据接收
+    fn conn_recv(&mut self, token: Token) {
+        #[cfg(unix)]
+        if self.control_conns.contains_key(&token) {
+            return self.control_conn_recv(token);
+        }
+        self.dyndns_conn_recv(token)
+    }
+
+    /// 动态dns的tcp连接数据接收, 每个连接处理一行请求, 回复后关闭连接, 请求超过最大长度时直接关闭连接
+    fn dyndns_conn_recv(&mut self, token: Token) {
+        let conn = match self.dyndns_conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        match read_request_line(&mut conn.stream, &mut conn.data) {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                log::warn!("dyndns request from {} is too long, connection closed", conn.addr);
+                return self.close_dyndns_conn(token);
+            },
+            Err(e) => {
+                log::debug!("dyndns connection {} read failed: {}", conn.addr, e);
+                return self.close_dyndns_conn(token);
+            },
+        }
+
+        let (data, addr) = (std::mem::take(&mut conn.data), conn.addr);
+        let data = match data.last() {
+            Some(b'\r') => &data[..data.len() - 1],
+            _ => &data[..],
+        };
+        if let Some(mut rep) = self.dyn_dns(data, &addr) {
+            rep.push('\n');
+            if let Some(conn) = self.dyndns_conns.get_mut(&token) {
+                if let Err(e) = conn.stream.write_all(rep.as_bytes()) {
+                    log::error!("dyndns reply to {} failed: {}", addr, e);
+                }
             }
         }
+        self.close_dyndns_conn(token);
+    }
 
-        Ok(())
+    /// 关闭动态dns的tcp连接
+    fn close_dyndns_conn(&mut self, token: Token) {
+        if let Some(mut conn) = self.dyndns_conns.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+        }
     }
 
-    fn client_recv(&mut self, req_buffer: &mut BytePacketBuffer) -> Result<()> {
+    /// 关闭超时的动态dns tcp连接及管理控制连接
+    fn clear_conns_of_timeout(&mut self, now: u64) {
+        let tokens: Vec<Token> = self.dyndns_conns.iter()
+            .filter(|(_, conn)| conn.expire < now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            self.close_dyndns_conn(token);
+        }
+        #[cfg(unix)]
+        {
+            let tokens: Vec<Token> = self.control_conns.iter()
+                .filter(|(_, conn)| conn.expire < now)
+                .map(|(token, _)| *token)
+                .collect();
+            for token in tokens {
+                self.close_control_conn(token);
+            }
+        }
+    }
+
+    /// 接收上级dns服务器(`token`为UP_SERVER_TOKEN)或迭代查询的其它域名服务器的回复
+    fn client_recv(&mut self, req_buffer: &mut BytePacketBuffer, token: Token) -> Result<()> {
         loop {
-            req_buffer.pos = 0;
-            let (packet_size, _) = match self.up_socket.recv_from(&mut req_buffer.buf) {
+            let socket = match token {
+                NS_SERVER_TOKEN => &self.ns_socket,
+                NS6_SERVER_TOKEN => match self.ns_socket6 {
+                    Some(ref socket) => socket,
+                    None => break,
+                },
+                _ => &self.up_socket,
+            };
+            let (packet_size, _) = match socket.recv_from(req_buffer.recv_buf()) {
                 Ok((packet_size, source_address)) => (packet_size, source_address),
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // 已连接的socket收到icmp端口不可达时返回该错误, 立即结束等待该服务器回复的查询
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused && token == UP_SERVER_TOKEN => {
+                    self.fail_upstream_query(&e);
+                    continue;
+                },
                 Err(e) => anyhow::bail!(anyhow::Error::new(e).context("client recv failed")),
             };
-            req_buffer.len = packet_size;
+            req_buffer.set_len(packet_size);
 
             match DnsPacket::from_buffer(req_buffer) {
                 Ok(dns_packet) => {
@@ -181,58 +1591,530 @@ impl DnsServer {
         Ok(())
     }
 
+    /// 上级dns服务器不可达, 向仅转发给该服务器(未转向其它域名服务器)的查询回复SERVFAIL,
+    /// 而不是等到超时后丢弃
+    ///
+    /// 每个icmp错误对应一个发出的数据包, 错误中不含查询id, 因此只结束最早发出的一个查询,
+    /// 其它查询仍等待回复或超时, 避免一次偶发的错误使所有查询失败
+    fn fail_upstream_query(&mut self, e: &std::io::Error) {
+        self.metrics.counter("dns.upstream_errors", 1);
+        let id = self.queries.iter()
+            .filter(|(_, q)| q.forword == 0 && q.count.get() == 0)
+            .min_by_key(|(_, q)| q.sent.get())
+            .map(|(id, _)| *id);
+        let Some(query) = id.and_then(|id| self.queries.remove(&id)) else {
+            log::warn!("parent dns server {} unreachable: {e}", self.up_dns_addr);
+            return;
+        };
+        log::warn!("parent dns server {} unreachable: {e}, query {} failed", self.up_dns_addr, query.question.name);
+        self.upstreams.borrow_mut().entry(self.up_dns_addr).or_default().errors += 1;
+        if self.metrics.enabled() {
+            self.metrics.counter(&format!("dns.upstream.{}.errors", self.up_dns_addr), 1);
+        }
+        self.response_upstream_failure(&query);
+    }
+
+    /// 按失败策略回复上级dns服务器失败的查询
+    fn response_upstream_failure(&mut self, query: &Query) {
+        let result = match self.failure_policy {
+            FailurePolicy::ServFail => self.response_servfail(query),
+            FailurePolicy::NxDomain => self.response(ResultCode::NXDOMAIN, query, None),
+            // 分组专用上级的查询不使用全局缓存中的过期记录
+            FailurePolicy::Stale if query.upstream.get().is_some_and(|addr| self.is_group_upstream(&addr)) => self.response_servfail(query),
+            FailurePolicy::Stale => match self.resolvers.iter_mut().find_map(|r| r.stale(&query.question)) {
+                Some(answers) => {
+                    log::debug!("answer stale records of {}", query.question.name);
+                    self.metrics.counter("dns.stale", 1);
+                    self.response_with_ttl(ResultCode::NOERROR, query, Some(&answers.records), answers.ttl)
+                },
+                None => self.response_servfail(query),
+            },
+        };
+        if let Err(e) = result {
+            log::error!("response upstream failure of {} failed: {e}", query.question.name);
+        }
+    }
+
+    /// 回复上级失败的查询SERVFAIL, 并缓存失败结果
+    fn response_servfail(&mut self, query: &Query) -> Result<()> {
+        let group_upstream = query.upstream.get().is_some_and(|addr| self.is_group_upstream(&addr));
+        if !self.servfail_ttl.is_zero() && !group_upstream {
+            if self.servfail_cache.len() >= MAX_SERVFAIL_ENTRIES {
+                self.clear_servfail_of_expired();
+            }
+            if self.servfail_cache.len() < MAX_SERVFAIL_ENTRIES {
+                let key = (query.question.name.to_ascii_lowercase(), query.question.qtype);
+                self.servfail_cache.insert(key, Instant::now() + self.servfail_ttl);
+            }
+        }
+        self.response(ResultCode::SERVFAIL, query, None)
+    }
+
+    /// 查询最近是否因上级失败回复过SERVFAIL
+    fn is_servfail_cached(&self, question: &DnsQuestion) -> bool {
+        if self.servfail_cache.is_empty() {
+            return false;
+        }
+        let key = (question.name.to_ascii_lowercase(), question.qtype);
+        matches!(self.servfail_cache.get(&key), Some(expire) if *expire > Instant::now())
+    }
+
+    /// 清理过期的上级失败结果
+    fn clear_servfail_of_expired(&mut self) {
+        let now = Instant::now();
+        self.servfail_cache.retain(|_, expire| *expire > now);
+    }
+
     fn handle_query(&mut self, query: &Query) -> Result<()> {
         log::debug!("Received query: {:?}", query.question);
+        self.metrics.counter("dns.queries", 1);
+        if let Some(ref mut analytics) = self.analytics {
+            analytics.record(&query.question.name, query.addr.ip(), now_of_unix());
+        }
+
+        // 非IN类别的查询不进入解析链, 只回复CHAOS类别的服务器信息
+        match query.question.qclass {
+            QueryClass::IN | QueryClass::ANY => {},
+            QueryClass::CH => return self.response_chaos(query),
+            qclass => {
+                log::debug!("unsupported class {qclass} of query {} from {}", query.question.name, query.addr);
+                return self.response(ResultCode::NOTIMP, query, None);
+            },
+        }
 
-        // 尝试本地查找
-        if let Some(rec) = self.local_lookup(&query.question.name) {
-            log::debug!("answer from local: {:?}", rec);
-            let answers = [rec];
-            self.response(ResultCode::NOERROR, &query, Some(&answers))?;
-            return Ok(());
+        // 查询钩子可以直接给出回复
+        for hook in &self.query_hooks {
+            let action = hook(&query.addr, &query.question);
+            if action != HookAction::Continue {
+                self.metrics.counter("dns.hook", 1);
+            }
+            match action {
+                HookAction::Continue => {},
+                HookAction::Answer(answers) => return self.response(ResultCode::NOERROR, query, Some(&answers)),
+                HookAction::Error(rescode) => return self.response(rescode, query, None),
+            }
         }
 
-        // 本地没找到, 而且也没有指定上级dns
-        if self.up_dns_addr == IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)) {
-            log::debug!("answer from local: {} not found, return refused", query.question.name);
-            self.response(ResultCode::NXDOMAIN, &query, None)?;
-            return Ok(());
+        // 按顺序调用解析链, 解析期间暂时取出以便解析器访问服务器的本地域名表,
+        // 客户端不期望或不允许递归查询时跳过依赖上级dns服务器的解析器
+        // 客户端所属分组的hosts及规则脚本优先于解析链, 设置了上级dns服务器的分组不使用全局缓存,
+        // 以免组内客户端得到全局上级的(未过滤的)结果
+        let group = self.client_group(&query.addr.ip());
+        let group_upstream = group.and_then(|i| self.groups[i].config.upstream);
+        let mut resolvers = std::mem::take(&mut self.resolvers);
+        let mut groups = std::mem::take(&mut self.groups);
+        let ctx = ResolveContext::new(self, query.addr);
+        let recursion = query.recursion && self.recursion_allowed(&query.addr);
+        let skipped = !recursion && resolvers.iter().any(|r| r.recursive());
+        let result = match group.and_then(|i| groups[i].lookup(&ctx, &query.question, self.ttl).map(|r| (i, r))) {
+            Some((i, result)) => {
+                log::debug!("{} resolved by group {}", query.question.name, groups[i].config.name);
+                Some(("group", result))
+            },
+            None => resolvers.iter_mut()
+                .filter(|r| recursion || !r.recursive())
+                .filter(|r| group_upstream.is_none() || r.name() != "cache")
+                .map(|r| (r.name(), r.lookup(&ctx, &query.question)))
+                .find(|(_, result)| *result != ResolveResult::Next),
+        };
+        self.resolvers = resolvers;
+        self.groups = groups;
+        // 单标签域名在转发前尝试补全搜索域后缀
+        let result = match result {
+            Some((_, ResolveResult::Forward(_) | ResolveResult::Next)) | None => match self.search_lookup(&query.question) {
+                Some(answers) => Some(("search", ResolveResult::Answer(answers.into()))),
+                None => result,
+            },
+            result => result,
+        };
+        // 桥接后缀下本地无法解析的域名转为mdns查询, 不转发给上级dns服务器,
+        // 非递归或不允许递归的查询不向局域网发送查询, 与转发一样回复拒绝
+        let unresolved = matches!(result, Some(("forward", _) | (_, ResolveResult::Forward(_) | ResolveResult::Next)) | None);
+        if unresolved && recursion {
+            if let Some(local_name) = self.mdns_bridge.as_ref().and_then(|b| b.local_name(&query.question.name)) {
+                self.metrics.counter("dns.resolver.mdns", 1);
+                return self.mdns_lookup(query, &local_name);
+            }
+            // 单标签域名在局域网内通过llmnr/netbios查询
+            if self.name_fallback.as_ref().is_some_and(|f| f.accepts(&query.question)) {
+                self.metrics.counter("dns.resolver.fallback", 1);
+                return self.fallback_lookup(query);
+            }
+        }
+        if let (Some((name, _)), true) = (&result, self.metrics.enabled()) {
+            self.metrics.counter(&format!("dns.resolver.{name}"), 1);
         }
 
-        // 转向上级dns服务器发起查询
-        if self.queries.len() < MAX_QUERIES_LEN {
-            let req_id = self.next_req_id();
-            self.queries.insert(req_id, query.clone());
-            self.send_request(&self.up_dns_addr, req_id, &query.question)
-        } else {
-            self.response(ResultCode::REFUSED, &query, None)
+        match result {
+            Some((name, ResolveResult::Answer(answers))) => {
+                log::debug!("answer from {name}: {:?}", answers);
+                if answers.records.iter().any(|r| matches!(r, DnsRecord::A { addr, .. } if addr.is_unspecified())) {
+                    self.metrics.counter("dns.blocked", 1);
+                    if self.block_log {
+                        let client = query.addr.ip();
+                        match self.client_name(&client) {
+                            Some(host) => log::info!(target: BLOCKED_TARGET, "blocked {} {} for {client}({host})",
+                                    query.question.name, query.question.qtype),
+                            None => log::info!(target: BLOCKED_TARGET, "blocked {} {} for {client}",
+                                    query.question.name, query.question.qtype),
+                        }
+                    }
+                    self.block_hooks.iter().for_each(|hook| hook(&query.addr, &query.question));
+                }
+                self.response_with_ttl(ResultCode::NOERROR, query, Some(&answers.records), answers.ttl)
+            },
+            Some((name, ResolveResult::Error(rescode))) => {
+                log::debug!("answer from {name}: {} {rescode}", query.question.name);
+                self.response(rescode, query, None)
+            },
+            // 上级最近对该查询失败, 在缓存时间内直接回复, 不再转发
+            Some((_, ResolveResult::Forward(_))) if group_upstream.is_none() && self.is_servfail_cached(&query.question) => {
+                log::debug!("servfail of {} cached, return servfail", query.question.name);
+                self.metrics.counter("dns.servfail_cached", 1);
+                self.response(ResultCode::SERVFAIL, query, None)
+            },
+            // 转向上级dns服务器发起查询
+            Some((_, ResolveResult::Forward(up_dns_addr))) => {
+                // 分组设置了上级dns服务器时替代全局的上级dns服务器
+                let up_dns_addr = match group_upstream {
+                    Some(addr) if up_dns_addr == self.up_dns_addr => addr,
+                    _ => up_dns_addr,
+                };
+                if self.queries.len() < MAX_QUERIES_LEN {
+                    let req_id = self.next_req_id();
+                    self.queries.insert(req_id, query.clone());
+                    self.metrics.counter("dns.forwarded", 1);
+                    self.send_request(&up_dns_addr, req_id, &query.question)
+                } else {
+                    self.response(ResultCode::REFUSED, query, None)
+                }
+            },
+            // 非递归(或不允许递归)的查询无法用本地数据回复
+            Some((_, ResolveResult::Next)) | None if skipped => {
+                log::debug!("non-recursive query {} from {} not answered locally, return refused", query.question.name, query.addr);
+                self.response(ResultCode::REFUSED, query, None)
+            },
+            // 所有解析器都无法解析
+            Some((_, ResolveResult::Next)) | None => {
+                log::debug!("no resolver answered {}, return nxdomain", query.question.name);
+                self.response(ResultCode::NXDOMAIN, query, None)
+            },
+        }
+    }
+
+    /// 通过mdns桥接解析, 缓存中没有时发送mdns查询, 收到应答或超时后回复客户端
+    fn mdns_lookup(&mut self, query: &Query, local_name: &str) -> Result<()> {
+        let bridge = match self.mdns_bridge.as_mut() {
+            Some(bridge) => bridge,
+            None => return self.response(ResultCode::NXDOMAIN, query, None),
+        };
+        // mdns只桥接地址记录
+        if !matches!(query.question.qtype, QueryType::A | QueryType::AAAA) {
+            return self.response(ResultCode::NOERROR, query, None);
+        }
+        if let Some(answers) = bridge.cached(&query.question, Instant::now()) {
+            return self.response(ResultCode::NOERROR, query, (!answers.is_empty()).then_some(&answers[..]));
+        }
+        if self.mdns_queries.len() >= MAX_QUERIES_LEN {
+            return self.response(ResultCode::REFUSED, query, None);
+        }
+
+        let id = self.mdns_queries.next_id();
+        let sent = bridge.send_query(id, local_name, query.question.qtype, &mut PooledBuffer::new(&self.pool));
+        match sent {
+            Ok(()) => {
+                log::debug!("mdns query {local_name} {} for {}", query.question.qtype, query.question.name);
+                self.mdns_queries.insert(id, query.clone(), Instant::now() + LOOKUP_TIMEOUT);
+                Ok(())
+            },
+            Err(e) => {
+                log::error!("mdns bridge of {} failed: {e:?}", query.question.name);
+                self.response(ResultCode::SERVFAIL, query, None)
+            },
+        }
+    }
+
+    /// 接收mdns应答, 应答中有查询的地址(或主机只有另一类型的地址)时回复客户端, 之后同一查询的其它应答被忽略
+    fn mdns_recv(&mut self, req_buffer: &mut BytePacketBuffer) {
+        loop {
+            let Some(ref mut bridge) = self.mdns_bridge else {
+                return;
+            };
+            let packet = match bridge.recv(req_buffer) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return,
+                Err(e) => {
+                    log::debug!("mdns response error: {e:?}");
+                    continue;
+                },
+            };
+            let Some(query) = self.mdns_queries.get(packet.header.id) else {
+                continue;
+            };
+            let Some(answers) = bridge.on_response(&query.question, &packet, Instant::now()) else {
+                continue;
+            };
+            if let Some(query) = self.mdns_queries.remove(packet.header.id) {
+                self.metrics.counter("dns.mdns.answered", 1);
+                let answers = (!answers.is_empty()).then_some(&answers[..]);
+                if let Err(e) = self.response(ResultCode::NOERROR, &query, answers) {
+                    log::error!("response mdns answer of {} failed: {e:?}", query.question.name);
+                }
+            }
         }
     }
 
-    /// 本地dns条目查询服务
-    fn local_lookup(&self, qname: &str) -> Option<DnsRecord> {
-        match self.hosts.get(qname) {
-            Some(addr) => {
-                Some(DnsRecord::A {
-                    domain: String::from(qname),
-                    addr: *addr,
-                    ttl: self.ttl,
-                })
+    /// 通过llmnr/netbios解析单标签域名, 缓存中没有时在局域网内查询, 收到应答或超时后回复客户端
+    fn fallback_lookup(&mut self, query: &Query) -> Result<()> {
+        let fallback = match self.name_fallback.as_mut() {
+            Some(fallback) => fallback,
+            None => return self.response(ResultCode::NXDOMAIN, query, None),
+        };
+        if let Some(answers) = fallback.cached(&query.question, Instant::now()) {
+            return self.response(ResultCode::NOERROR, query, Some(&answers));
+        }
+        if self.fallback_queries.len() >= MAX_QUERIES_LEN {
+            return self.response(ResultCode::REFUSED, query, None);
+        }
+
+        let id = self.fallback_queries.next_id();
+        let sent = fallback.send_query(id, &query.question, &mut PooledBuffer::new(&self.pool));
+        match sent {
+            Ok(()) => {
+                log::debug!("name fallback query {} {}", query.question.name, query.question.qtype);
+                self.fallback_queries.insert(id, query.clone(), Instant::now() + FALLBACK_TIMEOUT);
+                Ok(())
+            },
+            Err(e) => {
+                log::error!("name fallback of {} failed: {e:?}", query.question.name);
+                self.response(ResultCode::SERVFAIL, query, None)
             },
-            None => None
         }
     }
 
+    /// 接收llmnr/netbios应答, 以最先到达的应答回复客户端
+    fn fallback_recv(&mut self, req_buffer: &mut BytePacketBuffer) {
+        let received = match self.name_fallback {
+            Some(ref fallback) => fallback.recv_answers(req_buffer),
+            None => return,
+        };
+        for answer in received {
+            let (Some(query), Some(fallback)) = (self.fallback_queries.get(answer.id), self.name_fallback.as_mut()) else {
+                continue;
+            };
+            let answers = fallback.on_answer(&query.question, &answer, Instant::now());
+            if answers.is_empty() {
+                continue;
+            }
+            if let Some(query) = self.fallback_queries.remove(answer.id) {
+                self.metrics.counter("dns.fallback.answered", 1);
+                if let Err(e) = self.response(ResultCode::NOERROR, &query, Some(&answers)) {
+                    log::error!("response name fallback answer of {} failed: {e:?}", query.question.name);
+                }
+            }
+        }
+    }
+
+    /// 超时未收到应答的mdns及llmnr/netbios查询回复域名不存在
+    fn clear_lan_queries_of_timeout(&mut self) {
+        let now = Instant::now();
+        for (name, metric, expired) in [("mdns", "dns.mdns.timeouts", self.mdns_queries.take_expired(now)),
+                ("fallback", "dns.fallback.timeouts", self.fallback_queries.take_expired(now))] {
+            for query in expired {
+                log::debug!("no {name} answer of {}, return nxdomain", query.question.name);
+                self.metrics.counter(metric, 1);
+                if let Err(e) = self.response(ResultCode::NXDOMAIN, &query, None) {
+                    log::error!("response {name} timeout of {} failed: {e:?}", query.question.name);
+                }
+            }
+        }
+    }
+
+    /// 单标签域名依次补全搜索域后缀后在本地域名表中查找, 返回别名记录及补全域名的记录
+    fn search_lookup(&self, question: &DnsQuestion) -> Option<Vec<DnsRecord>> {
+        if question.name.is_empty() || question.name.contains('.') {
+            return None;
+        }
+        self.search_domains.iter().find_map(|suffix| {
+            let host = format!("{}.{suffix}", question.name);
+            let records = self.local_lookup(&host, question.qtype)?;
+            let mut answers = vec![DnsRecord::CNAME { domain: question.name.clone(), host, ttl: self.ttl }];
+            if question.qtype != QueryType::CNAME {
+                answers.extend(records.iter().cloned());
+            }
+            Some(answers)
+        })
+    }
+
+    /// 回复CHAOS类别的服务器信息查询, 未配置或不支持的名称回复REFUSED
+    fn response_chaos(&mut self, query: &Query) -> Result<()> {
+        let question = &query.question;
+        let text = match (question.qtype, question.name.to_ascii_lowercase().as_str()) {
+            (QueryType::TXT, "version.bind" | "version.server") => &self.chaos_version,
+            (QueryType::TXT, "hostname.bind" | "id.server") => &self.chaos_hostname,
+            _ => "",
+        };
+        if text.is_empty() {
+            log::debug!("chaos query {} {} from {} refused", question.name, question.qtype, query.addr);
+            return self.response(ResultCode::REFUSED, query, None);
+        }
+        self.metrics.counter("dns.chaos", 1);
+        let answers = [DnsRecord::TXT { domain: question.name.clone(), text: text.to_string(), ttl: 0 }];
+        self.response(ResultCode::NOERROR, query, Some(&answers))
+    }
+
+    /// 解析链是否提供递归查询(转发给上级dns服务器), 用于设置回复的RA标志
+    fn recursion_available(&self) -> bool {
+        self.resolvers.iter().any(|r| r.recursive())
+    }
+
+    /// 客户端是否允许递归查询
+    fn recursion_allowed(&self, client: &SocketAddr) -> bool {
+        self.recursion_acl.is_empty() || self.recursion_acl.contains(&client.ip())
+    }
+
+    /// 上级dns服务器的回复通知解析链
+    fn notify_resolvers(&mut self, question: &DnsQuestion, response: &DnsPacket) {
+        for r in self.resolvers.iter_mut() {
+            r.on_response(question, response.header.rescode, &response.answers);
+        }
+    }
+
+    /// 本地dns条目查询服务, 域名存在别名记录时返回别名及其在本地可解析的地址,
+    /// 查询TXT/CNAME记录时返回对应记录, 查询A/AAAA记录时返回对应类型的地址,
+    /// 域名没有该类型的地址时(如仅有ipv4地址的屏蔽域名)返回全部地址,
+    /// 查询结果缓存至域名表变化, 未找到的域名不缓存
+    pub(crate) fn local_lookup(&self, qname: &str, qtype: QueryType) -> Option<Arc<[DnsRecord]>> {
+        if let Some(records) = self.local_answers.borrow().get(qname, qtype) {
+            return Some(records.clone());
+        }
+        let records: Arc<[DnsRecord]> = self.find_local_answers(qname, qtype)?.into();
+        let mut local_answers = self.local_answers.borrow_mut();
+        if local_answers.len() >= MAX_LOCAL_ANSWERS {
+            local_answers.clear();
+        }
+        local_answers.insert(qname, qtype, records.clone());
+        Some(records)
+    }
+
+    /// 在本地域名表中查找并生成应答记录
+    fn find_local_answers(&self, qname: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        let mut answers = Vec::new();
+        let mut name = qname;
+        // 多跟随一次, 使超长的别名链在回复时被识别为超出限制
+        for _ in 0..=self.max_cnames {
+            let records = self.find_records(name).unwrap_or_default();
+            let cname = records.iter().find_map(|(r, ttl)| match r {
+                HostRecord::Cname(host) => Some((host, ttl)),
+                _ => None,
+            });
+            if let Some((host, ttl)) = cname {
+                answers.push(DnsRecord::CNAME { domain: name.to_string(), host: host.clone(), ttl: ttl.unwrap_or(self.ttl) });
+                if qtype == QueryType::CNAME {
+                    return Some(answers);
+                }
+                name = host;
+                continue;
+            }
+
+            if qtype == QueryType::TXT {
+                let txts: Vec<_> = records.iter().filter_map(|(r, ttl)| match r {
+                    HostRecord::Txt(text) => Some(DnsRecord::TXT {
+                        domain: name.to_string(), text: text.clone(), ttl: ttl.unwrap_or(self.ttl),
+                    }),
+                    _ => None,
+                }).collect();
+                if !txts.is_empty() {
+                    answers.extend(txts);
+                    return Some(answers);
+                }
+            }
+
+            if let Some(addrs) = self.find_host(name) {
+                answers.extend(host_answers(name, addrs, qtype, self.ttl));
+            }
+            break;
+        }
+
+        // 别名指向的域名在本地无法解析时, 仅返回别名记录, 由客户端继续解析
+        if answers.is_empty() { None } else { Some(answers) }
+    }
+
+    /// 客户端所属的分组序号, 按分组顺序匹配网段或arp表中的mac地址
+    fn client_group(&self, client: &IpAddr) -> Option<usize> {
+        if self.groups.is_empty() {
+            return None;
+        }
+        let client = client.to_canonical();
+        let mac = self.client_macs.get(&client);
+        self.groups.iter().position(|g| g.config.matches(&client, mac))
+    }
+
+    /// 是否为分组专用(不同于全局)的上级dns服务器
+    fn is_group_upstream(&self, addr: &IpAddr) -> bool {
+        *addr != self.up_dns_addr && self.groups.iter().any(|g| g.config.upstream == Some(*addr))
+    }
+
+    /// 有按mac地址分组的客户端时重新读取arp表
+    fn refresh_client_macs(&mut self) {
+        if self.groups.iter().any(|g| !g.config.macs.is_empty()) {
+            self.client_macs = read_arp_table();
+        }
+    }
+
+    /// 客户端地址在本地域名表(静态域名及动态域名)中对应的域名, 有多个时取最短的, 用于识别客户端,
+    /// 反向索引在域名表变化后的首次使用时生成
+    fn client_name(&mut self, client: &IpAddr) -> Option<&str> {
+        let local = &self.local;
+        let names = self.client_names.get_or_insert_with(|| {
+            let mut names: HashMap<IpAddr, String> = HashMap::new();
+            for (name, addrs) in local.hosts.iter() {
+                for addr in addrs {
+                    match names.get(&addr.addr) {
+                        Some(n) if (n.len(), n.as_str()) <= (name.len(), name.as_str()) => {},
+                        _ => { names.insert(addr.addr, name.clone()); },
+                    }
+                }
+            }
+            names
+        });
+        names.get(&client.to_canonical()).map(String::as_str)
+    }
+
+    /// 查找本地域名, 本地hosts优先于远程hosts, 最后查找屏蔽域名库
+    fn find_host(&self, qname: &str) -> Option<&[HostAddr]> {
+        find_in_hosts(&self.local.hosts, &self.local.blocked, qname)
+            .or_else(|| self.remote_tables.iter().find_map(|t| find_in_hosts(&t.hosts, &t.blocked, qname)))
+            .or_else(|| match self.block_db {
+                Some(ref db) if match_host_names(qname, |name| db.contains(name).then_some(())).is_some() => Some(BLOCKED_ADDRS),
+                _ => None,
+            })
+    }
+
+    /// 查找本地域名的非A记录, 本地hosts优先于远程hosts
+    fn find_records(&self, qname: &str) -> Option<&[(HostRecord, Option<u32>)]> {
+        std::iter::once(&self.local).chain(&self.remote_tables)
+            .find_map(|t| t.records.get(qname))
+            .map(|r| r.as_slice())
+    }
+
     fn handle_response(&mut self, response: &DnsPacket) -> Result<()> {
         let query = match self.queries.remove(&response.header.id) {
             Some(c) => c,
             None => return Ok(()),
         };
+        if let Some(addr) = query.upstream.get() {
+            self.record_upstream_response(addr, query.sent.get().elapsed(), response.header.rescode);
+        }
 
         // 查询结果正确
         if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
             // 非递归查询, 直接返回
             if query.forword == 0 {
+                // 分组专用的上级dns服务器的回复(如过滤后的结果)不通知解析链, 避免进入全局缓存
+                if !query.upstream.get().is_some_and(|addr| self.is_group_upstream(&addr)) {
+                    self.notify_resolvers(&query.question, response);
+                }
                 return self.response(response.header.rescode, &query, Some(&response.answers));
             }
 
@@ -265,9 +2147,17 @@ impl DnsServer {
             }
         }
 
+        // 上级dns服务器无法完成解析, 原样回复客户端
+        if response.header.rescode == ResultCode::SERVFAIL {
+            return match self.remove_top_query(&query) {
+                Some(ref top_query) => self.response_servfail(top_query),
+                None => Ok(()),
+            };
+        }
+
         // 递归查询次数限制
-        if query.count.get() > MAX_FORWARD_COUNT {
-            return self.response(ResultCode::REFUSED, &query, None);
+        if query.count.get() >= self.max_forwards || query.count.get() as u16 >= self.query_budget {
+            return self.response_over_budget(&query);
         }
 
         // 否则, 尝试用新的dns服务器再次进行查找
@@ -287,10 +2177,14 @@ impl DnsServer {
         let new_query = Query::new(QueryData {
             id: 0,
             addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
-            question: DnsQuestion { name: String::from(new_ns_name), qtype: QueryType::A },
+            question: DnsQuestion::new(String::from(new_ns_name), QueryType::A),
             forword: response.header.id,
-            expire: expire_of_unix(),
+            expire: query_deadline(),
             count: Cell::new(query.count.get() + 1),
+            start: Instant::now(),
+            upstream: Cell::new(None),
+            sent: Cell::new(Instant::now()),
+            recursion: true,
         });
         let new_req_id = self.next_req_id();
         self.queries.insert(response.header.id, query.clone());
@@ -303,46 +2197,165 @@ impl DnsServer {
         log::debug!("Attempting lookup of {:?} {} with ns {}",
                 question.qtype, question.name, dns_addr);
 
-        let mut packet = DnsPacket::new();
-        packet.header.id = req_id;
-        packet.header.questions = 1;
-        packet.header.recursion_desired = true;
-        packet.questions.push(question.clone());
+        let packet = DnsPacket::builder()
+            .id(req_id)
+            .recursion_desired(true)
+            .question(question.clone())
+            .build();
 
-        let mut req_buffer = BytePacketBuffer::new();
+        let mut req_buffer = PooledBuffer::new(&self.pool);
         packet.write(&mut req_buffer)?;
-        self.up_socket.send_to(&req_buffer.buf[..req_buffer.pos], SocketAddr::new(*dns_addr, 53))
-                .with_context(|| "socket send data failed")?;
+        // 上级dns服务器使用已连接的socket发送, 无需每次指定地址
+        let ns_addr = SocketAddr::new(*dns_addr, 53);
+        match (*dns_addr == self.up_dns_addr, &self.ns_socket6) {
+            // 待读取的icmp错误属于之前发出的数据包, 不应使本次查询失败, 重发一次
+            (true, _) => self.up_socket.send(req_buffer.data()).or_else(|e| match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => self.up_socket.send(req_buffer.data()),
+                _ => Err(e),
+            }),
+            (false, _) if dns_addr.is_ipv4() => self.ns_socket.send_to(req_buffer.data(), ns_addr),
+            (false, Some(socket)) => socket.send_to(req_buffer.data(), ns_addr),
+            (false, None) => anyhow::bail!("send request to {dns_addr} failed: ipv6 is unavailable"),
+        }.with_context(|| format!("send request to {dns_addr} failed"))?;
+        self.upstreams.borrow_mut().entry(*dns_addr).or_default().queries += 1;
+        if self.metrics.enabled() {
+            self.metrics.counter(&format!("dns.upstream.{dns_addr}.queries"), 1);
+        }
+        if let Some(query) = self.queries.get(&req_id) {
+            query.sent.set(Instant::now());
+        }
+
+        // 记录到发起查询的客户端请求上, 迭代查询ns别名时向上查找
+        let mut id = req_id;
+        for _ in 0..=self.max_forwards {
+            match self.queries.get(&id) {
+                Some(query) if query.forword != 0 => {
+                    query.upstream.set(Some(*dns_addr));
+                    id = query.forword;
+                },
+                Some(query) => {
+                    query.upstream.set(Some(*dns_addr));
+                    break;
+                },
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 向查询客户端回复查询结果
+    fn response(&self, resp_code: ResultCode, query: &Query, answers: Option<&[DnsRecord]>) -> Result<()> {
+        self.response_with_ttl(resp_code, query, answers, None)
+    }
+
+    /// 向查询客户端回复查询结果, `ttl`不为None时(如缓存条目的剩余时间)在写入时替换应答记录的ttl
+    fn response_with_ttl(&self, resp_code: ResultCode, query: &Query, answers: Option<&[DnsRecord]>, ttl: Option<u32>) -> Result<()> {
+        // 仅在存在回复钩子时复制应答记录, 供钩子修改
+        let (mut resp_code, mut ttl) = (resp_code, ttl);
+        let mut answers = Cow::Borrowed(answers.unwrap_or_default());
+
+        // 别名链过长或工作量超出上限时回复SERVFAIL
+        if resp_code == ResultCode::NOERROR {
+            let cnames = answers.iter().filter(|r| matches!(r, DnsRecord::CNAME { .. })).count();
+            if cnames > self.max_cnames as usize || query.count.get() as usize + cnames > self.query_budget as usize {
+                log::warn!("query {} from {} exceeded work budget with {} cnames after {} forwards, return servfail",
+                        query.question.name, query.addr, cnames, query.count.get());
+                self.metrics.counter("dns.over_budget", 1);
+                resp_code = ResultCode::SERVFAIL;
+                answers = Cow::Borrowed(&[]);
+            }
+        }
+        if !self.response_hooks.is_empty() {
+            let answers = answers.to_mut();
+            if let Some(ttl) = ttl.take() {
+                answers.iter_mut().for_each(|rec| rec.set_ttl(ttl));
+            }
+            for hook in &self.response_hooks {
+                hook(&query.addr, &query.question, &mut resp_code, answers);
+            }
+        }
+
+        let mut response = DnsResponse::new(query.id, resp_code, &query.question, &answers);
+        response.ttl = ttl;
+        response.header.recursion_desired = query.recursion;
+        response.header.recursion_available = self.recursion_available() && self.recursion_allowed(&query.addr);
+        if log::log_enabled!(log::Level::Trace) {
+            log::trace!("response to {}:\n{}", query.addr, response.to_packet());
+        }
+
+        let mut res_buffer = PooledBuffer::new(&self.pool);
+        response.write(&mut res_buffer)?;
+
+        // 超过udp报文长度限制时, 设置截断标志并去掉应答记录
+        if res_buffer.data().len() > MAX_UDP_PACKET_LEN {
+            log::debug!("response of {} is truncated", query.question.name);
+            self.metrics.counter("dns.truncated", 1);
+            response.header.truncated_message = true;
+            response.answers = &[];
+            res_buffer.clear();
+            response.write(&mut res_buffer)?;
+        }
+
+        self.socket.send_to(res_buffer.data(), query.addr).with_context(|| "response send data failed")?;
+        self.metrics.counter(rcode_metric(resp_code), 1);
+        let elapsed = query.start.elapsed();
+        self.metrics.histogram("dns.latency_ms", elapsed.as_secs_f64() * 1000.0);
+
+        if let Some(msg) = self.slow_query_message(query, resp_code, elapsed) {
+            log::warn!(target: SLOW_QUERY_TARGET, "{msg}");
+        }
 
         Ok(())
     }
 
-    /// 向查询客户端回复查询结果
-    fn response(&self, resp_code: ResultCode, query: &Query, answers: Option<&[DnsRecord]>) -> Result<()> {
-        let mut res_packet = DnsPacket::new();
-        res_packet.header.id = query.id;
-        res_packet.header.rescode = resp_code;
-        res_packet.header.recursion_desired = true;
-        res_packet.header.recursion_available = true;
-        res_packet.header.response = true;
-        res_packet.questions.push(query.question.clone());
-
-        if let Some(answers) = answers {
-            for rec in answers {
-                log::debug!("Answer: {:?}", rec);
-                res_packet.answers.push(rec.clone());
+    /// 回复耗时超过慢查询阈值时生成日志内容, 未设置阈值或未超过时返回None
+    fn slow_query_message(&self, query: &Query, resp_code: ResultCode, elapsed: Duration) -> Option<String> {
+        if self.slow_query.is_zero() || elapsed < self.slow_query {
+            return None;
+        }
+        let upstream = query.upstream.get().map_or_else(|| String::from("none"), |addr| addr.to_string());
+        Some(format!("slow query {} {:?} from {} took {}ms, rcode {}, upstream {}, forwards {}",
+                query.question.name, query.question.qtype, query.addr, elapsed.as_millis(), resp_code, upstream, query.count.get()))
+    }
+
+    /// 记录上级dns服务器的回复, SERVFAIL/REFUSED等错误码计为失败
+    fn record_upstream_response(&self, addr: IpAddr, latency: Duration, rescode: ResultCode) {
+        let error = !matches!(rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN);
+        let mut upstreams = self.upstreams.borrow_mut();
+        let stats = upstreams.entry(addr).or_default();
+        stats.responses += 1;
+        stats.latency_sum += latency;
+        stats.latency_max = stats.latency_max.max(latency);
+        if error {
+            stats.errors += 1;
+        }
+        if self.metrics.enabled() {
+            if error {
+                self.metrics.counter(&format!("dns.upstream.{addr}.errors"), 1);
             }
+            self.metrics.histogram(&format!("dns.upstream.{addr}.latency_ms"), latency.as_secs_f64() * 1000.0);
         }
+    }
 
-        let mut res_buffer = BytePacketBuffer::new();
-        res_packet.write(&mut res_buffer)?;
-
-        let len = res_buffer.pos();
-        let data = res_buffer.get_range(0, len).with_context(|| "response create data failed")?;
-
-        self.socket.send_to(data, query.addr).with_context(|| "response send data failed")?;
+    /// 查询的转发跳转次数超出限制, 删除迭代查询链并回复发起查询的客户端SERVFAIL
+    fn response_over_budget(&mut self, query: &Query) -> Result<()> {
+        let top = match self.remove_top_query(query) {
+            Some(top) => top,
+            None => return Ok(()),
+        };
+        log::warn!("query {} from {} exceeded forward limit after {} forwards, return servfail",
+                top.question.name, top.addr, query.count.get());
+        self.metrics.counter("dns.over_budget", 1);
+        self.response(ResultCode::SERVFAIL, &top, None)
+    }
 
-        Ok(())
+    /// 得到迭代查询链顶端的客户端查询, 同时删除链上的其它待查询项
+    fn remove_top_query(&mut self, query: &Query) -> Option<Query> {
+        match query.forword {
+            0 => Some(query.clone()),
+            id => self.remove_recursive_query(id),
+        }
     }
 
     /// 递归删除指定查询id的所有待查询项
@@ -364,15 +2377,173 @@ impl DnsServer {
 
     /// 清理待查询队列, 将所有超时的查询项删除
     fn clear_queries_of_timeout(&mut self) {
-        let now = now_of_unix();
+        let expired = self.queries.expire(Instant::now());
+        if expired.is_empty() {
+            return;
+        }
+        self.metrics.counter("dns.timeouts", expired.len() as u64);
+        // 迭代查询链以客户端查询计一次超时, 记在最后发送查询的上级上
+        for addr in expired.iter().filter(|q| q.forword == 0).filter_map(|q| q.upstream.get()) {
+            self.upstreams.borrow_mut().entry(addr).or_default().timeouts += 1;
+            if self.metrics.enabled() {
+                self.metrics.counter(&format!("dns.upstream.{addr}.timeouts"), 1);
+            }
+        }
+        // 只回复客户端的查询, 解析ns别名的内部查询随所属的客户端查询一起超时
+        for query in expired.iter().filter(|q| q.forword == 0) {
+            self.response_upstream_failure(query);
+        }
+    }
+
+    /// 定期输出仪表类指标
+    fn report_gauges(&self) {
+        self.metrics.gauge("dns.pending", self.queries.len() as f64);
+        self.metrics.gauge("dns.pool.idle", self.pool_stats().idle as f64);
+        self.metrics.gauge("dns.hosts", self.local.hosts.len() as f64);
+        let events = self.event_stats();
+        self.metrics.gauge("dns.events.capacity", events.capacity as f64);
+        self.metrics.gauge("dns.events.peak", events.peak as f64);
+        self.metrics.gauge("dns.events.full_polls", events.full_polls as f64);
+        self.metrics.gauge("dns.events.poll_timeout_ms", events.poll_timeout.as_millis() as f64);
+        self.metrics.flush();
+    }
+
+    /// 密钥文件发生变化时重新加载, 加载失败则继续使用原有密钥
+    fn reload_key_file(&mut self) {
+        if let Some(ref mut key_file) = self.key_file {
+            match key_file.reload_if_changed() {
+                Ok(true) => log::info!("dyndns key file reloaded, {} keys", key_file.keys().len()),
+                Ok(false) => {},
+                Err(e) => log::error!("dyndns key file reload failed: {:?}", e),
+            }
+        }
+    }
+
+    /// dhcp租约文件发生变化时重新注册客户端主机名, 读取失败则保留原有域名
+    fn refresh_dhcp_leases(&mut self, now: u64) {
+        let leases = match self.dhcp_leases.as_mut().map(LeaseFile::read_if_changed) {
+            Some(Ok(Some(leases))) => leases,
+            Some(Err(e)) => return log::error!("dhcp lease file refresh failed: {:?}", e),
+            _ => return,
+        };
+        self.apply_dhcp_leases(leases, now);
+        log::info!("dhcp lease file reloaded, {} hosts", self.dhcp_hosts.len());
+    }
+
+    /// 用dhcp租约替换原来由租约注册的域名, 已有的静态及动态注册的同名域名优先, 租约到期时间记入租约表
+    ///
+    /// 保留名称(如wpad)不注册; 未设置域名后缀时主机名直接作为单标签域名, 不能与本地域名的后缀
+    /// (如nas.lan中的lan)相同, 避免客户端接管整个域
+    fn apply_dhcp_leases(&mut self, leases: Vec<DhcpLease>, now: u64) {
+        let suffixes: HashSet<&str> = match self.dhcp_domain.is_empty() {
+            true => self.local.hosts.keys().filter_map(|host| host.rsplit_once('.').map(|(_, tld)| tld)).collect(),
+            false => HashSet::new(),
+        };
+        let mut hosts: HashMap<String, (Vec<String>, Option<u64>)> = HashMap::new();
+        for lease in leases.into_iter().filter(|lease| lease.expire.is_none_or(|expire| expire > now)) {
+            if is_reserved_name(&lease.host) || suffixes.contains(lease.host.as_str()) {
+                log::warn!("dhcp host {} of {} is reserved, ignore it", lease.host, lease.ip);
+                continue;
+            }
+            let host = match self.dhcp_domain.is_empty() {
+                true => lease.host,
+                false => format!("{}.{}", lease.host, self.dhcp_domain),
+            };
+            let (ips, expire) = hosts.entry(host).or_insert((Vec::new(), lease.expire));
+            ips.push(lease.ip.to_string());
+            *expire = expire.zip(lease.expire).map(|(a, b)| a.max(b));
+        }
+
+        for host in std::mem::take(&mut self.dhcp_hosts) {
+            self.local.remove(&host);
+            self.leases.remove(&host);
+            self.runtime_hosts.remove(&host);
+        }
+        for (host, (ips, expire)) in hosts {
+            if self.local.hosts.contains_key(&host) || self.runtime_hosts.contains(&host) {
+                log::debug!("dhcp host {host} is already registered, ignore it");
+                continue;
+            }
+            let ttl = expire.map(|expire| (expire - now).min(self.ttl as u64) as u32);
+            if let Err(e) = add_host(&mut self.local.hosts, &mut self.local.blocked, &host, &ips.join(","), ttl) {
+                log::warn!("dhcp host {host} ignored: {e}");
+                continue;
+            }
+            if let Some(expire) = expire {
+                self.leases.insert(host.clone(), expire);
+            }
+            self.runtime_hosts.insert(host.clone());
+            self.dhcp_hosts.insert(host);
+        }
+        self.local_changed();
+    }
 
-        self.queries.retain(|k, v| {
-            let keep = now <= v.expire;
+    /// 删除租约已过期的动态域名及dhcp域名
+    fn clear_leases_of_expired(&mut self, now: u64) {
+        let (hosts, changed, runtime_hosts) = (&mut self.local.hosts, &mut self.hosts_changed, &mut self.runtime_hosts);
+        let (dhcp_hosts, overridden) = (&mut self.dhcp_hosts, &mut self.overridden);
+        self.leases.retain(|host, expire| {
+            let keep = now <= *expire;
             if !keep {
-                log::trace!("request id {} is timeout, remove it", k);
+                match overridden.remove(host) {
+                    Some(addrs) => {
+                        log::info!("lease of {} expired, restore its static address", host);
+                        hosts.insert(host.clone(), addrs);
+                    },
+                    None => {
+                        log::info!("lease of {} expired, remove it", host);
+                        hosts.remove(host);
+                    },
+                }
+                runtime_hosts.remove(host);
+                dhcp_hosts.remove(host);
+                *changed = true;
             }
             keep
         });
+        if self.hosts_changed {
+            self.client_names = None;
+            self.clear_local_answers();
+        }
+    }
+
+    /// 启动远程hosts的后台刷新线程, 刷新结果通过通道传回主线程
+    fn start_remote_refresh(&mut self) {
+        if self.remote_sources.is_empty() || self.remote_refresh == 0 {
+            return;
+        }
+
+        let (tx, rx) = channel();
+        let mut sources = std::mem::take(&mut self.remote_sources);
+        let interval = Duration::from_secs(self.remote_refresh);
+        self.remote_rx = Some(rx);
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            for (i, source) in sources.iter_mut().enumerate() {
+                match source.fetch().map(|config| config.map(HostTable::load)) {
+                    Ok(Some(table)) => {
+                        log::info!("remote hosts {} refreshed, {} hosts, {} blocked",
+                            source.url(), table.hosts.len(), table.blocked.len());
+                        if tx.send((i, table)).is_err() {
+                            return;
+                        }
+                    },
+                    Ok(None) => log::debug!("remote hosts {} not modified", source.url()),
+                    Err(e) => log::error!("remote hosts {} refresh failed: {:?}", source.url(), e),
+                }
+            }
+        });
+    }
+
+    /// 接收后台刷新线程下载的远程hosts, 替换对应来源的域名表
+    fn update_remote_hosts(&mut self) {
+        if let Some(ref rx) = self.remote_rx {
+            while let Ok((i, table)) = rx.try_recv() {
+                self.remote_tables[i] = table;
+                self.local_answers.get_mut().clear();
+            }
+        }
     }
 
     /// 获取下一个查询请求id
@@ -381,57 +2552,342 @@ impl DnsServer {
         self.curr_req_id
     }
 
-    /// 动态dns更新函数
-    fn dyn_dns(&mut self, req_buffer: &BytePacketBuffer, rep_addr: &SocketAddr) -> Result<bool> {
-        if req_buffer.len < C_DYNDNS_MIN_LEN
-                || &req_buffer.buf[..C_DNYDNS_MAGIC.len()] != C_DNYDNS_MAGIC {
-            return Ok(false);
+    /// 动态dns更新函数, 返回需要回复给客户端的内容, None表示不回复
+    fn dyn_dns(&mut self, data: &[u8], rep_addr: &SocketAddr) -> Option<String> {
+
+        // 处于封禁状态的来源地址, 直接丢弃数据包
+        let now = now_of_unix();
+        if self.auth_lock.is_banned(&rep_addr.ip(), now) {
+            log::debug!("dyndns packet from banned address {} ignored", rep_addr.ip());
+            self.audit(false, "banned", rep_addr, "", "", "");
+            return None;
         }
 
         // 解析包
-        let text = String::from_utf8_lossy(&req_buffer.buf[.. req_buffer.len]);
+        let text = String::from_utf8_lossy(data);
         log::debug!("dyndns packet received: {}", text);
         let params: Vec<&str> = text.split(' ').collect();
+        let json = params.last().map(|s| s.trim_end()) == Some(C_DYNDNS_FORMAT_JSON);
 
         // 校验参数数量
-        if params.len() < C_DYNDNS_PARAM_COUNT {
-            log::info!("dyndns packet format error");
-            self.socket.send_to("error".as_bytes(), *rep_addr).with_context(|| "dyndns packet format error")?;
-            return Ok(true);
+        if !is_dyn_dns(data) || params.len() < C_DYNDNS_PARAM_COUNT {
+            return self.dyn_dns_reject(rep_addr, json, "format", "", "");
         }
+        let json = params.get(C_DYNDNS_PARAM_FORMAT).map(|s| s.trim_end()) == Some(C_DYNDNS_FORMAT_JSON);
 
+        let (host, req_ip) = (params[C_DYNDNS_PARAM_HOST], params[C_DYNDNS_PARAM_IP]);
         log::debug!("dyndns packet: DIGEST = {}, ID = {}, HOST = {}, IP = {}",
-                params[C_DYNDNS_PARAM_DIGEST],
-                params[C_DYNDNS_PARAM_ID],
-                params[C_DYNDNS_PARAM_HOST],
-                params[C_DYNDNS_PARAM_IP]);
+                params[C_DYNDNS_PARAM_DIGEST], params[C_DYNDNS_PARAM_ID], host, req_ip);
 
         // 校验参数md5
-        if !check_dyndns_md5(&params, &self.key) {
-            log::info!("dyndns packet checksum error");
-            self.socket.send_to("error".as_bytes(), *rep_addr).with_context(|| "dyndns packet checksum error")?;
-            return Ok(true);
+        if !self.check_dyndns_keys(&params) {
+            if self.auth_lock.fail(rep_addr.ip(), now) {
+                log::warn!("dyndns too many authentication failures from {}, banned temporarily", rep_addr.ip());
+            }
+            return self.dyn_dns_reject(rep_addr, json, "checksum", host, req_ip);
         }
+        self.auth_lock.success(&rep_addr.ip());
+
         // 校验参数提交时间
-        if !check_dyndns_time(&params[C_DYNDNS_PARAM_ID])? {
-            log::info!("dyndns packet time error");
-            self.socket.send_to("error".as_bytes(), *rep_addr).with_context(|| "dyndns packet time error")?;
-            return Ok(true);
+        if !check_dyndns_time(params[C_DYNDNS_PARAM_ID]) {
+            return self.dyn_dns_reject(rep_addr, json, "time", host, req_ip);
+        }
+
+        // 校验域名是否在允许的后缀范围内
+        if !is_allowed_domain(host, &self.domains) {
+            return self.dyn_dns_reject(rep_addr, json, "domain", host, req_ip);
+        }
+
+        // 0.0.0.0或::表示使用请求的来源地址, 多个地址以逗号分隔(如双栈主机的ipv4,ipv6)
+        let ip = req_ip.split(',')
+            .map(|s| match s.trim() {
+                "0.0.0.0" | "::" => rep_addr.ip().to_canonical().to_string(),
+                s => s.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let old_ip = self.local.hosts.get(host).map(|addrs| join_ips(addrs));
+        if let Err(e) = self.update_host(host, &ip) {
+            log::info!("dyndns register host failed: {:?}", e);
+            return self.dyn_dns_reject(rep_addr, json, "address", host, &ip);
+        }
+        let old_ip = old_ip.as_deref().unwrap_or("");
+        self.audit(true, "ok", rep_addr, host, old_ip, &ip);
+        if let (Some(hook), true) = (&self.change_hook, old_ip != ip) {
+            hook.run(host, old_ip, &ip);
+        }
+        if self.lease_time > 0 {
+            self.leases.insert(host.to_string(), now.saturating_add(self.lease_time));
+        }
+
+        match json {
+            true => Some(json_reply("ok", host, &ip, self.ttl)),
+            false => Some(format!("{} {}", host, ip)),
+        }
+    }
+
+    /// 拒绝动态dns更新请求, 记录审计日志并回复错误信息
+    fn dyn_dns_reject(&mut self, rep_addr: &SocketAddr, json: bool, reason: &str, host: &str, ip: &str) -> Option<String> {
+        log::info!("dyndns packet from {} rejected: {}", rep_addr, reason);
+        let old_ip = self.local.hosts.get(host).map(|addrs| join_ips(addrs)).unwrap_or_default();
+        self.audit(false, reason, rep_addr, host, &old_ip, ip);
+        match json {
+            true => Some(json_reply(reason, host, ip, 0)),
+            false => Some(String::from("error")),
+        }
+    }
+
+    /// 写入动态dns审计日志
+    fn audit(&mut self, accepted: bool, reason: &str, addr: &SocketAddr, host: &str, old_ip: &str, new_ip: &str) {
+        if let Some(ref mut audit_log) = self.audit_log {
+            audit_log.write(&AuditRecord { time: now_of_unix(), accepted, reason, addr, host, old_ip, new_ip });
+        }
+    }
+
+    /// 使用所有有效密钥校验动态dns数据包, 任意一个密钥校验通过即可
+    fn check_dyndns_keys(&self, params: &[&str]) -> bool {
+        match self.key_file {
+            Some(ref key_file) => {
+                (!self.key.is_empty() && check_dyndns_md5(params, &self.key))
+                    || key_file.keys().iter().any(|key| check_dyndns_md5(params, key))
+            },
+            None => check_dyndns_md5(params, &self.key),
+        }
+    }
+
+}
+
+impl HostTable {
+
+    /// 逐条读取hosts解析器中的记录生成域名表, 不在内存中保留完整的条目列表,
+    /// 格式错误的行及无效的记录记录警告后跳过, 不影响其它记录
+    fn load(config: HostsConfig) -> HostTable {
+        let mut table = HostTable::default();
+        for entry in config {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("{e}, line ignored");
+                    continue;
+                },
+            };
+            if let Err(e) = table.add(&entry) {
+                log::warn!("hosts entry {} {:?} ignored: {}", entry.host, entry.record, e);
+            }
+        }
+        table
+    }
+
+    /// 删除域名的全部记录
+    fn remove(&mut self, host: &str) {
+        self.hosts.remove(host);
+        self.records.remove(host);
+        self.blocked.remove(host);
+    }
+
+    /// 添加一条hosts记录
+    fn add(&mut self, entry: &HostEntry) -> Result<()> {
+        match entry.record {
+            HostRecord::A(ref ip) => add_host(&mut self.hosts, &mut self.blocked, &entry.host, ip, entry.ttl),
+            ref record => {
+                self.records.entry(entry.host.clone()).or_default().push((record.clone(), entry.ttl));
+                Ok(())
+            },
+        }
+    }
+
+}
+
+/// 向域名字典添加域名, ip可以是逗号分隔的多个地址, 同一域名多次添加时累加为记录集,
+/// 地址为0.0.0.0且未指定ttl的屏蔽域名加入屏蔽集合, 以节省大型屏蔽列表的内存占用
+fn add_host(hosts: &mut Hosts, blocked: &mut BlockSet, host: &str, ip: &str, ttl: Option<u32>) -> Result<()> {
+    if ttl.is_none() && ip.trim() == "0.0.0.0" && !hosts.contains_key(host) {
+        blocked.insert(host);
+        return Ok(());
+    }
+    let addrs = parse_ips(ip, ttl)?;
+    let entry = hosts.entry(host.to_string()).or_default();
+    for addr in addrs {
+        match entry.iter_mut().find(|a| a.addr == addr.addr) {
+            Some(a) => a.ttl = addr.ttl,
+            None => entry.push(addr),
+        }
+    }
+    Ok(())
+}
+
+/// 在域名字典中查找域名, 精确匹配优先, 然后依次匹配以`.`开头的通配域名(匹配域名本身及其所有子域名)
+fn find_in_hosts<'a>(hosts: &'a Hosts, blocked: &BlockSet, qname: &str) -> Option<&'a [HostAddr]> {
+    match_host_names(qname, |name| match hosts.get(name) {
+        Some(addrs) => Some(addrs.as_slice()),
+        None if blocked.contains(name) => Some(BLOCKED_ADDRS),
+        None => None,
+    })
+}
+
+/// 按优先级依次以域名表中可以匹配查询域名的名称调用`f`, 返回首个非None的结果:
+/// 域名本身、以`.`开头的通配名称、各上级域名的通配名称, 通配名称在栈上拼接, 不分配内存
+fn match_host_names<T>(qname: &str, mut f: impl FnMut(&str) -> Option<T>) -> Option<T> {
+    if let Some(found) = f(qname) {
+        return Some(found);
+    }
+    let mut buf = [0u8; 256];
+    let found = match buf.get_mut(1..=qname.len()) {
+        Some(dst) => {
+            dst.copy_from_slice(qname.as_bytes());
+            buf[0] = b'.';
+            f(std::str::from_utf8(&buf[..=qname.len()]).unwrap_or_default())
+        },
+        None => f(&format!(".{qname}")),
+    };
+    found.or_else(|| qname.match_indices('.').find_map(|(i, _)| f(&qname[i..])))
+}
+
+/// 按原hosts文件内容导出域名, 已输出的域名记录在written中
+fn export_lines(out: &mut String, text: &str, hosts: &Hosts, blocked: &BlockSet, written: &mut HashSet<String>) {
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+
+        // 包含文件中的域名追加在末尾输出, 包含指令改为注释, 避免重新加载时重复
+        if trimmed.starts_with("#include ") || trimmed.starts_with("#include\t") {
+            out.push_str(&format!("# {line}\n"));
+            continue;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        if trimmed.split_whitespace().next().is_some_and(|t| t.eq_ignore_ascii_case("CNAME") || t.eq_ignore_ascii_case("TXT")) {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        if let Some(address) = trimmed.strip_prefix("address=/") {
+            let mut items: Vec<&str> = address.trim_end().split('/').collect();
+            items.pop();
+            written.extend(items.iter().map(|d| format!(".{}", d.trim_start_matches('.').to_lowercase())));
+            out.push_str(line);
+            out.push('\n');
+            continue;
         }
 
-        let ip = match params[C_DYNDNS_PARAM_IP] {
-            "0.0.0.0" => rep_addr.ip().to_string(),
-            s => s.to_string(),
+        let (content, comment) = match line.find('#') {
+            Some(pos) => (&line[..pos], Some(&line[pos..])),
+            None => (line, None),
         };
+        let host = match content.split_whitespace().nth(1) {
+            Some(host) => host,
+            None => {
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            },
+        };
+        if written.contains(host) {
+            continue;
+        }
+        if let Some(addrs) = hosts.get(host) {
+            for (i, addr) in addrs.iter().enumerate() {
+                export_addr(out, host, addr, if i == 0 { comment } else { None });
+            }
+            written.insert(host.to_string());
+        } else if blocked.contains(host) {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+}
+
+/// 导出原hosts文件中不存在的域名
+fn export_rest(out: &mut String, hosts: &Hosts, written: &HashSet<String>) {
+    let mut rest: Vec<_> = hosts.iter().filter(|(host, _)| !written.contains(*host)).collect();
+    if rest.is_empty() {
+        return;
+    }
+    rest.sort_by(|a, b| a.0.cmp(b.0));
+
+    out.push_str("# ---- dynamic ----\n");
+    for (host, addrs) in rest {
+        match host.strip_prefix('.') {
+            Some(domain) => out.push_str(&format!("address=/{domain}/{}\n", join_ips(addrs))),
+            None => addrs.iter().for_each(|addr| export_addr(out, host, addr, None)),
+        }
+    }
+}
+
+/// 输出一条hosts格式的地址记录
+fn export_addr(out: &mut String, host: &str, addr: &HostAddr, comment: Option<&str>) {
+    out.push_str(&format!("{} {host}", addr.addr));
+    if let Some(ttl) = addr.ttl {
+        out.push_str(&format!(" {ttl}"));
+    }
+    if let Some(comment) = comment {
+        out.push_str(&format!(" {comment}"));
+    }
+    out.push('\n');
+}
+
+/// 解析逗号分隔的ipv4/ipv6地址列表
+fn parse_ips(ip: &str, ttl: Option<u32>) -> Result<Vec<HostAddr>> {
+    ip.split(',')
+        .map(|s| match s.trim().parse() {
+            Ok(addr) => Ok(HostAddr { addr, ttl }),
+            Err(_) => anyhow::bail!("ip {s} isn't ipv4 or ipv6 address"),
+        })
+        .collect()
+}
+
+/// 将地址列表转为逗号分隔的字符串
+fn join_ips(addrs: &[HostAddr]) -> String {
+    addrs.iter().map(|addr| addr.addr.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// 删除并返回在`now`之前超时的局域网(mdns/llmnr/netbios)查询
+/// 域名地址对应查询类型的应答记录, 域名没有该类型的地址时(如仅有ipv4地址的屏蔽域名)返回全部地址
+fn host_answers(domain: &str, addrs: &[HostAddr], qtype: QueryType, default_ttl: u32) -> Vec<DnsRecord> {
+    let family: Vec<&HostAddr> = addrs.iter().filter(|a| match qtype {
+        QueryType::A => a.addr.is_ipv4(),
+        QueryType::AAAA => a.addr.is_ipv6(),
+        _ => true,
+    }).collect();
+    let addrs = if family.is_empty() { addrs.iter().collect() } else { family };
+    addrs.into_iter().map(|addr| host_record(domain, addr, default_ttl)).collect()
+}
 
-        self.register_host(params[C_DYNDNS_PARAM_HOST], &ip).with_context(|| "dyndns register host failed")?;
+/// 本地地址对应的A或AAAA记录
+fn host_record(domain: &str, addr: &HostAddr, default_ttl: u32) -> DnsRecord {
+    let ttl = addr.ttl.unwrap_or(default_ttl);
+    match addr.addr {
+        IpAddr::V4(addr) => DnsRecord::A { domain: domain.to_string(), addr, ttl },
+        IpAddr::V6(addr) => DnsRecord::AAAA { domain: domain.to_string(), addr, ttl },
+    }
+}
 
-        let rep = format!("{} {}", params[C_DYNDNS_PARAM_HOST], ip);
-        self.socket.send_to(rep.as_bytes(), *rep_addr)?;
+/// 判断数据包是否为动态dns更新数据包
+fn is_dyn_dns(data: &[u8]) -> bool {
+    data.len() >= C_DYNDNS_MIN_LEN && data.starts_with(C_DNYDNS_MAGIC)
+}
 
-        Ok(true)
+fn host_entry(host: &str, ip: &str, ttl: Option<u32>) -> HostEntry {
+    HostEntry {
+        host: host.trim_end_matches('.').to_ascii_lowercase(),
+        record: HostRecord::A(ip.to_string()),
+        ttl,
     }
+}
 
+/// 回复码计数器的名称
+fn rcode_metric(code: ResultCode) -> &'static str {
+    match code {
+        ResultCode::NOERROR => "dns.rcode.noerror",
+        ResultCode::FORMERR => "dns.rcode.formerr",
+        ResultCode::SERVFAIL => "dns.rcode.servfail",
+        ResultCode::NXDOMAIN => "dns.rcode.nxdomain",
+        ResultCode::NOTIMP => "dns.rcode.notimp",
+        ResultCode::REFUSED => "dns.rcode.refused",
+    }
 }
 
 /// 得到当前时间的unix时间表示(自1970-01-01以来的秒数)
@@ -439,12 +2895,58 @@ fn now_of_unix() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
+/// 从非阻塞连接读取一行请求追加到`data`(不含换行符), 先检查长度再追加, 超长的请求不再继续接收
+///
+/// 返回Ok(true)表示已读完一行或对端已关闭写入, Ok(false)表示需等待更多数据,
+/// 请求超过最大长度时返回InvalidData错误
+fn read_request_line(stream: &mut impl Read, data: &mut Vec<u8>) -> std::io::Result<bool> {
+    let mut buf = [0; C_DYNDNS_MAX_LEN];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => {
+                let line_end = buf[..n].iter().position(|c| *c == b'\n');
+                let len = line_end.unwrap_or(n);
+                if data.len() + len > C_DYNDNS_MAX_LEN {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request is too long"));
+                }
+                data.extend_from_slice(&buf[..len]);
+                if line_end.is_some() {
+                    return Ok(true);
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 /// 基于当前时间的过期时间
 fn expire_of_unix() -> u64 {
     now_of_unix() + QUERY_TIMEOUT
 }
 
-fn check_dyndns_md5(params: &Vec<&str>, key: &str) -> bool {
+/// 创建连接到上级dns服务器的socket, 地址族与上级服务器一致, 未指定上级服务器(0.0.0.0)时不连接
+fn connect_upstream(up_dns_addr: IpAddr) -> Result<UdpSocket> {
+    let bind_addr = match up_dns_addr {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(bind_addr)
+            .with_context(|| format!("bind dns parent server socket {bind_addr} failed"))?;
+    if !up_dns_addr.is_unspecified() {
+        socket.connect(SocketAddr::new(up_dns_addr, 53))
+                .with_context(|| format!("connect parent dns server {up_dns_addr} failed"))?;
+    }
+    Ok(socket)
+}
+
+/// 基于当前时间的查询超时时刻
+fn query_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(QUERY_TIMEOUT)
+}
+
+fn check_dyndns_md5(params: &[&str], key: &str) -> bool {
     let mut ctx = md5::Context::new();
     ctx.consume(params[C_DYNDNS_PARAM_ID].as_bytes());
     ctx.consume(params[C_DYNDNS_PARAM_HOST].as_bytes());
@@ -459,8 +2961,879 @@ fn check_dyndns_md5(params: &Vec<&str>, key: &str) -> bool {
     result
 }
 
-fn check_dyndns_time(id: &str) -> Result<bool> {
+fn check_dyndns_time(id: &str) -> bool {
     let now = now_of_unix() - C_2023_01_01;
-    let id_num: u64 = id.parse()?;
-    Ok(id_num <= now + C_DYNDNS_TIME_RANGE && id_num >= now - C_DYNDNS_TIME_RANGE)
+    match id.parse::<u64>() {
+        Ok(id_num) => id_num <= now + C_DYNDNS_TIME_RANGE && id_num + C_DYNDNS_TIME_RANGE >= now,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的客户端查询数据, 需要修改其它字段时使用结构体更新语法
+    fn query_data(addr: SocketAddr, name: &str, qtype: QueryType) -> QueryData {
+        QueryData {
+            id: 1,
+            addr,
+            question: DnsQuestion::new(name.to_string(), qtype),
+            forword: 0,
+            expire: query_deadline(),
+            count: Cell::new(0),
+            start: Instant::now(),
+            upstream: Cell::new(None),
+            sent: Cell::new(Instant::now()),
+            recursion: true,
+        }
+    }
+
+    /// 测试用的dns客户端, 向服务器提交查询并接收回复
+    struct TestClient(std::net::UdpSocket);
+
+    impl TestClient {
+        fn new() -> TestClient {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            TestClient(socket)
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.0.local_addr().unwrap()
+        }
+
+        fn query(&self, name: &str, qtype: QueryType) -> Query {
+            Query::new(query_data(self.addr(), name, qtype))
+        }
+
+        /// 提交查询, 不等待回复, 上级地址不可达时转发可能失败, 忽略错误
+        fn forward(&self, server: &mut DnsServer, name: &str, qtype: QueryType) {
+            let _ = server.handle_query(&self.query(name, qtype));
+        }
+
+        /// 提交查询并返回服务器的回复
+        fn ask(&self, server: &mut DnsServer, name: &str, qtype: QueryType) -> DnsPacket {
+            self.ask_query(server, &self.query(name, qtype))
+        }
+
+        fn ask_query(&self, server: &mut DnsServer, query: &Query) -> DnsPacket {
+            server.handle_query(query).unwrap();
+            self.recv()
+        }
+
+        fn recv(&self) -> DnsPacket {
+            let mut buf = [0u8; 512];
+            let n = self.0.recv(&mut buf).unwrap();
+            DnsPacket::from_bytes(&buf[..n]).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_export_hosts() {
+        let (mut hosts, mut blocked) = (Hosts::new(), BlockSet::default());
+        let text = "# local hosts\n127.0.0.1 a.lan # router\n127.0.0.2 b.lan 60\n127.0.0.9 gone.lan\n\
+            0.0.0.0 ad.com\naddress=/track.com/#\n#include hosts.d/*.hosts\n127.0.0.3 a.lan\n";
+        let data = text.replace("#include hosts.d/*.hosts\n", "").into_bytes();
+        for entry in HostsConfig::with_data("", data) {
+            let entry = entry.unwrap();
+            if let HostRecord::A(ref ip) = entry.record {
+                add_host(&mut hosts, &mut blocked, &entry.host, ip, entry.ttl).unwrap();
+            }
+        }
+        hosts.remove("gone.lan");
+        hosts.insert("nas.lan".to_string(), parse_ips("192.168.1.5", None).unwrap());
+
+        let mut out = String::new();
+        let mut written = HashSet::new();
+        export_lines(&mut out, text, &hosts, &blocked, &mut written);
+        export_rest(&mut out, &hosts, &written);
+        assert_eq!("# local hosts\n127.0.0.1 a.lan # router\n127.0.0.3 a.lan\n127.0.0.2 b.lan 60\n\
+            0.0.0.0 ad.com\naddress=/track.com/#\n# #include hosts.d/*.hosts\n# ---- dynamic ----\n192.168.1.5 nas.lan\n", out);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let data = b"127.0.0.5 nas.lan\n0.0.0.0 ad.com\n127.0.0.1 workstation.lan\n127.0.0.1 pc.lan\n".to_vec();
+        for entry in HostsConfig::with_data("", data) {
+            server.register_host(&entry.unwrap()).unwrap();
+        }
+        // 屏蔽日志用本地域名表识别客户端
+        assert_eq!(Some("pc.lan"), server.client_name(&IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(None, server.client_name(&IpAddr::from([127, 0, 0, 2])));
+        // 域名表变化后重新生成反向索引
+        server.update_host("pc2.lan", "127.0.0.2").unwrap();
+        assert_eq!(Some("pc2.lan"), server.client_name(&IpAddr::from([127, 0, 0, 2])));
+        server.set_block_log(true);
+
+        let blocked = Rc::new(Cell::new(0));
+        let counter = blocked.clone();
+        server.on_block(move |_, _| counter.set(counter.get() + 1));
+        server.on_query(|_, q| match q.name.as_str() {
+            "inject.lan" => HookAction::Answer(vec![DnsRecord::A { domain: q.name.clone(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 }]),
+            "deny.lan" => HookAction::Error(ResultCode::REFUSED),
+            _ => HookAction::Continue,
+        });
+        server.on_response(|_, q, rescode, answers| if q.name == "nas.lan" {
+            answers.iter_mut().for_each(|r| r.set_ttl(5));
+            *rescode = ResultCode::NOERROR;
+        });
+
+        let client = TestClient::new();
+        let mut ask = |name: &str| client.ask(&mut server, name, QueryType::A);
+
+        let packet = ask("inject.lan");
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 1)), packet.get_random_a());
+        assert_eq!(ResultCode::REFUSED, ask("deny.lan").header.rescode);
+        assert_eq!(5, ask("nas.lan").answers[0].ttl());
+        assert_eq!(0, blocked.get());
+        ask("ad.com");
+        assert_eq!(1, blocked.get());
+        assert_eq!(ResultCode::NXDOMAIN, ask("other.lan").header.rescode);
+    }
+
+    #[test]
+    fn test_host_handle() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let handle = server.host_handle();
+        let other = handle.clone();
+        std::thread::spawn(move || {
+            other.register("PC1.lan.", "192.168.1.10", Some(60)).unwrap();
+            other.register("pc2.lan", "192.168.1.11", None).unwrap();
+            other.register("pc2.lan", "192.168.1.12", None).unwrap();
+            other.register("bad.lan", "192.168.1", None).unwrap();
+        }).join().unwrap();
+        server.apply_host_commands();
+
+        let addrs = |server: &DnsServer, host: &str| server.find_host(host).map(|a| a.len());
+        assert_eq!(Some(1), addrs(&server, "pc1.lan"));
+        assert_eq!(Some(2), addrs(&server, "pc2.lan"));
+        assert_eq!(None, addrs(&server, "bad.lan"));
+
+        handle.replace("pc2.lan", "192.168.1.20", None).unwrap();
+        handle.unregister("pc1.lan").unwrap();
+        server.apply_host_commands();
+        assert_eq!(None, addrs(&server, "pc1.lan"));
+        assert_eq!(Some(1), addrs(&server, "pc2.lan"));
+
+        drop(server);
+        assert!(handle.register("pc3.lan", "192.168.1.30", None).is_err());
+    }
+
+    #[test]
+    fn test_dyndns_conns() {
+        use std::io::{Read, Write};
+
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        server.set_dyndns_listen("127.0.0.1:0").unwrap();
+        let addr = server.dyndns_listener.as_ref().unwrap().local_addr().unwrap();
+        let accept = |server: &mut DnsServer| {
+            std::thread::sleep(Duration::from_millis(50));
+            server.dyndns_accept().unwrap();
+        };
+
+        // 超过最大长度的请求直接关闭连接
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        accept(&mut server);
+        let token = *server.dyndns_conns.keys().next().unwrap();
+        client.write_all(&[b'a'; C_DYNDNS_MAX_LEN + 1]).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        server.dyndns_conn_recv(token);
+        assert!(server.dyndns_conns.is_empty());
+        assert_eq!(0, client.read(&mut [0u8; 16]).unwrap_or(0));
+
+        // 连接数达到上限后不再接受新连接
+        let clients: Vec<_> = (0..C_DYNDNS_MAX_CONNS + 1).map(|_| std::net::TcpStream::connect(addr).unwrap()).collect();
+        accept(&mut server);
+        assert_eq!(C_DYNDNS_MAX_CONNS, server.dyndns_conns.len());
+        drop(clients);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_control_conns() {
+        use std::io::{Read, Write};
+
+        let path = std::env::temp_dir().join(format!("minidns-control-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        // 路径是普通文件时不删除, 返回错误
+        std::fs::write(path, b"data").unwrap();
+        assert!(server.set_control_socket(path).is_err());
+        std::fs::remove_file(path).unwrap();
+        server.set_control_socket(path).unwrap();
+
+        // 命令分多次发送, 未收到换行符前不会阻塞等待
+        let mut client = std::os::unix::net::UnixStream::connect(path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        server.control_accept();
+        let token = *server.control_conns.keys().next().unwrap();
+        client.write_all(b"log-le").unwrap();
+        server.conn_recv(token);
+        assert_eq!(1, server.control_conns.len());
+        client.write_all(b"vel\n").unwrap();
+        server.conn_recv(token);
+        assert!(server.control_conns.is_empty());
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("log level: "));
+        assert!(server.control_command("log-level a=b=c").starts_with("error: "));
+
+        // 大于socket缓冲区的回复在连接可写时继续发送
+        for i in 0..20000 {
+            server.register_host(&host_entry(&format!("host{i}.dyn.lan"), "192.168.1.5", None)).unwrap();
+        }
+        let mut client = std::os::unix::net::UnixStream::connect(path).unwrap();
+        client.write_all(b"export\n").unwrap();
+        server.control_accept();
+        let token = *server.control_conns.keys().next().unwrap();
+        server.conn_recv(token);
+        assert_eq!(1, server.control_conns.len());
+        let reader = std::thread::spawn(move || {
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).map(|_| reply)
+        });
+        while !server.control_conns.is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+            server.conn_recv(token);
+        }
+        let reply = reader.join().unwrap().unwrap();
+        assert_eq!(20001, reply.lines().count());
+        assert!(reply.contains("192.168.1.5 host19999.dyn.lan\n"));
+
+        // 连接数达到上限后不再接受新连接
+        let clients: Vec<_> = (0..MAX_CONTROL_CONNS + 1).map(|_| std::os::unix::net::UnixStream::connect(path).unwrap()).collect();
+        server.control_accept();
+        assert_eq!(MAX_CONTROL_CONNS, server.control_conns.len());
+        server.clear_conns_of_timeout(now_of_unix() + QUERY_TIMEOUT + 1);
+        assert!(server.control_conns.is_empty());
+        drop(clients);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_dual_stack_host() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        server.update_host("pc.lan", "192.168.1.10,fd00::10").unwrap();
+        server.update_host("v4.lan", "192.168.1.20").unwrap();
+
+        let lookup = |server: &DnsServer, host: &str, qtype: QueryType| server.local_lookup(host, qtype)
+            .map(|records| records.iter().map(|r| format!("{r:?}")).collect::<Vec<_>>().join(" "));
+        assert!(lookup(&server, "pc.lan", QueryType::A).unwrap().contains("192.168.1.10"));
+        let aaaa = lookup(&server, "pc.lan", QueryType::AAAA).unwrap();
+        assert!(aaaa.starts_with("AAAA") && aaaa.contains("fd00::10") && !aaaa.contains("192.168.1.10"));
+        // 没有ipv6地址时返回ipv4地址
+        assert!(lookup(&server, "v4.lan", QueryType::AAAA).unwrap().contains("192.168.1.20"));
+
+        // 只替换同类地址
+        server.update_host("pc.lan", "192.168.1.11").unwrap();
+        server.update_host("pc.lan", "fd00::11").unwrap();
+        assert_eq!(Some(String::from("192.168.1.11,fd00::11")), server.find_host("pc.lan").map(join_ips));
+        assert!(server.update_host("pc.lan", "fd00::zz").is_err());
+
+        // 查询结果共享至域名表变化
+        let records = server.local_lookup("pc.lan", QueryType::A).unwrap();
+        assert!(Arc::ptr_eq(&records, &server.local_lookup("pc.lan", QueryType::A).unwrap()));
+        server.update_host("pc.lan", "192.168.1.12").unwrap();
+        assert!(lookup(&server, "pc.lan", QueryType::A).unwrap().contains("192.168.1.12"));
+        assert_eq!(None, server.local_lookup("nas.lan", QueryType::A));
+
+        // 通配域名匹配域名本身及其子域名, 超长域名同样可以匹配
+        server.update_host(".home", "192.168.1.30").unwrap();
+        let long = format!("{}.home", "a".repeat(300));
+        for host in ["home", "pc.home", long.as_str()] {
+            assert!(lookup(&server, host, QueryType::A).unwrap().contains("192.168.1.30"), "{host}");
+        }
+    }
+
+    #[test]
+    fn test_lease_restores_static_host() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let data = b"192.168.1.1 router.lan\n".to_vec();
+        for entry in HostsConfig::with_data("", data) {
+            server.local.add(&entry.unwrap()).unwrap();
+        }
+        // 动态dns覆盖静态域名及新建域名, 租约过期后前者恢复静态地址, 后者删除
+        let now = now_of_unix();
+        for (host, ip) in [("router.lan", "10.0.0.1"), ("pc.lan", "10.0.0.2")] {
+            server.update_host(host, ip).unwrap();
+            server.leases.insert(host.to_string(), now + 60);
+        }
+        server.update_host("router.lan", "10.0.0.3").unwrap();
+        let addr = |server: &DnsServer, host: &str| server.find_host(host).map(join_ips);
+        assert_eq!(Some(String::from("10.0.0.3")), addr(&server, "router.lan"));
+        server.clear_leases_of_expired(now + 61);
+        assert_eq!(Some(String::from("192.168.1.1")), addr(&server, "router.lan"));
+        assert_eq!(None, addr(&server, "pc.lan"));
+        assert!(server.runtime_hosts.is_empty() && server.overridden.is_empty());
+    }
+
+    #[test]
+    fn test_reload_hosts() {
+        let path = std::env::temp_dir().join("minidns_reload_test.hosts");
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "192.168.1.1 router.lan\n0.0.0.0 ad.com\n").unwrap();
+
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        server.load_hosts_file(&path).unwrap();
+        server.update_host("pc.lan", "192.168.1.10").unwrap();
+        let handle = server.host_handle();
+        handle.register("nas.lan", "192.168.1.5", None).unwrap();
+        handle.set_ttl(60).unwrap();
+        server.apply_host_commands();
+        assert_eq!(60, server.ttl);
+
+        std::fs::write(&path, "192.168.1.2 router.lan\n0.0.0.0 tracker.com\n192.168.1.6 nas.lan\n").unwrap();
+        handle.reload_hosts(vec![path.clone()]).unwrap();
+        server.apply_host_commands();
+        let addr = |server: &DnsServer, host: &str| server.find_host(host).map(|a| a[0].addr.to_string());
+        assert_eq!(Some(String::from("192.168.1.2")), addr(&server, "router.lan"));
+        assert_eq!(None, addr(&server, "ad.com"));
+        assert_eq!(Some(String::from("0.0.0.0")), addr(&server, "tracker.com"));
+        assert_eq!(Some(String::from("192.168.1.10")), addr(&server, "pc.lan"));
+        // 文件中的域名优先于运行时注册的同名域名
+        assert_eq!(Some(String::from("192.168.1.6")), addr(&server, "nas.lan"));
+        assert!(!server.runtime_hosts.contains("nas.lan"));
+
+        // 加载失败时保留原来的域名表
+        handle.reload_hosts(vec![String::from("/nonexistent/hosts")]).unwrap();
+        server.apply_host_commands();
+        assert_eq!(Some(String::from("192.168.1.2")), addr(&server, "router.lan"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_script() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let data = b"127.0.0.5 nas.lan\n".to_vec();
+        for entry in HostsConfig::with_data("", data) {
+            server.register_host(&entry.unwrap()).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("mdns-script-{}.rules", std::process::id()));
+        std::fs::write(&path, "qname == tv.lan => answer 10.0.0.7 60
+qname ~ *.nas.lan => rewrite nas.lan
+            qname == nas.lan => refused
+qname ~ *.ads.com => nxdomain
+").unwrap();
+        server.set_script_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(vec!["local", "script", "forward"], server.resolvers.iter().map(|r| r.name()).collect::<Vec<_>>());
+
+        let client = TestClient::new();
+        let mut ask = |name: &str| client.ask(&mut server, name, QueryType::A);
+
+        let packet = ask("tv.lan");
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 7)), packet.get_random_a());
+        assert_eq!(60, packet.answers[0].ttl());
+        let packet = ask("www.nas.lan");
+        assert_eq!(2, packet.answers.len());
+        assert_eq!(Some(Ipv4Addr::new(127, 0, 0, 5)), packet.get_random_a());
+        // 本地域名表优先于脚本
+        assert_eq!(ResultCode::NOERROR, ask("nas.lan").header.rescode);
+        assert_eq!(ResultCode::NXDOMAIN, ask("x.ads.com").header.rescode);
+    }
+
+    #[test]
+    fn test_client_groups() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        let data = b"127.0.0.5 nas.lan\n".to_vec();
+        for entry in HostsConfig::with_data("", data) {
+            server.register_host(&entry.unwrap()).unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("mdns-groups-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("kids.hosts"), "0.0.0.0 games.com\n10.0.0.9 nas.lan\n").unwrap();
+        std::fs::write(dir.join("kids.rules"), "qname ~ *.video.com => nxdomain\n").unwrap();
+        std::fs::write(dir.join("groups.toml"), "[[group]]\nname = \"guest\"\nclients = [\"192.168.9.0/24\"]\n\
+            [[group]]\nname = \"kids\"\nclients = [\"127.0.0.1/32\"]\nhosts = [\"kids.hosts\"]\nscript = \"kids.rules\"\n\
+            upstream = \"127.0.0.78\"\n").unwrap();
+        server.load_client_groups(dir.join("groups.toml").to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(Some("kids"), server.client_group_name(&"::ffff:127.0.0.1".parse().unwrap()));
+        assert_eq!(Some("guest"), server.client_group_name(&"192.168.9.3".parse().unwrap()));
+        assert_eq!(None, server.client_group_name(&"10.1.1.1".parse().unwrap()));
+
+        let client = TestClient::new();
+
+        // 组的hosts及规则脚本优先于全局的域名表
+        assert_eq!(Some(Ipv4Addr::UNSPECIFIED), client.ask(&mut server, "games.com", QueryType::A).get_random_a());
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 9)), client.ask(&mut server, "nas.lan", QueryType::A).get_random_a());
+        assert_eq!(ResultCode::NXDOMAIN, client.ask(&mut server, "www.video.com", QueryType::A).header.rescode);
+
+        // 其余查询转发给组的上级dns服务器, 组的上级地址不可达, 转发可能失败
+        client.forward(&mut server, "www.example.com", QueryType::A);
+        let query = server.queries.get(&server.curr_req_id).unwrap();
+        assert_eq!(Some(IpAddr::from([127, 0, 0, 78])), query.upstream.get());
+
+        // 设置了上级的分组不使用全局缓存, 仍转发给组的上级
+        server.set_resolver_chain("cache,local,forward").unwrap();
+        let question = DnsQuestion::new("cached.example.com".to_string(), QueryType::A);
+        let record = DnsRecord::A { domain: "cached.example.com".to_string(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 };
+        server.notify_resolvers(&question, &DnsPacket::builder().question(question.clone()).answer(record).build());
+        client.forward(&mut server, "cached.example.com", QueryType::A);
+        let query = server.queries.get(&server.curr_req_id).unwrap();
+        assert_eq!(("cached.example.com", Some(IpAddr::from([127, 0, 0, 78]))), (query.question.name.as_str(), query.upstream.get()));
+    }
+
+    #[test]
+    fn test_dhcp_leases() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let data = b"192.168.1.2 router.lan\n".to_vec();
+        for entry in HostsConfig::with_data("", data) {
+            server.register_host(&entry.unwrap()).unwrap();
+        }
+        let now = now_of_unix();
+        let path = std::env::temp_dir().join(format!("mdns-dhcp-{}.leases", std::process::id()));
+        std::fs::write(&path, format!("{} 3c:22:fb:01:02:03 192.168.1.20 laptop *\n\
+            {} 3c:22:fb:01:02:04 192.168.1.21 phone *\n\
+            0 3c:22:fb:01:02:05 192.168.1.22 router *\n\
+            {} 3c:22:fb:01:02:06 192.168.1.23 old *\n", now + 60, now + 3600, now - 10)).unwrap();
+        server.set_dhcp_leases(path.to_str().unwrap(), ".LAN").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 生存时间不超过租约的剩余时间, 静态域名优先, 已过期的租约忽略
+        let lookup = |server: &DnsServer, name: &str| server.find_host(name).map(|addrs| addrs[0]);
+        let laptop = lookup(&server, "laptop.lan").unwrap();
+        assert_eq!(("192.168.1.20".parse().unwrap(), Some(60)), (laptop.addr, laptop.ttl));
+        assert_eq!(Some(300), lookup(&server, "phone.lan").unwrap().ttl);
+        assert_eq!("192.168.1.2".parse::<IpAddr>().unwrap(), lookup(&server, "router.lan").unwrap().addr);
+        assert_eq!(None, lookup(&server, "old.lan"));
+
+        // 租约文件变化后删除已释放的租约
+        let lease = |host: &str, ip: &str, expire| DhcpLease { host: host.to_string(), ip: ip.parse().unwrap(), expire };
+        server.apply_dhcp_leases(vec![lease("phone", "192.168.1.31", Some(now + 3600))], now);
+        assert_eq!(None, lookup(&server, "laptop.lan"));
+        assert_eq!("192.168.1.31".parse::<IpAddr>().unwrap(), lookup(&server, "phone.lan").unwrap().addr);
+        // 重新加载hosts文件时保留, 租约到期后删除
+        server.reload_hosts_files(Vec::new()).unwrap();
+        assert!(lookup(&server, "phone.lan").is_some());
+        server.clear_leases_of_expired(now + 3601);
+        assert_eq!(None, lookup(&server, "phone.lan"));
+        assert!(server.dhcp_hosts.is_empty());
+
+        // 保留名称及与本地域名后缀相同的单标签主机名不注册
+        server.dhcp_domain.clear();
+        server.register_host(&host_entry("nas.lan", "192.168.1.5", None)).unwrap();
+        server.apply_dhcp_leases(vec![lease("wpad", "192.168.1.40", None), lease("lan", "192.168.1.41", None),
+                lease("tv", "192.168.1.42", None)], now);
+        assert_eq!(None, lookup(&server, "wpad"));
+        assert_eq!(None, lookup(&server, "lan"));
+        assert!(lookup(&server, "tv").is_some());
+    }
+
+    #[test]
+    fn test_mdns_bridge() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let responder = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        responder.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        server.mdns_bridge = Some(MdnsBridge::with_target("mdns.lan", responder.local_addr().unwrap()).unwrap());
+
+        let client = TestClient::new();
+        let ask = |server: &mut DnsServer, name: &str| server.handle_query(&client.query(name, QueryType::A)).unwrap();
+
+        // 桥接后缀下的查询转为mdns查询, 应答方单播回复
+        ask(&mut server, "printer.mdns.lan");
+        let mut buf = [0u8; 512];
+        let (n, from) = responder.recv_from(&mut buf).unwrap();
+        let request = DnsPacket::from_bytes(&buf[..n]).unwrap();
+        assert_eq!("printer.local", request.questions[0].name);
+        let record = DnsRecord::A { domain: "printer.local".to_string(), addr: Ipv4Addr::new(192, 168, 1, 30), ttl: 120 };
+        let mut response = BytePacketBuffer::new();
+        DnsPacket::builder().id(request.header.id).response(ResultCode::NOERROR).answer(record).build().write(&mut response).unwrap();
+        responder.send_to(response.data(), from).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        server.mdns_recv(&mut BytePacketBuffer::new());
+        let packet = client.recv();
+        assert_eq!(vec![DnsRecord::A { domain: "printer.mdns.lan".to_string(), addr: Ipv4Addr::new(192, 168, 1, 30), ttl: 120 }], packet.answers);
+        assert!(server.mdns_queries.is_empty());
+
+        // 缓存期间直接回复
+        assert_eq!(Some(Ipv4Addr::new(192, 168, 1, 30)), client.ask(&mut server, "printer.mdns.lan", QueryType::A).get_random_a());
+
+        // 不允许递归的客户端不触发mdns查询
+        server.set_recursion_acl("192.168.0.0/16").unwrap();
+        assert_ne!(ResultCode::NOERROR, client.ask(&mut server, "scanner.mdns.lan", QueryType::A).header.rescode);
+        assert!(server.mdns_queries.is_empty());
+        server.set_recursion_acl("").unwrap();
+
+        // 超时未应答回复域名不存在
+        ask(&mut server, "scanner.mdns.lan");
+        assert_eq!(1, server.mdns_queries.len());
+        server.mdns_queries.expire_all(Instant::now());
+        server.clear_lan_queries_of_timeout();
+        assert_eq!(ResultCode::NXDOMAIN, client.recv().header.rescode);
+    }
+
+    #[test]
+    fn test_name_fallback() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let llmnr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let netbios = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        llmnr.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        netbios.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        server.name_fallback = Some(NameFallback::with_targets("llmnr,netbios",
+            llmnr.local_addr().unwrap(), netbios.local_addr().unwrap()).unwrap());
+
+        let client = TestClient::new();
+        let ask = |server: &mut DnsServer, name: &str, qtype: QueryType| server.handle_query(&client.query(name, qtype)).unwrap();
+        let mut buf = [0u8; 512];
+
+        // A记录同时发送llmnr及netbios查询, 以netbios应答回复
+        ask(&mut server, "FileServer", QueryType::A);
+        let (n, _) = llmnr.recv_from(&mut buf).unwrap();
+        assert_eq!("fileserver", DnsPacket::from_bytes(&buf[..n]).unwrap().questions[0].name);
+        let (n, from) = netbios.recv_from(&mut buf).unwrap();
+        let mut response = buf[..n].to_vec();
+        response[2..8].copy_from_slice(&[0x85, 0, 0, 0, 0, 1]);
+        response.extend_from_slice(&[0, 0, 0, 120, 0, 6, 0, 0, 192, 168, 1, 40]);
+        netbios.send_to(&response, from).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        server.fallback_recv(&mut BytePacketBuffer::new());
+        let packet = client.recv();
+        assert_eq!(vec![DnsRecord::A { domain: "fileserver".to_string(), addr: Ipv4Addr::new(192, 168, 1, 40), ttl: 60 }], packet.answers);
+        assert!(server.fallback_queries.is_empty());
+
+        // AAAA记录只发送llmnr查询
+        ask(&mut server, "nas", QueryType::AAAA);
+        let (n, from) = llmnr.recv_from(&mut buf).unwrap();
+        let request = DnsPacket::from_bytes(&buf[..n]).unwrap();
+        let record = DnsRecord::AAAA { domain: "nas".to_string(), addr: "fd00::9".parse().unwrap(), ttl: 30 };
+        let mut response = BytePacketBuffer::new();
+        DnsPacket::builder().id(request.header.id).response(ResultCode::NOERROR).question(request.questions[0].clone())
+            .answer(record.clone()).build().write(&mut response).unwrap();
+        llmnr.send_to(response.data(), from).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        server.fallback_recv(&mut BytePacketBuffer::new());
+        assert_eq!(vec![record], client.recv().answers);
+
+        // 多标签域名照常解析, 超时未应答回复域名不存在
+        ask(&mut server, "www.example.com", QueryType::A);
+        assert!(server.fallback_queries.is_empty());
+        client.recv();
+        ask(&mut server, "printer", QueryType::A);
+        server.fallback_queries.expire_all(Instant::now());
+        server.clear_lan_queries_of_timeout();
+        assert_eq!(ResultCode::NXDOMAIN, client.recv().header.rescode);
+
+        // 不允许递归的客户端不触发llmnr/netbios查询
+        server.set_recursion_acl("192.168.0.0/16").unwrap();
+        assert_ne!(ResultCode::NOERROR, client.ask(&mut server, "scanner", QueryType::A).header.rescode);
+        assert!(server.fallback_queries.is_empty());
+    }
+
+    #[test]
+    fn test_queries_expire() {
+        let query = |expire: Instant| Query::new(QueryData { expire, ..query_data("127.0.0.1:1000".parse().unwrap(), "www.lan", QueryType::A) });
+        let now = Instant::now();
+        let mut queries = Queries::new();
+        queries.insert(1, query(now + Duration::from_secs(3)));
+        queries.insert(2, query(now + Duration::from_secs(1)));
+        queries.insert(3, query(now + Duration::from_secs(2)));
+        assert_eq!(Some(now + Duration::from_secs(1)), queries.next_deadline());
+
+        // 已回复的查询及被新查询复用的请求id不计为超时
+        queries.remove(&3);
+        queries.insert(2, query(now + Duration::from_secs(5)));
+        assert_eq!(0, queries.expire(now).len());
+        assert_eq!(0, queries.expire(now + Duration::from_secs(2)).len());
+        assert_eq!(1, queries.expire(now + Duration::from_secs(3)).len());
+        assert!(queries.get(&1).is_none());
+        assert_eq!(Some(now + Duration::from_secs(5)), queries.next_deadline());
+        assert_eq!(1, queries.expire(now + Duration::from_secs(5)).len());
+        assert_eq!(0, queries.len());
+        assert_eq!(None, queries.next_deadline());
+    }
+
+    #[test]
+    fn test_event_tuner() {
+        // 固定模式只统计, 不调整容量及超时时间
+        let mut tuner = EventTuner::new(4, Duration::from_secs(10), false);
+        assert!(!tuner.observe(4));
+        assert!(!tuner.observe(0));
+        assert_eq!(EventStats { capacity: 4, poll_timeout: Duration::from_secs(10), peak: 4, full_polls: 1, resizes: 0 }, tuner.stats);
+
+        let mut tuner = EventTuner::new(4, Duration::from_millis(300), true);
+        assert_eq!(MIN_POLL_TIMEOUT, tuner.stats.poll_timeout);
+        assert!(tuner.observe(4));
+        assert!(tuner.observe(8));
+        assert_eq!(16, tuner.stats.capacity);
+
+        // 空闲时poll超时逐次翻倍直至最大值, 有事件时恢复
+        assert!(!tuner.observe(0));
+        assert!(!tuner.observe(0));
+        assert_eq!(Duration::from_millis(300), tuner.stats.poll_timeout);
+        assert!(!tuner.observe(1));
+        assert_eq!(MIN_POLL_TIMEOUT, tuner.stats.poll_timeout);
+
+        // 持续低负载后容量减半, 不低于初始容量
+        let shrinks = (0..EVENT_SHRINK_POLLS * 3).filter(|_| tuner.observe(1)).count();
+        assert_eq!(2, shrinks);
+        assert_eq!(4, tuner.stats.capacity);
+        assert_eq!(4, tuner.stats.resizes);
+    }
+
+    #[test]
+    fn test_upstream_unreachable() {
+        // 上级dns服务器的端口没有监听, 已连接的socket收到端口不可达后立即回复SERVFAIL
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        let client = TestClient::new();
+        let query = client.query("www.example.com", QueryType::A);
+        server.handle_query(&query).unwrap();
+        assert_eq!(1, server.queries.len());
+        assert_eq!(Some(IpAddr::from([127, 0, 0, 77])), query.upstream.get());
+
+        std::thread::sleep(Duration::from_millis(100));
+        let mut req_buffer = BytePacketBuffer::new();
+        server.client_recv(&mut req_buffer, UP_SERVER_TOKEN).unwrap();
+        assert_eq!(0, server.queries.len());
+        assert_eq!(ResultCode::SERVFAIL, client.recv().header.rescode);
+
+        // 一个错误只结束最早发出的查询
+        let (a, b) = (client.query("a.example.com", QueryType::A), client.query("b.example.com", QueryType::A));
+        b.sent.set(a.sent.get() + Duration::from_millis(1));
+        server.queries.insert(2, b);
+        server.queries.insert(3, a);
+        server.fail_upstream_query(&std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(1, server.queries.len());
+        let resp = client.recv();
+        assert_eq!((ResultCode::SERVFAIL, "a.example.com"), (resp.header.rescode, resp.questions[0].name.as_str()));
+        assert_eq!("b.example.com", server.queries.iter().next().unwrap().1.question.name);
+
+        // ipv6的域名服务器使用独立的socket发送
+        if server.ns_socket6.is_some() {
+            let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+            server.send_request(&"::1".parse().unwrap(), 1, &question).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_slow_query() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        let query = Query::new(QueryData {
+            start: Instant::now() - Duration::from_millis(20),
+            ..query_data("127.0.0.1:1000".parse().unwrap(), "www.example.com", QueryType::A)
+        });
+        query.upstream.set(Some(IpAddr::from([127, 0, 0, 77])));
+        let elapsed = query.start.elapsed();
+        assert_eq!(None, server.slow_query_message(&query, ResultCode::NOERROR, elapsed));
+
+        // 超过阈值的查询记录客户端、回复码及使用的上级dns
+        server.set_slow_query(Duration::from_millis(10));
+        let msg = server.slow_query_message(&query, ResultCode::SERVFAIL, elapsed).unwrap();
+        assert!(msg.starts_with("slow query www.example.com A from 127.0.0.1:1000 took "));
+        assert!(msg.ends_with("rcode SERVFAIL, upstream 127.0.0.77, forwards 0"));
+        assert_eq!(None, server.slow_query_message(&query, ResultCode::NOERROR, Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        assert_eq!(FailurePolicy::Stale, " Stale".parse::<FailurePolicy>().unwrap());
+        assert!("refused".parse::<FailurePolicy>().is_err());
+
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        server.set_resolver_chain("cache,forward").unwrap();
+        let cached = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let record = DnsRecord::A { domain: "www.example.com".to_string(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 };
+        server.notify_resolvers(&cached, &DnsPacket::builder().question(cached.clone()).answer(record).build());
+
+        let client = TestClient::new();
+        let fail = |server: &mut DnsServer, name: &str| {
+            server.response_upstream_failure(&client.query(name, QueryType::A));
+            client.recv()
+        };
+
+        assert_eq!(ResultCode::SERVFAIL, fail(&mut server, "www.example.com").header.rescode);
+        server.set_failure_policy(FailurePolicy::NxDomain);
+        assert_eq!(ResultCode::NXDOMAIN, fail(&mut server, "www.example.com").header.rescode);
+
+        // stale策略回复缓存的结果, 没有缓存时回复SERVFAIL
+        server.set_failure_policy(FailurePolicy::Stale);
+        let packet = fail(&mut server, "www.example.com");
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(matches!(packet.answers[..], [DnsRecord::A { addr, .. }] if addr == Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(ResultCode::SERVFAIL, fail(&mut server, "other.example.com").header.rescode);
+    }
+
+    #[test]
+    fn test_servfail_cache() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        // 上级地址不可达, 转发可能失败, 只检查是否尝试转发
+        let client = TestClient::new();
+
+        // 上级回复SERVFAIL时原样回复客户端并缓存
+        client.forward(&mut server, "broken.example.com", QueryType::A);
+        let question = DnsQuestion::new("broken.example.com".to_string(), QueryType::A);
+        let response = DnsPacket::builder().id(server.curr_req_id).response(ResultCode::SERVFAIL).question(question).build();
+        server.handle_response(&response).unwrap();
+        assert_eq!(ResultCode::SERVFAIL, client.recv().header.rescode);
+        assert_eq!(0, server.queries.len());
+
+        // 缓存期间相同的查询不再转发
+        client.forward(&mut server, "Broken.example.com", QueryType::A);
+        assert_eq!(ResultCode::SERVFAIL, client.recv().header.rescode);
+        assert_eq!(0, server.queries.len());
+        client.forward(&mut server, "broken.example.com", QueryType::AAAA);
+        assert_eq!(1, server.queries.len());
+
+        server.set_servfail_ttl(Duration::ZERO);
+        client.forward(&mut server, "broken.example.com", QueryType::A);
+        assert_eq!(2, server.queries.len());
+    }
+
+    #[test]
+    fn test_upstream_stats() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        server.set_servfail_ttl(Duration::ZERO);
+        let client = TestClient::new();
+
+        // 一次成功的回复及一次SERVFAIL
+        for rescode in [ResultCode::NOERROR, ResultCode::SERVFAIL] {
+            // 清除上次发送引起的端口不可达错误
+            std::thread::sleep(Duration::from_millis(50));
+            let _ = server.up_socket.take_error();
+            server.handle_query(&client.query("www.example.com", QueryType::A)).unwrap();
+            let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+            let record = DnsRecord::A { domain: "www.example.com".to_string(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 };
+            let response = DnsPacket::builder().id(server.curr_req_id).response(rescode).question(question).answer(record).build();
+            server.handle_response(&response).unwrap();
+            client.recv();
+        }
+        // 超时未回复
+        let expired = Query::new(QueryData { expire: Instant::now(), ..query_data(client.addr(), "slow.example.com", QueryType::A) });
+        expired.upstream.set(Some(IpAddr::from([127, 0, 0, 77])));
+        server.queries.insert(100, expired);
+        server.clear_queries_of_timeout();
+        client.recv();
+
+        let stats = server.upstream_stats();
+        assert_eq!(1, stats.len());
+        let (addr, s) = stats[0];
+        assert_eq!(IpAddr::from([127, 0, 0, 77]), addr);
+        assert_eq!((2, 2, 1, 1), (s.queries, s.responses, s.errors, s.timeouts));
+        assert_eq!(1.0, s.error_rate());
+        assert!(s.latency_max >= s.avg_latency());
+        let report = server.control_command("upstreams");
+        assert!(report.lines().nth(1).unwrap().starts_with("127.0.0.77 "));
+    }
+
+    #[test]
+    fn test_search_domains() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        server.register_host(&host_entry("nas.home.lan", "192.168.1.2", None)).unwrap();
+        server.register_host(&host_entry("printer", "192.168.1.9", None)).unwrap();
+        // 上级地址不可达, 转发可能失败, 只检查是否尝试转发
+        let client = TestClient::new();
+        let ask = |server: &mut DnsServer, name: &str| client.forward(server, name, QueryType::A);
+
+        // 未设置搜索域时单标签域名照常转发
+        ask(&mut server, "nas");
+        assert_eq!(1, server.queries.len());
+
+        server.set_search_domains("example.com, .home.lan");
+        ask(&mut server, "nas");
+        let packet = client.recv();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(matches!(&packet.answers[..], [DnsRecord::CNAME { domain, host, .. }, DnsRecord::A { addr, .. }]
+            if domain == "nas" && host == "nas.home.lan" && *addr == Ipv4Addr::new(192, 168, 1, 2)));
+
+        // 本地可直接解析的单标签域名及多标签域名不补全
+        ask(&mut server, "printer");
+        let packet = client.recv();
+        assert!(matches!(&packet.answers[..], [DnsRecord::A { domain, .. }] if domain == "printer"));
+        ask(&mut server, "tv");
+        ask(&mut server, "nas.lan");
+        assert_eq!(3, server.queries.len());
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let data = b"CNAME a.lan b.lan\nCNAME b.lan c.lan\nCNAME c.lan d.lan\n127.0.0.5 d.lan\nCNAME x.lan y.lan\nCNAME y.lan x.lan\n".to_vec();
+        for entry in HostsConfig::with_data("", data) {
+            server.register_host(&entry.unwrap()).unwrap();
+        }
+        let client = TestClient::new();
+        let ask = |server: &mut DnsServer, name: &str, count: u8| {
+            let query = client.query(name, QueryType::A);
+            query.count.set(count);
+            client.ask_query(server, &query)
+        };
+
+        assert_eq!(4, ask(&mut server, "a.lan", 0).answers.len());
+        // 循环的别名链在超过跟随次数后回复SERVFAIL
+        let packet = ask(&mut server, "x.lan", 0);
+        assert_eq!(ResultCode::SERVFAIL, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        server.set_query_limits(10, 2, 16);
+        assert_eq!(ResultCode::SERVFAIL, ask(&mut server, "a.lan", 0).header.rescode);
+        assert_eq!(ResultCode::NOERROR, ask(&mut server, "b.lan", 0).header.rescode);
+        // 转发跳转次数与别名跟随次数之和超出工作量上限
+        server.set_query_limits(10, 8, 5);
+        assert_eq!(ResultCode::NOERROR, ask(&mut server, "a.lan", 2).header.rescode);
+        assert_eq!(ResultCode::SERVFAIL, ask(&mut server, "a.lan", 3).header.rescode);
+    }
+
+    #[test]
+    fn test_chaos() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        let client = TestClient::new();
+        let ask = |server: &mut DnsServer, name: &str, qtype: QueryType, qclass: QueryClass| {
+            let mut data = query_data(client.addr(), name, qtype);
+            data.question.qclass = qclass;
+            client.ask_query(server, &Query::new(data))
+        };
+
+        // 未配置时拒绝, 且不转发给上级
+        let packet = ask(&mut server, "version.bind", QueryType::TXT, QueryClass::CH);
+        assert_eq!(ResultCode::REFUSED, packet.header.rescode);
+        assert_eq!(0, server.queries.len());
+
+        server.set_chaos("minidns 1.0", "dns1.lan");
+        let packet = ask(&mut server, "VERSION.BIND", QueryType::TXT, QueryClass::CH);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(QueryClass::CH, packet.questions[0].qclass);
+        assert!(matches!(&packet.answers[..], [DnsRecord::TXT { text, .. }] if text == "minidns 1.0"));
+        let packet = ask(&mut server, "id.server", QueryType::TXT, QueryClass::CH);
+        assert!(matches!(&packet.answers[..], [DnsRecord::TXT { text, .. }] if text == "dns1.lan"));
+        assert_eq!(ResultCode::REFUSED, ask(&mut server, "authors.bind", QueryType::TXT, QueryClass::CH).header.rescode);
+        assert_eq!(ResultCode::REFUSED, ask(&mut server, "version.bind", QueryType::A, QueryClass::CH).header.rescode);
+        assert_eq!(ResultCode::NOTIMP, ask(&mut server, "version.bind", QueryType::TXT, QueryClass::HS).header.rescode);
+        assert_eq!(0, server.queries.len());
+    }
+
+    #[test]
+    fn test_recursion_flags() {
+        let mut server = DnsServer::create("127.0.0.1:0", "127.0.0.77", 300, "").unwrap();
+        server.register_host(&host_entry("nas.lan", "192.168.1.2", None)).unwrap();
+        let client = TestClient::new();
+        let ask = |server: &mut DnsServer, name: &str, recursion: bool| {
+            client.ask_query(server, &Query::new(QueryData { recursion, ..query_data(client.addr(), name, QueryType::A) }))
+        };
+
+        // 非递归查询只用本地数据回复, 回复原样带回RD标志, 提供转发时设置RA标志
+        let packet = ask(&mut server, "nas.lan", false);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(!packet.header.recursion_desired && packet.header.recursion_available);
+        let packet = ask(&mut server, "www.example.com", false);
+        assert_eq!(ResultCode::REFUSED, packet.header.rescode);
+        assert_eq!(0, server.queries.len());
+
+        // 不在访问控制列表中的客户端只能查询本地域名
+        server.set_recursion_acl("192.168.0.0/16, fd00::/8").unwrap();
+        assert_eq!(ResultCode::REFUSED, ask(&mut server, "www.example.com", true).header.rescode);
+        let packet = ask(&mut server, "nas.lan", true);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(!packet.header.recursion_available);
+        server.set_recursion_acl("127.0.0.0/8").unwrap();
+        assert!(ask(&mut server, "nas.lan", true).header.recursion_available);
+
+        // 未设置上级dns服务器时不提供递归查询
+        let mut server = DnsServer::create("127.0.0.1:0", "0.0.0.0", 300, "").unwrap();
+        let packet = ask(&mut server, "www.example.com", true);
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.header.recursion_desired && !packet.header.recursion_available);
+    }
 }
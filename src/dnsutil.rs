@@ -1,8 +1,14 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use anyhow::Result;
 use crate::bufutil::*;
 
+const MAX_PACKET_LEN: usize = 65535; // 数据包的最大长度(tcp)
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ResultCode {
     NOERROR  = 0,   // 没有错误
     FORMERR  = 1,   // 报文格式错误（Format error），服务器不能理解请求的报文
@@ -20,11 +26,19 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            _ => ResultCode::NOERROR,
         }
     }
 }
-#[derive(Clone, Debug)]
+
+impl fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DnsHeader {
     pub id: u16, // 16 bits
 
@@ -46,7 +60,22 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bits
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        DnsHeader::new()
+    }
+}
+
 impl DnsHeader {
+    /// dig风格的标志名称列表, 如qr rd ra
+    pub fn flags(&self) -> Vec<&'static str> {
+        [
+            (self.response, "qr"), (self.authoritative_answer, "aa"), (self.truncated_message, "tc"),
+            (self.recursion_desired, "rd"), (self.recursion_available, "ra"),
+            (self.authed_data, "ad"), (self.checking_disabled, "cd"),
+        ].iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect()
+    }
+
     pub fn new() -> DnsHeader {
         DnsHeader {
             id: 0,
@@ -105,7 +134,7 @@ impl DnsHeader {
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
                 | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8,
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write(
@@ -126,23 +155,26 @@ impl DnsHeader {
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum QueryType {
     UNKNOWN(u16),
     A,     // 1
     NS,    // 2
     CNAME, // 5
     MX,    // 15
+    TXT,   // 16
     AAAA,  // 28
 }
 
 impl QueryType {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
         }
     }
@@ -153,27 +185,103 @@ impl QueryType {
             2 => QueryType::NS,
             5 => QueryType::CNAME,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
 
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(x) => write!(f, "TYPE{x}"),
+            qtype => fmt::Debug::fmt(qtype, f),
+        }
+    }
+}
+
+/// 从类型名称(忽略大小写)、TYPE加数字或数字解析查询类型, 与Display的输出格式对应
+impl std::str::FromStr for QueryType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.to_ascii_uppercase();
+        let qtype = match upper.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "MX" => QueryType::MX,
+            "TXT" => QueryType::TXT,
+            "AAAA" => QueryType::AAAA,
+            _ => match upper.strip_prefix("TYPE").unwrap_or(&upper).parse() {
+                Ok(num) => QueryType::from_num(num),
+                Err(_) => anyhow::bail!("unknown query type {s}"),
+            },
+        };
+        Ok(qtype)
+    }
+}
+
+/// 查询类别, 除CHAOS类别的服务器信息查询外均为IN
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum QueryClass {
+    UNKNOWN(u16),
+    IN,    // 1
+    CH,    // 3
+    HS,    // 4
+    ANY,   // 255
+}
+
+impl QueryClass {
+    pub fn to_num(self) -> u16 {
+        match self {
+            QueryClass::UNKNOWN(x) => x,
+            QueryClass::IN => 1,
+            QueryClass::CH => 3,
+            QueryClass::HS => 4,
+            QueryClass::ANY => 255,
+        }
+    }
+
+    pub fn from_num(num: u16) -> QueryClass {
+        match num {
+            1 => QueryClass::IN,
+            3 => QueryClass::CH,
+            4 => QueryClass::HS,
+            255 => QueryClass::ANY,
+            _ => QueryClass::UNKNOWN(num),
+        }
+    }
+}
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryClass::UNKNOWN(x) => write!(f, "CLASS{x}"),
+            qclass => fmt::Debug::fmt(qclass, f),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub qclass: QueryClass,
 }
 
 impl DnsQuestion {
     pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
-        DnsQuestion { name, qtype }
+        DnsQuestion { name, qtype, qclass: QueryClass::IN }
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
-        let _ = buffer.read_u16()?; // class
+        self.qclass = QueryClass::from_num(buffer.read_u16()?); // class
 
         Ok(())
     }
@@ -183,14 +291,14 @@ impl DnsQuestion {
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass.to_num())?;
 
         Ok(())
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[allow(dead_code)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
@@ -219,6 +327,11 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        text: String,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
@@ -232,19 +345,32 @@ impl DnsRecord {
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
-        let qtype = QueryType::from_num(qtype_num);
         let _ = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
-        match qtype {
+        // 实际读取的记录数据长度与声明不一致时, 严格模式下报错, 否则以声明的长度为准
+        let start = buffer.pos();
+        let record = DnsRecord::read_data(buffer, domain, qtype_num, ttl, data_len)?;
+        let end = start + data_len as usize;
+        if buffer.pos() != end {
+            if buffer.is_strict() {
+                anyhow::bail!(BufferError::RdataLength { expected: data_len as usize, actual: buffer.pos() - start });
+            }
+            buffer.seek(end)?;
+        }
+        Ok(record)
+    }
+
+    fn read_data(buffer: &mut BytePacketBuffer, domain: String, qtype_num: u16, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        match QueryType::from_num(qtype_num) {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
 
                 Ok(DnsRecord::A { domain, addr, ttl })
@@ -256,13 +382,13 @@ impl DnsRecord {
                 let raw_addr4 = buffer.read_u32()?;
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
                 );
 
                 Ok(DnsRecord::AAAA { domain, addr, ttl })
@@ -299,6 +425,22 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::TXT => {
+                // 由多个长度前缀的字符串组成, 合并为一个字符串
+                let mut text = Vec::with_capacity(data_len as usize);
+                let end = buffer.pos() + data_len as usize;
+                while buffer.pos() < end {
+                    let len = buffer.read()? as usize;
+                    text.extend_from_slice(buffer.get_range(buffer.pos(), len)?);
+                    buffer.step(len)?;
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain,
+                    text: String::from_utf8_lossy(&text).into_owned(),
+                    ttl,
+                })
+            }
             QueryType::UNKNOWN(_) => {
                 buffer.step(data_len as usize)?;
 
@@ -313,7 +455,19 @@ impl DnsRecord {
     }
 
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+        self.write_class(buffer, QueryClass::IN)
+    }
+
+    /// 以指定的类别写入记录, 用于回复CHAOS类别的查询
+    pub fn write_class(&self, buffer: &mut BytePacketBuffer, qclass: QueryClass) -> Result<usize> {
+        self.write_with_ttl(buffer, qclass, None)
+    }
+
+    /// 以指定的类别及ttl写入记录, `ttl_override`为None时使用记录自身的ttl,
+    /// 用于以剩余时间回复共享的缓存记录而不复制记录
+    pub fn write_with_ttl(&self, buffer: &mut BytePacketBuffer, qclass: QueryClass, ttl_override: Option<u32>) -> Result<usize> {
         let start_pos = buffer.pos();
+        let class = qclass.to_num();
 
         match *self {
             DnsRecord::A {
@@ -323,8 +477,8 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl_override.unwrap_or(ttl))?;
                 buffer.write_u16(4)?;
 
                 let octets = addr.octets();
@@ -340,8 +494,8 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl_override.unwrap_or(ttl))?;
 
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
@@ -358,8 +512,8 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl_override.unwrap_or(ttl))?;
 
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
@@ -377,8 +531,8 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl_override.unwrap_or(ttl))?;
 
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
@@ -389,6 +543,34 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::TXT {
+                ref domain,
+                ref text,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl_override.unwrap_or(ttl))?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // 每个字符串最长255字节, 超长时拆分为多个字符串
+                let data = text.as_bytes();
+                if data.is_empty() {
+                    buffer.write(0)?;
+                }
+                for chunk in data.chunks(255) {
+                    buffer.write(chunk.len() as u8)?;
+                    for b in chunk {
+                        buffer.write(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
@@ -396,8 +578,8 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl_override.unwrap_or(ttl))?;
                 buffer.write_u16(16)?;
 
                 for octet in &addr.segments() {
@@ -411,9 +593,79 @@ impl DnsRecord {
 
         Ok(buffer.pos() - start_pos)
     }
+
+    /// 域名转为小写并去掉末尾的点
+    fn normalize(&mut self) {
+        match self {
+            DnsRecord::NS { domain, host, .. }
+            | DnsRecord::CNAME { domain, host, .. }
+            | DnsRecord::MX { domain, host, .. } => {
+                normalize_name(domain);
+                normalize_name(host);
+            }
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. } => normalize_name(domain),
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => *ttl,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: u32) {
+        *self.ttl_mut() = ttl;
+    }
+
+    fn ttl_mut(&mut self) -> &mut u32 {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => ttl,
+        }
+    }
+}
+
+fn normalize_name(name: &mut String) {
+    name.make_ascii_lowercase();
+    while name.ends_with('.') {
+        name.pop();
+    }
 }
 
-#[derive(Clone, Debug)]
+/// dig风格的记录显示: 域名 ttl 类 类型 数据
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (domain, ttl, qtype, data) = match self {
+            DnsRecord::UNKNOWN { domain, qtype, data_len, ttl } =>
+                (domain, ttl, QueryType::UNKNOWN(*qtype), format!("\\# {data_len}")),
+            DnsRecord::A { domain, addr, ttl } => (domain, ttl, QueryType::A, addr.to_string()),
+            DnsRecord::NS { domain, host, ttl } => (domain, ttl, QueryType::NS, format!("{host}.")),
+            DnsRecord::CNAME { domain, host, ttl } => (domain, ttl, QueryType::CNAME, format!("{host}.")),
+            DnsRecord::MX { domain, priority, host, ttl } => (domain, ttl, QueryType::MX, format!("{priority} {host}.")),
+            DnsRecord::TXT { domain, text, ttl } =>
+                (domain, ttl, QueryType::TXT, format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))),
+            DnsRecord::AAAA { domain, addr, ttl } => (domain, ttl, QueryType::AAAA, addr.to_string()),
+        };
+        write!(f, "{domain}.\t{ttl}\tIN\t{qtype}\t{data}")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DnsPacket {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
@@ -422,6 +674,12 @@ pub struct DnsPacket {
     pub resources: Vec<DnsRecord>,
 }
 
+impl Default for DnsPacket {
+    fn default() -> Self {
+        DnsPacket::new()
+    }
+}
+
 impl DnsPacket {
     pub fn new() -> DnsPacket {
         DnsPacket {
@@ -433,6 +691,62 @@ impl DnsPacket {
         }
     }
 
+    pub fn builder() -> DnsPacketBuilder {
+        DnsPacketBuilder { packet: DnsPacket::new() }
+    }
+
+    /// 序列化为json字符串
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// 序列化为字节数组, 不使用域名压缩, 相同内容的数据包总是得到相同的输出
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = BytePacketBuffer::with_capacity(MAX_PACKET_LEN);
+        self.write(&mut buffer)?;
+        Ok(buffer.data().to_vec())
+    }
+
+    /// 从字节数组解析数据包
+    pub fn from_bytes(data: &[u8]) -> Result<DnsPacket> {
+        let mut buffer = BytePacketBuffer::with_capacity(data.len());
+        buffer.recv_buf().copy_from_slice(data);
+        buffer.set_len(data.len());
+        DnsPacket::from_buffer(&mut buffer)
+    }
+
+    /// 规范化数据包: 域名转为小写并去掉末尾的点, 各节的记录排序, 丢弃无法序列化的未知类型记录,
+    /// 并更新头部的记录数量, 规范化后的数据包可以直接比较或序列化后逐字节比较
+    pub fn normalize(&mut self) {
+        for question in &mut self.questions {
+            normalize_name(&mut question.name);
+        }
+        for records in [&mut self.answers, &mut self.authorities, &mut self.resources] {
+            records.retain(|rec| !matches!(rec, DnsRecord::UNKNOWN { .. }));
+            records.iter_mut().for_each(DnsRecord::normalize);
+            records.sort();
+        }
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
+    /// 比较两个数据包是否等价, 忽略请求id、记录顺序及ttl, 用于与其他dns服务器的应答进行比较
+    pub fn equivalent(&self, other: &DnsPacket) -> bool {
+        let canonical = |packet: &DnsPacket| {
+            let mut packet = packet.clone();
+            packet.normalize();
+            packet.header.id = 0;
+            for records in [&mut packet.answers, &mut packet.authorities, &mut packet.resources] {
+                records.iter_mut().for_each(|rec| *rec.ttl_mut() = 0);
+            }
+            packet
+        };
+        canonical(self) == canonical(other)
+    }
+
     pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
         let mut result = DnsPacket::new();
         result.header.read(buffer)?;
@@ -459,13 +773,15 @@ impl DnsPacket {
         Ok(result)
     }
 
-    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+    /// 序列化到缓冲区, 头部的各部分数量按实际记录数写入
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let mut header = self.header.clone();
+        header.questions = self.questions.len() as u16;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = self.authorities.len() as u16;
+        header.resource_entries = self.resources.len() as u16;
 
-        self.header.write(buffer)?;
+        header.write(buffer)?;
 
         for question in &self.questions {
             question.write(buffer)?;
@@ -486,7 +802,6 @@ impl DnsPacket {
     /// It's useful to be able to pick a random A record from a packet. When we
     /// get multiple IP's for a single name, it doesn't matter which one we
     /// choose, so in those cases we can now pick one at random.
-    #[allow(dead_code)]
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
         self.answers
             .iter()
@@ -532,7 +847,7 @@ impl DnsPacket {
                         _ => None,
                     })
             })
-            .map(|addr| *addr)
+            .copied()
             // Finally, pick the first valid entry
             .next()
     }
@@ -549,3 +864,295 @@ impl DnsPacket {
             .next()
     }
 }
+
+/// dig风格的数据包显示
+impl fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = &self.header;
+        let opcode = match h.opcode {
+            0 => String::from("QUERY"),
+            1 => String::from("IQUERY"),
+            2 => String::from("STATUS"),
+            n => n.to_string(),
+        };
+        writeln!(f, ";; ->>HEADER<<- opcode: {opcode}, status: {}, id: {}", h.rescode, h.id)?;
+
+        writeln!(f, ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            h.flags().join(" "), self.questions.len(), self.answers.len(),
+            self.authorities.len(), self.resources.len())?;
+
+        writeln!(f, "\n;; QUESTION SECTION:")?;
+        for q in &self.questions {
+            writeln!(f, ";{}.\t\t{}\t{}", q.name, q.qclass, q.qtype)?;
+        }
+        for (title, records) in [("ANSWER", &self.answers), ("AUTHORITY", &self.authorities), ("ADDITIONAL", &self.resources)] {
+            if !records.is_empty() {
+                writeln!(f, "\n;; {title} SECTION:")?;
+                for rec in records {
+                    writeln!(f, "{rec}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 服务器回复客户端的数据包, 借用查询条目及应答记录直接序列化,
+/// 避免每次回复都将记录集复制到新建的[`DnsPacket`]中
+pub struct DnsResponse<'a> {
+    pub header  : DnsHeader,           // 头部, 各部分数量在序列化时按实际记录数写入
+    pub question: &'a DnsQuestion,     // 查询条目
+    pub answers : &'a [DnsRecord],     // 应答记录
+    pub ttl     : Option<u32>,         // 写入时替换应答记录的ttl, 为None时使用记录自身的ttl
+}
+
+impl<'a> DnsResponse<'a> {
+    /// 创建回复, 设置响应标志及回复码
+    pub fn new(id: u16, rescode: ResultCode, question: &'a DnsQuestion, answers: &'a [DnsRecord]) -> DnsResponse<'a> {
+        let mut header = DnsHeader::new();
+        header.id = id;
+        header.response = true;
+        header.rescode = rescode;
+        DnsResponse { header, question, answers, ttl: None }
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let mut header = self.header.clone();
+        header.questions = 1;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = 0;
+        header.resource_entries = 0;
+
+        header.write(buffer)?;
+        self.question.write(buffer)?;
+        // CHAOS类别的查询以相同类别回复, 其它均为IN
+        let qclass = match self.question.qclass {
+            QueryClass::CH => QueryClass::CH,
+            _ => QueryClass::IN,
+        };
+        for rec in self.answers {
+            rec.write_with_ttl(buffer, qclass, self.ttl)?;
+        }
+
+        Ok(())
+    }
+
+    /// 复制为完整的数据包, 用于输出调试日志
+    pub fn to_packet(&self) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header = self.header.clone();
+        packet.questions.push(self.question.clone());
+        packet.answers.extend(self.answers.iter().cloned().map(|mut rec| {
+            if let Some(ttl) = self.ttl {
+                rec.set_ttl(ttl);
+            }
+            rec
+        }));
+        packet.header.questions = 1;
+        packet.header.answers = self.answers.len() as u16;
+        packet
+    }
+}
+
+/// 数据包构建器, 以链式调用的方式设置标志及各部分记录
+pub struct DnsPacketBuilder {
+    packet: DnsPacket,
+}
+
+impl DnsPacketBuilder {
+    pub fn id(mut self, id: u16) -> Self {
+        self.packet.header.id = id;
+        self
+    }
+
+    pub fn response(mut self, rescode: ResultCode) -> Self {
+        self.packet.header.response = true;
+        self.packet.header.rescode = rescode;
+        self
+    }
+
+    pub fn recursion_desired(mut self, value: bool) -> Self {
+        self.packet.header.recursion_desired = value;
+        self
+    }
+
+    pub fn recursion_available(mut self, value: bool) -> Self {
+        self.packet.header.recursion_available = value;
+        self
+    }
+
+    pub fn authoritative(mut self, value: bool) -> Self {
+        self.packet.header.authoritative_answer = value;
+        self
+    }
+
+    pub fn truncated(mut self, value: bool) -> Self {
+        self.packet.header.truncated_message = value;
+        self
+    }
+
+    pub fn question(mut self, question: DnsQuestion) -> Self {
+        self.packet.questions.push(question);
+        self
+    }
+
+    pub fn answer(mut self, record: DnsRecord) -> Self {
+        self.packet.answers.push(record);
+        self
+    }
+
+    pub fn answers<I: IntoIterator<Item = DnsRecord>>(mut self, records: I) -> Self {
+        self.packet.answers.extend(records);
+        self
+    }
+
+    pub fn authority(mut self, record: DnsRecord) -> Self {
+        self.packet.authorities.push(record);
+        self
+    }
+
+    pub fn resource(mut self, record: DnsRecord) -> Self {
+        self.packet.resources.push(record);
+        self
+    }
+
+    /// 生成数据包, 头部的各部分数量与实际记录数保持一致
+    pub fn build(mut self) -> DnsPacket {
+        let p = &mut self.packet;
+        p.header.questions = p.questions.len() as u16;
+        p.header.answers = p.answers.len() as u16;
+        p.header.authoritative_entries = p.authorities.len() as u16;
+        p.header.resource_entries = p.resources.len() as u16;
+        self.packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_display() {
+        let packet = DnsPacket::builder()
+            .id(1234)
+            .response(ResultCode::NOERROR)
+            .recursion_desired(true)
+            .recursion_available(true)
+            .question(DnsQuestion::new("www.lan".to_string(), QueryType::A))
+            .answer(DnsRecord::CNAME { domain: "www.lan".to_string(), host: "nas.lan".to_string(), ttl: 300 })
+            .answer(DnsRecord::A { domain: "nas.lan".to_string(), addr: Ipv4Addr::new(127, 0, 0, 5), ttl: 300 })
+            .build();
+        assert_eq!(2, packet.header.answers);
+
+        assert_eq!(";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 1234\n\
+            ;; flags: qr rd ra; QUERY: 1, ANSWER: 2, AUTHORITY: 0, ADDITIONAL: 0\n\n\
+            ;; QUESTION SECTION:\n;www.lan.\t\tIN\tA\n\n\
+            ;; ANSWER SECTION:\nwww.lan.\t300\tIN\tCNAME\tnas.lan.\nnas.lan.\t300\tIN\tA\t127.0.0.5\n",
+            packet.to_string());
+
+        let txt = DnsRecord::TXT { domain: "a.lan".to_string(), text: "say \"hi\"".to_string(), ttl: 60 };
+        assert_eq!("a.lan.\t60\tIN\tTXT\t\"say \\\"hi\\\"\"", txt.to_string());
+        assert_eq!("TYPE99", QueryType::UNKNOWN(99).to_string());
+        assert_eq!(QueryType::AAAA, "aaaa".parse().unwrap());
+        assert_eq!(QueryType::MX, "15".parse().unwrap());
+        assert_eq!(QueryType::UNKNOWN(99), "TYPE99".parse().unwrap());
+        assert!("SOA".parse::<QueryType>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let packet = DnsPacket::builder()
+            .id(42)
+            .response(ResultCode::NOERROR)
+            .question(DnsQuestion::new("www.lan".to_string(), QueryType::MX))
+            .answer(DnsRecord::MX { domain: "www.lan".to_string(), priority: 10, host: "mail.lan".to_string(), ttl: 60 })
+            .answer(DnsRecord::TXT { domain: "www.lan".to_string(), text: "x".repeat(300), ttl: 60 })
+            .authority(DnsRecord::NS { domain: "lan".to_string(), host: "ns.lan".to_string(), ttl: 60 })
+            .resource(DnsRecord::AAAA { domain: "ns.lan".to_string(), addr: "fd00::1".parse().unwrap(), ttl: 60 })
+            .build();
+        let data = packet.to_bytes().unwrap();
+        assert_eq!(data, packet.to_bytes().unwrap());
+        let parsed = DnsPacket::from_bytes(&data).unwrap();
+        assert_eq!(packet, parsed);
+        assert_eq!(data, parsed.to_bytes().unwrap());
+        assert!(DnsPacket::from_bytes(&data[..data.len() - 1]).is_err());
+
+        // 大小写、末尾的点、记录顺序、id及ttl不同的应答等价
+        let other = DnsPacket::builder()
+            .id(7)
+            .response(ResultCode::NOERROR)
+            .question(DnsQuestion::new("WWW.lan.".to_string(), QueryType::MX))
+            .answer(DnsRecord::TXT { domain: "www.LAN".to_string(), text: "x".repeat(300), ttl: 5 })
+            .answer(DnsRecord::MX { domain: "www.lan".to_string(), priority: 10, host: "Mail.lan.".to_string(), ttl: 5 })
+            .authority(DnsRecord::NS { domain: "lan".to_string(), host: "ns.lan".to_string(), ttl: 5 })
+            .resource(DnsRecord::AAAA { domain: "ns.lan".to_string(), addr: "fd00::1".parse().unwrap(), ttl: 5 })
+            .build();
+        assert!(packet.equivalent(&other));
+        assert_ne!(packet, other);
+
+        let mut normalized = other.clone();
+        normalized.normalize();
+        assert_eq!("www.lan", normalized.questions[0].name);
+        assert!(matches!(normalized.answers[0], DnsRecord::MX { .. }));
+
+        let mut other = other;
+        other.answers.pop();
+        assert!(!packet.equivalent(&other));
+    }
+
+    #[test]
+    fn test_response() {
+        let question = DnsQuestion::new("www.lan".to_string(), QueryType::A);
+        let answers = vec![
+            DnsRecord::A { domain: "www.lan".to_string(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 },
+            DnsRecord::A { domain: "www.lan".to_string(), addr: Ipv4Addr::new(10, 0, 0, 2), ttl: 60 },
+        ];
+        let mut response = DnsResponse::new(9, ResultCode::NOERROR, &question, &answers);
+        response.header.recursion_available = true;
+        let mut buffer = BytePacketBuffer::with_capacity(MAX_PACKET_LEN);
+        response.write(&mut buffer).unwrap();
+
+        let packet = DnsPacket::builder()
+            .id(9)
+            .response(ResultCode::NOERROR)
+            .recursion_available(true)
+            .question(question.clone())
+            .answers(answers.clone())
+            .build();
+        assert_eq!(packet.to_bytes().unwrap(), buffer.data());
+        assert_eq!(packet, response.to_packet());
+
+        // 写入时替换记录的ttl, 记录本身不变
+        response.ttl = Some(20);
+        buffer.clear();
+        response.write(&mut buffer).unwrap();
+        let packet = DnsPacket::from_bytes(buffer.data()).unwrap();
+        assert_eq!(vec![20, 20], packet.answers.iter().map(DnsRecord::ttl).collect::<Vec<_>>());
+        assert_eq!(packet.answers, response.to_packet().answers);
+        assert_eq!(60, answers[0].ttl());
+    }
+
+    #[test]
+    fn test_query_class() {
+        let mut question = DnsQuestion::new("version.bind".to_string(), QueryType::TXT);
+        question.qclass = QueryClass::CH;
+        let answers = vec![DnsRecord::TXT { domain: "version.bind".to_string(), text: "minidns".to_string(), ttl: 0 }];
+        let response = DnsResponse::new(7, ResultCode::NOERROR, &question, &answers);
+        let mut buffer = BytePacketBuffer::with_capacity(MAX_PACKET_LEN);
+        response.write(&mut buffer).unwrap();
+
+        // 问题及应答记录均使用CH类别
+        let data = buffer.data();
+        let name_len = "version.bind".len() + 2;
+        let question_end = 12 + name_len + 4;
+        assert_eq!([0, 16, 0, 3], data[question_end - 4..question_end]);
+        assert_eq!([0, 16, 0, 3], data[question_end + name_len..question_end + name_len + 4]);
+        let packet = DnsPacket::from_bytes(data).unwrap();
+        assert_eq!(QueryClass::CH, packet.questions[0].qclass);
+        assert!(packet.to_string().contains(";version.bind.\t\tCH\tTXT\n"));
+
+        assert_eq!(QueryClass::UNKNOWN(254), QueryClass::from_num(254));
+        assert_eq!("CLASS254", QueryClass::UNKNOWN(254).to_string());
+        assert_eq!(255, QueryClass::ANY.to_num());
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{SyncSender, TrySendError, sync_channel};
+use std::time::Duration;
+use anyhow::{Result, Context};
+use super::httputil;
+
+// 认证失败锁定常量定义
+const MAX_AUTH_FAILURES: u32   = 5;         // 统计周期内允许的最大认证失败次数
+const AUTH_FAILURE_WINDOW: u64 = 60 * 10;   // 认证失败次数的统计周期(秒)
+const AUTH_BAN_TIME: u64       = 60 * 60;   // 超过失败次数后的封禁时间(秒)
+const MAX_AUTH_ENTRIES: usize  = 4096;      // 失败记录的最大条目数, 防止伪造来源地址的请求耗尽内存
+const HOOK_TIMEOUT: u64        = 5;         // webhook连接及读写超时时间(秒)
+const MAX_HOOK_QUEUE: usize    = 64;        // 等待执行的钩子数量上限
+
+// 来源地址的认证失败记录
+struct AuthFailure {
+    count       : u32,    // 统计周期内的失败次数
+    first_time  : u64,    // 统计周期的开始时间
+    banned_until: u64,    // 封禁截止时间, 0表示未封禁
+}
+
+/// 动态dns认证失败锁定, 对短时间内多次认证失败的来源地址进行临时封禁,
+/// 减缓对弱密钥的暴力猜测
+///
+/// 失败记录达到上限时先清除过期的记录, 仍然满时淘汰最早开始统计的未封禁记录,
+/// 全部处于封禁状态时淘汰最早解封的记录
+pub struct AuthLock {
+    failures: HashMap<IpAddr, AuthFailure>,
+}
+
+impl AuthLock {
+
+    pub fn new() -> AuthLock {
+        AuthLock { failures: HashMap::new() }
+    }
+
+    /// 来源地址当前是否处于封禁状态
+    pub fn is_banned(&self, ip: &IpAddr, now: u64) -> bool {
+        match self.failures.get(ip) {
+            Some(f) => f.banned_until > now,
+            None => false,
+        }
+    }
+
+    /// 记录一次认证失败, 返回该来源地址是否因本次失败而被封禁
+    pub fn fail(&mut self, ip: IpAddr, now: u64) -> bool {
+        if self.failures.len() >= MAX_AUTH_ENTRIES && !self.failures.contains_key(&ip) {
+            self.evict(now);
+        }
+        let f = self.failures.entry(ip).or_insert(AuthFailure { count: 0, first_time: now, banned_until: 0 });
+        if f.first_time + AUTH_FAILURE_WINDOW < now {
+            f.count = 0;
+            f.first_time = now;
+        }
+
+        f.count += 1;
+        if f.count >= MAX_AUTH_FAILURES && f.banned_until <= now {
+            f.banned_until = now + AUTH_BAN_TIME;
+            return true;
+        }
+        false
+    }
+
+    /// 认证成功后清除该来源地址的失败记录
+    pub fn success(&mut self, ip: &IpAddr) {
+        self.failures.remove(ip);
+    }
+
+    /// 清除已过期的失败记录及封禁
+    pub fn clear_expired(&mut self, now: u64) {
+        self.failures.retain(|_, f| f.banned_until > now || f.first_time + AUTH_FAILURE_WINDOW >= now);
+    }
+
+    // 失败记录已满时腾出一个位置
+    fn evict(&mut self, now: u64) {
+        self.clear_expired(now);
+        if self.failures.len() < MAX_AUTH_ENTRIES {
+            return;
+        }
+        let oldest = self.failures.iter()
+            .min_by_key(|(_, f)| (f.banned_until > now, f.banned_until, f.first_time))
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = oldest {
+            self.failures.remove(&ip);
+        }
+    }
+
+}
+
+/// 动态dns审计记录
+pub struct AuditRecord<'a> {
+    pub time    : u64,           // 记录时间, Unix格式: 自1970-01-01至今的秒数
+    pub accepted: bool,          // 是否接受本次更新
+    pub reason  : &'a str,       // 拒绝原因, 接受时为"ok"
+    pub addr    : &'a SocketAddr, // 请求来源地址
+    pub host    : &'a str,       // 请求更新的域名
+    pub old_ip  : &'a str,       // 更新前的ip, 无则为空字符串
+    pub new_ip  : &'a str,       // 请求更新的ip
+}
+
+/// 动态dns审计日志, 每次更新请求写入一行json, 便于安全审查时进行机器解析
+pub struct AuditLog {
+    path: String,
+    file: File,
+}
+
+impl AuditLog {
+
+    pub fn new(path: &str) -> Result<AuditLog> {
+        let file = std::fs::OpenOptions::new().append(true).create(true).open(path)
+            .with_context(|| format!("open dyndns audit file {path} failed"))?;
+        Ok(AuditLog { path: path.to_string(), file })
+    }
+
+    pub fn write(&mut self, rec: &AuditRecord) {
+        let line = format!("{{\"time\":{},\"result\":\"{}\",\"reason\":{},\"addr\":\"{}\",\"host\":{},\"old_ip\":{},\"new_ip\":{}}}\n",
+            rec.time,
+            if rec.accepted { "accepted" } else { "rejected" },
+            json_str(rec.reason),
+            rec.addr,
+            json_str(rec.host),
+            json_str(rec.old_ip),
+            json_str(rec.new_ip));
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            log::error!("write dyndns audit file {} failed: {}", self.path, e);
+        }
+    }
+
+}
+
+/// 动态dns json格式回复的版本号
+pub const REPLY_VERSION: u32 = 1;
+
+/// 动态dns回复的状态码, 由拒绝原因得到, 0表示成功
+pub fn reply_code(reason: &str) -> u32 {
+    match reason {
+        "ok"       => 0,
+        "format"   => 1,   // 数据包格式错误
+        "checksum" => 2,   // 认证失败
+        "time"     => 3,   // 提交时间超出允许范围
+        "domain"   => 4,   // 域名不在允许的后缀范围内
+        "address"  => 5,   // ip地址格式错误
+        _          => 99,
+    }
+}
+
+/// 生成json格式的动态dns回复
+pub fn json_reply(reason: &str, host: &str, ip: &str, ttl: u32) -> String {
+    let code = reply_code(reason);
+    format!("{{\"version\":{},\"status\":\"{}\",\"code\":{},\"host\":{},\"ip\":{},\"ttl\":{},\"message\":{}}}",
+        REPLY_VERSION,
+        if code == 0 { "ok" } else { "error" },
+        code,
+        json_str(host),
+        json_str(ip),
+        ttl,
+        json_str(reason))
+}
+
+/// 将字符串转换为json字符串格式(含双引号)
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// 解析以逗号分隔的域名后缀列表, 统一转为小写并去除开头的'.'
+pub fn parse_suffixes(suffixes: &str) -> Vec<String> {
+    suffixes.split(',')
+        .map(|s| s.trim().trim_start_matches('.').to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// 判断域名是否属于允许的后缀列表, 列表为空时允许所有域名
+pub fn is_allowed_domain(host: &str, suffixes: &[String]) -> bool {
+    if suffixes.is_empty() {
+        return true;
+    }
+
+    let host = host.to_lowercase();
+    suffixes.iter().any(|s| {
+        host == *s || (host.len() > s.len() && host.ends_with(s.as_str())
+            && host.as_bytes()[host.len() - s.len() - 1] == b'.')
+    })
+}
+
+/// 动态域名ip变化时触发的钩子, 以`http://`开头时作为webhook地址,
+/// 以POST方式提交`host`、`old_ip`、`new_ip`参数, 否则作为外部命令执行,
+/// 命令参数依次为: 域名 原ip 新ip(新注册的域名原ip为空字符串)
+///
+/// 钩子在唯一的后台线程中依次执行, 不会阻塞dns服务, 等待执行的钩子超过上限时丢弃新的变化,
+/// 避免大量更新时创建过多的线程或进程
+pub struct ChangeHook {
+    tx: SyncSender<(String, String, String)>,   // 向后台线程发送的域名、原ip、新ip
+}
+
+impl ChangeHook {
+
+    pub fn new(hook: &str) -> ChangeHook {
+        let (tx, rx) = sync_channel::<(String, String, String)>(MAX_HOOK_QUEUE);
+        let hook = hook.to_string();
+        // 发送端随服务释放后接收结束, 线程随之退出
+        std::thread::spawn(move || {
+            for (host, old_ip, new_ip) in rx {
+                let r = if hook.starts_with("http://") {
+                    post_webhook(&hook, &host, &old_ip, &new_ip)
+                } else {
+                    exec_command(&hook, &host, &old_ip, &new_ip)
+                };
+                match r {
+                    Ok(()) => log::debug!("dyndns change hook for {host} {old_ip} -> {new_ip} finished"),
+                    Err(e) => log::error!("dyndns change hook for {host} failed: {:?}", e),
+                }
+            }
+        });
+        ChangeHook { tx }
+    }
+
+    /// 将域名的ip变化加入执行队列, 队列已满时丢弃并返回false
+    pub fn run(&self, host: &str, old_ip: &str, new_ip: &str) -> bool {
+        match self.tx.try_send((host.to_string(), old_ip.to_string(), new_ip.to_string())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("dyndns change hook queue is full, change of {host} {old_ip} -> {new_ip} dropped");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+}
+
+fn exec_command(cmd: &str, host: &str, old_ip: &str, new_ip: &str) -> Result<()> {
+    let status = std::process::Command::new(cmd)
+        .args([host, old_ip, new_ip])
+        .status()
+        .with_context(|| format!("execute command {cmd} failed"))?;
+    if !status.success() {
+        anyhow::bail!("command {cmd} exit with {status}");
+    }
+    Ok(())
+}
+
+fn post_webhook(url: &str, host: &str, old_ip: &str, new_ip: &str) -> Result<()> {
+    let body = format!("host={host}&old_ip={old_ip}&new_ip={new_ip}");
+    let headers = [("Content-Type", "application/x-www-form-urlencoded")];
+    let res = httputil::request("POST", url, &headers, body.as_bytes(), Duration::from_secs(HOOK_TIMEOUT))?;
+    if !(200..300).contains(&res.status) {
+        anyhow::bail!("webhook {url} response status {}", res.status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_hook_queue() {
+        // webhook服务器不回复, 后台线程阻塞在第一个钩子上, 之后的变化在队列中等待
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let hook = ChangeHook::new(&format!("http://{}/", listener.local_addr().unwrap()));
+        let queued = (0..MAX_HOOK_QUEUE + 2).filter(|_| hook.run("pc.lan", "", "10.0.0.1")).count();
+        assert!((MAX_HOOK_QUEUE..=MAX_HOOK_QUEUE + 1).contains(&queued));
+    }
+
+    #[test]
+    fn test_auth_lock() {
+        let ip: IpAddr = "192.168.1.2".parse().unwrap();
+        let mut lock = AuthLock::new();
+
+        for _ in 1..MAX_AUTH_FAILURES {
+            assert!(!lock.fail(ip, 100));
+        }
+        assert!(!lock.is_banned(&ip, 100));
+        assert!(lock.fail(ip, 100));
+        assert!(lock.is_banned(&ip, 101));
+        assert!(!lock.is_banned(&ip, 100 + AUTH_BAN_TIME));
+
+        lock.clear_expired(100 + AUTH_BAN_TIME + AUTH_FAILURE_WINDOW);
+        assert!(lock.failures.is_empty());
+
+        lock.fail(ip, 100);
+        lock.success(&ip);
+        assert!(lock.failures.is_empty());
+
+        // 记录数量有上限, 已封禁的地址不会被未封禁的记录挤掉
+        (0..MAX_AUTH_FAILURES).for_each(|_| { lock.fail(ip, 100); });
+        for i in 0..MAX_AUTH_ENTRIES as u32 + 10 {
+            lock.fail(IpAddr::from((0x0a00_0000 + i).to_be_bytes()), 100 + i as u64 / 10);
+        }
+        assert_eq!(MAX_AUTH_ENTRIES, lock.failures.len());
+        assert!(lock.is_banned(&ip, 101));
+        assert!(!lock.failures.contains_key(&"10.0.0.0".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn test_allowed_domain() {
+        let suffixes = parse_suffixes(" .dyn.example.com, Home.Lan ,");
+        assert_eq!(vec!["dyn.example.com", "home.lan"], suffixes);
+
+        assert!(is_allowed_domain("nas.dyn.example.com", &suffixes));
+        assert!(is_allowed_domain("dyn.example.com", &suffixes));
+        assert!(is_allowed_domain("PC.home.lan", &suffixes));
+        assert!(!is_allowed_domain("evildyn.example.com", &suffixes));
+        assert!(!is_allowed_domain("www.google.com", &suffixes));
+        assert!(is_allowed_domain("www.google.com", &[]));
+    }
+
+    #[test]
+    fn test_json_str() {
+        assert_eq!(r#""abc""#, json_str("abc"));
+        assert_eq!(r#""a\"b\\c\r\n\u0001""#, json_str("a\"b\\c\r\n\u{1}"));
+    }
+
+    #[test]
+    fn test_json_reply() {
+        assert_eq!(r#"{"version":1,"status":"ok","code":0,"host":"a.com","ip":"1.2.3.4","ttl":300,"message":"ok"}"#,
+            json_reply("ok", "a.com", "1.2.3.4", 300));
+        assert_eq!(r#"{"version":1,"status":"error","code":2,"host":"a.com","ip":"","ttl":0,"message":"checksum"}"#,
+            json_reply("checksum", "a.com", "", 0));
+    }
+}
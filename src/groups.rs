@@ -0,0 +1,187 @@
+//! 客户端分组策略, 按网段或mac地址将客户端分组, 每组可以有独立的hosts(屏蔽列表)、规则脚本及上级dns
+//!
+//! 分组配置文件为toml格式, 每个分组是一个`[[group]]`表, 客户端按分组在文件中的顺序匹配第一个分组:
+//! ```toml
+//! [[group]]
+//! name     = "kids"
+//! clients  = ["192.168.1.100/30", "3c:22:fb:01:02:03"]
+//! hosts    = ["kids-block.hosts", "/etc/mdns/social.hosts"]
+//! script   = "kids.rules"
+//! upstream = "1.1.1.3"
+//! ```
+//! - `name`: 分组名称, 不能重复
+//! - `clients`: 网段或mac地址数组; mac地址通过系统的arp表(/proc/net/arp)对应到ipv4地址
+//! - `hosts`: hosts格式文件数组, 组内的域名及屏蔽域名优先于全局的解析链
+//! - `script`: 组的规则脚本, 在组的hosts之后、全局的解析链之前执行
+//! - `upstream`: 组的上级dns服务器, 替代全局的上级dns服务器转发组内客户端的查询
+//!
+//! 相对路径以分组配置文件所在的目录为基准
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::{Context, Result};
+use super::acl::Acl;
+
+const ARP_TABLE: &str = "/proc/net/arp";  // linux系统的arp表
+
+/// mac地址
+pub type MacAddr = [u8; 6];
+
+/// 一个客户端分组的配置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupConfig {
+    pub name    : String,          // 分组名称
+    pub nets    : Acl,             // 属于该组的客户端网段
+    pub macs    : Vec<MacAddr>,    // 属于该组的客户端mac地址
+    pub hosts   : Vec<String>,     // 组的hosts文件
+    pub script  : String,          // 组的规则脚本文件, 为空表示没有
+    pub upstream: Option<IpAddr>,  // 组的上级dns服务器, 为None时使用全局的上级dns服务器
+}
+
+impl GroupConfig {
+    /// 客户端是否属于该组, `mac`为客户端在arp表中的mac地址
+    pub fn matches(&self, ip: &IpAddr, mac: Option<&MacAddr>) -> bool {
+        self.nets.contains(ip) || mac.is_some_and(|mac| self.macs.contains(mac))
+    }
+}
+
+/// 加载分组配置文件
+pub fn load_groups(path: &str) -> Result<Vec<GroupConfig>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read group file {path} failed"))?;
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut groups = parse_groups(&text).with_context(|| format!("parse group file {path} failed"))?;
+    // 相对路径以配置文件所在的目录为基准
+    let resolve = |file: &mut String| if Path::new(file.as_str()).is_relative() {
+        *file = base.join(file.as_str()).to_string_lossy().into_owned();
+    };
+    for group in groups.iter_mut() {
+        group.hosts.iter_mut().for_each(resolve);
+        if !group.script.is_empty() {
+            resolve(&mut group.script);
+        }
+    }
+    Ok(groups)
+}
+
+/// 解析toml格式的分组配置
+pub fn parse_groups(text: &str) -> Result<Vec<GroupConfig>> {
+    let config = appconfig::Config::with_toml(text)?;
+    let mut groups: Vec<GroupConfig> = Vec::new();
+    for i in 0.. {
+        let table = config.table(&format!("group.{i}"))?;
+        if table.is_empty() {
+            break;
+        }
+
+        let mut group = GroupConfig::default();
+        for (key, value) in table.iter() {
+            let items = || value.split(',').map(str::trim).filter(|s| !s.is_empty());
+            match key.as_str() {
+                "name" => group.name = value.trim().to_string(),
+                "clients" => for item in items() {
+                    match parse_mac(item) {
+                        Some(mac) => group.macs.push(mac),
+                        None => group.nets.push(item.parse().with_context(|| format!("group {}: client {item} isn't a network or mac", i + 1))?),
+                    }
+                },
+                "hosts" => group.hosts.extend(items().map(str::to_string)),
+                "script" => group.script = value.to_string(),
+                "upstream" => group.upstream = Some(value.parse().with_context(|| format!("group {}: upstream {value} format error", i + 1))?),
+                _ => anyhow::bail!("group {}: unknown key {key}, expect name/clients/hosts/script/upstream", i + 1),
+            }
+        }
+        if group.name.is_empty() || groups.iter().any(|g| g.name == group.name) {
+            anyhow::bail!("group {}: group name [{}] is empty or duplicated", i + 1, group.name);
+        }
+        groups.push(group);
+    }
+    // 旧的ini格式或拼错的表名不会得到任何分组, 报错而不是静默地不分组
+    if groups.is_empty() {
+        anyhow::bail!("no [[group]] table found");
+    }
+    Ok(groups)
+}
+
+/// 解析mac地址(如3c:22:fb:01:02:03或3c-22-fb-01-02-03)
+pub fn parse_mac(value: &str) -> Option<MacAddr> {
+    let mut mac = [0u8; 6];
+    let mut parts = value.split([':', '-']);
+    for byte in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(mac),
+    }
+}
+
+/// 读取系统的arp表, 得到局域网内ipv4地址对应的mac地址, 不支持的系统返回空表
+pub fn read_arp_table() -> HashMap<IpAddr, MacAddr> {
+    match std::fs::read_to_string(ARP_TABLE) {
+        Ok(text) => parse_arp_table(&text),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// 解析linux的arp表, 格式: IP address, HW type, Flags, HW address, Mask, Device, 忽略未完成的条目
+pub fn parse_arp_table(text: &str) -> HashMap<IpAddr, MacAddr> {
+    text.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [ip, _, flags, mac, ..] if flags != "0x0" => Some((ip.parse().ok()?, parse_mac(mac)?)),
+            _ => None,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_groups() {
+        let groups = parse_groups("# groups\n\
+            [[group]]\n\
+            name = \"kids\"\n\
+            clients = [\"192.168.1.100/30\", \"3C:22:FB:01:02:03\",\n  \"10.0.0.9\"]\n\
+            hosts = [\"kids.hosts\", \"/etc/mdns/social.hosts\"]\n\
+            script = \"kids.rules\"\n\
+            upstream = \"1.1.1.3\"\n\
+            [[group]]\n\
+            name = \"adults\"\n\
+            clients = \"192.168.1.0/24\"\n").unwrap();
+        assert_eq!(2, groups.len());
+        let kids = &groups[0];
+        assert_eq!(("kids", Some("1.1.1.3".parse().unwrap())), (kids.name.as_str(), kids.upstream));
+        assert_eq!(vec![[0x3c, 0x22, 0xfb, 1, 2, 3]], kids.macs);
+        assert_eq!(vec!["kids.hosts", "/etc/mdns/social.hosts"], kids.hosts);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(kids.matches(&ip("192.168.1.101"), None));
+        assert!(kids.matches(&ip("10.0.0.9"), None));
+        assert!(!kids.matches(&ip("192.168.1.20"), None));
+        assert!(kids.matches(&ip("192.168.1.20"), Some(&[0x3c, 0x22, 0xfb, 1, 2, 3])));
+        assert!(groups[1].matches(&ip("192.168.1.20"), None));
+
+        assert!(parse_groups("[[group]]\nclients = \"10.0.0.1\"\n").is_err());
+        assert!(parse_groups("[[group]]\nname = \"a\"\nclients = \"lan\"\n").is_err());
+        assert!(parse_groups("[[group]]\nname = \"a\"\nblock = \"a.hosts\"\n").is_err());
+        assert!(parse_groups("[[group]]\nname = \"a\"\n[[group]]\nname = \"a\"\n").is_err());
+        assert!(parse_groups("[kids]\nclients = 10.0.0.1\n").is_err());
+    }
+
+    #[test]
+    fn test_arp_table() {
+        let table = parse_arp_table("IP address       HW type     Flags       HW address            Mask     Device\n\
+            192.168.1.20     0x1         0x2         3c:22:fb:01:02:03     *        eth0\n\
+            192.168.1.21     0x1         0x0         00:00:00:00:00:00     *        eth0\n");
+        assert_eq!(1, table.len());
+        assert_eq!(Some(&[0x3c, 0x22, 0xfb, 1, 2, 3]), table.get(&"192.168.1.20".parse::<IpAddr>().unwrap()));
+        assert_eq!(None, parse_mac("3c:22:fb:01:02"));
+        assert_eq!(None, parse_mac("3c:22:fb:01:02:03:04"));
+    }
+}
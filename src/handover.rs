@@ -0,0 +1,274 @@
+//! 零停机重启, 新进程从正在运行的旧进程接收已绑定的监听socket, 升级程序时不丢失查询
+//!
+//! 旧进程在指定路径监听unix socket, 新进程启动时连接该路径, 旧进程通过SCM_RIGHTS发送
+//! dns及动态dns的监听socket后停止读取, 等待转发中的查询完成后退出, 交接期间到达的查询
+//! 由内核缓存在新旧进程共享的socket中, 由新进程处理
+//!
+//! 也支持systemd的socket激活(LISTEN_FDS), 由systemd绑定dns端口并传给服务进程, 按socket类型区分:
+//! 第一个udp socket为dns监听socket, 第二个udp socket及tcp socket为动态dns独立端口的socket
+
+use std::net::{TcpListener, UdpSocket};
+
+pub(crate) const DNS_SOCKET: &str = "dns";                // dns监听socket的名称
+pub(crate) const DYNDNS_UDP_SOCKET: &str = "dyndns-udp";  // 动态dns独立端口udp socket的名称
+pub(crate) const DYNDNS_TCP_SOCKET: &str = "dyndns-tcp";  // 动态dns独立端口tcp socket的名称
+
+/// 从旧进程或systemd接收的监听socket
+#[derive(Debug, Default)]
+pub struct Inherited {
+    pub dns       : Option<UdpSocket>,     // dns监听socket
+    pub dyndns_udp: Option<UdpSocket>,     // 动态dns独立端口的udp socket
+    pub dyndns_tcp: Option<TcpListener>,   // 动态dns独立端口的tcp socket
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io::Read;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+    use anyhow::{Context, Result};
+    use super::*;
+
+    const MAX_FDS: usize = 8;                                 // 一次交接的最大socket数量
+    const TAKE_OVER_TIMEOUT: Duration = Duration::from_secs(5);  // 等待旧进程发送socket的超时时间
+    const SD_LISTEN_FDS_START: RawFd = 3;                     // systemd传递的第一个socket的文件描述符
+
+    /// 删除上次运行遗留的unix socket文件以便重新绑定, 路径不存在时忽略,
+    /// 路径是普通文件等其它类型时返回错误, 以免配置错误时删除其它文件
+    pub fn remove_stale_socket(path: &str) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+                .with_context(|| format!("remove stale socket {path} failed")),
+            Ok(_) => anyhow::bail!("{path} exists and is not a unix socket"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("check socket path {path} failed"))),
+        }
+    }
+
+    /// 连接旧进程的交接路径并接收监听socket, 路径不存在或没有进程监听时返回None
+    pub fn take_over(path: &str) -> Result<Option<Inherited>> {
+        let mut stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => return Ok(None),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("connect handover socket {path} failed"))),
+        };
+        stream.set_read_timeout(Some(TAKE_OVER_TIMEOUT))?;
+
+        let (names, fds) = recv_fds(stream.as_raw_fd()).with_context(|| format!("receive sockets from {path} failed"))?;
+        if names.split(',').filter(|s| !s.is_empty()).count() != fds.len() {
+            anyhow::bail!("handover message [{names}] doesn't match {} received sockets", fds.len());
+        }
+        // 旧进程在发送后关闭连接, 读取结束表示旧进程已停止读取监听socket
+        let _ = stream.read(&mut [0; 1]);
+
+        let mut inherited = Inherited::default();
+        for (name, fd) in names.split(',').zip(fds) {
+            match name {
+                DNS_SOCKET => inherited.dns = Some(UdpSocket::from(fd)),
+                DYNDNS_UDP_SOCKET => inherited.dyndns_udp = Some(UdpSocket::from(fd)),
+                DYNDNS_TCP_SOCKET => inherited.dyndns_tcp = Some(TcpListener::from(fd)),
+                _ => log::warn!("unknown handover socket {name} ignored"),
+            }
+        }
+        Ok(Some(inherited))
+    }
+
+    /// systemd socket激活传递的监听socket, 未通过socket激活启动时返回None
+    ///
+    /// 读取后清除LISTEN_PID及LISTEN_FDS环境变量, 避免子进程(如变更钩子命令)误认为socket是传给自己的
+    pub fn systemd_sockets() -> Option<Inherited> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        let pid: u32 = pid?.parse().ok()?;
+        let count: RawFd = count?.parse().ok()?;
+        if pid != std::process::id() || count <= 0 {
+            return None;
+        }
+        let fds = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count)).map(|fd| {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            unsafe { OwnedFd::from_raw_fd(fd) }
+        });
+        Some(sockets_of_fds(fds))
+    }
+
+    /// 按socket类型分配监听socket, 非socket或多余的文件描述符被关闭
+    pub(super) fn sockets_of_fds(fds: impl Iterator<Item = OwnedFd>) -> Inherited {
+        let mut inherited = Inherited::default();
+        for fd in fds {
+            match socket_type(fd.as_raw_fd()) {
+                Some(libc::SOCK_DGRAM) if inherited.dns.is_none() => inherited.dns = Some(UdpSocket::from(fd)),
+                Some(libc::SOCK_DGRAM) if inherited.dyndns_udp.is_none() => inherited.dyndns_udp = Some(UdpSocket::from(fd)),
+                Some(libc::SOCK_STREAM) if inherited.dyndns_tcp.is_none() => inherited.dyndns_tcp = Some(TcpListener::from(fd)),
+                ty => log::warn!("unexpected systemd socket fd {} of type {ty:?} closed", fd.as_raw_fd()),
+            }
+        }
+        inherited
+    }
+
+    /// 文件描述符的socket类型(SOCK_DGRAM/SOCK_STREAM等), 不是socket时返回None
+    fn socket_type(fd: RawFd) -> Option<libc::c_int> {
+        let mut ty: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut ty as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+        (r == 0).then_some(ty)
+    }
+
+    /// 向新进程发送监听socket, `sockets`为socket名称及文件描述符
+    pub(crate) fn send_sockets(stream: RawFd, sockets: &[(&str, RawFd)]) -> Result<()> {
+        if sockets.len() > MAX_FDS {
+            anyhow::bail!("too many sockets to hand over");
+        }
+        let names = sockets.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(",");
+        let fds: Vec<RawFd> = sockets.iter().map(|(_, fd)| *fd).collect();
+
+        let mut cmsg_buf = [0u64; 16];
+        let mut iov = libc::iovec { iov_base: names.as_ptr() as *mut libc::c_void, iov_len: names.len() };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        let fds_len = std::mem::size_of_val(fds.as_slice()) as u32;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+        if unsafe { libc::sendmsg(stream, &msg, 0) } < 0 {
+            return Err(anyhow::Error::new(std::io::Error::last_os_error()).context("send sockets failed"));
+        }
+        Ok(())
+    }
+
+    /// 接收socket名称列表(逗号分隔)及对应的文件描述符
+    fn recv_fds(stream: RawFd) -> Result<(String, Vec<OwnedFd>)> {
+        let mut buf = [0u8; 256];
+        let mut cmsg_buf = [0u64; 16];
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&cmsg_buf) as _;
+
+        let n = unsafe { libc::recvmsg(stream, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let mut fds = Vec::new();
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+                    for i in 0..count {
+                        fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            anyhow::bail!("too many sockets received");
+        }
+        Ok((String::from_utf8_lossy(&buf[..n as usize]).into_owned(), fds))
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use anyhow::Result;
+    use super::*;
+
+    pub fn take_over(_path: &str) -> Result<Option<Inherited>> {
+        anyhow::bail!("socket handover is only supported on unix")
+    }
+
+    pub fn systemd_sockets() -> Option<Inherited> {
+        None
+    }
+}
+
+pub use imp::{systemd_sockets, take_over};
+#[cfg(unix)]
+pub(crate) use imp::{remove_stale_socket, send_sockets};
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use super::*;
+
+    #[test]
+    fn test_take_over() {
+        let path = std::env::temp_dir().join(format!("minidns-handover-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        assert!(take_over(&path).unwrap().is_none());
+
+        let dns = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dns_addr = dns.local_addr().unwrap();
+        let listener = UnixListener::bind(&path).unwrap();
+        let old = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            send_sockets(stream.as_raw_fd(), &[(DNS_SOCKET, dns.as_raw_fd())]).unwrap();
+        });
+
+        let inherited = take_over(&path).unwrap().unwrap();
+        old.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let socket = inherited.dns.unwrap();
+        assert_eq!(dns_addr, socket.local_addr().unwrap());
+        assert!(inherited.dyndns_udp.is_none() && inherited.dyndns_tcp.is_none());
+
+        // 交接后的socket可以继续接收发往原地址的数据
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", dns_addr).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(4, socket.recv(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let path = std::env::temp_dir().join(format!("minidns-stale-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        remove_stale_socket(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        remove_stale_socket(&path).unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+
+        // 普通文件不会被删除
+        std::fs::write(&path, b"data").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert_eq!(b"data".to_vec(), std::fs::read(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sockets_of_fds() {
+        use std::os::unix::io::OwnedFd;
+
+        let dns = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dyndns_tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dyndns_udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addrs = (dns.local_addr().unwrap(), dyndns_udp.local_addr().unwrap(), dyndns_tcp.local_addr().unwrap());
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+
+        // 按类型分配, tcp socket可以位于udp socket之间, 非socket的文件描述符被忽略
+        let fds: Vec<OwnedFd> = vec![dns.into(), file.into(), dyndns_tcp.into(), dyndns_udp.into()];
+        let inherited = imp::sockets_of_fds(fds.into_iter());
+        assert_eq!(addrs.0, inherited.dns.unwrap().local_addr().unwrap());
+        assert_eq!(addrs.1, inherited.dyndns_udp.unwrap().local_addr().unwrap());
+        assert_eq!(addrs.2, inherited.dyndns_tcp.unwrap().local_addr().unwrap());
+    }
+}
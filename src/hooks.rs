@@ -0,0 +1,19 @@
+use std::net::SocketAddr;
+use super::dnsutil::{DnsQuestion, DnsRecord, ResultCode};
+
+/// 查询钩子的处理结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookAction {
+    Continue,                 // 继续正常解析
+    Answer(Vec<DnsRecord>),   // 直接以指定的记录回复
+    Error(ResultCode),        // 直接回复指定的错误码, 用于拒绝查询
+}
+
+/// 收到查询请求时调用, 参数为客户端地址及查询条目, 可以注入自定义记录或拒绝查询
+pub type QueryHook = Box<dyn Fn(&SocketAddr, &DnsQuestion) -> HookAction>;
+
+/// 回复客户端前调用, 可以修改回复码及应答记录, 如清空应答并设置REFUSED以否决该应答
+pub type ResponseHook = Box<dyn Fn(&SocketAddr, &DnsQuestion, &mut ResultCode, &mut Vec<DnsRecord>)>;
+
+/// 查询的域名被屏蔽(解析为0.0.0.0)时调用
+pub type BlockHook = Box<dyn Fn(&SocketAddr, &DnsQuestion)>;
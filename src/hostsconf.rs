@@ -1,63 +1,313 @@
-use anyhow::{Result, Context};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
+const INCLUDE_DIRECTIVE: &[u8] = b"#include";   // 包含其它hosts文件的指令
+const ADDRESS_DIRECTIVE: &[u8] = b"address=/";  // dnsmasq格式的地址指令
+const MAX_INCLUDE_DEPTH: usize = 8;             // 最大包含层级, 防止循环包含
+const READ_BUF_SIZE: usize = 64 * 1024;         // 文件读取缓冲区大小
+const QTYPE_CNAME: &str = "CNAME";              // 别名记录类型
+const QTYPE_TXT: &str = "TXT";                  // 文本记录类型
+
+type Result<T> = std::result::Result<T, HostsError>;
+
+/// hosts文件中记录的数据
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostRecord {
+    A(String),        // ip地址, 可以是逗号分隔的多个地址
+    Cname(String),    // 别名指向的域名
+    Txt(String),      // 文本内容
+}
+
+/// hosts文件中的一条记录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostEntry {
+    pub host  : String,         // 域名, 以`.`开头表示同时匹配域名本身及其所有子域名
+    pub record: HostRecord,     // 记录数据
+    pub ttl   : Option<u32>,    // 可选的生存时间, 为None时使用服务器缺省值
+}
+
+/// hosts文件解析错误的类型
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostsErrorKind {
+    Io(String),        // 文件读取失败
+    Format,            // 行格式错误
+    Ttl,               // ttl格式错误
+    Utf8,              // 内容不是有效的utf8编码
+    Include(String),   // 包含指令错误
+}
+
+/// hosts文件解析错误, 包含出错的文件名及行列位置(从1开始, 文件读取错误时为0)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostsError {
+    pub file  : String,
+    pub line  : usize,
+    pub column: usize,
+    pub kind  : HostsErrorKind,
+}
+
+impl fmt::Display for HostsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            HostsErrorKind::Io(ref e) => write!(f, "read hosts config {} failed: {e}", self.file),
+            HostsErrorKind::Format => write!(f, "hosts config {} format error in line {}, column {}", self.file, self.line, self.column),
+            HostsErrorKind::Ttl => write!(f, "hosts config {} ttl error in line {}, column {}", self.file, self.line, self.column),
+            HostsErrorKind::Utf8 => write!(f, "hosts config {} format is not utf8 in line {}", self.file, self.line),
+            HostsErrorKind::Include(ref e) => write!(f, "hosts config {} include error in line {}: {e}", self.file, self.line),
+        }
+    }
+}
+
+impl std::error::Error for HostsError {}
+
+/// hosts文件解析器, 作为迭代器依次返回文件中的每条记录, 行格式错误时返回错误后继续解析下一行,
+/// 文件读取或包含指令出错后迭代结束
+///
+/// 文件采用缓冲方式逐行读取解析, 内存占用与文件大小无关, 适用于百万行级别的屏蔽列表
+///
+/// 支持使用`#include file`指令包含其它hosts文件,
+/// 文件名可以使用通配符(`*`, `?`), 相对路径以当前文件所在目录为基准
+///
+/// 同时兼容dnsmasq格式的`address=/example.com/192.168.1.10`指令,
+/// 该指令同时匹配域名本身及其所有子域名, 返回的域名以`.`开头表示通配
+///
+/// 以记录类型开头的行声明非A记录, 格式为: `类型 域名 数据 [ttl]`, 支持的类型有
+/// `CNAME www.lan nas.lan` 及 `TXT _dmarc.example.com "v=DMARC1; p=none"`
 pub struct HostsConfig {
-    data: Vec<u8>,
-    pos: usize,
+    files  : Vec<HostsFile>,   // 正在解析的文件栈, 栈顶为当前解析的文件
+    pending: Vec<HostEntry>,   // address指令中尚未返回的记录, 逆序存放
+}
+
+// 单个hosts文件
+struct HostsFile {
+    name   : String,             // 文件名, 用于错误提示
+    reader : Box<dyn BufRead>,   // 文件读取器
+    line   : Vec<u8>,            // 当前行内容(不含换行符)
+    line_no: usize,              // 当前行号, 从1开始
+    skip_lf: bool,               // 上一行以'\r'结尾, 需要跳过紧随其后的'\n'
+    depth  : usize,              // 包含层级
+}
+
+// 解析得到的行
+enum HostsLine {
+    Host(Range<usize>, Range<usize>, Option<u32>),   // ip范围, 域名范围, ttl
+    Include(String, usize),                          // 包含指令的文件名及所在位置
+    Address(Vec<String>, String),                    // address指令的域名列表及ip
+    Record(HostEntry),                               // 指定记录类型的行
 }
 
 impl HostsConfig {
 
     pub fn new(filename: &str) -> Result<HostsConfig> {
         Ok(HostsConfig {
-            data: std::fs::read(filename).with_context(|| format!("read {filename} failed"))?,
-            pos: 0,
+            files: vec![HostsFile::new(filename, 0)?],
+            pending: Vec::new(),
         })
     }
 
-    pub fn next(&mut self) -> Result<Option<(&str, &str)>> {
+    /// 从内存数据创建解析器, 通常用于远程下载的hosts内容, 此时不支持包含指令
+    pub fn with_data(name: &str, data: Vec<u8>) -> HostsConfig {
+        HostsConfig {
+            files: vec![HostsFile::with_data(name, data, MAX_INCLUDE_DEPTH)],
+            pending: Vec::new(),
+        }
+    }
+
+    /// 读取下一条记录, 返回None表示所有文件已解析完毕
+    fn next_entry(&mut self) -> Result<Option<HostEntry>> {
+        loop {
+            if let Some(entry) = self.pending.pop() {
+                return Ok(Some(entry));
+            }
+
+            let file = match self.files.last_mut() {
+                Some(file) => file,
+                None => return Ok(None),
+            };
+
+            match file.next_line()? {
+                Some(HostsLine::Host(ip, host, ttl)) => {
+                    let ip = file.to_str(ip)?;
+                    let host = file.to_str(host)?;
+                    return Ok(Some(HostEntry { host, record: HostRecord::A(ip), ttl }));
+                },
+                Some(HostsLine::Include(pattern, pos)) => {
+                    if file.depth >= MAX_INCLUDE_DEPTH {
+                        return Err(file.error(pos, HostsErrorKind::Include(format!("{pattern} is too deep or not allowed"))));
+                    }
+                    let depth = file.depth + 1;
+                    let names = include_files(&file.name, &pattern)
+                        .map_err(|e| file.error(pos, HostsErrorKind::Include(e.to_string())))?;
+                    for name in names.iter().rev() {
+                        self.files.push(HostsFile::new(name, depth)?);
+                    }
+                },
+                Some(HostsLine::Address(domains, ip)) => {
+                    self.pending = domains.into_iter().rev()
+                        .map(|d| HostEntry { host: format!(".{d}"), record: HostRecord::A(ip.clone()), ttl: None })
+                        .collect();
+                },
+                Some(HostsLine::Record(entry)) => return Ok(Some(entry)),
+                None => { self.files.pop(); },
+            }
+        }
+    }
+
+}
+
+impl Iterator for HostsConfig {
+    type Item = Result<HostEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // 文件读取及包含出错后不再继续解析, 行格式错误时出错的行已读取, 可以继续解析后续的行
+                if matches!(e.kind, HostsErrorKind::Io(_) | HostsErrorKind::Include(_)) {
+                    self.files.clear();
+                    self.pending.clear();
+                }
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl HostsFile {
+
+    fn new(filename: &str, depth: usize) -> Result<HostsFile> {
+        let file = File::open(filename).map_err(|e| HostsError {
+            file: filename.to_string(), line: 0, column: 0, kind: HostsErrorKind::Io(e.to_string()),
+        })?;
+        Ok(HostsFile::with_reader(filename, Box::new(BufReader::with_capacity(READ_BUF_SIZE, file)), depth))
+    }
+
+    fn with_data(name: &str, data: Vec<u8>, depth: usize) -> HostsFile {
+        HostsFile::with_reader(name, Box::new(Cursor::new(data)), depth)
+    }
+
+    fn with_reader(name: &str, reader: Box<dyn BufRead>, depth: usize) -> HostsFile {
+        HostsFile { name: name.to_string(), reader, line: Vec::new(), line_no: 0, skip_lf: false, depth }
+    }
+
+    /// 生成当前行指定位置的解析错误
+    fn error(&self, pos: usize, kind: HostsErrorKind) -> HostsError {
+        HostsError { file: self.name.clone(), line: self.line_no, column: pos + 1, kind }
+    }
+
+    /// 将当前行指定范围的内容转为字符串
+    fn to_str(&self, range: Range<usize>) -> Result<String> {
+        let pos = range.start;
+        match std::str::from_utf8(&self.line[range]) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(self.error(pos, HostsErrorKind::Utf8)),
+        }
+    }
+
+    /// 读取下一行到行缓冲区, 支持'\n', '\r\n', '\r'三种换行符, 返回false表示文件已结束
+    fn read_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        let mut found = false;
+        loop {
+            let buf = self.reader.fill_buf().map_err(|e| HostsError {
+                file: self.name.clone(), line: self.line_no, column: 0, kind: HostsErrorKind::Io(e.to_string()),
+            })?;
+            if buf.is_empty() {
+                if found {
+                    self.line_no += 1;
+                }
+                return Ok(found);
+            }
+
+            let mut start = 0;
+            if self.skip_lf {
+                self.skip_lf = false;
+                if buf[0] == b'\n' {
+                    start = 1;
+                }
+            }
+
+            match buf[start..].iter().position(|c| *c == b'\r' || *c == b'\n') {
+                Some(p) => {
+                    self.line.extend_from_slice(&buf[start..start + p]);
+                    self.skip_lf = buf[start + p] == b'\r';
+                    self.reader.consume(start + p + 1);
+                    self.line_no += 1;
+                    return Ok(true);
+                },
+                None => {
+                    let len = buf.len();
+                    self.line.extend_from_slice(&buf[start..]);
+                    self.reader.consume(len);
+                    found = found || len > start;
+                },
+            }
+        }
+    }
+
+    /// 解析下一条有效行, 返回None表示文件已结束
+    fn next_line(&mut self) -> Result<Option<HostsLine>> {
+        while self.read_line()? {
+            if let Some(line) = self.parse_line()? {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 解析当前行, 空行及注释行返回None
+    fn parse_line(&mut self) -> Result<Option<HostsLine>> {
         // #[derive(Eq)]
-        enum Status { Start, Comment, Ip, IpEnd, Host, HostEnd, LineComment, FmtError }
+        enum Status { Start, Comment, Ip, IpEnd, Host, HostEnd, Ttl, TtlEnd, LineComment, FmtError }
 
-        let (mut pos, len) = (self.pos, self.data.len());
+        let (mut pos, len) = (0, self.line.len());
         let mut status = Status::Start;
         let (mut ip_begin, mut ip_end) = (0, 0);
         let (mut host_begin, mut host_end) = (0, 0);
+        let (mut ttl_begin, mut ttl_end) = (0, 0);
 
         while pos < len {
-            let c = self.data[pos];
+            let c = self.line[pos];
 
             match status {
                 Status::Start => {
                     match c {
-                        b'\t' | b' ' | b'\r' | b'\n' => {},
-                        b'#' => status = Status::Comment,
+                        b'\t' | b' ' => {},
+                        b'#' => {
+                            if let Some(pattern) = self.include_line(pos) {
+                                return Ok(Some(HostsLine::Include(pattern, pos)));
+                            }
+                            status = Status::Comment;
+                        },
+                        b'a' if self.line[pos..].starts_with(ADDRESS_DIRECTIVE) => {
+                            return self.address_line(pos).map(Some);
+                        },
+                        b'C' | b'c' | b'T' | b't' if record_type(&self.line[pos..]).is_some() => {
+                            return self.record_line(pos).map(Some);
+                        },
                         _ => { status = Status::Ip; ip_begin = pos; },
                     }
                 },
-                Status::Comment => {
-                    match c {
-                        b'\r' | b'\n' => status = Status::Start,
-                        _ => {},
-                    }
-                },
+                Status::Comment | Status::LineComment => break,
                 Status::Ip => {
                     match c {
                         b'\t' | b' ' => { status = Status::IpEnd; ip_end = pos; },
-                        b'\r' | b'\n' | b'#' => { status = Status::FmtError; break; },
+                        b'#' => { status = Status::FmtError; break; },
                         _ => {},
                     }
                 },
                 Status::IpEnd => {
                     match c {
                         b'\t' | b' ' => {},
-                        b'\r' | b'\n' | b'#' => { status = Status::FmtError; break; },
+                        b'#' => { status = Status::FmtError; break; },
                         _ => { status = Status::Host; host_begin = pos; },
                     }
                 },
                 Status::Host => {
                     match c {
                         b'\t' | b' ' => { status = Status::HostEnd; host_end = pos; },
-                        b'\r' | b'\n' => { status = Status::HostEnd; host_end = pos; break; },
                         b'#' => { status = Status::LineComment; host_end = pos; },
                         _ => {},
                     }
@@ -66,16 +316,25 @@ impl HostsConfig {
                 Status::HostEnd => {
                     match c {
                         b'\t' | b' ' => {},
-                        b'\r' | b'\n' => break,
                         b'#' => status = Status::LineComment,
+                        b'0'..=b'9' => { status = Status::Ttl; ttl_begin = pos; },
                         _ => { status = Status::FmtError; break; },
                     }
 
                 },
-                Status::LineComment => {
+                Status::Ttl => {
                     match c {
-                        b'\r' | b'\n' => break,
-                        _ => {},
+                        b'0'..=b'9' => {},
+                        b'\t' | b' ' => { status = Status::TtlEnd; ttl_end = pos; },
+                        b'#' => { status = Status::LineComment; ttl_end = pos; },
+                        _ => { status = Status::FmtError; break; },
+                    }
+                },
+                Status::TtlEnd => {
+                    match c {
+                        b'\t' | b' ' => {},
+                        b'#' => status = Status::LineComment,
+                        _ => { status = Status::FmtError; break; },
                     }
                 },
                 Status::FmtError => break,
@@ -86,57 +345,178 @@ impl HostsConfig {
 
         match status {
             Status::Start | Status::Comment => return Ok(None),
-            Status::Ip | Status::IpEnd | Status::FmtError => {
-                let p = if pos == len { pos } else { pos - 1};
-                let line = HostsConfig::location_line(&self.data, p);
-                anyhow::bail!("hosts config format error in line {line}");
-            },
+            Status::Ip | Status::IpEnd | Status::FmtError => return Err(self.error(pos, HostsErrorKind::Format)),
             Status::Host => host_end = pos,
+            Status::Ttl => ttl_end = pos,
             _ => {},
         }
 
-        self.pos = if pos == len { pos } else { pos + 1 };
+        let ttl = match ttl_begin < ttl_end {
+            true => {
+                let ttl = self.to_str(ttl_begin..ttl_end)?;
+                Some(ttl.parse().map_err(|_| self.error(ttl_begin, HostsErrorKind::Ttl))?)
+            },
+            false => None,
+        };
 
-        if let Ok(ip) = std::str::from_utf8(&self.data[ip_begin..ip_end]) {
-            if let Ok(host) = std::str::from_utf8(&self.data[host_begin..host_end]) {
-                return Ok(Some((host, ip)));
-            }
+        Ok(Some(HostsLine::Host(ip_begin..ip_end, host_begin..host_end, ttl)))
+    }
+
+    /// 解析dnsmasq格式的地址指令: address=/domain1/domain2/.../ip, ip为`#`时表示0.0.0.0
+    fn address_line(&self, pos: usize) -> Result<HostsLine> {
+        let line = match std::str::from_utf8(&self.line[pos + ADDRESS_DIRECTIVE.len()..]) {
+            Ok(line) => line,
+            Err(_) => return Err(self.error(pos, HostsErrorKind::Utf8)),
+        };
+        let mut items: Vec<&str> = line.trim_end().split('/').collect();
+        let ip = match items.pop() {
+            Some("#") => "0.0.0.0",
+            Some(ip) if !ip.is_empty() => ip,
+            _ => "",
+        };
+        let domains: Vec<String> = items.iter()
+            .map(|d| d.trim_start_matches('.').to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+
+        if ip.is_empty() || domains.is_empty() {
+            return Err(self.error(pos, HostsErrorKind::Format));
         }
+        Ok(HostsLine::Address(domains, ip.to_string()))
+    }
+
+    /// 解析指定记录类型的行: 类型 域名 数据 [ttl], TXT记录的数据可以用双引号包含空格
+    fn record_line(&self, pos: usize) -> Result<HostsLine> {
+        let line = match std::str::from_utf8(&self.line[pos..]) {
+            Ok(line) => line,
+            Err(_) => return Err(self.error(pos, HostsErrorKind::Utf8)),
+        };
+        let format_error = || self.error(pos, HostsErrorKind::Format);
 
-        anyhow::bail!("hosts config format is not utf8");
+        let (kind, rest) = line.split_once([' ', '\t']).ok_or_else(format_error)?;
+        let (host, rest) = rest.trim_start().split_once([' ', '\t']).ok_or_else(format_error)?;
+        let rest = rest.trim_start();
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) if kind.eq_ignore_ascii_case("TXT") => parse_quoted(quoted).ok_or_else(format_error)?,
+            _ => {
+                let end = rest.find([' ', '\t', '#']).unwrap_or(rest.len());
+                (rest[..end].to_string(), &rest[end..])
+            },
+        };
+
+        // 可选的ttl及注释
+        let rest = rest.trim_start();
+        let rest = if rest.starts_with('#') { "" } else { rest };
+        let end = rest.find([' ', '\t', '#']).unwrap_or(rest.len());
+        let ttl = match &rest[..end] {
+            "" => None,
+            ttl => Some(ttl.parse().map_err(|_| self.error(pos, HostsErrorKind::Ttl))?),
+        };
+        let rest = rest[end..].trim_start();
+        if value.is_empty() || !(rest.is_empty() || rest.starts_with('#')) {
+            return Err(format_error());
+        }
+
+        let record = match kind.eq_ignore_ascii_case(QTYPE_CNAME) {
+            true => HostRecord::Cname(value.trim_end_matches('.').to_string()),
+            false => HostRecord::Txt(value),
+        };
+        Ok(HostsLine::Record(HostEntry { host: host.to_string(), record, ttl }))
     }
 
-    fn location_line(data: &[u8], pos: usize) -> usize {
-        let len = data.len();
-        let max_pos = if pos < len { pos } else { len };
-        let mut line = 1;
-        for pos in 0..max_pos {
-            let c = data[pos];
-            if c == b'\n' || (c == b'\r' && (pos + 1 < len && data[pos + 1] != b'\n')) {
-                line += 1;
-            }
+    /// 解析包含指令, 返回指令中的文件名
+    fn include_line(&self, pos: usize) -> Option<String> {
+        let data = &self.line[pos..];
+        if !data.starts_with(INCLUDE_DIRECTIVE)
+                || !matches!(data.get(INCLUDE_DIRECTIVE.len()), Some(b' ' | b'\t')) {
+            return None;
+        }
+
+        let pattern = String::from_utf8_lossy(&data[INCLUDE_DIRECTIVE.len()..]);
+        Some(pattern.trim().to_string())
+    }
+
+}
+
+/// 判断行是否以记录类型开头, 返回记录类型(大写)
+fn record_type(data: &[u8]) -> Option<&'static str> {
+    [QTYPE_CNAME, QTYPE_TXT].into_iter().find(|t| {
+        data.len() > t.len() && data[..t.len()].eq_ignore_ascii_case(t.as_bytes())
+            && matches!(data[t.len()], b' ' | b'\t')
+    })
+}
+
+/// 解析双引号包含的字符串(不含开头的引号), 支持`\"`及`\\`转义, 返回字符串及剩余内容
+fn parse_quoted(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
         }
+    }
+    None
+}
 
-        line
+/// 得到包含指令对应的文件列表, 文件名支持通配符
+fn include_files(parent: &str, pattern: &str) -> std::io::Result<Vec<String>> {
+    let path = match Path::new(parent).parent() {
+        Some(dir) => dir.join(pattern),
+        None => PathBuf::from(pattern),
+    };
+
+    let file_name = path.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    if !file_name.contains(['*', '?']) {
+        return Ok(vec![path.to_string_lossy().into_owned()]);
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file() && wildcard_match(file_name.as_bytes(), name.as_bytes()) {
+            names.push(dir.join(name).to_string_lossy().into_owned());
+        }
     }
+    names.sort();
+    Ok(names)
+}
 
+/// 通配符匹配, 支持`*`(任意个字符)及`?`(单个字符)
+pub(crate) fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => (0..=text.len()).any(|i| wildcard_match(&pattern[1..], &text[i..])),
+        Some(b'?') => !text.is_empty() && wildcard_match(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && wildcard_match(&pattern[1..], &text[1..]),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::HostsConfig;
+    use super::*;
 
     macro_rules! next_ok {
         ($hc:expr, $host:expr, $ip:expr) => {
-            let (h, i) = $hc.next().unwrap().unwrap();
-            assert_eq!($host, h);
-            assert_eq!($ip, i);
+            next_ok!($hc, $host, $ip, None);
+        };
+        ($hc:expr, $host:expr, $ip:expr, $ttl:expr) => {
+            let e = $hc.next().unwrap().unwrap();
+            assert_eq!($host, e.host);
+            assert_eq!(HostRecord::A($ip.to_string()), e.record);
+            assert_eq!($ttl, e.ttl);
         };
     }
 
     macro_rules! next_error {
         ($hc:expr) => {
-            if let Ok(_) = $hc.next() {
+            if !matches!($hc.next(), Some(Err(_))) {
                 panic!("expect HostsConfig::next return Err, but is return Ok");
             };
         };
@@ -144,20 +524,20 @@ mod tests {
 
     #[test]
     fn test_hostsconfig() {
-        let lines = b"\r\r \n\n \r\n \n\r";
-        assert_eq!(7, HostsConfig::location_line(lines, lines.len() - 1));
+        let mut hf = HostsFile::with_data("", b"\r\r \n\n \r\n \n\r".to_vec(), 0);
+        while hf.read_line().unwrap() {}
+        assert_eq!(7, hf.line_no);
 
         fn set_data(hc: &mut HostsConfig, data: &[u8]) {
-            hc.data.clear();
-            hc.data.extend_from_slice(data);
-            hc.pos = 0;
+            hc.files = vec![HostsFile::with_data("", data.to_vec(), 0)];
         }
 
-        let mut hc = HostsConfig { data: Vec::new(), pos: 0 };
-        assert!(None == hc.next().unwrap());
+        let mut hc = HostsConfig { files: Vec::new(), pending: Vec::new() };
+        set_data(&mut hc, b"");
+        assert!(hc.next().is_none());
 
         set_data(&mut hc, b"  #comment \r\n # comment");
-        assert!(None == hc.next().unwrap());
+        assert!(hc.next().is_none());
 
         set_data(&mut hc, b"a");
         next_error!(hc);
@@ -168,11 +548,78 @@ mod tests {
         set_data(&mut hc, b"127.0.0.1 a.a.com");
         next_ok!(hc, "a.a.com", "127.0.0.1");
 
-        set_data(&mut hc, b"127.0.0.1 a.a.com\n 127.0.0.2 b.a.com #comment\r  #comment\r\n127.0.0.3 c.a.com  \n 1 2 \n 3 4 5");
+        set_data(&mut hc, b"127.0.0.1 a.a.com\n 127.0.0.2 b.a.com #comment\r  #comment\r\n127.0.0.3 c.a.com  \n 1 2 \n 3 4 5\n 6 7 8 9");
         next_ok!(hc, "a.a.com", "127.0.0.1");
         next_ok!(hc, "b.a.com", "127.0.0.2");
         next_ok!(hc, "c.a.com", "127.0.0.3");
         next_ok!(hc, "2", "1");
+        next_ok!(hc, "4", "3", Some(5));
+        next_error!(hc);
+
+        set_data(&mut hc, b"192.168.1.5 nas 3600\n192.168.1.6\tnas2\t60 # comment\n192.168.1.7 nas3 600a");
+        next_ok!(hc, "nas", "192.168.1.5", Some(3600));
+        next_ok!(hc, "nas2", "192.168.1.6", Some(60));
+        let e = hc.next().unwrap().unwrap_err();
+        assert_eq!((HostsErrorKind::Format, 3, 21), (e.kind, e.line, e.column));
+        assert!(hc.next().is_none());
+
+        set_data(&mut hc, b"127.0.0.1 a.lan\n::1 localhost ip6-localhost\n127.0.0.2 b.lan\n");
+        next_ok!(hc, "a.lan", "127.0.0.1");
+        next_error!(hc);
+        next_ok!(hc, "b.lan", "127.0.0.2");
+        assert!(hc.next().is_none());
+
+        set_data(&mut hc, b"127.0.0.1 a.lan\n127.0.0.2 b.lan\n");
+        let hosts: Vec<String> = hc.map(|e| e.unwrap().host).collect();
+        assert_eq!(vec!["a.lan", "b.lan"], hosts);
+    }
+
+    #[test]
+    fn test_include() {
+        assert!(wildcard_match(b"*.hosts", b"team-a.hosts"));
+        assert!(wildcard_match(b"team-?.hosts", b"team-a.hosts"));
+        assert!(!wildcard_match(b"*.hosts", b"team-a.conf"));
+
+        let dir = std::env::temp_dir().join(format!("mdns-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("hosts.d")).unwrap();
+        std::fs::write(dir.join("hosts.conf"), "127.0.0.1 a.lan\n#include hosts.d/*.hosts\n127.0.0.4 d.lan\n").unwrap();
+        std::fs::write(dir.join("hosts.d/1.hosts"), "127.0.0.2 b.lan\n").unwrap();
+        std::fs::write(dir.join("hosts.d/2.hosts"), "# comment\n127.0.0.3 c.lan 60\n127.0.0.3\n").unwrap();
+
+        let mut hc = HostsConfig::new(dir.join("hosts.conf").to_str().unwrap()).unwrap();
+        next_ok!(hc, "a.lan", "127.0.0.1");
+        next_ok!(hc, "b.lan", "127.0.0.2");
+        next_ok!(hc, "c.lan", "127.0.0.3", Some(60));
+        let e = hc.next().unwrap().unwrap_err().to_string();
+        assert!(e.contains("2.hosts") && e.contains("line 3"), "{e}");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_address() {
+        let mut hc = HostsConfig { files: Vec::new(), pending: Vec::new() };
+        hc.files.push(HostsFile::with_data("", b"address=/example.com/192.168.1.10\r\n127.0.0.1 a.lan\naddress=/ad.com/.track.com/#\naddress=/x.com/".to_vec(), 0));
+        next_ok!(hc, ".example.com", "192.168.1.10");
+        next_ok!(hc, "a.lan", "127.0.0.1");
+        next_ok!(hc, ".ad.com", "0.0.0.0");
+        next_ok!(hc, ".track.com", "0.0.0.0");
+        next_error!(hc);
+    }
+
+    #[test]
+    fn test_record() {
+        let data = b"CNAME www.lan nas.lan.\n\tTXT _dmarc.example.com \"v=DMARC1; p=\\\"none\\\"\" 600 # dmarc\n\
+            txt a.lan hello#comment\ntxt.lan 127.0.0.1\nTXT b.lan \"unterminated";
+        let mut hc = HostsConfig::with_data("", data.to_vec());
+        let e = hc.next().unwrap().unwrap();
+        assert_eq!(("www.lan", HostRecord::Cname("nas.lan".to_string()), None), (e.host.as_str(), e.record, e.ttl));
+        let e = hc.next().unwrap().unwrap();
+        assert_eq!(("_dmarc.example.com", HostRecord::Txt("v=DMARC1; p=\"none\"".to_string()), Some(600)),
+            (e.host.as_str(), e.record, e.ttl));
+        let e = hc.next().unwrap().unwrap();
+        assert_eq!(("a.lan", HostRecord::Txt("hello".to_string())), (e.host.as_str(), e.record));
+        next_ok!(hc, "127.0.0.1", "txt.lan");
         next_error!(hc);
     }
 
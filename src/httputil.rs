@@ -0,0 +1,132 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::{Result, Context};
+
+const HTTP_PREFIX: &str    = "http://";   // 仅支持http协议
+const MAX_REDIRECTS: usize = 3;           // 最大重定向次数
+const MAX_RESPONSE_LEN: u64 = 64 << 20;   // 响应(含响应头)的最大长度, 防止异常的服务器耗尽内存
+
+/// http响应
+pub struct HttpResponse {
+    pub status : u16,                     // 响应状态码
+    pub headers: Vec<(String, String)>,   // 响应头
+    pub body   : Vec<u8>,                 // 响应内容
+}
+
+impl HttpResponse {
+    /// 获取指定名称的响应头(忽略大小写)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// 简单的http/1.0客户端请求, 自动跟随重定向(支持相对路径), 响应超过64MB时返回错误
+///
+/// * `method`: 请求方法, GET/POST等
+/// * `url`: 请求地址, 格式为: http://host[:port][/path]
+/// * `headers`: 附加的请求头
+/// * `body`: 请求内容
+/// * `timeout`: 连接及读写超时时间
+pub fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> Result<HttpResponse> {
+    let mut url = url.to_string();
+    for _ in 0..MAX_REDIRECTS {
+        let res = request_once(method, &url, headers, body, timeout)?;
+        match (res.status, res.header("Location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => {
+                log::debug!("http request {url} redirect to {location}");
+                url = redirect_url(&url, location);
+            },
+            _ => return Ok(res),
+        }
+    }
+    anyhow::bail!("http request {url} too many redirects")
+}
+
+fn request_once(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> Result<HttpResponse> {
+    // 解析url, 格式为: http://host[:port][/path]
+    let addr = match url.strip_prefix(HTTP_PREFIX) {
+        Some(addr) => addr,
+        None => anyhow::bail!("unsupported url {url}, only http:// is supported"),
+    };
+    let (addr, path) = match addr.find('/') {
+        Some(pos) => (&addr[..pos], &addr[pos..]),
+        None => (addr, "/"),
+    };
+    let server = if addr.contains(':') { addr.to_string() } else { format!("{addr}:80") };
+    let server = server.to_socket_addrs()?.next()
+        .with_context(|| format!("can't resolve http address {addr}"))?;
+
+    let mut stream = TcpStream::connect_timeout(&server, timeout)
+        .with_context(|| format!("connect {url} failed"))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut req = format!("{method} {path} HTTP/1.0\r\nHost: {addr}\r\nConnection: close\r\n");
+    for (k, v) in headers {
+        req.push_str(&format!("{k}: {v}\r\n"));
+    }
+    if !body.is_empty() {
+        req.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut data = Vec::new();
+    stream.take(MAX_RESPONSE_LEN + 1).read_to_end(&mut data).with_context(|| format!("read {url} response failed"))?;
+    if data.len() as u64 > MAX_RESPONSE_LEN {
+        anyhow::bail!("{url} response is larger than {MAX_RESPONSE_LEN} bytes");
+    }
+    parse_response(&data).with_context(|| format!("{url} response format error"))
+}
+
+/// 重定向的目标地址, Location为以`/`开头的相对路径时使用原地址的主机
+fn redirect_url(url: &str, location: &str) -> String {
+    match (location.strip_prefix('/'), url.strip_prefix(HTTP_PREFIX)) {
+        (Some(_), Some(addr)) if !location.starts_with("//") => {
+            let host = addr.split('/').next().unwrap_or(addr);
+            format!("{HTTP_PREFIX}{host}{location}")
+        },
+        _ => location.to_string(),
+    }
+}
+
+fn parse_response(data: &[u8]) -> Result<HttpResponse> {
+    let head_end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => anyhow::bail!("http response header not complete"),
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .with_context(|| "http response status line error")?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Ok(HttpResponse { status, headers, body: data[head_end + 4..].to_vec() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, redirect_url};
+
+    #[test]
+    fn test_parse_response() {
+        let res = parse_response(b"HTTP/1.1 200 OK\r\nETag: \"abc\"\r\nContent-Type: text/plain\r\n\r\n127.0.0.1 a.lan\n").unwrap();
+        assert_eq!(200, res.status);
+        assert_eq!(Some("\"abc\""), res.header("etag"));
+        assert_eq!(b"127.0.0.1 a.lan\n", &res.body[..]);
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+
+        assert_eq!("http://a.lan:8080/new", redirect_url("http://a.lan:8080/old/hosts", "/new"));
+        assert_eq!("http://b.lan/hosts", redirect_url("http://a.lan/hosts", "http://b.lan/hosts"));
+    }
+}
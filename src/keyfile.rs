@@ -0,0 +1,95 @@
+use std::time::SystemTime;
+use anyhow::{Result, Context};
+
+/// 动态dns更新密钥文件
+///
+/// 文件格式为每行一个密钥, 空行及以'#'开头的行将被忽略,
+/// 允许同时存在多个有效密钥, 以便在密钥轮换期间新旧密钥都能通过校验
+pub struct KeyFile {
+    path    : String,                // 密钥文件路径
+    modified: Option<SystemTime>,    // 最后一次加载时文件的修改时间
+    keys    : Vec<String>,           // 当前有效的密钥列表
+}
+
+impl KeyFile {
+
+    pub fn new(path: &str) -> Result<KeyFile> {
+        let mut kf = KeyFile { path: path.to_string(), modified: None, keys: Vec::new() };
+        kf.load()?;
+        if kf.keys.is_empty() {
+            anyhow::bail!("key file {path} not contains any key");
+        }
+        Ok(kf)
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    /// 文件修改时间发生变化时重新加载密钥, 返回是否进行了重新加载
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)
+            .with_context(|| format!("read key file {} metadata failed", self.path))?
+            .modified().ok();
+        if modified.is_some() && modified == self.modified {
+            return Ok(false);
+        }
+
+        self.load()?;
+        Ok(true)
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let meta = std::fs::metadata(&self.path)
+            .with_context(|| format!("read key file {} metadata failed", self.path))?;
+        check_permissions(&self.path, &meta);
+
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("read key file {} failed", self.path))?;
+        let keys = parse_keys(&text);
+        // 轮换过程中文件可能处于被截断的中间状态, 此时保留原有密钥
+        if keys.is_empty() && !self.keys.is_empty() {
+            anyhow::bail!("key file {} is empty, keep the previous keys", self.path);
+        }
+
+        self.keys = keys;
+        self.modified = meta.modified().ok();
+        log::debug!("load {} keys from key file {}", self.keys.len(), self.path);
+        Ok(())
+    }
+
+}
+
+/// 解析密钥文件内容, 每行一个密钥, 空行及以'#'开头的行将被忽略
+pub fn parse_keys(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// 密钥文件允许组或其他用户访问时给出警告
+#[cfg(unix)]
+fn check_permissions(path: &str, meta: &std::fs::Metadata) {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = meta.permissions().mode();
+    if mode & 0o077 != 0 {
+        log::warn!("key file {path} is accessible by other users (mode {:o}), suggest chmod 600", mode & 0o777);
+    }
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &str, _meta: &std::fs::Metadata) {}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_keys;
+
+    #[test]
+    fn test_parse_keys() {
+        assert!(parse_keys("").is_empty());
+        assert!(parse_keys("# comment\r\n\r\n  \n").is_empty());
+        assert_eq!(vec!["abc", "new key"], parse_keys("abc\r\n# old key\n  new key  \n"));
+    }
+}
@@ -0,0 +1,300 @@
+//! 局域网名称解析, 本地无法解析的单标签域名不转发给上级dns服务器, 而是在局域网内通过
+//! llmnr组播(RFC 4795)及netbios广播(RFC 1002)查询, 用于依赖这两种协议互相发现的windows混合网络
+//!
+//! 应答只接受来自局域网地址及协议端口(llmnr为5355, netbios为137)的数据包, 且名称必须与问题一致
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use mio::{Interest, Registry, Token, net::UdpSocket};
+use super::bufutil::BytePacketBuffer;
+use super::dnsutil::{DnsPacket, DnsQuestion, DnsRecord, QueryType};
+use super::lanquery::{LanCache, is_lan_source};
+
+const LLMNR_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 252)), 5355);  // llmnr组播地址
+const NETBIOS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 137);           // netbios名称服务广播地址
+const NETBIOS_FLAGS: u16 = 0x0110;     // netbios名称查询标志: 期望递归及广播
+const NETBIOS_NB: u16 = 0x0020;        // netbios名称记录类型
+const NETBIOS_NAME_LEN: usize = 15;    // netbios名称的最大长度, 第16个字节为服务类型
+const MAX_TTL: u32 = 60;               // 回复及缓存的最长生存时间, netbios的ttl通常长达数天
+
+/// 等待llmnr/netbios应答的最长时间(RFC 4795建议的LLMNR_TIMEOUT)
+pub const FALLBACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 从llmnr或netbios应答中得到的名称及地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanAnswer {
+    pub id   : u16,           // 查询id
+    pub name : String,        // 应答的名称(小写)
+    pub addrs: Vec<IpAddr>,   // 名称对应的地址
+    pub ttl  : u32,           // 生存时间
+}
+
+/// 局域网名称解析, 本地无法解析的单标签域名通过llmnr及netbios在局域网内查询, 不询问上级dns服务器
+pub struct NameFallback {
+    llmnr         : Option<UdpSocket>,   // llmnr查询socket
+    netbios       : Option<UdpSocket>,   // netbios名称查询socket
+    llmnr_target  : SocketAddr,          // llmnr查询的目标地址
+    netbios_target: SocketAddr,          // netbios查询的目标地址
+    cache         : LanCache,            // 应答缓存
+}
+
+impl NameFallback {
+
+    /// 按逗号分隔的协议名称(llmnr/netbios)创建
+    pub fn new(protocols: &str) -> Result<NameFallback> {
+        NameFallback::with_targets(protocols, LLMNR_ADDR, NETBIOS_ADDR)
+    }
+
+    /// 向指定地址发送查询, 用于测试
+    pub fn with_targets(protocols: &str, llmnr_target: SocketAddr, netbios_target: SocketAddr) -> Result<NameFallback> {
+        let (mut llmnr, mut netbios) = (None, None);
+        for protocol in protocols.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+                .with_context(|| format!("bind {protocol} socket failed"))?;
+            match protocol.to_ascii_lowercase().as_str() {
+                "llmnr" => {
+                    // llmnr查询的ip ttl应为1, 只在本网段内传播(RFC 4795 2.5)
+                    if let Err(e) = socket.set_multicast_ttl_v4(1) {
+                        log::warn!("set llmnr multicast ttl failed: {e}");
+                    }
+                    llmnr = Some(socket);
+                },
+                "netbios" => {
+                    socket.set_broadcast(true).with_context(|| "enable netbios broadcast failed")?;
+                    netbios = Some(socket);
+                },
+                _ => anyhow::bail!("unknown name fallback protocol {protocol}, expect llmnr/netbios"),
+            }
+        }
+        if llmnr.is_none() && netbios.is_none() {
+            anyhow::bail!("name fallback protocols is empty");
+        }
+        Ok(NameFallback { llmnr, netbios, llmnr_target, netbios_target, cache: LanCache::default() })
+    }
+
+    /// 启用的协议名称
+    pub fn protocols(&self) -> String {
+        let names = [("llmnr", self.llmnr.is_some()), ("netbios", self.netbios.is_some())];
+        names.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect::<Vec<_>>().join(",")
+    }
+
+    /// 注册所有socket的可读事件, 共用同一个token
+    pub fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        for socket in self.llmnr.iter_mut().chain(self.netbios.iter_mut()) {
+            registry.register(socket, token, Interest::READABLE)
+                .with_context(|| format!("register socket event {} fail", token.0))?;
+        }
+        Ok(())
+    }
+
+    /// 是否可以查询: 单标签域名的A记录, 或启用llmnr时的AAAA记录
+    pub fn accepts(&self, question: &DnsQuestion) -> bool {
+        let name = &question.name;
+        let valid = !name.is_empty() && !name.contains('.');
+        match question.qtype {
+            QueryType::A => valid,
+            QueryType::AAAA => valid && self.llmnr.is_some(),
+            _ => false,
+        }
+    }
+
+    /// 缓存的应答, ttl为剩余时间
+    pub fn cached(&self, question: &DnsQuestion, now: Instant) -> Option<Vec<DnsRecord>> {
+        self.cache.cached(question, now)
+    }
+
+    /// 以指定的查询id同时发送llmnr及netbios(仅A记录)查询, 全部发送失败时返回错误
+    pub fn send_query(&mut self, id: u16, question: &DnsQuestion, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let mut result = Err(anyhow::anyhow!("no name fallback protocol for {} {}", question.name, question.qtype));
+        if let Some(ref socket) = self.llmnr {
+            let packet = DnsPacket::builder().id(id).question(DnsQuestion::new(question.name.clone(), question.qtype)).build();
+            packet.write(buffer)?;
+            result = socket.send_to(buffer.data(), self.llmnr_target).map(|_| ())
+                .with_context(|| format!("send llmnr query of {} failed", question.name));
+        }
+        if let (Some(socket), QueryType::A) = (&self.netbios, question.qtype) {
+            let sent = netbios_query(id, &question.name).and_then(|data| {
+                socket.send_to(&data, self.netbios_target).with_context(|| format!("send netbios query of {} failed", question.name))
+            });
+            match sent {
+                Ok(_) => result = Ok(()),
+                Err(e) if result.is_ok() => log::warn!("{e:?}"),
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    /// 接收所有已到达的llmnr及netbios应答, 忽略格式错误、否定应答及不是来自局域网协议端口的应答
+    pub fn recv_answers(&self, buffer: &mut BytePacketBuffer) -> Vec<LanAnswer> {
+        let mut answers = Vec::new();
+        let sockets = [(&self.llmnr, self.llmnr_target, parse_llmnr as fn(&mut BytePacketBuffer) -> Option<LanAnswer>),
+            (&self.netbios, self.netbios_target, parse_netbios_buffer)];
+        for (socket, target, parse) in sockets {
+            let Some(socket) = socket else {
+                continue;
+            };
+            loop {
+                match socket.recv_from(buffer.recv_buf()) {
+                    Ok((size, from)) if is_lan_source(&from, &target) => {
+                        buffer.set_len(size);
+                        answers.extend(parse(buffer));
+                    },
+                    Ok((_, from)) => log::debug!("name fallback answer from {from} ignored, not from a lan address and port {}", target.port()),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        log::debug!("name fallback recv failed: {e}");
+                        break;
+                    },
+                }
+            }
+        }
+        answers
+    }
+
+    /// 应答与问题的名称一致时缓存问题类型的地址记录并返回, 其它名称的应答不缓存
+    pub fn on_answer(&mut self, question: &DnsQuestion, answer: &LanAnswer, now: Instant) -> Vec<DnsRecord> {
+        if !answer.name.eq_ignore_ascii_case(&question.name) {
+            return Vec::new();
+        }
+        let ttl = answer.ttl.min(MAX_TTL);
+        let records: Vec<DnsRecord> = answer.addrs.iter().filter_map(|addr| match (*addr, question.qtype) {
+            (IpAddr::V4(addr), QueryType::A) => Some(DnsRecord::A { domain: question.name.clone(), addr, ttl }),
+            (IpAddr::V6(addr), QueryType::AAAA) => Some(DnsRecord::AAAA { domain: question.name.clone(), addr, ttl }),
+            _ => None,
+        }).collect();
+
+        if !records.is_empty() {
+            self.cache.insert(question, records.clone(), ttl, now);
+        }
+        records
+    }
+
+}
+
+/// 解析llmnr应答, 格式与dns应答相同
+fn parse_llmnr(buffer: &mut BytePacketBuffer) -> Option<LanAnswer> {
+    let packet = DnsPacket::from_buffer(buffer).ok()?;
+    let name = packet.questions.first()?.name.to_ascii_lowercase();
+    let mut ttl = u32::MAX;
+    let addrs: Vec<IpAddr> = packet.answers.iter().filter_map(|record| match record {
+        DnsRecord::A { domain, addr, ttl: t } if domain.eq_ignore_ascii_case(&name) => { ttl = ttl.min(*t); Some(IpAddr::V4(*addr)) },
+        DnsRecord::AAAA { domain, addr, ttl: t } if domain.eq_ignore_ascii_case(&name) => { ttl = ttl.min(*t); Some(IpAddr::V6(*addr)) },
+        _ => None,
+    }).collect();
+    (!addrs.is_empty()).then_some(LanAnswer { id: packet.header.id, name, addrs, ttl })
+}
+
+fn parse_netbios_buffer(buffer: &mut BytePacketBuffer) -> Option<LanAnswer> {
+    parse_netbios(buffer.data())
+}
+
+/// 构建netbios名称查询数据包, 名称转为大写并以空格补足15个字节, 服务类型为0(工作站)
+fn netbios_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    if name.len() > NETBIOS_NAME_LEN || !name.is_ascii() {
+        anyhow::bail!("netbios name {name} is too long or not ascii");
+    }
+    let mut data = Vec::with_capacity(50);
+    for value in [id, NETBIOS_FLAGS, 1, 0, 0, 0] {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    data.push(32);
+    let padded = format!("{:<15}\0", name.to_ascii_uppercase());
+    for b in padded.bytes() {
+        data.push(b'A' + (b >> 4));
+        data.push(b'A' + (b & 0x0f));
+    }
+    data.push(0);
+    data.extend_from_slice(&NETBIOS_NB.to_be_bytes());
+    data.extend_from_slice(&1u16.to_be_bytes());
+    Ok(data)
+}
+
+/// 解析netbios名称查询的肯定应答, 应答数据为多个(标志, ipv4地址)
+fn parse_netbios(data: &[u8]) -> Option<LanAnswer> {
+    let u16_at = |pos: usize| data.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let (id, flags) = (u16_at(0)?, u16_at(2)?);
+    // 必须是应答且回复码为0
+    if flags & 0x8000 == 0 || flags & 0x000f != 0 || u16_at(6)? == 0 {
+        return None;
+    }
+    // 应答中不含问题, 直接读取应答记录的名称
+    let mut pos = 12;
+    let mut encoded = Vec::new();
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        match len {
+            0 => break,
+            len if len & 0xc0 != 0 => return None,
+            len => {
+                encoded.extend_from_slice(data.get(pos..pos + len)?);
+                pos += len;
+            },
+        }
+    }
+    if encoded.len() != 32 || u16_at(pos)? != NETBIOS_NB {
+        return None;
+    }
+    let name: Vec<u8> = encoded.chunks(2).map(|c| (c[0].wrapping_sub(b'A') << 4) | (c[1].wrapping_sub(b'A') & 0x0f)).collect();
+    let name = String::from_utf8_lossy(&name[..NETBIOS_NAME_LEN]).trim_end().to_ascii_lowercase();
+    let ttl = data.get(pos + 4..pos + 8).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))?;
+    let rdlen = u16_at(pos + 8)? as usize;
+    let rdata = data.get(pos + 10..pos + 10 + rdlen)?;
+    let addrs: Vec<IpAddr> = rdata.chunks_exact(6).map(|c| IpAddr::V4(Ipv4Addr::new(c[2], c[3], c[4], c[5]))).collect();
+    (!addrs.is_empty()).then_some(LanAnswer { id, name, addrs, ttl })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netbios_packet() {
+        let query = netbios_query(0x1234, "fileserver").unwrap();
+        assert_eq!(50, query.len());
+        assert_eq!(&[0x12, 0x34, 0x01, 0x10, 0, 1], &query[..6]);
+        // F=0x46 -> "EG", 空格=0x20 -> "CA", 服务类型0 -> "AA"
+        assert_eq!(b"EG", &query[13..15]);
+        assert_eq!(b"CAAA", &query[41..45]);
+        assert!(netbios_query(1, "a-very-long-computer-name").is_err());
+
+        // 由查询数据包改为应答: 设置应答标志, 问题数改为应答数, 附加ttl及地址
+        let mut response = query.clone();
+        response[2..4].copy_from_slice(&0x8500u16.to_be_bytes());
+        response[4..8].copy_from_slice(&[0, 0, 0, 1]);
+        response.extend_from_slice(&300000u32.to_be_bytes());
+        response.extend_from_slice(&[0, 12, 0, 0, 192, 168, 1, 40, 0, 0, 192, 168, 2, 40]);
+        let answer = parse_netbios(&response).unwrap();
+        assert_eq!(LanAnswer { id: 0x1234, name: "fileserver".to_string(),
+            addrs: vec!["192.168.1.40".parse().unwrap(), "192.168.2.40".parse().unwrap()], ttl: 300000 }, answer);
+        // 否定应答及查询数据包
+        response[3] = 0x03;
+        assert_eq!(None, parse_netbios(&response));
+        assert_eq!(None, parse_netbios(&query));
+    }
+
+    #[test]
+    fn test_name_fallback() {
+        assert!(NameFallback::new("wins").is_err());
+        assert!(NameFallback::new("").is_err());
+        let mut fallback = NameFallback::new("netbios").unwrap();
+        assert_eq!("netbios", fallback.protocols());
+        assert!(fallback.accepts(&DnsQuestion::new("fileserver".to_string(), QueryType::A)));
+        assert!(!fallback.accepts(&DnsQuestion::new("fileserver".to_string(), QueryType::AAAA)));
+        assert!(!fallback.accepts(&DnsQuestion::new("www.example.com".to_string(), QueryType::A)));
+
+        let now = Instant::now();
+        let question = DnsQuestion::new("FileServer".to_string(), QueryType::A);
+        let answer = LanAnswer { id: 1, name: "fileserver".to_string(), addrs: vec!["192.168.1.40".parse().unwrap()], ttl: 300000 };
+        let records = fallback.on_answer(&question, &answer, now);
+        assert_eq!(vec![DnsRecord::A { domain: "FileServer".to_string(), addr: Ipv4Addr::new(192, 168, 1, 40), ttl: MAX_TTL }], records);
+        let question = DnsQuestion::new("fileserver".to_string(), QueryType::A);
+        assert_eq!(Some(20), fallback.cached(&question, now + Duration::from_secs(40)).map(|r| r[0].ttl()));
+        assert_eq!(None, fallback.cached(&question, now + Duration::from_secs(60)));
+        let other = LanAnswer { name: "printer".to_string(), ..answer };
+        assert!(fallback.on_answer(&question, &other, now).is_empty());
+        assert_eq!(None, fallback.cached(&DnsQuestion::new("printer".to_string(), QueryType::A), now));
+    }
+}
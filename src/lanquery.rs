@@ -0,0 +1,169 @@
+//! 局域网名称查询(mdns桥接及llmnr/netbios)共用的应答缓存、等待应答的查询表及应答来源检查
+//!
+//! 局域网查询的应答没有上级dns服务器那样固定的来源地址, 任何主机都可以发送伪造的应答,
+//! 因此查询id随机生成, 只接受来自局域网地址及协议端口的应答, 且只缓存与问题一致的记录
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use super::dnsutil::{DnsQuestion, DnsRecord, QueryType};
+
+const MAX_CACHE_ENTRIES: usize = 1000;   // 缓存的最大条目数
+
+/// 以(域名, 查询类型)为键的应答缓存, 记录为空表示域名存在但没有该类型的记录(NODATA)
+#[derive(Default)]
+pub struct LanCache {
+    entries: HashMap<(String, QueryType), (Instant, Vec<DnsRecord>)>, // 应答记录及其过期时刻
+}
+
+impl LanCache {
+
+    /// 缓存的应答, ttl为剩余时间
+    pub fn cached(&self, question: &DnsQuestion, now: Instant) -> Option<Vec<DnsRecord>> {
+        let (expire, records) = self.entries.get(&(question.name.to_ascii_lowercase(), question.qtype))?;
+        let ttl = expire.checked_duration_since(now).filter(|ttl| !ttl.is_zero())?.as_secs().max(1) as u32;
+        Some(records.iter().cloned().map(|mut rec| { rec.set_ttl(ttl); rec }).collect())
+    }
+
+    /// 缓存问题的应答, ttl为0时删除缓存, 缓存已满时先清除过期的条目, 仍然满时不再缓存
+    pub fn insert(&mut self, question: &DnsQuestion, records: Vec<DnsRecord>, ttl: u32, now: Instant) {
+        let key = (question.name.to_ascii_lowercase(), question.qtype);
+        if ttl == 0 {
+            self.entries.remove(&key);
+            return;
+        }
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            self.entries.retain(|_, (expire, _)| *expire > now);
+        }
+        if self.entries.len() < MAX_CACHE_ENTRIES || self.entries.contains_key(&key) {
+            self.entries.insert(key, (now + Duration::from_secs(ttl as u64), records));
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+}
+
+/// 以查询id为键的等待应答的查询及其超时时刻, 查询id随机生成, 使局域网外的主机难以伪造应答
+pub struct PendingQueries<T> {
+    queries: HashMap<u16, (T, Instant)>,
+}
+
+impl<T> Default for PendingQueries<T> {
+    fn default() -> Self {
+        PendingQueries { queries: HashMap::new() }
+    }
+}
+
+impl<T> PendingQueries<T> {
+
+    /// 未被使用的随机查询id
+    pub fn next_id(&self) -> u16 {
+        loop {
+            let id = random_u16();
+            if !self.queries.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    pub fn insert(&mut self, id: u16, query: T, deadline: Instant) {
+        self.queries.insert(id, (query, deadline));
+    }
+
+    pub fn get(&self, id: u16) -> Option<&T> {
+        self.queries.get(&id).map(|(query, _)| query)
+    }
+
+    pub fn remove(&mut self, id: u16) -> Option<T> {
+        self.queries.remove(&id).map(|(query, _)| query)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+
+    /// 所有查询的超时时刻
+    pub fn deadlines(&self) -> impl Iterator<Item = Instant> + '_ {
+        self.queries.values().map(|(_, deadline)| *deadline)
+    }
+
+    /// 删除并返回已超时的查询
+    pub fn take_expired(&mut self, now: Instant) -> Vec<T> {
+        let ids: Vec<u16> = self.queries.iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    #[cfg(test)]
+    pub fn expire_all(&mut self, now: Instant) {
+        self.queries.values_mut().for_each(|(_, deadline)| *deadline = now);
+    }
+
+}
+
+/// 应答是否来自局域网内的协议端口: 来源端口与查询的目标端口(如mdns的5353)相同,
+/// 且来源地址为私有、链路本地或环回地址
+pub fn is_lan_source(from: &SocketAddr, target: &SocketAddr) -> bool {
+    let local = match from.ip() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // fc00::/7为唯一本地地址, fe80::/10为链路本地地址
+            first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80 || ip.is_loopback()
+        },
+    };
+    local && from.port() == target.port()
+}
+
+/// 随机的16位数, 使用标准库为每个RandomState生成的随机密钥, 无需额外的随机数依赖
+fn random_u16() -> u16 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(Instant::now().elapsed().as_nanos());
+    hasher.finish() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_lan_cache() {
+        let now = Instant::now();
+        let mut cache = LanCache::default();
+        let question = DnsQuestion::new("Printer.mdns.lan".to_string(), QueryType::A);
+        let record = DnsRecord::A { domain: "printer.mdns.lan".to_string(), addr: Ipv4Addr::new(192, 168, 1, 30), ttl: 120 };
+        cache.insert(&question, vec![record], 120, now);
+        let question = DnsQuestion::new("printer.mdns.lan".to_string(), QueryType::A);
+        assert_eq!(Some(60), cache.cached(&question, now + Duration::from_secs(60)).map(|r| r[0].ttl()));
+        assert_eq!(None, cache.cached(&question, now + Duration::from_secs(120)));
+        cache.insert(&question, Vec::new(), 0, now);
+        assert_eq!(0, cache.len());
+
+        let mut pending = PendingQueries::default();
+        let id = pending.next_id();
+        pending.insert(id, "a", now + Duration::from_secs(1));
+        assert_eq!(Some(&"a"), pending.get(id));
+        assert!(pending.take_expired(now).is_empty());
+        assert_eq!(vec!["a"], pending.take_expired(now + Duration::from_secs(1)));
+        assert!(pending.is_empty());
+
+        let target: SocketAddr = "224.0.0.251:5353".parse().unwrap();
+        assert!(is_lan_source(&"192.168.1.30:5353".parse().unwrap(), &target));
+        assert!(is_lan_source(&"[fe80::30]:5353".parse().unwrap(), &target));
+        assert!(!is_lan_source(&"192.168.1.30:53".parse().unwrap(), &target));
+        assert!(!is_lan_source(&"8.8.8.8:5353".parse().unwrap(), &target));
+    }
+}
@@ -0,0 +1,60 @@
+//! 迷你dns转发服务器引擎, 支持静态域名(hosts文件)、远程屏蔽列表及动态域名更新
+//!
+//! 主要模块:
+//! - [`dnsserver`] dns转发服务器, 本地域名优先, 其余查询转发给上级dns服务器
+//! - [`dnsutil`] dns数据包的解析、构建及序列化
+//! - [`bufutil`] 数据包读写缓冲区及缓冲池
+//! - [`client`] 带超时及重试的dns查询客户端
+//! - [`hostsconf`] hosts格式文件的流式解析
+//! - [`blockset`] 节省内存的屏蔽域名集合
+//! - [`resolver`] 可组合的解析链(本地域名表、缓存、转发)
+//! - [`hooks`] 查询、应答及屏蔽事件的回调钩子
+//! - [`metrics`] 运行指标输出接口
+//! - [`script`] 转发前按规则脚本自定义回复
+//! - [`publicip`] 通过http接口或stun服务检测本机公网ip
+//! - [`bench`] dns压力测试, 统计延迟分位数及错误率
+//! - [`acl`] 客户端访问控制列表(ipv4/ipv6网段)
+//! - [`analytics`] 滑动窗口内查询最多的域名及客户端统计
+//! - [`groups`] 客户端分组策略(按网段或mac地址分组的hosts、规则脚本及上级dns)
+//! - [`handover`] 重启时在新旧进程间交接监听socket, 升级不丢失查询
+//!
+//! 在其他程序中嵌入dns服务:
+//!
+//! ```no_run
+//! use minidns::DnsServer;
+//!
+//! let mut server = DnsServer::create("127.0.0.1:5353", "223.5.5.5", 300, "").unwrap();
+//! server.load_hosts_file("/etc/mdns/hosts.conf").unwrap();
+//! server.run(128).unwrap();
+//! ```
+
+pub mod acl;
+pub mod analytics;
+pub mod bench;
+pub mod blockset;
+pub mod bufutil;
+pub mod client;
+pub mod dnsserver;
+pub mod dnsutil;
+pub mod groups;
+pub mod handover;
+pub mod hooks;
+pub mod hostsconf;
+pub mod metrics;
+pub mod publicip;
+pub mod resolver;
+pub mod script;
+mod dhcplease;
+mod dyndns;
+mod httputil;
+mod keyfile;
+mod lanname;
+mod lanquery;
+mod mdnsbridge;
+mod remotehosts;
+
+pub use dnsserver::{DnsServer, HostCommand, HostHandle};
+pub use dnsutil::{DnsPacket, DnsQuestion, DnsRecord, DnsResponse, QueryClass, QueryType, ResultCode};
+pub use hostsconf::{HostEntry, HostRecord, HostsConfig};
+pub use dyndns::json_str;
+pub use keyfile::parse_keys;
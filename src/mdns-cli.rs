@@ -1,27 +1,87 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use std::net::UdpSocket;
+use minidns::client::{Client, NoReply};
+use minidns::{DnsPacket, DnsQuestion, DnsRecord, QueryType, json_str};
 
 const APP_NAME: &str = "mini dns client";   // 应用程序内部名称
 const APP_VER: &str = "2.0.6";      // 应用程序版本
 const C_MAGIC: &str = "kdns";
 const C_2023_01_01: u64 = 1672531200;
 
+// 子命令列表, 不带子命令时为update
+const COMMANDS: appconfig::Commands = &[
+    ("update", "send the dynamic dns update"),
+    ("query", "look up a name like dig: query NAME [TYPE] [@SERVER[:PORT]]"),
+];
+
 appconfig::appconfig_define!(AppConf,
     debug : bool   => ["D",  "debug", "", "set debug mode"],
-    domain: String => ["n",  "domain", "DOMAIN", "set dynamic domain name"],
+    domain: Vec<String> => ["n", "domain", "DOMAIN", "set dynamic domain names(comma separated)"],
+    domain_file: String => ["", "domain-file", "DOMAIN_FILE", "read more dynamic domain names from file, one per line"],
     ip    : String => ["i",  "ip", "IP", "set dynamic ip address"],
-    key   : String => ["k",  "key", "KEY", "set dynamic updated key"],
-    dns   : String => ["d",  "dns", "DNS", "set dynamic dns server address"]
+    ip6   : String => ["",   "ip6", "IP6", "also set dynamic ipv6 address, 'auto' for the local ipv6 address"],
+    iface : String => ["",   "iface", "IFACE", "use the first global address of the network interface(like eth0) when ip is omitted, also for --ip6 auto"],
+    ip_detect: String => ["", "ip-detect", "URL", "detect public ip by http(s)://url(https uses curl) or stun://host[:port] when ip is omitted"],
+    key   : String => ["k",  "key", "KEY", "set dynamic updated key, read from file when starting with @"] @secret,
+    key_file: String => ["K", "key-file", "KEY_FILE", "set dynamic updated key file"],
+    dns   : String => ["d",  "dns", "DNS", "set dynamic dns server address(host or host:port)"],
+    server_port: String => ["P", "server-port", "SERVER_PORT", "set dynamic dns server port when dns has no port"],
+    tcp   : bool   => ["",   "tcp", "", "send the update over tcp only, by default tcp is used when udp gets no reply"],
+    json  : bool   => ["j",  "json", "", "print the result of each domain as a json line(status, host, ip, rtt...)"],
+    timeout: Duration => ["t", "timeout", "TIMEOUT", "reply timeout of each attempt(like 500ms/5s)"],
+    retries: u32   => ["r",  "retries", "RETRIES", "retry times when no reply, waiting 1s, 2s, 4s... between retries"],
+    verify: bool   => ["",   "verify", "", "query the domain from the server after update, fail if the answer doesn't match the ip"],
+    daemon: bool   => ["",   "daemon", "", "keep running, re-send the update when the ip changes"],
+    interval: Duration => ["", "interval", "INTERVAL", "ip check interval of daemon mode(like 30s/5m/1h)"]
 );
 
 impl Default for AppConf {
     fn default() -> Self {
         AppConf {
             debug  : false,
-            domain : String::new(),
+            domain : Vec::new(),
+            domain_file: String::new(),
             ip     : String::from("0.0.0.0"),
+            ip6    : String::new(),
+            iface  : String::new(),
+            ip_detect: String::new(),
             key    : String::new(),
+            key_file: String::new(),
             dns    : String::new(),
+            server_port: String::from("53"),
+            tcp    : false,
+            json   : false,
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            verify : false,
+            daemon : false,
+            interval: Duration::from_secs(300),
+        }
+    }
+}
+
+appconfig::appconfig_define!(QueryConf,
+    server : String   => ["s", "server", "SERVER", "dns server address(host or host:port), same as @SERVER"],
+    qtype  : String   => ["t", "type", "TYPE", "query type(A/AAAA/CNAME/MX/NS/TXT or number), same as the TYPE argument"],
+    timeout: Duration => ["",  "timeout", "TIMEOUT", "reply timeout of each attempt(like 500ms/5s)"],
+    retries: u32      => ["r", "retries", "RETRIES", "retry times when no reply"],
+    short  : bool     => ["",  "short", "", "print the answer data only"],
+    json   : bool     => ["j", "json", "", "print the result as json(status, answers, rtt...)"],
+);
+
+impl Default for QueryConf {
+    fn default() -> Self {
+        QueryConf {
+            server : String::from("127.0.0.1"),
+            qtype  : String::from("A"),
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            short  : false,
+            json   : false,
         }
     }
 }
@@ -40,44 +100,601 @@ fn now_of_unix() -> u64 {
     std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
 }
 
-fn main() -> Result<()> {
-    let version = format!("{APP_NAME} version {APP_VER} CopyLeft Kivensoft 2015-2023.");
-    let mut ac = AppConf::default();
-    if !appconfig::parse_args_ext(&mut ac, &version, |ac| !ac.domain.is_empty() && !ac.dns.is_empty())? {
-        return Ok(())
-    }
-    if ac.debug {
-        unsafe { DEBUG = true; }
+/// 读取密钥文件中的第一个有效密钥(忽略空行及'#'开头的注释行)
+fn read_key_file(path: &str) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("read key file {path} failed: {e}"))?;
+    minidns::parse_keys(&text).into_iter().next()
+        .ok_or_else(|| anyhow::anyhow!("key file {path} not contains any key"))
+}
+
+/// 密钥来自配置文件且该文件允许组或其他用户访问时给出警告
+#[cfg(unix)]
+fn check_conf_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+    let from_conf = appconfig::sources().iter()
+        .any(|(name, source)| *name == "key" && matches!(source, appconfig::Source::ConfigFile(_)));
+    if let (true, Some(path)) = (from_conf, appconfig::config_file()) {
+        if let Ok(meta) = std::fs::metadata(&path) {
+            let mode = meta.permissions().mode();
+            if mode & 0o077 != 0 {
+                eprintln!("warning: config file {path} contains the key and is accessible by other users (mode {:o}), suggest chmod 600",
+                        mode & 0o777);
+            }
+        }
     }
-    dbg_out!("application config setting: {:#?}", ac);
+}
+
+#[cfg(not(unix))]
+fn check_conf_permissions() {}
 
+/// 读取域名文件中的域名列表(忽略空行及'#'开头的注释行)
+fn read_domain_file(path: &str) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("read domain file {path} failed: {e}"))?;
+    Ok(text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+const MIN_BACKOFF: Duration = Duration::from_secs(10);   // 守护模式更新失败后的首次重试间隔
+const DETECT_TIMEOUT: Duration = Duration::from_secs(5); // 公网ip检测的超时时间
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);  // 无回复时首次重试前的等待时间
+
+// 进程退出码
+const EXIT_OK: i32       = 0;   // 更新成功
+const EXIT_ERROR: i32    = 1;   // 参数错误或其它错误
+const EXIT_NO_REPLY: i32 = 2;   // 重试后服务器仍无回复(超时或网络错误)
+const EXIT_REJECTED: i32 = 3;   // 服务器拒绝更新(密钥错误、时间误差过大或域名不允许)
+const EXIT_FORMAT: i32   = 4;   // 服务器无法解析请求(数据包或ip格式错误)
+const EXIT_MISMATCH: i32 = 5;   // 更新成功, 但随后查询得到的地址与更新的ip不一致
+
+const IP6_PROBE: &str = "[2400:3200::1]:53";             // 检测本机ipv6地址时连接的公共地址(不发送数据)
+
+/// 生成动态dns更新数据包, `ip`为0.0.0.0时由服务器使用请求的来源地址
+fn update_packet(ac: &AppConf, domain: &str, ip: &str) -> String {
     let id = now_of_unix() - C_2023_01_01;
     let digest = {
         let mut ctx = md5::Context::new();
         ctx.consume(id.to_string().as_bytes());
-        ctx.consume(ac.domain.as_bytes());
-        ctx.consume(ac.ip.as_bytes());
+        ctx.consume(domain.as_bytes());
+        ctx.consume(ip.as_bytes());
         ctx.consume(ac.key.as_bytes());
         format!("{:x}", ctx.compute())
     };
 
     dbg_out!("MAGIC = {}, DIGEST = {}, ID = {}, DOMAIN = {}, IP = {}",
-            C_MAGIC, digest, id, ac.domain, ac.ip);
-    let packet = format!("{} {} {} {} {}", C_MAGIC, digest, id, ac.domain, ac.ip);
+            C_MAGIC, digest, id, domain, ip);
+    // 始终请求json格式的回复, 以便根据拒绝原因返回不同的退出码
+    format!("{} {} {} {} {} json", C_MAGIC, digest, id, domain, ip)
+}
+
+/// 发送动态dns更新请求, 返回服务器的回复, udp无回复时改用tcp发送(服务器启用了dyndns-port)
+fn send_update(ac: &AppConf, client: &Client, domain: &str, ip: &str) -> Result<String> {
+    let packet = update_packet(ac, domain, ip);
+    if !ac.tcp {
+        dbg_out!("send packet to {}, message = {}", client.server(), packet);
+        match client.exchange(packet.as_bytes()) {
+            Ok(reply) => {
+                let rep_msg = String::from_utf8_lossy(&reply).into_owned();
+                dbg_out!("receive from {}, nread = {}, message = {}", client.server(), reply.len(), rep_msg);
+                return Ok(rep_msg);
+            },
+            Err(e) if e.downcast_ref::<NoReply>().is_some() => dbg_out!("{e}, fall back to tcp"),
+            Err(e) => return Err(e),
+        }
+    }
+    send_update_tcp(client.server(), &packet, ac.timeout)
+}
 
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_read_timeout(Some(std::time::Duration::new(5, 0)))?;
-    socket.set_write_timeout(Some(std::time::Duration::new(5, 0)))?;
+/// 通过tcp发送更新请求, 请求及回复各为一行, 服务器回复后关闭连接
+fn send_update_tcp(server: SocketAddr, packet: &str, timeout: Duration) -> Result<String> {
+    dbg_out!("send packet to tcp://{}, message = {}", server, packet);
+    let mut stream = TcpStream::connect_timeout(&server, timeout)
+        .map_err(|e| anyhow::anyhow!("connect tcp://{server} failed: {e}"))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(format!("{packet}\n").as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).map_err(|e| anyhow::anyhow!("read reply from tcp://{server} failed: {e}"))?;
+    dbg_out!("receive from tcp://{}, nread = {}, message = {}", server, reply.len(), reply.trim_end());
+    match reply.trim_end() {
+        "" => anyhow::bail!("no reply from tcp://{server}"),
+        reply => Ok(reply.to_string()),
+    }
+}
 
-    let dns_addr = format!("{}:53", ac.dns);
-    let mut buf = [0; 512];
+/// 更新服务器地址, 未带端口时使用server-port指定的端口
+fn server_addr(ac: &AppConf) -> String {
+    let dns = ac.dns.as_str();
+    if dns.parse::<SocketAddr>().is_ok() || (dns.matches(':').count() == 1 && !dns.starts_with('[')) {
+        return dns.to_string();
+    }
+    match dns.parse::<Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]:{}", ac.server_port),
+        Err(_) => format!("{dns}:{}", ac.server_port),
+    }
+}
+
+/// 服务器的回复是否表示更新成功
+fn is_accepted(reply: &str) -> bool {
+    reply_exit_code(reply) == EXIT_OK
+}
 
-    dbg_out!("send packet to {}, message = {}", ac.dns, packet);
-    socket.send_to(packet.as_bytes(), dns_addr)?;
-    let (nread, addr) = socket.recv_from(&mut buf)?;
-    let rep_msg = String::from_utf8_lossy(&buf[..nread]);
-    dbg_out!("receive from {}, nread = {}, message = {}", addr, nread, rep_msg);
-    println!("{}", rep_msg);
+/// 根据服务器回复得到退出码, 不支持json的旧版服务器失败时回复error, 无法区分原因
+fn reply_exit_code(reply: &str) -> i32 {
+    if !reply.starts_with('{') {
+        return if reply.trim() == "error" { EXIT_REJECTED } else { EXIT_OK };
+    }
+    match json_field(reply, "code").and_then(|code| code.parse::<u32>().ok()) {
+        Some(0) => EXIT_OK,
+        Some(1 | 5) => EXIT_FORMAT,
+        _ => EXIT_REJECTED,
+    }
+}
+
+/// 从服务器回复的json中提取字段值, 回复的字段值不含转义字符, 无需完整的json解析
+fn json_field<'a>(reply: &'a str, name: &str) -> Option<&'a str> {
+    let pos = reply.find(&format!("\"{name}\":"))?;
+    let value = &reply[pos + name.len() + 3..];
+    match value.strip_prefix('"') {
+        Some(s) => s.split('"').next(),
+        None => value.split([',', '}']).next(),
+    }
+}
+
+/// 更新结果的json格式: 服务器的json回复加上往返时间(毫秒), 旧版服务器的文本回复及无回复时生成相同格式的json
+fn update_json(domain: &str, ip: &str, result: &Result<String>, rtt: Duration) -> String {
+    let rtt = rtt.as_millis();
+    match result {
+        Ok(reply) if reply.starts_with('{') && reply.ends_with('}') =>
+            format!("{},\"rtt\":{rtt}}}", &reply[..reply.len() - 1]),
+        Ok(reply) => {
+            let ok = reply_exit_code(reply) == EXIT_OK;
+            let ip = reply.split_whitespace().nth(1).filter(|_| ok).unwrap_or(ip);
+            format!("{{\"status\":\"{}\",\"host\":{},\"ip\":{},\"rtt\":{rtt},\"message\":{}}}",
+                if ok { "ok" } else { "error" }, json_str(domain), json_str(ip), json_str(reply.trim()))
+        },
+        Err(e) => format!("{{\"status\":\"error\",\"host\":{},\"ip\":{},\"rtt\":null,\"message\":{}}}",
+            json_str(domain), json_str(ip), json_str(&format!("{e:#}"))),
+    }
+}
+
+/// 更新成功的回复中服务器实际设置的ip(0.0.0.0已替换为来源地址), 可以是逗号分隔的多个地址
+fn reply_ip(reply: &str) -> Option<&str> {
+    match reply.starts_with('{') {
+        true => json_field(reply, "ip"),
+        false => reply.split_whitespace().nth(1),
+    }
+}
+
+/// 更新后向同一服务器查询域名的A/AAAA记录, 返回不一致的原因, 查询失败时返回Err
+fn verify_update(client: &Client, domain: &str, ips: &str) -> Result<Option<String>> {
+    for ip in ips.split(',') {
+        let ip: IpAddr = ip.trim().parse()?;
+        let qtype = if ip.is_ipv4() { QueryType::A } else { QueryType::AAAA };
+        let packet = client.resolve(domain, qtype)?;
+        let addrs: Vec<IpAddr> = packet.answers.iter().filter_map(|rec| match rec {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        }).collect();
+        if !addrs.contains(&ip) {
+            let answer = addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(",");
+            return Ok(Some(format!("{qtype} query answered [{answer}], expected {ip}")));
+        }
+        dbg_out!("verify {domain} {qtype} {ip} ok");
+    }
+    Ok(None)
+}
+
+/// 回复的文本格式: 未指定--json时, 成功输出`域名 ip`, 失败输出error
+fn reply_text(reply: &str) -> String {
+    if !reply.starts_with('{') {
+        return reply.to_string();
+    }
+    match (reply_exit_code(reply), json_field(reply, "host"), json_field(reply, "ip")) {
+        (EXIT_OK, Some(host), Some(ip)) => format!("{host} {ip}"),
+        _ => String::from("error"),
+    }
+}
+
+/// 是否指定了ip, 未指定时为0.0.0.0
+fn has_ip(ac: &AppConf) -> bool {
+    ac.ip.parse::<IpAddr>().map_or(true, |ip| !ip.is_unspecified())
+}
+
+/// 是否在本地确定ip(网卡地址或公网ip检测)并填入更新请求, 否则由服务器使用请求的来源地址
+fn local_ip_mode(ac: &AppConf) -> bool {
+    !ac.iface.is_empty() || !ac.ip_detect.is_empty()
+}
+
+/// 当前ip: 指定了ip时使用指定值, 其次为网卡地址、公网ip检测结果, 否则为访问服务器时使用的本机地址
+fn current_ip(ac: &AppConf, server: SocketAddr) -> Result<IpAddr> {
+    if let Ok(ip) = ac.ip.parse::<IpAddr>() {
+        if !ip.is_unspecified() {
+            return Ok(ip);
+        }
+    }
+    if !ac.iface.is_empty() {
+        return iface_ip(&ac.iface, true);
+    }
+    if !ac.ip_detect.is_empty() {
+        return minidns::publicip::detect(&ac.ip_detect, DETECT_TIMEOUT);
+    }
+    let bind_addr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(server)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// 当前ipv6地址: 未指定时为None, auto为指定网卡或访问外网时使用的本机ipv6地址
+fn current_ip6(ac: &AppConf, server: SocketAddr) -> Result<Option<IpAddr>> {
+    match ac.ip6.as_str() {
+        "" => Ok(None),
+        "auto" if !ac.iface.is_empty() => iface_ip(&ac.iface, false).map(Some),
+        "auto" => {
+            let target = if server.is_ipv6() { server } else { IP6_PROBE.parse()? };
+            let socket = UdpSocket::bind("[::]:0")?;
+            socket.connect(target).map_err(|e| anyhow::anyhow!("detect local ipv6 address failed: {e}"))?;
+            Ok(Some(socket.local_addr()?.ip()))
+        },
+        s => match s.parse::<Ipv6Addr>() {
+            Ok(ip) => Ok(Some(IpAddr::V6(ip))),
+            Err(_) => anyhow::bail!("invalid ipv6 address {s}"),
+        },
+    }
+}
+
+/// 网卡的第一个全局地址(非回环、非链路本地), `v4`为true时取ipv4地址, 否则取ipv6地址
+fn iface_ip(name: &str, v4: bool) -> Result<IpAddr> {
+    let addrs = iface_addrs(name)?;
+    dbg_out!("interface {name} addresses: {addrs:?}");
+    addrs.into_iter()
+        .find(|ip| ip.is_ipv4() == v4 && is_global(ip))
+        .ok_or_else(|| anyhow::anyhow!("interface {name} has no global {} address", if v4 { "ipv4" } else { "ipv6" }))
+}
+
+/// 是否为可用于更新的地址, 排除回环、未指定及链路本地地址(169.254.0.0/16, fe80::/10)
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_unspecified() && !ip.is_link_local(),
+        IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_unspecified() && (ip.segments()[0] & 0xffc0) != 0xfe80,
+    }
+}
+
+/// 读取网卡的全部地址
+#[cfg(unix)]
+fn iface_addrs(name: &str) -> Result<Vec<IpAddr>> {
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        anyhow::bail!("read network interfaces failed: {}", std::io::Error::last_os_error());
+    }
+
+    let (mut found, mut addrs) = (false, Vec::new());
+    let mut p = ifap;
+    while !p.is_null() {
+        let ifa = unsafe { &*p };
+        p = ifa.ifa_next;
+        if unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        found = true;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr))));
+            },
+            libc::AF_INET6 => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                addrs.push(IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr)));
+            },
+            _ => {},
+        }
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+
+    if !found {
+        anyhow::bail!("network interface {name} not found");
+    }
+    Ok(addrs)
+}
+
+#[cfg(not(unix))]
+fn iface_addrs(_name: &str) -> Result<Vec<IpAddr>> {
+    anyhow::bail!("--iface is only supported on unix")
+}
+
+/// 在更新请求的ip后追加ipv6地址, 以逗号分隔
+fn append_ip6(ip: String, ip6: Option<IpAddr>) -> String {
+    match ip6 {
+        Some(ip6) => format!("{ip},{ip6}"),
+        None => ip,
+    }
+}
+
+/// 守护模式, 定期检测ip, 变化后重新发送各域名的更新请求, 有失败时按指数退避重试未更新的域名
+fn run_daemon(ac: &AppConf, client: &Client) -> Result<()> {
+    let level = if ac.debug { log::LevelFilter::Debug } else { log::LevelFilter::Info };
+    asynclog::init_log(level, String::new(), 0, true, false).map_err(|e| anyhow::anyhow!("init log failed: {e}"))?;
+    log::info!("{} daemon started, domain {}, server {}, interval {}",
+            APP_NAME, ac.domain.join(","), client.server(), appconfig::format_duration(ac.interval));
+
+    let mut last_ip: HashMap<&str, (IpAddr, Option<IpAddr>)> = HashMap::new();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let current = current_ip(ac, client.server())
+            .and_then(|ip| Ok((ip, current_ip6(ac, client.server())?)));
+        let wait = match current {
+            Ok((ip, ip6)) => {
+                let mut failed = false;
+                for domain in ac.domain.iter() {
+                    let last = last_ip.get(domain.as_str()).copied();
+                    if last == Some((ip, ip6)) {
+                        log::debug!("domain {domain} ip {} not changed", append_ip6(ip.to_string(), ip6));
+                        continue;
+                    }
+                    // 检测到的公网ip需要放在请求中, 否则由服务器使用请求的来源地址
+                    match send_update(ac, client, domain, &append_ip6(update_ip(ac, ip), ip6)) {
+                        // 更新后查询的地址不一致时不记录本次的ip, 按失败处理
+                        Ok(reply) if is_accepted(&reply) && ac.verify && !verified(client, domain, &reply) => failed = true,
+                        Ok(reply) if is_accepted(&reply) => {
+                            log::info!("domain {domain} updated, ip {} -> {}, reply: {reply}",
+                                    last.map_or(String::from("none"), |(ip, ip6)| append_ip6(ip.to_string(), ip6)),
+                                    append_ip6(ip.to_string(), ip6));
+                            last_ip.insert(domain, (ip, ip6));
+                        },
+                        Ok(reply) => {
+                            log::error!("domain {domain} update rejected: {reply}");
+                            failed = true;
+                        },
+                        Err(e) => {
+                            log::warn!("domain {domain} update failed, retry in {}: {e:?}", appconfig::format_duration(backoff));
+                            failed = true;
+                        },
+                    }
+                }
+                match failed {
+                    true => next_backoff(&mut backoff, ac.interval),
+                    false => {
+                        backoff = MIN_BACKOFF;
+                        ac.interval
+                    },
+                }
+            },
+            Err(e) => {
+                log::warn!("detect ip failed: {e:?}");
+                ac.interval
+            },
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+/// 守护模式下校验更新结果, 不一致或查询失败时记录日志
+fn verified(client: &Client, domain: &str, reply: &str) -> bool {
+    match reply_ip(reply).map(|ips| verify_update(client, domain, ips)) {
+        Some(Ok(None)) | None => true,
+        Some(Ok(Some(reason))) => {
+            log::error!("domain {domain} verify failed: {reason}");
+            false
+        },
+        Some(Err(e)) => {
+            log::warn!("domain {domain} verify failed: {e:#}");
+            false
+        },
+    }
+}
+
+/// 更新请求中的ip, 仅在使用网卡地址或公网ip检测时填写本地确定的ip, 否则使用参数指定的值
+fn update_ip(ac: &AppConf, ip: IpAddr) -> String {
+    match local_ip_mode(ac) {
+        true => ip.to_string(),
+        false => ac.ip.clone(),
+    }
+}
+
+/// 返回本次的重试等待时间, 并将下次的等待时间加倍(不超过max)
+fn next_backoff(backoff: &mut Duration, max: Duration) -> Duration {
+    let wait = *backoff;
+    *backoff = (wait * 2).min(max.max(MIN_BACKOFF));
+    wait
+}
+
+/// dig风格的查询, 参数依次为域名、可选的查询类型及以@开头的服务器地址
+fn query() -> Result<i32> {
+    let mut qc = QueryConf::default();
+    if !appconfig::parse_command_args(&mut qc, &version(), "query", COMMANDS, "update", |_| !appconfig::free_args().is_empty())? {
+        return Ok(EXIT_OK)
+    }
+
+    let mut name = None;
+    for arg in appconfig::free_args() {
+        match arg.strip_prefix('@') {
+            Some(server) => qc.server = server.to_string(),
+            None if name.is_none() => name = Some(arg),
+            None => qc.qtype = arg,
+        }
+    }
+    let name = name.unwrap_or_default();
+    let qtype: QueryType = qc.qtype.parse()?;
+
+    let mut client = Client::new(&qc.server)?;
+    client.set_timeout(qc.timeout);
+    client.set_retries(qc.retries);
+    let start = Instant::now();
+    let packet = match client.query(DnsQuestion::new(name.trim_end_matches('.').to_ascii_lowercase(), qtype)) {
+        Ok(packet) => packet,
+        Err(e) if qc.json => {
+            println!("{{\"status\":\"error\",\"server\":\"{}\",\"message\":{}}}", client.server(), json_str(&format!("{e:#}")));
+            return Ok(EXIT_NO_REPLY);
+        },
+        Err(e) => {
+            eprintln!(";; {e:#}");
+            return Ok(EXIT_NO_REPLY);
+        },
+    };
+
+    if qc.json {
+        println!("{}", query_json(&packet, client.server(), start.elapsed()));
+        return Ok(EXIT_OK);
+    }
+    if qc.short {
+        // 仅输出记录数据, 即dig风格记录显示的最后一列
+        for rec in packet.answers.iter() {
+            println!("{}", rec.to_string().rsplit('\t').next().unwrap_or_default());
+        }
+        return Ok(EXIT_OK);
+    }
+    println!("\n; <<>> mdns-cli {APP_VER} <<>> {name} {qtype} @{}", client.server());
+    println!("{packet}");
+    println!(";; Query time: {} msec", start.elapsed().as_millis());
+    println!(";; SERVER: {}", client.server());
+    Ok(EXIT_OK)
+}
+
+/// 查询结果的json格式, 包括状态、标志、问题、各部分记录及往返时间(毫秒)
+fn query_json(packet: &DnsPacket, server: SocketAddr, rtt: Duration) -> String {
+    let h = &packet.header;
+    let flags: Vec<String> = h.flags().into_iter().map(json_str).collect();
+    let questions: Vec<String> = packet.questions.iter()
+        .map(|q| format!("{{\"name\":{},\"type\":\"{}\"}}", json_str(&q.name), q.qtype))
+        .collect();
+    let records = |records: &[DnsRecord]| records.iter().map(record_json).collect::<Vec<_>>().join(",");
+    format!("{{\"status\":\"{}\",\"id\":{},\"flags\":[{}],\"server\":\"{server}\",\"rtt\":{},\"questions\":[{}],\"answers\":[{}],\"authorities\":[{}],\"additionals\":[{}]}}",
+        h.rescode, h.id, flags.join(","), rtt.as_millis(), questions.join(","),
+        records(&packet.answers), records(&packet.authorities), records(&packet.resources))
+}
+
+/// 记录的json格式, 数据为dig风格显示的最后一列, TXT记录为原始文本
+fn record_json(rec: &DnsRecord) -> String {
+    let text = rec.to_string();
+    let cols: Vec<&str> = text.splitn(5, '\t').collect();
+    let data = match rec {
+        DnsRecord::TXT { text, .. } => text.as_str(),
+        _ => cols.get(4).copied().unwrap_or_default(),
+    };
+    format!("{{\"name\":{},\"ttl\":{},\"type\":\"{}\",\"data\":{}}}",
+        json_str(cols[0].trim_end_matches('.')), rec.ttl(), cols.get(3).copied().unwrap_or_default(), json_str(data))
+}
+
+fn version() -> String {
+    format!("{APP_NAME} version {APP_VER} CopyLeft Kivensoft 2015-2023.")
+}
+
+fn main() {
+    let command = match appconfig::get_command(COMMANDS, "update") {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(EXIT_ERROR);
+        },
+    };
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    let result = match command {
+        "update" => update(),
+        "query" => query(),
+        _ => unreachable!("command {command} not handled"),
+    };
+    let code = result.unwrap_or_else(|e| {
+        eprintln!("Error: {e:?}");
+        EXIT_ERROR
+    });
+    std::process::exit(code);
+}
+
+/// 发送动态dns更新请求
+fn update() -> Result<i32> {
+    let mut ac = AppConf::default();
+    if !appconfig::parse_command_args(&mut ac, &version(), "update", COMMANDS, "update",
+            |ac| (!ac.domain.is_empty() || !ac.domain_file.is_empty()) && !ac.dns.is_empty())? {
+        return Ok(EXIT_OK)
+    }
+    if ac.debug {
+        unsafe { DEBUG = true; }
+    }
+    check_conf_permissions();
+    dbg_out!("application config setting: {:#?}", ac);
+
+    if !ac.domain_file.is_empty() {
+        let domains = read_domain_file(&ac.domain_file)?;
+        ac.domain.extend(domains);
+    }
+    if ac.domain.is_empty() {
+        anyhow::bail!("no domain to update");
+    }
+
+    // 从密钥文件读取密钥, 避免密钥出现在命令行参数中
+    if ac.key.is_empty() && !ac.key_file.is_empty() {
+        ac.key = read_key_file(&ac.key_file)?;
+    }
+
+    let mut client = Client::new(&server_addr(&ac))?;
+    client.set_timeout(ac.timeout);
+    client.set_retries(ac.retries);
+    client.set_backoff(RETRY_BACKOFF);
+    if ac.daemon {
+        return run_daemon(&ac, &client).map(|_| EXIT_OK);
+    }
+
+    // 未指定ip时使用网卡地址或检测公网ip, 解决多网卡、NAT或代理后面服务器获取的来源地址不正确的问题
+    if !has_ip(&ac) && local_ip_mode(&ac) {
+        ac.ip = current_ip(&ac, client.server())?.to_string();
+        dbg_out!("detected ip: {}", ac.ip);
+    }
+    let ip = append_ip6(ac.ip.clone(), current_ip6(&ac, client.server())?);
+
+    // 逐个域名发送更新, 退出码为第一个失败域名的退出码
+    let (mut code, mut updated) = (EXIT_OK, 0);
+    for domain in ac.domain.iter() {
+        let start = Instant::now();
+        let result = send_update(&ac, &client, domain, &ip);
+        if ac.json {
+            println!("{}", update_json(domain, &ip, &result, start.elapsed()));
+        }
+        let domain_code = match result {
+            Ok(reply) => {
+                if !ac.json {
+                    println!("{}", reply_text(&reply));
+                }
+                let domain_code = reply_exit_code(&reply);
+                if domain_code != EXIT_OK {
+                    eprintln!("domain {domain} update rejected: {}", json_field(&reply, "message").unwrap_or("error"));
+                }
+                match (domain_code, reply_ip(&reply)) {
+                    (EXIT_OK, Some(ips)) if ac.verify => match verify_update(&client, domain, ips) {
+                        Ok(None) => EXIT_OK,
+                        Ok(Some(reason)) => {
+                            eprintln!("domain {domain} verify failed: {reason}");
+                            EXIT_MISMATCH
+                        },
+                        Err(e) => {
+                            eprintln!("domain {domain} verify failed: {e:#}");
+                            EXIT_NO_REPLY
+                        },
+                    },
+                    _ => domain_code,
+                }
+            },
+            Err(e) => {
+                eprintln!("domain {domain} update failed: {e:?}");
+                EXIT_NO_REPLY
+            },
+        };
+        if domain_code == EXIT_OK {
+            updated += 1;
+        } else if code == EXIT_OK {
+            code = domain_code;
+        }
+    }
+    if ac.domain.len() > 1 {
+        eprintln!("{updated} of {} domains updated", ac.domain.len());
+    }
 
-    Ok(())
+    Ok(code)
 }
@@ -1,10 +1,9 @@
-mod bufutil;
-mod dnsutil;
-mod dnsserver;
-mod hostsconf;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-use dnsserver::*;
-use hostsconf::*;
+use appconfig::Size;
+use minidns::{DnsServer, HostHandle};
+use minidns::metrics::MetricsSink;
 
 const APP_NAME: &str = "mini dns server";   // 应用程序内部名称
 const APP_VER: &str = "2.0.6";      // 应用程序版本
@@ -17,16 +16,79 @@ const G_BANNER: &str = r##"
 /_/ /_/ /_/_/_/ /_/_/\__,_/_/ /_/____/
 "##;
 
+// 子命令列表, 不带子命令时为serve
+const COMMANDS: appconfig::Commands = &[
+    ("serve", "run the dns server"),
+    ("bench", "generate query load against a dns server and report latency and errors"),
+    ("healthcheck", "query the configured listen address, exit 0 if the server replies in time, otherwise 1"),
+    ("check", "validate the config, hosts files, listen address and parent dns without serving"),
+    ("blockdb", "build a memory-mapped block db from blocked(0.0.0.0) names of hosts files: blockdb -o FILE HOSTS..."),
+    ("ctl", "send a command to the control socket of the running server: ctl top [N] | ctl stats | ctl upstreams | ctl export | ctl log-level [LEVEL]"),
+];
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);   // check子命令探测上级dns服务器的超时时间
+const CHECK_NAME: &str = "example.com";                   // check子命令探测上级dns服务器时查询的域名
+
 appconfig::appconfig_define!(AppConf,
-    log_level : String => ["L",  "log-level",    "LOG_LEVEL", "set log level(trace/debug/info/warn/error/off)"],
+    log_level : String => ["L",  "log-level",    "LOG_LEVEL", "set log level(trace/debug/info/warn/error/off), or per-module directives like info,minidns::dnsserver=trace"] @group("Logging"),
     log_file  : String => ["F",  "log-file",     "LOG_FILE", "set log file path"],
-    log_max   : String => ["M",  "log-max",      "LogFileMaxSize", "log file max size(unit: k/m/g)"],
-    host      : String => ["H",  "host", "HOST", "set dns server listen address"],
-    port      : String => ["p",  "port", "PORT", "set dns server listen port"],
+    log_max   : Size   => ["M",  "log-max",      "LogFileMaxSize", "log file max size(unit: k/m/g)"],
+    log_backups: u32   => ["",   "log-backups",  "LOG_BACKUPS", "number of rotated log files to keep"],
+    log_route : String => ["",   "log-route",    "LOG_ROUTE", "write log targets to separate files(target=file, comma separated)"],
+    log_json  : bool   => ["",   "log-json",     "LOG_JSON", "write log file as json lines"],
+    log_format: String => ["",   "log-format",   "LOG_FORMAT", "set log line pattern(%d date, %l level, %t target, %n line, %m message, %k fields, %T thread)"],
+    log_syslog: String => ["",   "log-syslog",   "LOG_SYSLOG", "also write log to syslog(local, unix socket path, udp://host:port or tcp://host:port)"],
+    log_journald: bool => ["",   "log-journald", "LOG_JOURNALD", "also write log to systemd-journald"],
+    log_stderr: bool   => ["",   "log-stderr",   "LOG_STDERR", "write warn/error log to stderr instead of stdout"],
+    log_precision: String => ["", "log-precision", "LOG_PRECISION", "set log timestamp precision(s/ms/us)"] @hidden,
+    log_utc   : bool   => ["",   "log-utc",      "LOG_UTC", "use utc log timestamps"],
+    log_thread: bool   => ["",   "log-thread",   "LOG_THREAD", "include thread name in log records"] @hidden,
+    log_color : String => ["",   "log-color",    "LOG_COLOR", "colorize console log(auto/always/never)"],
+    log_dedup : bool   => ["",   "log-dedup",    "LOG_DEDUP", "collapse repeated log messages"] @hidden,
+    log_rate  : u32    => ["",   "log-rate",     "LOG_RATE", "set max log messages per second(0: unlimited)"] @hidden,
+    host      : IpAddr => ["H",  "host", "HOST", "set dns server listen address"] @group("Network"),
+    port      : u16    => ["p",  "port", "PORT", "set dns server listen port"] @range(1, 65535),
     dns       : String => ["d",  "dns", "DNS",   "set parent dns server address"],
-    hosts_file: String => ["b",  "hosts-file",   "HOSTS_FILE", "set hosts file path"],
-    ttl       : String => ["t",  "ttl", "TTL",   "set dns record ttl seconds"],
-    key       : String => ["k",  "key", "KEY",   "set dyndns update key"]
+    hosts_file: Vec<String> => ["b", "hosts-file", "HOSTS_FILE", "set hosts file paths or http:// urls(comma separated)"] @group("Hosts"),
+    hosts_refresh: u64 => ["",  "hosts-refresh", "HOSTS_REFRESH", "set remote hosts refresh minutes(0: never refresh)"],
+    block_db  : String => ["",   "block-db", "BLOCK_DB", "set memory-mapped block db file(built by the blockdb command)"],
+    dhcp_leases: String => ["",  "dhcp-leases", "DHCP_LEASES", "set dhcp lease file(dnsmasq or isc dhcpd) to resolve dhcp client host names"],
+    dhcp_domain: String => ["",  "dhcp-domain", "DHCP_DOMAIN", "append this domain suffix to dhcp client host names"],
+    ttl       : u32    => ["t",  "ttl", "TTL",   "set dns record ttl seconds"] @min(1) @group("Network"),
+    key       : String => ["k",  "key", "KEY",   "set dyndns update key(@file: read from file)"] @secret @group("DynDNS"),
+    key_file  : String => ["K",  "key-file", "KEY_FILE", "set dyndns update key file(one key per line)"],
+    lease     : u64    => ["l",  "lease", "LEASE", "set dyndns lease hours(0: never expire)"],
+    hook      : String => ["",   "hook", "HOOK", "set dyndns ip change hook(webhook url or command)"],
+    domains   : String => ["",   "domains", "DOMAINS", "set dyndns allowed domain suffixes(comma separated)"],
+    audit_file: String => ["",   "audit-file", "AUDIT_FILE", "set dyndns audit log file path"],
+    dyndns_port: u16   => ["",  "dyndns-port", "DYNDNS_PORT", "set dyndns dedicated udp/tcp port(0: share dns port)"],
+    export_file: String => ["",  "export-file", "EXPORT_FILE", "set host table export file path(rewritten on change)"] @group("Hosts"),
+    export    : bool   => ["",   "export", "", "export host table to export-file(or stdout) and exit, from the running server if the control socket is reachable, otherwise static hosts files only"],
+    handover  : String => ["",   "handover", "HANDOVER", "set unix socket path to take over listening sockets on restart"] @group("Network"),
+    control   : String => ["",   "control", "CONTROL", "set admin control unix socket path(used by the ctl command)"],
+    strict_parsing: bool => ["", "strict-parsing", "STRICT_PARSING", "reject malformed dns packets(bad labels, pointers, record lengths)"] @group("Network") @hidden,
+    resolvers : String => ["",   "resolvers", "RESOLVERS", "set resolver chain(comma separated: local/cache/forward)"],
+    upstream_failure: String => ["", "upstream-failure", "UPSTREAM_FAILURE", "reply when the parent dns fails(servfail/stale/nxdomain)"],
+    servfail_ttl: Duration => ["", "servfail-ttl", "SERVFAIL_TTL", "cache parent dns failures for this duration to avoid query storms(like 5s, 0: disabled)"],
+    allow_recursion: String => ["", "allow-recursion", "ALLOW_RECURSION", "set client networks allowed to recurse(comma separated, others get local answers only)"],
+    max_forwards: u8   => ["",   "max-forwards", "MAX_FORWARDS", "set max referral hops per query when resolving via other name servers"] @range(1, 255) @hidden,
+    max_cnames: u8     => ["",   "max-cnames", "MAX_CNAMES", "set max cname chain length per query"] @range(1, 255) @hidden,
+    query_budget: u16  => ["",   "query-budget", "QUERY_BUDGET", "set max referral hops plus cname follows per query(exceeded: servfail)"] @min(1) @hidden,
+    search_domains: String => ["", "search-domains", "SEARCH_DOMAINS", "expand single-label queries with these suffixes before forwarding(comma separated)"],
+    mdns_bridge: String => ["",  "mdns-bridge", "MDNS_BRIDGE", "resolve names under this suffix via lan mdns(like mdns.lan: printer.mdns.lan -> printer.local)"],
+    name_fallback: String => ["", "name-fallback", "NAME_FALLBACK", "resolve unknown single-label names on the lan(comma separated: llmnr/netbios)"],
+    chaos_version: String => ["", "chaos-version", "CHAOS_VERSION", "answer CHAOS version.bind queries with this text(empty: refused)"],
+    chaos_hostname: String => ["", "chaos-hostname", "CHAOS_HOSTNAME", "answer CHAOS hostname.bind/id.server queries with this text(empty: refused)"],
+    script    : String => ["",   "script", "SCRIPT", "set answer rule script file(evaluated before forwarding)"],
+    groups    : String => ["",   "groups", "GROUPS", "set client group policy file(toml, per group hosts, script and parent dns)"],
+    metrics_log: bool  => ["",   "metrics-log", "METRICS_LOG", "write metrics summary to log periodically"] @group("Options"),
+    slow_query: Duration => ["",  "slow-query", "SLOW_QUERY", "log queries slower than this threshold to target minidns::slowquery(like 500ms, 0: disabled)"],
+    block_log : bool   => ["",   "block-log", "BLOCK_LOG", "log client and domain of blocked queries(log target minidns::blocked)"],
+    top_window: Duration => ["",  "top-window", "TOP_WINDOW", "count top domains and clients over this sliding window(like 10m, 0: disabled)"],
+    conf_watch: bool   => ["",   "conf-watch", "CONF_WATCH", "watch config file, apply log-level/ttl/hosts-file changes without restart"],
+    event_capacity: u32 => ["",  "event-capacity", "EVENT_CAPACITY", "set max events per poll(initial value in adaptive mode)"] @min(1) @hidden,
+    poll_timeout: Duration => ["", "poll-timeout", "POLL_TIMEOUT", "set max poll wait time(like 500ms/10s)"] @hidden,
+    adaptive_events: bool => ["", "adaptive-events", "ADAPTIVE_EVENTS", "adjust event capacity and poll timeout by load"] @hidden
 );
 
 impl Default for AppConf {
@@ -34,38 +96,186 @@ impl Default for AppConf {
         AppConf {
             log_level  : String::from("info"),
             log_file   : String::new(),
-            log_max    : String::from("10m"),
-            host       : String::from("0.0.0.0"),
-            port       : String::from("53"),
+            log_max    : Size(10 << 20),
+            log_backups: 1,
+            log_route  : String::new(),
+            log_json   : false,
+            log_format : String::new(),
+            log_syslog : String::new(),
+            log_journald: false,
+            log_stderr : false,
+            log_precision: String::from("ms"),
+            log_utc    : false,
+            log_thread : false,
+            log_color  : String::from("auto"),
+            log_dedup  : false,
+            log_rate   : 0,
+            host       : IpAddr::from([0, 0, 0, 0]),
+            port       : 53,
             dns        : String::from("0.0.0.0"), // 根服务器a的地址 198.41.0.4
-            hosts_file : String::new(),
-            ttl        : String::from("300"),
+            hosts_file : Vec::new(),
+            hosts_refresh: 60,
+            block_db   : String::new(),
+            dhcp_leases: String::new(),
+            dhcp_domain: String::new(),
+            ttl        : 300,
             key        : String::new(),
+            key_file   : String::new(),
+            lease      : 0,
+            hook       : String::new(),
+            domains    : String::new(),
+            audit_file : String::new(),
+            dyndns_port: 0,
+            export_file: String::new(),
+            export     : false,
+            handover   : String::new(),
+            control    : String::new(),
+            strict_parsing: false,
+            resolvers  : String::from("local,forward"),
+            upstream_failure: String::from("servfail"),
+            servfail_ttl: Duration::from_secs(5),
+            allow_recursion: String::new(),
+            max_forwards: 10,
+            max_cnames : 8,
+            query_budget: 16,
+            search_domains: String::new(),
+            mdns_bridge: String::new(),
+            name_fallback: String::new(),
+            chaos_version: String::new(),
+            chaos_hostname: String::new(),
+            script     : String::new(),
+            groups     : String::new(),
+            metrics_log: false,
+            slow_query : Duration::ZERO,
+            block_log  : false,
+            top_window : Duration::ZERO,
+            conf_watch : false,
+            event_capacity: 128,
+            poll_timeout: Duration::from_secs(10),
+            adaptive_events: false,
+        }
+    }
+}
+
+appconfig::appconfig_define!(BenchConf,
+    server  : String   => ["s", "server", "SERVER", "dns server address(host or host:port)"],
+    qps     : u32      => ["q", "qps", "QPS", "queries sent per second"] @min(1),
+    names   : String   => ["n", "names", "NAMES", "query names file, one 'name [TYPE]' per line"],
+    qtype   : String   => ["t", "type", "TYPE", "default query type of names without a type"],
+    duration: Duration => ["d", "duration", "DURATION", "how long to send queries(like 10s/1m)"],
+    timeout : Duration => ["",  "timeout", "TIMEOUT", "reply timeout, later replies are counted as lost"],
+);
+
+impl Default for BenchConf {
+    fn default() -> Self {
+        BenchConf {
+            server  : String::from("127.0.0.1:53"),
+            qps     : 1000,
+            names   : String::new(),
+            qtype   : String::from("A"),
+            duration: Duration::from_secs(10),
+            timeout : Duration::from_secs(2),
         }
     }
 }
 
+// 健康检查的监听地址选项与AppConf同名, 可以读取服务的配置文件及MINIDNS_环境变量
+appconfig::appconfig_define!(HealthConf,
+    host   : IpAddr   => ["H", "host", "HOST", "dns server listen address, unspecified address checks the loopback"],
+    port   : u16      => ["p", "port", "PORT", "dns server listen port"] @range(1, 65535),
+    name   : String   => ["n", "name", "NAME", "query name, better answered locally(hosts file) to not depend on the parent dns"],
+    timeout: Duration => ["",  "timeout", "TIMEOUT", "reply timeout(like 500ms/3s)"],
+    quiet  : bool     => ["q", "quiet", "", "print nothing, only set the exit code"],
+);
+
+impl Default for HealthConf {
+    fn default() -> Self {
+        HealthConf {
+            host   : IpAddr::from([0, 0, 0, 0]),
+            port   : 53,
+            name   : String::from("localhost"),
+            timeout: Duration::from_secs(3),
+            quiet  : false,
+        }
+    }
+}
+
+appconfig::appconfig_define!(BlockDbConf,
+    output: String => ["o", "output", "OUTPUT", "block db file to write"],
+);
+
+impl Default for BlockDbConf {
+    fn default() -> Self {
+        BlockDbConf {
+            output: String::from("blocked.db"),
+        }
+    }
+}
+
+appconfig::appconfig_define!(CtlConf,
+    control: String   => ["c", "control", "CONTROL", "control socket path of the running server"],
+    timeout: Duration => ["",  "timeout", "TIMEOUT", "reply timeout(like 500ms/3s)"],
+);
+
+impl Default for CtlConf {
+    fn default() -> Self {
+        CtlConf {
+            control: String::new(),
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+fn version() -> String {
+    format!("{APP_NAME} version {APP_VER} CopyLeft Kivensoft 2015-2023.")
+}
+
 fn init() -> bool {
-    let version = format!("{APP_NAME} version {APP_VER} CopyLeft Kivensoft 2015-2023.");
+    let version = version();
     let ac = AppConf::init();
-    if !appconfig::parse_args(ac, &version).unwrap() {
-        return false;
+    appconfig::set_env_prefix("MINIDNS");
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    // 类型化的配置项在解析时校验, 取值范围等规则在AppConf定义中声明, 失败时已列出所有错误项
+    match appconfig::parse_command_args(ac, &version, "serve", COMMANDS, "serve", |_| true) {
+        Ok(true) => {},
+        Ok(false) => return false,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        },
     }
-    ac.port.parse::<u16>().expect("can't parse app param port");
-    ac.ttl.parse::<u32>().expect("can't parse app param ttl");
 
-    let log_level = asynclog::parse_level(&ac.log_level).unwrap();
-    let log_max = asynclog::parse_size(&ac.log_max).unwrap();
+    let log_filter = asynclog::parse_filter(&ac.log_level).unwrap();
 
-    if log_level == log::Level::Trace {
+    if log_filter.max_level() == log::Level::Trace {
         println!("config setting: {ac:#?}\n");
     }
 
-    asynclog::Builder::new()
-        .level(log_level)
+    // 按日志目标分流到独立的日志文件, 如 minidns::dnsserver=/var/log/mdns-query.log
+    let log_builder = ac.log_route.split(',').map(str::trim).filter(|s| !s.is_empty())
+        .fold(asynclog::Builder::new(), |builder, route| {
+            let (target, file) = route.split_once('=').expect("can't parse app param log-route");
+            builder.route(target.trim(), file.trim())
+        });
+
+    log_builder
+        .filter(log_filter)
+        .env("MDNS_LOG")
         .log_file(ac.log_file.clone())
-        .log_file_max(log_max)
+        .log_file_max(u32::try_from(ac.log_max.0).expect("app param log-max must be less than 4g"))
+        .backups(ac.log_backups)
+        .json(ac.log_json)
+        .format(&ac.log_format)
+        .syslog(&ac.log_syslog)
+        .journald(ac.log_journald)
+        .precision(ac.log_precision.parse().expect("can't parse app param log-precision"))
+        .utc(ac.log_utc)
+        .thread(ac.log_thread)
+        .color(ac.log_color.parse().expect("can't parse app param log-color"))
+        .dedup(ac.log_dedup)
+        .rate_limit(ac.log_rate)
         .use_console(true)
+        .use_stderr(ac.log_stderr)
         .use_async(false)
         .builder()
         .expect("init log failed");
@@ -75,22 +285,612 @@ fn init() -> bool {
     true
 }
 
+/// 在每次输出指标前附加日志模块的健康状况, 仪表: `log.written`(已输出的日志条数),
+/// `log.bytes`(已输出的日志字节数), `log.rotations`(日志文件轮转次数), `log.dropped`(队列满时丢弃的日志条数)
+struct LoggerMetrics<S>(S);
+
+impl<S: MetricsSink> MetricsSink for LoggerMetrics<S> {
+    fn counter(&self, name: &str, value: u64) {
+        self.0.counter(name, value);
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        self.0.gauge(name, value);
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        self.0.histogram(name, value);
+    }
+
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn flush(&self) {
+        let stats = asynclog::stats();
+        self.0.gauge("log.written", stats.written as f64);
+        self.0.gauge("log.bytes", stats.bytes as f64);
+        self.0.gauge("log.rotations", stats.rotations as f64);
+        self.0.gauge("log.dropped", stats.dropped as f64);
+        self.0.flush();
+    }
+}
+
+/// 处理日志相关的信号: SIGHUP重新打开日志文件, 配合logrotate等外部工具轮转日志,
+/// SIGUSR1将日志级别提高一级(最高trace), SIGUSR2恢复启动时的日志级别, 无需重启即可排查问题,
+/// 需要在创建其它线程之前调用, 使所有线程都屏蔽这些信号, 由专门的线程等待
+#[cfg(unix)]
+fn handle_log_signals() {
+    let set = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+    let initial = asynclog::filter();
+
+    std::thread::spawn(move || loop {
+        let mut sig = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            continue;
+        }
+        match sig {
+            libc::SIGHUP => match asynclog::reopen() {
+                Ok(_) => log::info!("received SIGHUP, log file reopened"),
+                Err(e) => log::error!("received SIGHUP, reopen log file failed: {e}"),
+            },
+            libc::SIGUSR1 => {
+                let level = asynclog::filter().map_or(log::LevelFilter::Info, |f| f.max_level());
+                let level = level.increment_severity();
+                asynclog::set_level(level);
+                log::warn!("received SIGUSR1, log level changed to {level}");
+            },
+            libc::SIGUSR2 => {
+                if let Some(ref filter) = initial {
+                    asynclog::set_filter(filter.clone());
+                }
+                log::warn!("received SIGUSR2, log level restored");
+            },
+            _ => {},
+        }
+    });
+}
+
+fn watch_config(path: &str, handle: HostHandle) -> appconfig::ConfigWatcher {
+    let watcher = appconfig::watch_config(path, Duration::from_secs(2), move |r: anyhow::Result<(&AppConf, &[appconfig::Change])>| {
+        let (ac, changes) = match r {
+            Ok(v) => v,
+            Err(e) => return log::error!("reload config file failed, keep the current settings: {e:?}"),
+        };
+        for change in changes {
+            log::info!("config {} changed from [{}] to [{}]", change.name, change.old, change.new);
+            let result = match change.name {
+                "log-level" => asynclog::parse_filter(&ac.log_level)
+                    .map(asynclog::set_filter)
+                    .map_err(|e| anyhow::anyhow!("{e}")),
+                "ttl" => handle.set_ttl(ac.ttl),
+                "hosts-file" => {
+                    // 远程hosts来源在启动时创建, 变化后需要重启
+                    let is_remote = |s: &&str| s.starts_with("http://");
+                    if change.old.split(',').filter(is_remote).ne(change.new.split(',').filter(is_remote)) {
+                        log::warn!("remote hosts changes take effect after restart");
+                    }
+                    let files = ac.hosts_file.iter().filter(|s| !s.starts_with("http://")).cloned().collect();
+                    handle.reload_hosts(files)
+                },
+                name => {
+                    log::warn!("config {name} changes take effect after restart");
+                    Ok(())
+                },
+            };
+            if let Err(e) = result {
+                log::error!("apply config {} failed: {e:?}", change.name);
+            }
+        }
+    });
+    watcher.expect("watch config file failed")
+}
+
 fn main() {
+    let command = match appconfig::get_command(COMMANDS, "serve") {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        },
+    };
+    match command {
+        "serve" => serve(),
+        "bench" => if let Err(e) = bench() {
+            eprintln!("Error: {e:?}");
+            std::process::exit(1);
+        },
+        "healthcheck" => std::process::exit(healthcheck()),
+        "check" => std::process::exit(check()),
+        "blockdb" => if let Err(e) = blockdb() {
+            eprintln!("Error: {e:?}");
+            std::process::exit(1);
+        },
+        "ctl" => if let Err(e) = ctl() {
+            eprintln!("Error: {e:?}");
+            std::process::exit(1);
+        },
+        _ => unreachable!("command {command} not handled"),
+    }
+}
+
+/// 向服务的监听地址发送查询, 在超时前收到任意回复码的应答即为健康, 返回进程退出码
+fn healthcheck() -> i32 {
+    let mut hc = HealthConf::default();
+    appconfig::set_env_prefix("MINIDNS");
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    match appconfig::parse_command_args(&mut hc, &version(), "healthcheck", COMMANDS, "serve", |_| true) {
+        Ok(true) => {},
+        Ok(false) => return 0,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return 1;
+        },
+    }
+
+    // 监听所有地址时检查本机回环地址
+    let host = match hc.host {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::from([127, 0, 0, 1]),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let mut client = match minidns::client::Client::new(&SocketAddr::new(host, hc.port).to_string()) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("unhealthy: {e:#}");
+            return 1;
+        },
+    };
+    client.set_timeout(hc.timeout);
+    client.set_retries(0);
+
+    let start = std::time::Instant::now();
+    match client.resolve(&hc.name, minidns::QueryType::A) {
+        Ok(packet) => {
+            if !hc.quiet {
+                println!("healthy: {} answered {} {} in {}ms", client.server(), hc.name,
+                        packet.header.rescode, start.elapsed().as_millis());
+            }
+            0
+        },
+        Err(e) => {
+            if !hc.quiet {
+                eprintln!("unhealthy: {e:#}");
+            }
+            1
+        },
+    }
+}
+
+/// 按指定速率向服务器发送域名列表中的查询, 输出延迟分位数及错误率
+fn bench() -> anyhow::Result<()> {
+    let mut bc = BenchConf::default();
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    if !appconfig::parse_command_args(&mut bc, &version(), "bench", COMMANDS, "serve", |bc| !bc.names.is_empty())? {
+        return Ok(());
+    }
+
+    let text = std::fs::read_to_string(&bc.names)
+        .map_err(|e| anyhow::anyhow!("read names file {} failed: {e}", bc.names))?;
+    let questions = minidns::bench::parse_names(&text, bc.qtype.parse()?)?;
+    let opts = minidns::bench::BenchOptions {
+        server: minidns::client::Client::new(&bc.server)?.server(),
+        qps: bc.qps,
+        duration: bc.duration,
+        timeout: bc.timeout,
+    };
+    println!("sending {} names to {} at {} qps for {}...", questions.len(), opts.server, opts.qps,
+            appconfig::format_duration(opts.duration));
+    let report = minidns::bench::run(&opts, &questions)?;
+    println!("{report}");
+    Ok(())
+}
+
+/// 从hosts文件中的屏蔽域名(地址为0.0.0.0且未指定ttl)生成屏蔽域名库, 其它记录忽略
+fn blockdb() -> anyhow::Result<()> {
+    let mut bc = BlockDbConf::default();
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    if !appconfig::parse_command_args(&mut bc, &version(), "blockdb", COMMANDS, "serve",
+            |_| !appconfig::free_args().is_empty())? {
+        return Ok(());
+    }
+
+    let (mut blocked, mut ignored) = (Vec::new(), 0);
+    for path in appconfig::free_args() {
+        for entry in minidns::HostsConfig::new(&path)? {
+            let entry = entry?;
+            match entry.record {
+                minidns::HostRecord::A(ref ip) if ip.trim() == "0.0.0.0" && entry.ttl.is_none() => blocked.push(entry.host),
+                _ => ignored += 1,
+            }
+        }
+    }
+    let count = minidns::blockset::write_db(&bc.output, blocked.iter().map(String::as_str))?;
+    println!("{count} blocked names written to {}, {ignored} other records ignored", bc.output);
+    Ok(())
+}
+
+/// 向运行中服务的管理控制socket发送命令(剩余参数), 输出返回的结果
+fn ctl() -> anyhow::Result<()> {
+    let mut cc = CtlConf::default();
+    appconfig::set_env_prefix("MINIDNS");
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    if !appconfig::parse_command_args(&mut cc, &version(), "ctl", COMMANDS, "serve",
+            |_| !appconfig::free_args().is_empty())? {
+        return Ok(());
+    }
+    if cc.control.is_empty() {
+        anyhow::bail!("control socket path not set, use --control or the control option of the config file");
+    }
+
+    let reply = control_request(&cc.control, &appconfig::free_args().join(" "), cc.timeout)?;
+    print!("{reply}");
+    if reply.starts_with("error:") {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// 向运行中服务的管理控制socket发送一行命令, 返回回复内容
+fn control_request(path: &str, command: &str, timeout: Duration) -> anyhow::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::io::{Read, Write};
+
+        let mut stream = std::os::unix::net::UnixStream::connect(path)
+            .map_err(|e| anyhow::anyhow!("connect control socket {path} failed: {e}"))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.write_all(format!("{command}\n").as_bytes())?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        Ok(reply)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, command, timeout);
+        anyhow::bail!("control socket is only supported on unix")
+    }
+}
+
+/// 导出域名表, 配置了管理控制socket且服务正在运行时从运行中的服务导出(含运行期间注册的动态域名),
+/// 否则只导出静态配置的hosts文件(不下载远程hosts), 返回None表示需要加载静态配置后导出
+fn export_running(ac: &AppConf) -> Option<String> {
+    if ac.control.is_empty() {
+        return None;
+    }
+    match control_request(&ac.control, "export", Duration::from_secs(3)) {
+        Ok(reply) if !reply.starts_with("error:") => Some(reply),
+        Ok(reply) => {
+            log::warn!("export from running server failed: {}, export static hosts only", reply.trim());
+            None
+        },
+        Err(e) => {
+            log::info!("{e}, server not running, export static hosts only");
+            None
+        },
+    }
+}
+
+/// 检查配置而不启动服务: 解析配置及hosts等文件, 确认监听地址可以绑定并探测上级dns服务器,
+/// 列出发现的所有问题, 返回进程退出码, 没有问题时为0
+fn check() -> i32 {
+    let ac = AppConf::init();
+    appconfig::set_env_prefix("MINIDNS");
+    appconfig::set_build_info(appconfig::build_info!(APP_NAME, APP_VER));
+    match appconfig::parse_command_args(ac, &version(), "check", COMMANDS, "serve", |_| true) {
+        Ok(true) => {},
+        Ok(false) => return 0,
+        Err(e) => {
+            println!("[FAIL] config: {e:#}");
+            return 1;
+        },
+    }
+
+    let mut problems = 0;
+    let mut report = |item: String, result: anyhow::Result<String>| match result {
+        Ok(detail) if detail.is_empty() => println!("[ ok ] {item}"),
+        Ok(detail) => println!("[ ok ] {item}: {detail}"),
+        Err(e) => {
+            problems += 1;
+            println!("[FAIL] {item}: {e:#}");
+        },
+    };
+
+    report(String::from("config"), Ok(appconfig::config_file().unwrap_or_else(|| String::from("no config file"))));
+    report(String::from("log settings"), check_log_settings(ac).map(|_| String::new()));
+
+    // 监听地址被占用时, 可能是服务已在运行
+    let listen_addr = SocketAddr::new(ac.host, ac.port);
+    report(format!("listen udp {listen_addr}"), std::net::UdpSocket::bind(listen_addr).map(|_| String::new())
+        .map_err(|e| anyhow::anyhow!("bind failed: {e}")));
+    if ac.dyndns_port != 0 {
+        let dyndns_addr = SocketAddr::new(ac.host, ac.dyndns_port);
+        report(format!("dyndns listen udp {dyndns_addr}"), std::net::UdpSocket::bind(dyndns_addr).map(|_| String::new())
+            .map_err(|e| anyhow::anyhow!("bind failed: {e}")));
+        report(format!("dyndns listen tcp {dyndns_addr}"), std::net::TcpListener::bind(dyndns_addr).map(|_| String::new())
+            .map_err(|e| anyhow::anyhow!("bind failed: {e}")));
+    }
+
+    // 使用临时端口创建服务器以复用启动时的加载过程, 与export模式相同
+    let mut dns_server = match DnsServer::create("127.0.0.1:0", &ac.dns, ac.ttl, &ac.key) {
+        Ok(dns_server) => dns_server,
+        Err(e) => {
+            report(String::from("dns server"), Err(e));
+            println!("{problems} problem(s) found");
+            return 1;
+        },
+    };
+    for hosts_file in ac.hosts_file.iter() {
+        let result = match hosts_file.starts_with("http://") {
+            true => dns_server.add_remote_hosts(hosts_file),
+            false => dns_server.load_hosts_file(hosts_file),
+        };
+        report(format!("hosts file {hosts_file}"), result.map(|_| String::new()));
+    }
+    if !ac.block_db.is_empty() {
+        report(format!("block db {}", ac.block_db), dns_server.set_block_db(&ac.block_db).map(|_| String::new()));
+    }
+    if !ac.dhcp_leases.is_empty() {
+        report(format!("dhcp leases {}", ac.dhcp_leases), dns_server.set_dhcp_leases(&ac.dhcp_leases, &ac.dhcp_domain).map(|_| String::new()));
+    }
+    if !ac.key_file.is_empty() {
+        report(format!("key file {}", ac.key_file), dns_server.set_key_file(&ac.key_file).map(|_| String::new()));
+    }
+    if !ac.audit_file.is_empty() {
+        report(format!("audit file {}", ac.audit_file), dns_server.set_audit_file(&ac.audit_file).map(|_| String::new()));
+    }
+    report(format!("resolvers {}", ac.resolvers), dns_server.set_resolver_chain(&ac.resolvers).map(|_| String::new()));
+    report(format!("upstream failure policy {}", ac.upstream_failure),
+        ac.upstream_failure.parse::<minidns::dnsserver::FailurePolicy>().map(|_| String::new()));
+    if !ac.allow_recursion.is_empty() {
+        report(format!("allow recursion {}", ac.allow_recursion), dns_server.set_recursion_acl(&ac.allow_recursion).map(|_| String::new()));
+    }
+    if !ac.script.is_empty() {
+        report(format!("script {}", ac.script), dns_server.set_script_file(&ac.script).map(|_| String::new()));
+    }
+    if !ac.groups.is_empty() {
+        report(format!("groups {}", ac.groups), dns_server.load_client_groups(&ac.groups).map(|_| String::new()));
+    }
+    if !ac.mdns_bridge.is_empty() {
+        report(format!("mdns bridge {}", ac.mdns_bridge), dns_server.set_mdns_bridge(&ac.mdns_bridge).map(|_| String::new()));
+    }
+    if !ac.name_fallback.is_empty() {
+        report(format!("name fallback {}", ac.name_fallback), dns_server.set_name_fallback(&ac.name_fallback).map(|_| String::new()));
+    }
+
+    // 未设置上级dns服务器(0.0.0.0)时不转发, 无需探测
+    match ac.dns.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => report(String::from("parent dns"), Ok(String::from("not set, queries are not forwarded"))),
+        _ => report(format!("parent dns {}", ac.dns), probe_upstream(&ac.dns)),
+    }
+
+    match problems {
+        0 => {
+            println!("config check passed");
+            0
+        },
+        n => {
+            println!("{n} problem(s) found");
+            1
+        },
+    }
+}
+
+/// 检查日志相关的设置能否被解析, 与init中的处理一致
+fn check_log_settings(ac: &AppConf) -> anyhow::Result<()> {
+    asynclog::parse_filter(&ac.log_level).map_err(|e| anyhow::anyhow!("log-level: {e}"))?;
+    ac.log_precision.parse::<asynclog::Precision>().map_err(|e| anyhow::anyhow!("log-precision: {e}"))?;
+    ac.log_color.parse::<asynclog::ColorMode>().map_err(|e| anyhow::anyhow!("log-color: {e}"))?;
+    u32::try_from(ac.log_max.0).map_err(|_| anyhow::anyhow!("log-max must be less than 4g"))?;
+    for route in ac.log_route.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        if route.split_once('=').is_none() {
+            anyhow::bail!("log-route: {route} isn't target=file");
+        }
+    }
+    Ok(())
+}
+
+/// 向上级dns服务器发送查询, 收到任意回复码的应答即为可达, 返回回复码及耗时
+fn probe_upstream(server: &str) -> anyhow::Result<String> {
+    let mut client = minidns::client::Client::new(server)?;
+    client.set_timeout(CHECK_TIMEOUT);
+    client.set_retries(1);
+    let start = std::time::Instant::now();
+    let packet = client.resolve(CHECK_NAME, minidns::QueryType::A)?;
+    Ok(format!("{CHECK_NAME} answered {} in {}ms", packet.header.rescode, start.elapsed().as_millis()))
+}
+
+/// 绑定dns及动态dns的监听socket, 优先接管正在运行的实例(--handover)或systemd传递的socket
+fn bind_listen_sockets(dns_server: &mut DnsServer, ac: &AppConf) -> anyhow::Result<()> {
+    let inherited = match ac.handover.is_empty() {
+        true => None,
+        false => minidns::handover::take_over(&ac.handover)?,
+    };
+    let inherited = match inherited {
+        Some(inherited) => {
+            log::info!("take over listening sockets from {}", ac.handover);
+            inherited
+        }
+        None => match minidns::handover::systemd_sockets() {
+            Some(inherited) => {
+                log::info!("use listening sockets passed by systemd");
+                inherited
+            }
+            None => minidns::handover::Inherited::default(),
+        },
+    };
+
+    let socket = match inherited.dns {
+        Some(socket) => socket,
+        None => {
+            let addr = SocketAddr::new(ac.host, ac.port);
+            std::net::UdpSocket::bind(addr).map_err(|e| anyhow::anyhow!("bind dns server socket {addr} failed: {e}"))?
+        }
+    };
+    dns_server.set_listen_socket(socket)?;
+
+    if ac.dyndns_port != 0 {
+        // 只传入了udp或tcp之一时, 另一个由本进程绑定
+        let addr = SocketAddr::new(ac.host, ac.dyndns_port);
+        match (inherited.dyndns_udp, inherited.dyndns_tcp) {
+            (None, None) => dns_server.set_dyndns_listen(&addr.to_string())?,
+            (socket, listener) => {
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => std::net::UdpSocket::bind(addr).map_err(|e| anyhow::anyhow!("bind dyndns udp socket {addr} failed: {e}"))?,
+                };
+                let listener = match listener {
+                    Some(listener) => listener,
+                    None => std::net::TcpListener::bind(addr).map_err(|e| anyhow::anyhow!("bind dyndns tcp socket {addr} failed: {e}"))?,
+                };
+                dns_server.set_dyndns_sockets(socket, listener)?
+            },
+        }
+    }
+
+    if !ac.handover.is_empty() {
+        dns_server.set_handover(&ac.handover)?;
+    }
+    Ok(())
+}
+
+/// 启动时在后台线程探测上级dns服务器, 不可达时输出醒目的警告, 不影响服务启动
+fn probe_upstream_on_startup(dns: &str) {
+    // 未设置上级dns服务器(0.0.0.0)时不转发, 无需探测
+    match dns.parse::<IpAddr>() {
+        Ok(ip) if !ip.is_unspecified() => {},
+        _ => return,
+    }
+    let server = dns.to_string();
+    let result = std::thread::Builder::new().name(String::from("probe")).spawn(move || {
+        let start = std::time::Instant::now();
+        match probe_upstream(&server) {
+            Ok(result) => log::info!("parent dns server {server} is reachable, {result}"),
+            Err(e) => log::warn!("!!! parent dns server {server} is UNREACHABLE (no reply in {}ms: {e:#}), forwarded queries will fail !!!",
+                    start.elapsed().as_millis()),
+        }
+    });
+    if let Err(e) = result {
+        log::error!("start parent dns probe failed: {e}");
+    }
+}
+
+/// 启动dns服务
+fn serve() {
     if !init() { return; }
+    let _log_guard = asynclog::guard();
+    #[cfg(unix)]
+    handle_log_signals();
 
     let ac = AppConf::get();
 
-    let listen_addr = format!("{}:{}", ac.host, ac.port);
-    let ttl: u32 = ac.ttl.parse().unwrap();
-    let mut dns_server = DnsServer::create(&listen_addr, &ac.dns, ttl, &ac.key).expect("can't create dns server");
+    // 服务正在运行时直接导出其当前的域名表
+    if ac.export {
+        if let Some(hosts) = export_running(ac) {
+            match ac.export_file.is_empty() {
+                true => print!("{hosts}"),
+                false => std::fs::write(&ac.export_file, hosts).expect("write export file failed"),
+            }
+            return;
+        }
+    }
+
+    // 先使用临时端口加载配置, 最后再绑定(或从正在运行的实例接管)监听socket, 缩短升级时停止服务的时间
+    let mut dns_server = DnsServer::create("127.0.0.1:0", &ac.dns, ac.ttl, &ac.key).expect("can't create dns server");
+
+    // 加载动态dns密钥文件
+    if !ac.key_file.is_empty() {
+        dns_server.set_key_file(&ac.key_file).expect("load dyndns key file failed");
+    }
+    // 租约小时数过大导致溢出时视为永不过期
+    let lease_time = ac.lease.checked_mul(3600).unwrap_or_else(|| {
+        log::warn!("dyndns lease {} hours is too large, leases never expire", ac.lease);
+        0
+    });
+    dns_server.set_lease_time(lease_time);
+    dns_server.set_change_hook(&ac.hook);
+    dns_server.set_dyndns_domains(&ac.domains);
+    if !ac.audit_file.is_empty() {
+        dns_server.set_audit_file(&ac.audit_file).expect("open dyndns audit file failed");
+    }
+
+    // 加载hosts file, 以http://开头的为远程hosts
+    for hosts_file in ac.hosts_file.iter() {
+        // 远程hosts下载失败时不影响服务启动, 之后的定时刷新会重新下载, 导出时不需要下载
+        if hosts_file.starts_with("http://") {
+            if ac.export {
+                continue;
+            }
+            if let Err(e) = dns_server.add_remote_hosts(hosts_file) {
+                log::error!("load remote hosts {hosts_file} failed: {e:?}");
+            }
+            continue;
+        }
+        dns_server.load_hosts_file(hosts_file).expect("load host config failed");
+    }
+    dns_server.set_remote_refresh(ac.hosts_refresh * 60);
+    if !ac.dhcp_leases.is_empty() {
+        dns_server.set_dhcp_leases(&ac.dhcp_leases, &ac.dhcp_domain).expect("load dhcp lease file failed");
+    }
+    if !ac.block_db.is_empty() {
+        dns_server.set_block_db(&ac.block_db).expect("open block db failed");
+    }
+    dns_server.set_strict_parsing(ac.strict_parsing);
+    dns_server.set_resolver_chain(&ac.resolvers).expect("invalid resolver chain");
+    dns_server.set_failure_policy(ac.upstream_failure.parse().expect("invalid upstream failure policy"));
+    dns_server.set_servfail_ttl(ac.servfail_ttl);
+    dns_server.set_recursion_acl(&ac.allow_recursion).expect("invalid allow-recursion networks");
+    dns_server.set_query_limits(ac.max_forwards, ac.max_cnames, ac.query_budget);
+    dns_server.set_search_domains(&ac.search_domains);
+    dns_server.set_mdns_bridge(&ac.mdns_bridge).expect("create mdns bridge failed");
+    dns_server.set_name_fallback(&ac.name_fallback).expect("invalid name fallback protocols");
+    dns_server.set_chaos(&ac.chaos_version, &ac.chaos_hostname);
+    if ac.metrics_log {
+        dns_server.set_metrics_sink(Box::new(LoggerMetrics(minidns::metrics::LogMetrics::default())));
+    }
+    if !ac.script.is_empty() {
+        dns_server.set_script_file(&ac.script).expect("load script file failed");
+    }
+    if !ac.groups.is_empty() {
+        dns_server.load_client_groups(&ac.groups).expect("load client group file failed");
+    }
 
-    // 加载hosts file
-    if !ac.hosts_file.is_empty() {
-        let mut hosts_config = HostsConfig::new(&ac.hosts_file).expect("load app config file failed");
-        while let Some((host, ip)) = hosts_config.next().expect("load host config failed") {
-            dns_server.register_host(host, ip).expect("can't register host");
+    // 导出域名表后退出
+    if ac.export {
+        let hosts = dns_server.export_hosts();
+        match ac.export_file.is_empty() {
+            true => print!("{hosts}"),
+            false => std::fs::write(&ac.export_file, hosts).expect("write export file failed"),
         }
+        return;
+    }
+    if !ac.export_file.is_empty() {
+        dns_server.set_export_file(&ac.export_file);
     }
+    bind_listen_sockets(&mut dns_server, ac).expect("can't create dns server");
 
-    dns_server.run(128).unwrap();
+    // 监视配置文件, 修改后立即应用可重新加载的设置
+    let _conf_watcher = match (ac.conf_watch, appconfig::config_file()) {
+        (true, Some(path)) => Some(watch_config(&path, dns_server.host_handle())),
+        (true, None) => { log::warn!("no config file to watch"); None },
+        _ => None,
+    };
+
+    probe_upstream_on_startup(&ac.dns);
+    dns_server.set_slow_query(ac.slow_query);
+    dns_server.set_block_log(ac.block_log);
+    dns_server.set_analytics_window(ac.top_window);
+    if !ac.control.is_empty() {
+        dns_server.set_control_socket(&ac.control).expect("can't create control socket");
+    }
+    dns_server.set_event_tuning(ac.poll_timeout, ac.adaptive_events);
+    dns_server.run(ac.event_capacity as usize).unwrap();
 }
@@ -0,0 +1,178 @@
+//! mdns到单播dns的桥接, 使不支持mdns的客户端可以通过普通的dns查询解析局域网内通过mdns通告的主机
+//!
+//! `主机名.桥接后缀`的A/AAAA查询转为`主机名.local`的mdns一次性查询, 应答中的域名转回桥接后缀,
+//! 应答只接受来自局域网地址5353端口的数据包, 链路本地的ipv6地址(fe80::/10)对其它网段的客户端
+//! 不可用, 不会出现在回复中
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use mio::net::UdpSocket;
+use super::bufutil::BytePacketBuffer;
+use super::dnsutil::{DnsPacket, DnsQuestion, DnsRecord, QueryType};
+use super::lanquery::{LanCache, is_lan_source};
+
+const MDNS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353); // mdns组播地址
+const LOCAL_SUFFIX: &str = "local";      // mdns域名后缀
+
+/// 等待mdns应答的最长时间, 局域网内的应答方通常在百毫秒内回复
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// mdns到单播dns的桥接, 将`主机名.桥接后缀`的查询转为`主机名.local`的mdns查询,
+/// 应答中的域名转回桥接后缀后回复客户端, 使不支持mdns的客户端也能解析局域网内通过mdns通告的主机
+///
+/// 查询以一次性查询方式(RFC 6762 6.7)从非5353端口发出, 应答方以单播直接回复, 无需加入组播组
+pub struct MdnsBridge {
+    socket : UdpSocket,     // 发送mdns查询及接收应答的socket
+    target : SocketAddr,    // mdns查询的目标地址
+    suffix : String,        // 桥接后缀, 如mdns.lan
+    cache  : LanCache,      // 以桥接后的域名为键的应答缓存
+}
+
+impl MdnsBridge {
+
+    pub fn new(suffix: &str) -> Result<MdnsBridge> {
+        MdnsBridge::with_target(suffix, MDNS_ADDR)
+    }
+
+    /// 向指定地址发送mdns查询, 用于测试
+    pub fn with_target(suffix: &str, target: SocketAddr) -> Result<MdnsBridge> {
+        let suffix = suffix.trim_matches('.').to_ascii_lowercase();
+        if suffix.is_empty() || suffix == LOCAL_SUFFIX {
+            anyhow::bail!("mdns bridge suffix {suffix} is invalid");
+        }
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+            .with_context(|| "bind mdns bridge socket failed")?;
+        // mdns数据包的ip ttl应为255(RFC 6762 11)
+        if let Err(e) = socket.set_multicast_ttl_v4(255) {
+            log::warn!("set mdns multicast ttl failed: {e}");
+        }
+        Ok(MdnsBridge { socket, target, suffix, cache: LanCache::default() })
+    }
+
+    pub fn socket_mut(&mut self) -> &mut UdpSocket {
+        &mut self.socket
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    /// 桥接后缀下的域名对应的mdns域名, 如printer.mdns.lan对应printer.local, 不在桥接后缀下时返回None
+    pub fn local_name(&self, qname: &str) -> Option<String> {
+        let host = qname.strip_suffix(self.suffix.as_str())?.strip_suffix('.')?;
+        (!host.is_empty()).then(|| format!("{host}.{LOCAL_SUFFIX}"))
+    }
+
+    /// 缓存的应答, ttl为剩余时间, 记录为空表示主机没有该类型的地址
+    pub fn cached(&self, question: &DnsQuestion, now: Instant) -> Option<Vec<DnsRecord>> {
+        self.cache.cached(question, now)
+    }
+
+    /// 以指定的查询id发送mdns查询
+    pub fn send_query(&mut self, id: u16, local_name: &str, qtype: QueryType, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let packet = DnsPacket::builder()
+            .id(id)
+            .question(DnsQuestion::new(local_name.to_string(), qtype))
+            .build();
+        packet.write(buffer)?;
+        self.socket.send_to(buffer.data(), self.target)
+            .with_context(|| format!("send mdns query of {local_name} failed"))?;
+        Ok(())
+    }
+
+    /// 接收一个mdns应答, 没有待接收的数据时返回None, 不是来自局域网5353端口的数据包返回错误
+    pub fn recv(&self, buffer: &mut BytePacketBuffer) -> Result<Option<DnsPacket>> {
+        let size = match self.socket.recv_from(buffer.recv_buf()) {
+            Ok((size, from)) if is_lan_source(&from, &self.target) => size,
+            Ok((_, from)) => anyhow::bail!("mdns response from {from} ignored, not from a lan address and port {}", self.target.port()),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => anyhow::bail!(anyhow::Error::new(e).context("mdns bridge recv failed")),
+        };
+        buffer.set_len(size);
+        DnsPacket::from_buffer(buffer).map(Some)
+    }
+
+    /// 从应答(含附加记录)中取出问题域名的地址记录并转为桥接后缀下的记录, 只缓存问题本身的结果
+    ///
+    /// 返回None表示应答与问题无关或主机已下线(ttl为0), 应继续等待其它应答;
+    /// 主机只有另一类型的地址时(如查询仅有ipv4地址主机的AAAA记录)返回空的记录, 即NODATA
+    pub fn on_response(&mut self, question: &DnsQuestion, packet: &DnsPacket, now: Instant) -> Option<Vec<DnsRecord>> {
+        let host = question.name.to_ascii_lowercase().strip_suffix(self.suffix.as_str())?.strip_suffix('.')?.to_string();
+        let local_name = format!("{host}.{LOCAL_SUFFIX}");
+        let (mut records, mut others) = (Vec::new(), Vec::new());
+        for record in packet.answers.iter().chain(packet.resources.iter()) {
+            let (domain, qtype, ttl) = match record {
+                DnsRecord::A { domain, ttl, .. } => (domain, QueryType::A, *ttl),
+                // 链路本地地址只在应答方所在的链路上可用, 不转给其它网段的客户端
+                DnsRecord::AAAA { addr, .. } if addr.segments()[0] & 0xffc0 == 0xfe80 => continue,
+                DnsRecord::AAAA { domain, ttl, .. } => (domain, QueryType::AAAA, *ttl),
+                _ => continue,
+            };
+            if !domain.eq_ignore_ascii_case(&local_name) {
+                continue;
+            }
+            if qtype != question.qtype {
+                others.push(ttl);
+                continue;
+            }
+            let mut record = record.clone();
+            if let DnsRecord::A { domain, .. } | DnsRecord::AAAA { domain, .. } = &mut record {
+                *domain = question.name.clone();
+            }
+            records.push(record);
+        }
+
+        // ttl为0表示主机已下线(goodbye), 删除缓存
+        let ttl = match records.is_empty() {
+            true => others.iter().copied().min()?,
+            false => records.iter().map(DnsRecord::ttl).min().unwrap_or(0),
+        };
+        self.cache.insert(question, records.clone(), ttl, now);
+        (ttl > 0).then_some(records)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mdns_bridge() {
+        let mut bridge = MdnsBridge::new(".MDNS.lan").unwrap();
+        assert_eq!("mdns.lan", bridge.suffix());
+        assert_eq!(Some("printer.local".to_string()), bridge.local_name("printer.mdns.lan"));
+        assert_eq!(None, bridge.local_name("mdns.lan"));
+        assert_eq!(None, bridge.local_name("printer.xmdns.lan"));
+        assert!(MdnsBridge::new("local").is_err());
+
+        let now = Instant::now();
+        let question = DnsQuestion::new("printer.mdns.lan".to_string(), QueryType::A);
+        let packet = DnsPacket::builder()
+            .answer(DnsRecord::A { domain: "Printer.local".to_string(), addr: Ipv4Addr::new(192, 168, 1, 30), ttl: 120 })
+            .resource(DnsRecord::AAAA { domain: "printer.local".to_string(), addr: "fe80::30".parse().unwrap(), ttl: 120 })
+            .resource(DnsRecord::A { domain: "nas.local".to_string(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 120 })
+            .build();
+        let answers = bridge.on_response(&question, &packet, now);
+        assert_eq!(Some(vec![DnsRecord::A { domain: "printer.mdns.lan".to_string(), addr: Ipv4Addr::new(192, 168, 1, 30), ttl: 120 }]), answers);
+        // 附加记录中的其它主机不缓存
+        assert_eq!(1, bridge.cache.len());
+        assert_eq!(Some(60), bridge.cached(&question, now + Duration::from_secs(60)).map(|r| r[0].ttl()));
+        assert_eq!(None, bridge.cached(&question, now + Duration::from_secs(120)));
+
+        // 只有链路本地ipv6地址或只有ipv4地址的主机, AAAA查询的结果为NODATA
+        let aaaa = DnsQuestion::new("printer.mdns.lan".to_string(), QueryType::AAAA);
+        assert_eq!(Some(Vec::new()), bridge.on_response(&aaaa, &packet, now));
+        assert_eq!(Some(Vec::new()), bridge.cached(&aaaa, now));
+        let other = DnsQuestion::new("scanner.mdns.lan".to_string(), QueryType::A);
+        assert_eq!(None, bridge.on_response(&other, &packet, now));
+
+        // 主机下线的通告删除缓存
+        let packet = DnsPacket::builder()
+            .answer(DnsRecord::A { domain: "printer.local".to_string(), addr: Ipv4Addr::new(192, 168, 1, 30), ttl: 0 })
+            .build();
+        assert_eq!(None, bridge.on_response(&question, &packet, now));
+        assert_eq!(None, bridge.cached(&question, now));
+    }
+}
@@ -0,0 +1,141 @@
+//! 运行指标输出接口
+//!
+//! DnsServer在关键位置调用[`MetricsSink`], 嵌入方可以实现该接口对接自己的监控系统,
+//! 服务器输出的指标:
+//! - 计数器: `dns.queries`(收到的查询), `dns.malformed`(格式错误的数据包),
+//!   `dns.resolver.<名称>`(各解析器给出的结果, 客户端分组给出的结果为`dns.resolver.group`), `dns.hook`(查询钩子给出的结果),
+//!   `dns.blocked`(屏蔽的查询), `dns.forwarded`(转发给上级的查询), `dns.timeouts`(上级超时未回复的查询),
+//!   `dns.upstream_errors`(上级dns服务器端口不可达), `dns.stale`(上级失败时回复的过期缓存),
+//!   `dns.servfail_cached`(因上级最近失败直接回复SERVFAIL的查询),
+//!   `dns.chaos`(回复的CHAOS类别服务器信息查询), `dns.over_budget`(超出转发或别名跟随限制的查询),
+//!   `dns.mdns.answered|timeouts`(通过mdns桥接得到应答及超时未应答的查询),
+//!   `dns.fallback.answered|timeouts`(通过llmnr/netbios得到应答及超时未应答的单标签域名查询),
+//!   `dns.truncated`(被截断的回复), `dns.rcode.<回复码>`(各回复码的回复数量),
+//!   `dns.upstream.<地址>.queries|errors|timeouts`(各上级dns服务器的查询、失败及超时数量)
+//! - 仪表: `dns.pending`(等待上级回复的查询), `dns.pool.idle`(缓冲池空闲缓冲区), `dns.hosts`(本地域名数量),
+//!   `dns.events.capacity`(事件容量), `dns.events.peak`(单次poll最多事件数), `dns.events.full_polls`(事件填满容量的poll次数),
+//!   `dns.events.poll_timeout_ms`(poll的最长等待时间, 毫秒)
+//! - 直方图: `dns.latency_ms`(从收到查询到回复的耗时, 毫秒), `dns.upstream.<地址>.latency_ms`(各上级dns服务器的回复耗时)
+//!
+//! 名称中含有解析器或上级地址的指标需要在每次查询时拼接, [`MetricsSink::enabled`]返回false时不输出
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// 指标输出接口, 服务器在单线程中调用, 实现者需要自行处理跨线程汇总
+pub trait MetricsSink {
+    /// 计数器增加指定值
+    fn counter(&self, name: &str, value: u64);
+
+    /// 设置仪表的当前值
+    fn gauge(&self, name: &str, value: f64);
+
+    /// 记录一个直方图样本
+    fn histogram(&self, name: &str, value: f64);
+
+    /// 服务器定期调用(约10秒一次), 用于批量输出
+    fn flush(&self) {}
+
+    /// 是否输出指标, 返回false时服务器跳过名称中含有解析器或上级地址的指标, 避免每次查询拼接名称
+    fn enabled(&self) -> bool {
+        true
+    }
+}
+
+/// 不输出任何指标, 服务器的缺省实现
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {
+    fn counter(&self, _name: &str, _value: u64) {}
+    fn gauge(&self, _name: &str, _value: f64) {}
+    fn histogram(&self, _name: &str, _value: f64) {}
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+/// 汇总指标并在每次flush时写入日志, 计数器及直方图在输出后清零
+#[derive(Default)]
+pub struct LogMetrics {
+    counters  : RefCell<BTreeMap<String, u64>>,      // 自上次输出以来的计数
+    gauges    : RefCell<BTreeMap<String, f64>>,      // 仪表的最新值
+    histograms: RefCell<BTreeMap<String, Summary>>,  // 自上次输出以来的样本汇总
+}
+
+// 直方图样本汇总
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Summary {
+    count: u64,
+    sum  : f64,
+    min  : f64,
+    max  : f64,
+}
+
+impl LogMetrics {
+
+    /// 生成汇总文本并清零计数器及直方图, 没有任何指标时返回None
+    fn take_report(&self) -> Option<String> {
+        let counters = std::mem::take(&mut *self.counters.borrow_mut());
+        let histograms = std::mem::take(&mut *self.histograms.borrow_mut());
+        let gauges = self.gauges.borrow();
+
+        let mut items = Vec::new();
+        items.extend(counters.iter().map(|(k, v)| format!("{k}={v}")));
+        items.extend(gauges.iter().map(|(k, v)| format!("{k}={v}")));
+        items.extend(histograms.iter().map(|(k, s)| format!("{k}=[n={} avg={:.1} min={:.1} max={:.1}]",
+                s.count, s.sum / s.count as f64, s.min, s.max)));
+        match items.is_empty() {
+            true => None,
+            false => Some(items.join(" ")),
+        }
+    }
+
+}
+
+impl MetricsSink for LogMetrics {
+    fn counter(&self, name: &str, value: u64) {
+        *self.counters.borrow_mut().entry(name.to_string()).or_default() += value;
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        self.gauges.borrow_mut().insert(name.to_string(), value);
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        let mut histograms = self.histograms.borrow_mut();
+        let s = histograms.entry(name.to_string()).or_default();
+        if s.count == 0 || value < s.min { s.min = value; }
+        if s.count == 0 || value > s.max { s.max = value; }
+        s.count += 1;
+        s.sum += value;
+    }
+
+    fn flush(&self) {
+        if let Some(report) = self.take_report() {
+            log::info!("metrics: {report}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_metrics() {
+        let metrics = LogMetrics::default();
+        assert_eq!(None, metrics.take_report());
+
+        metrics.counter("dns.queries", 1);
+        metrics.counter("dns.queries", 2);
+        metrics.gauge("dns.pending", 5.0);
+        metrics.histogram("dns.latency_ms", 4.0);
+        metrics.histogram("dns.latency_ms", 2.0);
+        assert_eq!(Some("dns.queries=3 dns.pending=5 dns.latency_ms=[n=2 avg=3.0 min=2.0 max=4.0]".to_string()),
+                metrics.take_report());
+
+        // 计数器及直方图清零, 仪表保留最新值
+        assert_eq!(Some("dns.pending=5".to_string()), metrics.take_report());
+    }
+}
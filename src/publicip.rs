@@ -0,0 +1,236 @@
+//! 公网ip检测, 用于NAT或代理后面的主机获取自身的公网地址
+//!
+//! 支持两种检测方式:
+//! - `http://host[:port][/path]`, `https://host[:port][/path]` 访问返回纯文本ip的http接口(如https://api.ipify.org),
+//!   本项目不含tls实现, https接口通过系统的curl命令访问, 未安装curl时返回错误
+//! - `stun://host[:port]` 向stun服务器发送绑定请求(RFC 5389), 端口缺省为3478
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use super::httputil;
+
+const STUN_PREFIX: &str         = "stun://";
+const CURL_COMMAND: &str        = "curl";       // 访问https接口的命令
+const STUN_DEFAULT_PORT: u16    = 3478;         // stun服务缺省端口
+const STUN_RETRIES: u32         = 2;            // 超时后的重试次数
+const STUN_MAGIC_COOKIE: u32    = 0x2112A442;   // RFC 5389规定的固定值
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16  = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_XOR_MAPPED_ADDRESS_OLD: u16 = 0x8020;   // 部分旧版本服务器使用的类型值
+
+/// 通过`source`检测本机的公网ip, `source`格式见模块说明, `timeout`为每次请求的超时时间
+pub fn detect(source: &str, timeout: Duration) -> Result<IpAddr> {
+    if let Some(addr) = source.strip_prefix(STUN_PREFIX) {
+        detect_by_stun(addr.trim_end_matches('/'), timeout)
+    } else if source.starts_with("http://") {
+        detect_by_http(source, timeout)
+    } else if source.starts_with("https://") {
+        detect_by_curl(source, timeout)
+    } else {
+        anyhow::bail!("unsupported ip detect source {source}, must be http(s)://... or stun://host[:port]")
+    }
+}
+
+// 访问http接口, 回复内容为纯文本的ip地址
+fn detect_by_http(url: &str, timeout: Duration) -> Result<IpAddr> {
+    let res = httputil::request("GET", url, &[("Accept", "text/plain")], &[], timeout)?;
+    if res.status != 200 {
+        anyhow::bail!("detect ip from {url} failed, http status {}", res.status);
+    }
+    parse_reply(url, &res.body)
+}
+
+// 通过curl命令访问https接口, curl负责tls握手及证书校验
+fn detect_by_curl(url: &str, timeout: Duration) -> Result<IpAddr> {
+    let output = std::process::Command::new(CURL_COMMAND)
+        .args(["-fsS", "--proto", "=https", "--max-time", &format!("{:.3}", timeout.as_secs_f64()),
+            "-H", "Accept: text/plain", url])
+        .output()
+        .with_context(|| format!("detect ip from {url} failed, https requires the {CURL_COMMAND} command"))?;
+    if !output.status.success() {
+        anyhow::bail!("detect ip from {url} failed, {CURL_COMMAND} exit with {}: {}",
+            output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    parse_reply(url, &output.stdout)
+}
+
+// 解析http接口回复的纯文本ip地址
+fn parse_reply(url: &str, body: &[u8]) -> Result<IpAddr> {
+    let body = String::from_utf8_lossy(body);
+    body.trim().parse()
+        .with_context(|| format!("detect ip from {url} failed, invalid reply: {}", body.trim()))
+}
+
+// 向stun服务器发送绑定请求, 从回复中获取映射地址
+fn detect_by_stun(addr: &str, timeout: Duration) -> Result<IpAddr> {
+    let server = stun_server(addr)?;
+    let bind_addr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let tid = transaction_id();
+    let request = binding_request(&tid);
+    let mut buf = [0u8; 1024];
+    for _ in 0..=STUN_RETRIES {
+        socket.send(&request)?;
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(n) => match parse_binding_response(&buf[..n], &tid) {
+                    // 忽略事务id不一致的回复(如上一次超时请求的迟到回复)
+                    Ok(None) => continue,
+                    Ok(Some(ip)) => return Ok(ip),
+                    Err(e) => return Err(e.context(format!("stun server {server} reply error"))),
+                },
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e).with_context(|| format!("receive from stun server {server} failed")),
+            }
+        }
+        log::debug!("stun request to {server} timeout");
+    }
+    anyhow::bail!("stun server {server} no reply")
+}
+
+// 解析stun服务器地址, 格式为`host`或`host:port`, 未指定端口时使用3478
+fn stun_server(addr: &str) -> Result<SocketAddr> {
+    if let Ok(server) = addr.parse::<SocketAddr>() {
+        return Ok(server);
+    }
+    if let Ok(ip) = addr.trim_start_matches('[').trim_end_matches(']').parse() {
+        return Ok(SocketAddr::new(ip, STUN_DEFAULT_PORT));
+    }
+    let host = if addr.contains(':') { addr.to_string() } else { format!("{addr}:{STUN_DEFAULT_PORT}") };
+    host.to_socket_addrs()
+        .with_context(|| format!("can't resolve stun server {addr}"))?
+        .next()
+        .with_context(|| format!("can't resolve stun server {addr}"))
+}
+
+// 基于当前时间生成事务id
+fn transaction_id() -> [u8; 12] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let mut tid = [0u8; 12];
+    tid[..8].copy_from_slice(&(now.as_nanos() as u64).to_be_bytes());
+    tid[8..].copy_from_slice(&std::process::id().to_be_bytes());
+    tid
+}
+
+// 生成不带任何属性的绑定请求
+fn binding_request(tid: &[u8; 12]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(20);
+    data.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    data.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    data.extend_from_slice(tid);
+    data
+}
+
+// 解析绑定回复, 事务id不一致时返回None
+fn parse_binding_response(data: &[u8], tid: &[u8; 12]) -> Result<Option<IpAddr>> {
+    if data.len() < 20 || data[8..20] != tid[..] {
+        return Ok(None);
+    }
+    let msg_type = u16::from_be_bytes([data[0], data[1]]);
+    if msg_type != STUN_BINDING_SUCCESS {
+        anyhow::bail!("unexpected stun message type 0x{msg_type:04x}");
+    }
+    let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let attrs = data.get(20..20 + len).context("stun message length error")?;
+
+    let mut mapped = None;
+    let mut pos = 0;
+    while pos + 4 <= attrs.len() {
+        let attr_type = u16::from_be_bytes([attrs[pos], attrs[pos + 1]]);
+        let attr_len = u16::from_be_bytes([attrs[pos + 2], attrs[pos + 3]]) as usize;
+        let value = attrs.get(pos + 4..pos + 4 + attr_len).context("stun attribute length error")?;
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS | ATTR_XOR_MAPPED_ADDRESS_OLD => return parse_address(value, Some(&data[4..20])).map(Some),
+            ATTR_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {},
+        }
+        // 属性值按4字节对齐
+        pos += 4 + attr_len.div_ceil(4) * 4;
+    }
+    mapped.map(Some).context("stun reply not contains mapped address")
+}
+
+// 解析地址属性值, `xor_key`为魔数及事务id, 不为None时表示地址经过异或处理
+fn parse_address(value: &[u8], xor_key: Option<&[u8]>) -> Result<IpAddr> {
+    let xor = |addr: &[u8]| -> Vec<u8> {
+        match xor_key {
+            Some(key) => addr.iter().zip(key).map(|(a, k)| a ^ k).collect(),
+            None => addr.to_vec(),
+        }
+    };
+    match (value.get(1), value.len()) {
+        (Some(0x01), 8) => {
+            let addr: [u8; 4] = xor(&value[4..8]).try_into().unwrap();
+            Ok(IpAddr::V4(Ipv4Addr::from(addr)))
+        },
+        (Some(0x02), 20) => {
+            let addr: [u8; 16] = xor(&value[4..20]).try_into().unwrap();
+            Ok(IpAddr::V6(Ipv6Addr::from(addr)))
+        },
+        _ => anyhow::bail!("stun address attribute format error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_binding_response() {
+        let tid = [1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let request = binding_request(&tid);
+        assert_eq!(20, request.len());
+        assert_eq!(&[0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42], &request[..8]);
+
+        // 带SOFTWARE属性(需要填充)及XOR-MAPPED-ADDRESS, 地址为203.0.113.5:54321
+        let mut reply = vec![0x01, 0x01, 0x00, 0x14, 0x21, 0x12, 0xa4, 0x42];
+        reply.extend_from_slice(&tid);
+        reply.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0x00]);
+        reply.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+        reply.extend_from_slice(&(54321u16 ^ 0x2112).to_be_bytes());
+        reply.extend_from_slice(&[203 ^ 0x21, 0x12, 113 ^ 0xa4, 5 ^ 0x42]);
+        assert_eq!(Some("203.0.113.5".parse().unwrap()), parse_binding_response(&reply, &tid).unwrap());
+
+        // 事务id不一致
+        assert_eq!(None, parse_binding_response(&reply, &[0u8; 12]).unwrap());
+
+        // 仅有MAPPED-ADDRESS
+        let mut reply = vec![0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+        reply.extend_from_slice(&tid);
+        reply.extend_from_slice(&[0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x12, 0x34, 198, 51, 100, 7]);
+        assert_eq!(Some("198.51.100.7".parse().unwrap()), parse_binding_response(&reply, &tid).unwrap());
+
+        // 错误回复
+        reply[1] = 0x11;
+        assert!(parse_binding_response(&reply, &tid).is_err());
+    }
+
+    #[test]
+    fn test_detect_by_stun() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(20, n);
+            let mut reply = vec![0x01, 0x01, 0x00, 0x0c];
+            reply.extend_from_slice(&buf[4..20]);
+            reply.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0x00, 0x00]);
+            let IpAddr::V4(ip) = from.ip() else { unreachable!() };
+            reply.extend(ip.octets().iter().zip(STUN_MAGIC_COOKIE.to_be_bytes()).map(|(a, k)| a ^ k));
+            server.send_to(&reply, from).unwrap();
+        });
+        let ip = detect(&format!("stun://{addr}"), Duration::from_secs(2)).unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), ip);
+
+        assert!(detect("https://127.0.0.1:1/", Duration::from_secs(1)).is_err());
+        assert!(detect("ftp://a.b", Duration::from_secs(1)).is_err());
+    }
+}
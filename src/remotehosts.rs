@@ -0,0 +1,48 @@
+use std::time::Duration;
+use anyhow::Result;
+use super::hostsconf::HostsConfig;
+use super::httputil;
+
+const FETCH_TIMEOUT: u64 = 30;    // 下载超时时间(秒)
+
+/// 通过http下载的远程hosts/屏蔽列表, 使用ETag/If-Modified-Since避免重复下载未变化的内容
+pub struct RemoteHosts {
+    url          : String,           // 下载地址
+    etag         : Option<String>,   // 上次下载响应的ETag
+    last_modified: Option<String>,   // 上次下载响应的Last-Modified
+}
+
+impl RemoteHosts {
+
+    pub fn new(url: &str) -> RemoteHosts {
+        RemoteHosts { url: url.to_string(), etag: None, last_modified: None }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// 下载hosts内容, 返回该内容的解析器, 内容未变化(304)时返回None
+    pub fn fetch(&mut self) -> Result<Option<HostsConfig>> {
+        let mut headers = Vec::new();
+        if let Some(ref etag) = self.etag {
+            headers.push(("If-None-Match", etag.as_str()));
+        }
+        if let Some(ref last_modified) = self.last_modified {
+            headers.push(("If-Modified-Since", last_modified.as_str()));
+        }
+
+        let res = httputil::request("GET", &self.url, &headers, &[], Duration::from_secs(FETCH_TIMEOUT))?;
+        match res.status {
+            304 => return Ok(None),
+            200 => {},
+            status => anyhow::bail!("fetch {} response status {status}", self.url),
+        }
+
+        self.etag = res.header("ETag").map(String::from);
+        self.last_modified = res.header("Last-Modified").map(String::from);
+
+        Ok(Some(HostsConfig::with_data(&self.url, res.body)))
+    }
+
+}
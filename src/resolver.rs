@@ -0,0 +1,405 @@
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use super::dnsserver::DnsServer;
+use super::dnsutil::{DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+const CACHE_MAX_ENTRIES: usize = 10000;   // 缓存的最大条目数
+const STALE_MAX_SECS: u64 = 86400;        // 过期的缓存条目最多保留的时间(秒), 上级dns服务器失败时可用于回复
+const STALE_TTL: u32 = 30;                // 回复过期缓存条目时使用的ttl(RFC 8767的建议值)
+
+/// 解析器的解析结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveResult {
+    Answer(Answers),          // 解析成功, 直接回复客户端
+    Error(ResultCode),        // 回复指定的错误码
+    Forward(IpAddr),          // 转发给指定的上级dns服务器, 收到回复后再应答客户端
+    Next,                     // 无法解析, 交给下一个解析器
+}
+
+/// 应答记录集, 缓存及本地域名表的记录集以共享方式引用而不复制,
+/// 回复时以`ttl`(不为None时)替换各记录的ttl, 如缓存条目的剩余时间
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answers {
+    pub records: Arc<[DnsRecord]>,   // 应答记录
+    pub ttl    : Option<u32>,        // 回复时使用的ttl, 为None时使用记录自身的ttl
+}
+
+impl Answers {
+    pub fn new(records: Arc<[DnsRecord]>, ttl: Option<u32>) -> Answers {
+        Answers { records, ttl }
+    }
+
+    /// 替换ttl后的记录副本
+    pub fn to_vec(&self) -> Vec<DnsRecord> {
+        self.records.iter().cloned().map(|mut rec| {
+            if let Some(ttl) = self.ttl {
+                rec.set_ttl(ttl);
+            }
+            rec
+        }).collect()
+    }
+}
+
+impl From<Vec<DnsRecord>> for Answers {
+    fn from(records: Vec<DnsRecord>) -> Self {
+        Answers { records: records.into(), ttl: None }
+    }
+}
+
+/// 以(域名, 查询类型)为键的字典, 以借用的域名查找, 查找时不分配内存
+pub(crate) struct AnswerMap<V> {
+    map: HashMap<(Box<str>, QueryType), V>,
+}
+
+/// 键的借用形式, 拥有及借用两种形式的哈希及比较结果一致
+pub(crate) trait AnswerKey {
+    fn key(&self) -> (&str, QueryType);
+}
+
+impl AnswerKey for (Box<str>, QueryType) {
+    fn key(&self) -> (&str, QueryType) {
+        (&self.0, self.1)
+    }
+}
+
+impl AnswerKey for (&str, QueryType) {
+    fn key(&self) -> (&str, QueryType) {
+        (self.0, self.1)
+    }
+}
+
+impl<'a> Borrow<dyn AnswerKey + 'a> for (Box<str>, QueryType) {
+    fn borrow(&self) -> &(dyn AnswerKey + 'a) {
+        self
+    }
+}
+
+impl Hash for dyn AnswerKey + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+impl PartialEq for dyn AnswerKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for dyn AnswerKey + '_ {}
+
+impl<V> Default for AnswerMap<V> {
+    fn default() -> Self {
+        AnswerMap { map: HashMap::new() }
+    }
+}
+
+impl<V> AnswerMap<V> {
+    pub fn get(&self, name: &str, qtype: QueryType) -> Option<&V> {
+        self.map.get(&(name, qtype) as &dyn AnswerKey)
+    }
+
+    pub fn insert(&mut self, name: &str, qtype: QueryType, value: V) {
+        self.map.insert((name.into(), qtype), value);
+    }
+
+    pub fn remove(&mut self, name: &str, qtype: QueryType) -> Option<V> {
+        self.map.remove(&(name, qtype) as &dyn AnswerKey)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, QueryType, &V)> {
+        self.map.iter().map(|((name, qtype), value)| (&name[..], *qtype, value))
+    }
+}
+
+/// 解析时可以访问的服务器上下文
+pub struct ResolveContext<'a> {
+    pub client: SocketAddr,    // 查询客户端地址
+    server: &'a DnsServer,
+}
+
+impl<'a> ResolveContext<'a> {
+
+    pub(crate) fn new(server: &'a DnsServer, client: SocketAddr) -> ResolveContext<'a> {
+        ResolveContext { client, server }
+    }
+
+    /// 在服务器的本地域名表(静态域名、动态域名及远程hosts)中查找
+    pub fn local_lookup(&self, qname: &str, qtype: QueryType) -> Option<Arc<[DnsRecord]>> {
+        self.server.local_lookup(qname, qtype)
+    }
+
+}
+
+/// 解析器, DnsServer按顺序调用解析链中的解析器, 直到某个解析器给出结果
+pub trait Resolver {
+    /// 解析器名称, 用于日志及指标名称
+    fn name(&self) -> &'static str;
+
+    fn lookup(&mut self, ctx: &ResolveContext, question: &DnsQuestion) -> ResolveResult;
+
+    /// 是否依赖上级dns服务器(转发或缓存上级的应答), 客户端不期望递归查询(RD=0)时跳过,
+    /// 解析链中存在这类解析器时回复设置RA标志
+    fn recursive(&self) -> bool {
+        false
+    }
+
+    /// 收到上级dns服务器的回复后调用, 可用于缓存结果
+    fn on_response(&mut self, _question: &DnsQuestion, _rescode: ResultCode, _answers: &[DnsRecord]) {}
+
+    /// 上级dns服务器失败且失败策略为stale时调用, 返回可以使用的过期结果
+    fn stale(&mut self, _question: &DnsQuestion) -> Option<Answers> {
+        None
+    }
+}
+
+/// 本地域名表解析器
+pub struct LocalResolver;
+
+impl Resolver for LocalResolver {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn lookup(&mut self, ctx: &ResolveContext, question: &DnsQuestion) -> ResolveResult {
+        match ctx.local_lookup(&question.name, question.qtype) {
+            Some(records) => ResolveResult::Answer(Answers::new(records, None)),
+            None => ResolveResult::Next,
+        }
+    }
+}
+
+/// 转发解析器, 将查询转发给上级dns服务器, 未指定上级dns服务器(0.0.0.0)时回复域名不存在
+pub struct ForwardResolver {
+    upstream: IpAddr,   // 上级dns服务器地址
+}
+
+impl ForwardResolver {
+    pub fn new(upstream: IpAddr) -> ForwardResolver {
+        ForwardResolver { upstream }
+    }
+}
+
+impl Resolver for ForwardResolver {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    fn recursive(&self) -> bool {
+        !self.upstream.is_unspecified()
+    }
+
+    fn lookup(&mut self, _ctx: &ResolveContext, _question: &DnsQuestion) -> ResolveResult {
+        match self.upstream.is_unspecified() {
+            true => ResolveResult::Error(ResultCode::NXDOMAIN),
+            false => ResolveResult::Forward(self.upstream),
+        }
+    }
+}
+
+/// 缓存解析器, 缓存上级dns服务器的成功应答, 在最小ttl内直接回复, 回复的ttl为剩余时间,
+/// 过期的条目继续保留一天, 上级dns服务器失败时可以用于回复(serve stale),
+/// 缓存已满时淘汰最早过期的条目, 命中时共享缓存的记录集, 剩余时间在回复时写入
+pub struct CacheResolver {
+    entries    : AnswerMap<(u64, Arc<[DnsRecord]>)>,      // 缓存条目及其过期时间
+    expiry     : BinaryHeap<Reverse<(u64, Box<str>, u16)>>, // 条目的过期时间索引, 可能包含已更新或删除条目的过期项
+    max_entries: usize,                                   // 最大条目数
+}
+
+impl Default for CacheResolver {
+    fn default() -> Self {
+        CacheResolver::new(CACHE_MAX_ENTRIES)
+    }
+}
+
+impl CacheResolver {
+    pub fn new(max_entries: usize) -> CacheResolver {
+        CacheResolver { entries: AnswerMap::default(), expiry: BinaryHeap::new(), max_entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn get(&mut self, question: &DnsQuestion, now: u64) -> Option<Answers> {
+        let &(expire, ref records) = self.entries.get(&question.name, question.qtype)?;
+        if expire > now {
+            return Some(Answers::new(records.clone(), Some((expire - now) as u32)));
+        }
+        if expire + STALE_MAX_SECS <= now {
+            self.entries.remove(&question.name, question.qtype);
+        }
+        None
+    }
+
+    /// 查找过期时间不超过一天的条目, 已过期的条目使用固定的短ttl
+    fn get_stale(&self, question: &DnsQuestion, now: u64) -> Option<Answers> {
+        let (expire, records) = self.entries.get(&question.name, question.qtype)?;
+        if *expire + STALE_MAX_SECS <= now {
+            return None;
+        }
+        let ttl = match *expire > now {
+            true => (*expire - now) as u32,
+            false => STALE_TTL,
+        };
+        Some(Answers::new(records.clone(), Some(ttl)))
+    }
+
+    fn put(&mut self, question: &DnsQuestion, answers: &[DnsRecord], now: u64) {
+        let ttl = match answers.iter().map(DnsRecord::ttl).min() {
+            Some(ttl) if ttl > 0 => ttl as u64,
+            _ => return,
+        };
+        let (name, qtype) = (&question.name, question.qtype);
+        if self.entries.len() >= self.max_entries && self.entries.get(name, qtype).is_none() {
+            self.evict();
+        }
+        self.expiry.push(Reverse((now + ttl, name.as_str().into(), qtype.to_num())));
+        self.entries.insert(name, qtype, (now + ttl, answers.into()));
+        // 同一条目多次更新留下的过期项过多时重建索引
+        if self.expiry.len() > self.entries.len() * 2 + 16 {
+            self.expiry = self.entries.iter()
+                .map(|(name, qtype, (expire, _))| Reverse((*expire, name.into(), qtype.to_num())))
+                .collect();
+        }
+    }
+
+    /// 淘汰最早过期的条目, 跳过索引中已更新或删除条目的过期项
+    fn evict(&mut self) {
+        while let Some(Reverse((expire, name, qtype))) = self.expiry.pop() {
+            let qtype = QueryType::from_num(qtype);
+            if self.entries.get(&name, qtype).is_some_and(|(e, _)| *e == expire) {
+                self.entries.remove(&name, qtype);
+                return;
+            }
+        }
+    }
+}
+
+impl Resolver for CacheResolver {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    fn recursive(&self) -> bool {
+        true
+    }
+
+    fn lookup(&mut self, _ctx: &ResolveContext, question: &DnsQuestion) -> ResolveResult {
+        match self.get(question, now_of_unix()) {
+            Some(answers) => ResolveResult::Answer(answers),
+            None => ResolveResult::Next,
+        }
+    }
+
+    fn on_response(&mut self, question: &DnsQuestion, rescode: ResultCode, answers: &[DnsRecord]) {
+        if rescode == ResultCode::NOERROR {
+            self.put(question, answers, now_of_unix());
+        }
+    }
+
+    fn stale(&mut self, question: &DnsQuestion) -> Option<Answers> {
+        self.get_stale(question, now_of_unix())
+    }
+}
+
+/// 根据逗号分隔的名称(local/cache/forward)创建解析链
+pub fn resolver_chain(names: &str, upstream: IpAddr) -> Result<Vec<Box<dyn Resolver>>> {
+    let mut chain: Vec<Box<dyn Resolver>> = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match name {
+            "local" => chain.push(Box::new(LocalResolver)),
+            "cache" => chain.push(Box::<CacheResolver>::default()),
+            "forward" => chain.push(Box::new(ForwardResolver::new(upstream))),
+            _ => anyhow::bail!("unknown resolver {name}, expect local/cache/forward"),
+        }
+    }
+    if chain.is_empty() {
+        anyhow::bail!("resolver chain is empty");
+    }
+    Ok(chain)
+}
+
+fn now_of_unix() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let mut cache = CacheResolver::new(1);
+        let question = DnsQuestion::new("www.lan".to_string(), QueryType::A);
+        let answers = [
+            DnsRecord::A { domain: "www.lan".to_string(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl: 60 },
+            DnsRecord::A { domain: "www.lan".to_string(), addr: Ipv4Addr::new(10, 0, 0, 2), ttl: 30 },
+        ];
+        cache.put(&question, &answers, 100);
+        assert_eq!(1, cache.len());
+
+        let cached = cache.get(&question, 110).unwrap();
+        assert_eq!(vec![20, 20], cached.to_vec().iter().map(DnsRecord::ttl).collect::<Vec<_>>());
+        // 命中时共享缓存的记录集, 记录本身的ttl不变
+        assert!(Arc::ptr_eq(&cached.records, &cache.get(&question, 111).unwrap().records));
+        assert_eq!(60, cached.records[0].ttl());
+        assert!(cache.get(&DnsQuestion::new("www.lan".to_string(), QueryType::AAAA), 110).is_none());
+
+        // 缓存已满时淘汰最早过期的条目, 过期后移除
+        let other = DnsQuestion::new("nas.lan".to_string(), QueryType::A);
+        cache.put(&other, &answers, 110);
+        assert_eq!(1, cache.len());
+        assert!(cache.get_stale(&question, 110).is_none());
+        assert!(cache.get(&other, 110).is_some());
+        assert!(cache.get(&other, 140).is_none());
+
+        // 过期条目保留一段时间, 用于上级dns服务器失败时回复
+        let stale = cache.get_stale(&other, 140).unwrap();
+        assert_eq!(vec![STALE_TTL, STALE_TTL], stale.to_vec().iter().map(DnsRecord::ttl).collect::<Vec<_>>());
+        assert!(cache.get_stale(&other, 110 + 30 + STALE_MAX_SECS).is_none());
+        assert!(cache.get(&other, 110 + 30 + STALE_MAX_SECS).is_none());
+        assert!(cache.is_empty());
+
+        // ttl为0的应答不缓存
+        let answers = [DnsRecord::A { domain: "nas.lan".to_string(), addr: Ipv4Addr::new(10, 0, 0, 3), ttl: 0 }];
+        cache.put(&other, &answers, 110);
+        assert!(cache.is_empty());
+
+        // 反复更新同一条目时过期时间索引不会无限增长
+        let answers = [DnsRecord::A { domain: "nas.lan".to_string(), addr: Ipv4Addr::new(10, 0, 0, 3), ttl: 60 }];
+        (0..100).for_each(|i| cache.put(&other, &answers, 200 + i));
+        assert!(cache.expiry.len() <= 2 + 16);
+    }
+
+    #[test]
+    fn test_resolver_chain() {
+        let chain = resolver_chain("local, cache,forward", IpAddr::V4(Ipv4Addr::new(223, 5, 5, 5))).unwrap();
+        assert_eq!(vec!["local", "cache", "forward"], chain.iter().map(|r| r.name()).collect::<Vec<_>>());
+        assert!(resolver_chain("local,script", IpAddr::V4(Ipv4Addr::UNSPECIFIED)).is_err());
+        assert!(resolver_chain("", IpAddr::V4(Ipv4Addr::UNSPECIFIED)).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use anyhow::{Context, Result};
+use chrono::{Datelike, Timelike};
+use super::dnsutil::{DnsQuestion, DnsRecord, QueryType, ResultCode};
+use super::hostsconf::wildcard_match;
+use super::resolver::{ResolveContext, ResolveResult, Resolver};
+
+const RULE_ARROW: &str = "=>";   // 条件与动作的分隔符
+const COND_AND: &str = " and ";  // 条件之间的连接符
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];  // 星期的名称
+
+/// 规则脚本解析器, 在转发之前按规则决定如何回复
+///
+/// 脚本每行一条规则, 格式为`条件 and 条件 ... => 动作`, 以'#'开头的行为注释,
+/// 按顺序匹配, 第一条所有条件都满足的规则生效, 没有规则匹配时交给下一个解析器
+///
+/// 条件:
+/// - `qname == www.lan`, `qname != www.lan`: 域名相等/不等
+/// - `qname ~ *.ads.com`, `qname !~ *.lan`: 域名通配符匹配(*及?)
+/// - `qtype == AAAA`, `qtype != A`: 查询类型
+/// - `client == 192.168.1.9`, `client ~ 192.168.1.*`, `client in 192.168.1.0/24`: 客户端地址
+/// - `time in 09:00-17:00`: 查询时的本地时间在时间段内, 结束时间早于开始时间时跨越午夜(如22:00-06:00)
+/// - `day in mon-fri`, `day in sat,sun`: 查询时是星期几
+/// - `*`: 总是满足
+///
+/// 动作:
+/// - `answer [ip,ip...] [ttl]`: A查询回复指定地址, 其它类型的查询回复空应答
+/// - `rewrite host`: 回复指向host的别名记录, host在本地可解析时同时回复其记录
+/// - `nxdomain`, `refused`, `servfail`: 回复对应的错误码
+/// - `continue`: 停止匹配后续规则, 继续正常解析
+///
+/// 时间条件在每次查询时计算, 可用于定时屏蔽, 如工作日上课时间屏蔽社交网站, 但允许其中的学习网站:
+/// ```text
+/// qname ~ *.edu.example.com and day in mon-fri and time in 08:00-16:30 => continue
+/// qname ~ *.social.com and day in mon-fri and time in 08:00-16:30 => nxdomain
+/// ```
+///
+/// 没有嵌入rhai/lua等通用脚本解释器, 规则只能表达上述条件及动作的组合,
+/// 需要循环、变量等更复杂逻辑的需求不在支持范围内, 加载.rhai/.lua文件时返回错误而不是按规则解析
+pub struct ScriptResolver {
+    rules: Vec<Rule>,   // 规则列表
+    ttl  : u32,         // 未指定ttl时回复记录的生存时间
+}
+
+struct Rule {
+    line  : usize,       // 规则所在行号, 用于日志
+    conds : Vec<Cond>,   // 所有条件都满足时规则生效
+    action: Action,      // 规则生效时的动作
+}
+
+enum Cond {
+    Any,
+    Qname(Op, String),
+    Qtype(Op, String),
+    Client(Op, String),
+    ClientIn(u32, u32),   // 网络地址及掩码
+    Time(u32, u32),       // 开始及结束时间(当天的分钟数), 包含开始时间, 不包含结束时间
+    Day(u8),              // 星期的位集合, 最低位为星期一
+}
+
+// 查询时的本地时间
+#[derive(Clone, Copy)]
+struct Moment {
+    weekday: u8,    // 星期几, 0为星期一
+    minute : u32,   // 当天的分钟数
+}
+
+impl Moment {
+    fn now() -> Moment {
+        let now = chrono::Local::now();
+        Moment { weekday: now.weekday().num_days_from_monday() as u8, minute: now.hour() * 60 + now.minute() }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,         // ==
+    Ne,         // !=
+    Match,      // ~
+    NotMatch,   // !~
+}
+
+enum Action {
+    Answer(Vec<Ipv4Addr>, Option<u32>),
+    Rewrite(String),
+    Error(ResultCode),
+    Continue,
+}
+
+impl ScriptResolver {
+
+    /// 加载规则脚本文件
+    pub fn load(path: &str, ttl: u32) -> Result<ScriptResolver> {
+        if let Some(ext) = std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            if ext.eq_ignore_ascii_case("rhai") || ext.eq_ignore_ascii_case("lua") {
+                anyhow::bail!("script file {path}: {ext} scripts are not supported, only the rule syntax is available");
+            }
+        }
+        let text = std::fs::read_to_string(path)
+                .with_context(|| format!("read script file {path} failed"))?;
+        ScriptResolver::parse(&text, ttl).with_context(|| format!("parse script file {path} failed"))
+    }
+
+    /// 解析规则脚本
+    pub fn parse(text: &str, ttl: u32) -> Result<ScriptResolver> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(line, i + 1).with_context(|| format!("line {}: {line}", i + 1))?;
+            rules.push(rule);
+        }
+        Ok(ScriptResolver { rules, ttl })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+}
+
+impl Resolver for ScriptResolver {
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn lookup(&mut self, ctx: &ResolveContext, question: &DnsQuestion) -> ResolveResult {
+        let now = Moment::now();
+        let rule = match self.rules.iter().find(|r| r.conds.iter().all(|c| c.matches(&ctx.client, question, now))) {
+            Some(rule) => rule,
+            None => return ResolveResult::Next,
+        };
+        log::debug!("script rule at line {} matched {}", rule.line, question.name);
+
+        match rule.action {
+            Action::Answer(ref addrs, ttl) => {
+                let ttl = ttl.unwrap_or(self.ttl);
+                let answers = match question.qtype {
+                    QueryType::A => addrs.iter()
+                        .map(|addr| DnsRecord::A { domain: question.name.clone(), addr: *addr, ttl })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                ResolveResult::Answer(answers.into())
+            },
+            Action::Rewrite(ref host) => {
+                let mut answers = vec![DnsRecord::CNAME { domain: question.name.clone(), host: host.clone(), ttl: self.ttl }];
+                if question.qtype != QueryType::CNAME {
+                    answers.extend(ctx.local_lookup(host, question.qtype).iter().flat_map(|r| r.iter().cloned()));
+                }
+                ResolveResult::Answer(answers.into())
+            },
+            Action::Error(rescode) => ResolveResult::Error(rescode),
+            Action::Continue => ResolveResult::Next,
+        }
+    }
+}
+
+impl Cond {
+    fn matches(&self, client: &SocketAddr, question: &DnsQuestion, now: Moment) -> bool {
+        match self {
+            Cond::Any => true,
+            Cond::Qname(op, value) => op.apply(value, &question.name),
+            Cond::Qtype(op, value) => op.apply(value, &question.qtype.to_string()),
+            Cond::Client(op, value) => op.apply(value, &client.ip().to_string()),
+            Cond::ClientIn(net, mask) => match client.ip() {
+                IpAddr::V4(ip) => u32::from(ip) & mask == *net,
+                IpAddr::V6(_) => false,
+            },
+            Cond::Time(start, end) => match start <= end {
+                true => *start <= now.minute && now.minute < *end,
+                false => *start <= now.minute || now.minute < *end,
+            },
+            Cond::Day(days) => days & (1 << now.weekday) != 0,
+        }
+    }
+}
+
+impl Op {
+    fn apply(self, value: &str, text: &str) -> bool {
+        match self {
+            Op::Eq => value == text,
+            Op::Ne => value != text,
+            Op::Match => wildcard_match(value.as_bytes(), text.as_bytes()),
+            Op::NotMatch => !wildcard_match(value.as_bytes(), text.as_bytes()),
+        }
+    }
+}
+
+fn parse_rule(line: &str, line_no: usize) -> Result<Rule> {
+    let (conds, action) = match line.split_once(RULE_ARROW) {
+        Some(v) => v,
+        None => anyhow::bail!("missing {RULE_ARROW}"),
+    };
+    let conds = conds.split(COND_AND).map(parse_cond).collect::<Result<Vec<_>>>()?;
+    Ok(Rule { line: line_no, conds, action: parse_action(action)? })
+}
+
+fn parse_cond(cond: &str) -> Result<Cond> {
+    let tokens: Vec<&str> = cond.split_whitespace().collect();
+    let (field, op, value) = match tokens[..] {
+        ["*"] => return Ok(Cond::Any),
+        [field, op, value] => (field, op, value),
+        _ => anyhow::bail!("condition '{}' format error", cond.trim()),
+    };
+
+    match (field, op) {
+        ("client", "in") => return parse_net(value).map(|(net, mask)| Cond::ClientIn(net, mask)),
+        ("time", "in") => return parse_time_range(value).map(|(start, end)| Cond::Time(start, end)),
+        ("day", "in") => return parse_days(value).map(Cond::Day),
+        _ => {},
+    }
+    let op = match op {
+        "==" => Op::Eq,
+        "!=" => Op::Ne,
+        "~" => Op::Match,
+        "!~" => Op::NotMatch,
+        _ => anyhow::bail!("unknown operator {op}"),
+    };
+    match field {
+        "qname" => Ok(Cond::Qname(op, value.trim_end_matches('.').to_ascii_lowercase())),
+        "qtype" => Ok(Cond::Qtype(op, value.to_ascii_uppercase())),
+        "client" => Ok(Cond::Client(op, value.to_string())),
+        _ => anyhow::bail!("unknown field {field}, expect qname/qtype/client/time/day"),
+    }
+}
+
+fn parse_action(action: &str) -> Result<Action> {
+    let tokens: Vec<&str> = action.split_whitespace().collect();
+    match tokens[..] {
+        ["answer"] => Ok(Action::Answer(Vec::new(), None)),
+        ["answer", ips] => Ok(Action::Answer(parse_addrs(ips)?, None)),
+        ["answer", ips, ttl] => {
+            let ttl = ttl.parse().with_context(|| format!("ttl {ttl} format error"))?;
+            Ok(Action::Answer(parse_addrs(ips)?, Some(ttl)))
+        },
+        ["rewrite", host] => Ok(Action::Rewrite(host.trim_end_matches('.').to_ascii_lowercase())),
+        ["nxdomain"] => Ok(Action::Error(ResultCode::NXDOMAIN)),
+        ["refused"] => Ok(Action::Error(ResultCode::REFUSED)),
+        ["servfail"] => Ok(Action::Error(ResultCode::SERVFAIL)),
+        ["continue"] => Ok(Action::Continue),
+        _ => anyhow::bail!("action '{}' format error", action.trim()),
+    }
+}
+
+fn parse_addrs(ips: &str) -> Result<Vec<Ipv4Addr>> {
+    ips.split(',')
+        .map(|ip| ip.parse().with_context(|| format!("ip address {ip} format error")))
+        .collect()
+}
+
+/// 解析ipv4网段(如192.168.1.0/24), 返回网络地址及掩码
+fn parse_net(value: &str) -> Result<(u32, u32)> {
+    let (ip, bits) = value.split_once('/').unwrap_or((value, "32"));
+    let ip: Ipv4Addr = ip.parse().with_context(|| format!("network {value} format error"))?;
+    let bits: u32 = match bits.parse() {
+        Ok(bits) if bits <= 32 => bits,
+        _ => anyhow::bail!("network {value} prefix length error"),
+    };
+    let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+    Ok((u32::from(ip) & mask, mask))
+}
+
+/// 解析时间段(如09:00-17:00), 返回开始及结束时间在当天的分钟数
+fn parse_time_range(value: &str) -> Result<(u32, u32)> {
+    let parse_time = |time: &str| -> Option<u32> {
+        let (h, m) = time.split_once(':')?;
+        let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+        match (h, m) {
+            (24, 0) => Some(24 * 60),
+            (0..=23, 0..=59) => Some(h * 60 + m),
+            _ => None,
+        }
+    };
+    match value.split_once('-').map(|(start, end)| (parse_time(start), parse_time(end))) {
+        Some((Some(start), Some(end))) if start != end => Ok((start, end)),
+        _ => anyhow::bail!("time range {value} format error, expect like 09:00-17:00"),
+    }
+}
+
+/// 解析星期列表(如mon-fri、sat,sun), 返回星期的位集合
+fn parse_days(value: &str) -> Result<u8> {
+    let day = |name: &str| WEEKDAYS.iter().position(|d| name.eq_ignore_ascii_case(d));
+    let mut days = 0u8;
+    for item in value.split(',') {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (day(first), day(last)),
+            None => (day(item), day(item)),
+        };
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => anyhow::bail!("days {value} format error, expect like mon-fri or sat,sun"),
+        };
+        // 支持跨越周末的范围, 如fri-mon
+        let mut d = first;
+        loop {
+            days |= 1 << d;
+            if d == last {
+                break;
+            }
+            d = (d + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = ScriptResolver::parse("# rules\n\
+            qname ~ *.ads.com => nxdomain\n\
+            qname == tv.lan and client in 192.168.1.0/24 => answer 192.168.1.20,192.168.1.21 60\n\
+            qtype == aaaa => answer\n\
+            qname ~ *.corp.lan => rewrite corp.example.com.\n\
+            * => continue\n", 300).unwrap();
+        assert_eq!(5, script.len());
+
+        let rule_of = |client: &str, name: &str, qtype: QueryType| {
+            let client: SocketAddr = format!("{client}:5353").parse().unwrap();
+            let question = DnsQuestion::new(name.to_string(), qtype);
+            let now = Moment { weekday: 0, minute: 0 };
+            script.rules.iter().find(|r| r.conds.iter().all(|c| c.matches(&client, &question, now))).map(|r| r.line)
+        };
+        assert_eq!(Some(2), rule_of("10.0.0.1", "x.ads.com", QueryType::A));
+        assert_eq!(Some(3), rule_of("192.168.1.7", "tv.lan", QueryType::A));
+        assert_eq!(Some(6), rule_of("192.168.2.7", "tv.lan", QueryType::A));
+        assert_eq!(Some(4), rule_of("192.168.2.7", "tv.lan", QueryType::AAAA));
+        assert_eq!(Some(5), rule_of("10.0.0.9", "git.corp.lan", QueryType::A));
+
+        assert!(ScriptResolver::parse("qname == a.lan", 300).is_err());
+        assert!(ScriptResolver::parse("qname = a.lan => refused", 300).is_err());
+        assert!(ScriptResolver::parse("host == a.lan => refused", 300).is_err());
+        assert!(ScriptResolver::parse("* => answer 1.2.3", 300).is_err());
+        assert!(ScriptResolver::parse("client in 10.0.0.0/33 => refused", 300).is_err());
+        assert!(ScriptResolver::parse("* => drop", 300).is_err());
+        assert!(ScriptResolver::load("rules.Lua", 300).is_err_and(|e| e.to_string().contains("not supported")));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let script = ScriptResolver::parse("qname ~ *.edu.com and day in mon-fri and time in 08:00-16:30 => continue\n\
+            qname ~ *.com and day in Mon-Fri and time in 08:00-16:30 => nxdomain\n\
+            qname ~ *.game.lan and time in 22:00-06:00 => refused\n\
+            qname ~ *.tv.lan and day in fri-sun,wed => refused\n", 300).unwrap();
+        let client: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let rule_at = |name: &str, weekday: u8, hour: u32, minute: u32| {
+            let question = DnsQuestion::new(name.to_string(), QueryType::A);
+            let now = Moment { weekday, minute: hour * 60 + minute };
+            script.rules.iter().find(|r| r.conds.iter().all(|c| c.matches(&client, &question, now))).map(|r| r.line)
+        };
+        assert_eq!(Some(2), rule_at("x.social.com", 0, 8, 0));
+        assert_eq!(Some(1), rule_at("www.edu.com", 4, 12, 0));
+        assert_eq!(None, rule_at("x.social.com", 4, 16, 30));
+        assert_eq!(None, rule_at("x.social.com", 5, 12, 0));
+        assert_eq!(Some(3), rule_at("a.game.lan", 2, 23, 10));
+        assert_eq!(Some(3), rule_at("a.game.lan", 2, 5, 59));
+        assert_eq!(None, rule_at("a.game.lan", 2, 6, 0));
+        assert_eq!(Some(4), rule_at("a.tv.lan", 6, 12, 0));
+        assert_eq!(Some(4), rule_at("a.tv.lan", 2, 12, 0));
+        assert_eq!(None, rule_at("a.tv.lan", 3, 12, 0));
+
+        assert_eq!((0, 1440), parse_time_range("00:00-24:00").unwrap());
+        assert!(parse_time_range("9-17").is_err());
+        assert!(parse_time_range("09:00-09:00").is_err());
+        assert!(parse_time_range("09:00-25:00").is_err());
+        assert_eq!(0b1100001, parse_days("sat-mon").unwrap());
+        assert!(parse_days("weekday").is_err());
+    }
+
+    #[test]
+    fn test_parse_net() {
+        assert_eq!((0xC0A80100, 0xFFFFFF00), parse_net("192.168.1.77/24").unwrap());
+        assert_eq!((0x0A000001, u32::MAX), parse_net("10.0.0.1").unwrap());
+        assert_eq!((0, 0), parse_net("8.8.8.8/0").unwrap());
+    }
+}